@@ -1,36 +1,19 @@
-use axum::{
-    middleware::Next,
-    response::Response,
-    http::{Request, HeaderMap},
-    extract::Extension,
-};
-use governor::{DefaultKeyedRateLimiter, Quota, clock::DefaultClock};
-use std::{sync::Arc, num::NonZeroU32};
-use tokio::sync::Mutex;
+use axum::extract::Request;
+use lotabots_rate_limit::{axum::RateLimitLayer, RateLimit, RateLimitStore};
+use std::sync::Arc;
 
-type RateLimiter = Arc<Mutex<DefaultKeyedRateLimiter<String, DefaultClock>>>;
+/// 100 requests per minute per client. Keep `store` in Redis (see
+/// `lotabots_rate_limit::from_env`) so the limit holds across replicas.
+pub fn create_rate_limiter(store: Arc<dyn RateLimitStore>) -> RateLimitLayer {
+    RateLimitLayer::new(store, "api", RateLimit::per_minute(100)).with_key(forwarded_for)
+}
 
-pub async fn rate_limiter<B>(
-    Extension(limiter): Extension<RateLimiter>,
-    headers: HeaderMap,
-    request: Request<B>,
-    next: Next<B>,
-) -> Result<Response, ApiError> {
-    let ip = headers
+/// First address in `X-Forwarded-For`, as set by the load balancer
+fn forwarded_for(request: &Request) -> Option<String> {
+    request
+        .headers()
         .get("X-Forwarded-For")
         .and_then(|h| h.to_str().ok())
-        .unwrap_or("unknown");
-
-    let mut limiter = limiter.lock().await;
-    
-    if limiter.check_key(&ip.to_string()).is_err() {
-        return Err(ApiError::RateLimitExceeded);
-    }
-
-    Ok(next.run(request).await)
+        .and_then(|h| h.split(',').next())
+        .map(|ip| ip.trim().to_string())
 }
-
-pub fn create_rate_limiter() -> RateLimiter {
-    let quota = Quota::per_minute(NonZeroU32::new(100).unwrap());
-    Arc::new(Mutex::new(DefaultKeyedRateLimiter::keyed(quota)))
-} 
\ No newline at end of file
@@ -0,0 +1,168 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+
+use crate::{Result, Secret, SecretError, SecretManager};
+
+/// Tries several backends in order.
+///
+/// Lookups fall through to the next backend when a secret is missing or a
+/// backend is unavailable. Writes go to the first backend that accepts them,
+/// skipping read-only ones such as the environment.
+#[derive(Clone, Default)]
+pub struct ChainedSecretManager {
+    backends: Vec<Arc<dyn SecretManager>>,
+}
+
+impl ChainedSecretManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, backend: impl SecretManager + 'static) -> Self {
+        self.backends.push(Arc::new(backend));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.backends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backends.is_empty()
+    }
+}
+
+#[async_trait]
+impl SecretManager for ChainedSecretManager {
+    async fn get_secret(&self, name: &str) -> Result<Secret> {
+        let mut last_error = None;
+        for backend in &self.backends {
+            match backend.get_secret(name).await {
+                Ok(secret) => return Ok(secret),
+                Err(e) if e.is_not_found() => {}
+                Err(e) => {
+                    tracing::warn!("Secrets backend failed for {}: {}", name, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        // Report an outage rather than "not found" when a backend that might
+        // hold the secret could not be asked
+        Err(last_error.unwrap_or_else(|| SecretError::NotFound(name.to_string())))
+    }
+
+    async fn get_secret_version(&self, name: &str, version: u64) -> Result<Secret> {
+        let mut last_error = None;
+        for backend in &self.backends {
+            match backend.get_secret_version(name, version).await {
+                Ok(secret) => return Ok(secret),
+                Err(e) if e.is_not_found() => {}
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| SecretError::NotFound(format!("{} version {}", name, version))))
+    }
+
+    async fn create_secret(&self, name: &str, value: &str) -> Result<Secret> {
+        for backend in &self.backends {
+            match backend.create_secret(name, value).await {
+                Err(SecretError::ReadOnly(_)) => continue,
+                result => return result,
+            }
+        }
+        Err(SecretError::ReadOnly("chained"))
+    }
+
+    async fn revoke_secret(&self, name: &str) -> Result<()> {
+        for backend in &self.backends {
+            match backend.revoke_secret(name).await {
+                Err(SecretError::ReadOnly(_)) => continue,
+                result => return result,
+            }
+        }
+        Err(SecretError::ReadOnly("chained"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EncryptedFileSecretManager, EnvSecretManager};
+
+    struct Unavailable;
+
+    #[async_trait]
+    impl SecretManager for Unavailable {
+        async fn get_secret(&self, _name: &str) -> Result<Secret> {
+            Err(SecretError::Backend("connection refused".to_string()))
+        }
+
+        async fn create_secret(&self, _name: &str, _value: &str) -> Result<Secret> {
+            Err(SecretError::Backend("connection refused".to_string()))
+        }
+
+        async fn revoke_secret(&self, _name: &str) -> Result<()> {
+            Err(SecretError::Backend("connection refused".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_in_order() {
+        std::env::set_var("CHAIN_TEST_SHARED", "from-env");
+        std::env::set_var("CHAIN_TEST_ENV_ONLY", "env-only");
+        let dir = tempfile::tempdir().unwrap();
+        let file = EncryptedFileSecretManager::new(dir.path().join("secrets.enc"), &[1; 32]);
+        file.create_secret("CHAIN_TEST_SHARED", "from-file")
+            .await
+            .unwrap();
+
+        let chain = ChainedSecretManager::new()
+            .with(Unavailable)
+            .with(file)
+            .with(EnvSecretManager::new());
+
+        let secret = chain.get_secret("CHAIN_TEST_SHARED").await.unwrap();
+        assert_eq!(secret.value, "from-file");
+        let secret = chain.get_secret("CHAIN_TEST_ENV_ONLY").await.unwrap();
+        assert_eq!(secret.value, "env-only");
+
+        // Missing everywhere, but one backend was down
+        assert!(matches!(
+            chain.get_secret("CHAIN_TEST_MISSING").await,
+            Err(SecretError::Backend(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_writes_skip_read_only_backends() {
+        let dir = tempfile::tempdir().unwrap();
+        let chain = ChainedSecretManager::new()
+            .with(EnvSecretManager::new())
+            .with(EncryptedFileSecretManager::new(
+                dir.path().join("secrets.enc"),
+                &[1; 32],
+            ));
+
+        let created = chain.create_secret("chain/written", "value").await.unwrap();
+        assert_eq!(created.version, Some(1));
+        assert_eq!(
+            chain.get_secret("chain/written").await.unwrap().value,
+            "value"
+        );
+
+        chain.revoke_secret("chain/written").await.unwrap();
+        assert!(chain
+            .get_secret("chain/written")
+            .await
+            .unwrap_err()
+            .is_not_found());
+
+        let env_only = ChainedSecretManager::new().with(EnvSecretManager::new());
+        assert!(matches!(
+            env_only.create_secret("chain/written", "value").await,
+            Err(SecretError::ReadOnly(_))
+        ));
+    }
+}
@@ -0,0 +1,254 @@
+//! A column type that stores its value envelope encrypted.
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+    Decode, Encode, Postgres, Type,
+};
+use std::{fmt, marker::PhantomData, ops::Deref};
+
+use crate::{envelope, Result, SecretError};
+
+/// The column an [`Encrypted`] value is stored in. Values are sealed for
+/// their column, so a ciphertext copied into another column, or another
+/// table, does not decrypt.
+///
+/// They are not sealed for their row or tenant: a ciphertext copied to
+/// another row of the same column still decrypts. Binding the row would
+/// need its id wherever a value is encoded or decoded, which sqlx does not
+/// provide, and would protect little. Copying ciphertexts between rows
+/// takes write access to the table, which can as well rewrite the plain
+/// columns saying whom a row belongs to, such as `tenant_id` or
+/// `password_hash`. The encryption is there to keep values unreadable to
+/// whoever reads the database or its backups, not to authenticate rows.
+pub trait Column {
+    const TABLE: &'static str;
+    const COLUMN: &'static str;
+
+    /// The context values are sealed for: `table.column`
+    fn context() -> String {
+        format!("{}.{}", Self::TABLE, Self::COLUMN)
+    }
+}
+
+/// A value that is written to the `TEXT` column `C` as a sealed envelope and
+/// decrypted when read, using the cipher set with [`envelope::install`].
+///
+/// The value is sealed when it is created, so binding it cannot fail. Rows
+/// that still hold plaintext from before the column was encrypted are read
+/// as is and sealed in memory; [`Encrypted::needs_reencryption`] reports
+/// them, along with values wrapped by an older key-encryption key.
+///
+/// Serializes as the plain value, so API responses are unaffected.
+pub struct Encrypted<T, C> {
+    value: T,
+    sealed: String,
+    stale: bool,
+    column: PhantomData<C>,
+}
+
+impl<T: Serialize, C: Column> Encrypted<T, C> {
+    pub fn new(value: T) -> Result<Self> {
+        let plaintext = serde_json::to_vec(&value)
+            .map_err(|e| SecretError::Crypto(format!("Failed to encode value: {}", e)))?;
+        let sealed = envelope::installed()?.encrypt(&plaintext, C::context().as_bytes())?;
+        Ok(Self {
+            value,
+            sealed,
+            stale: false,
+            column: PhantomData,
+        })
+    }
+}
+
+impl<T, C> Encrypted<T, C> {
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    /// The KEK version the stored value was wrapped with, or `None` if it
+    /// was stored as plaintext
+    pub fn key_version(&self) -> Option<u64> {
+        if self.stale {
+            return None;
+        }
+        envelope::key_version(&self.sealed)
+    }
+
+    /// Whether the stored value is plaintext or wrapped with a KEK other
+    /// than the installed one. Writing the value back fixes both.
+    pub fn needs_reencryption(&self) -> bool {
+        self.stale
+            || envelope::installed()
+                .map(|cipher| self.key_version() != Some(cipher.current_version()))
+                .unwrap_or(false)
+    }
+}
+
+impl<T: DeserializeOwned + Serialize, C: Column> Encrypted<T, C> {
+    fn open(stored: &str) -> Result<Self> {
+        if !envelope::is_sealed(stored) {
+            // Plaintext written before the column was encrypted. Values
+            // that were plain strings are not valid JSON on their own.
+            let value = serde_json::from_str(stored)
+                .or_else(|_| serde_json::from_value(stored.into()))
+                .map_err(|e| SecretError::Crypto(format!("Failed to decode value: {}", e)))?;
+            let mut encrypted = Self::new(value)?;
+            encrypted.stale = true;
+            return Ok(encrypted);
+        }
+
+        let cipher = envelope::installed()?;
+        let plaintext = cipher.decrypt(stored, C::context().as_bytes())?;
+        let value = serde_json::from_slice(&plaintext)
+            .map_err(|e| SecretError::Crypto(format!("Failed to decode value: {}", e)))?;
+        Ok(Self {
+            value,
+            sealed: stored.to_string(),
+            stale: false,
+            column: PhantomData,
+        })
+    }
+}
+
+impl<T: DeserializeOwned + Serialize, C: Column> Encrypted<T, C> {
+    /// The value to store in place of `stored` to bring it up to date, or
+    /// `None` if it already is. Plaintext is encrypted and values sealed
+    /// under an older key-encryption key are re-wrapped without decrypting
+    /// the data.
+    pub fn reseal(stored: &str) -> Result<Option<String>> {
+        if envelope::is_sealed(stored) {
+            envelope::installed()?.reseal(stored)
+        } else {
+            Self::open(stored).map(|encrypted| Some(encrypted.sealed))
+        }
+    }
+}
+
+impl<T, C> Deref for Encrypted<T, C> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: Clone, C> Clone for Encrypted<T, C> {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+            sealed: self.sealed.clone(),
+            stale: self.stale,
+            column: PhantomData,
+        }
+    }
+}
+
+// Keep values out of logs
+impl<T, C> fmt::Debug for Encrypted<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encrypted")
+            .field("value", &"<redacted>")
+            .field("key_version", &self.key_version())
+            .finish()
+    }
+}
+
+impl<T: Serialize, C> Serialize for Encrypted<T, C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.value.serialize(serializer)
+    }
+}
+
+impl<'de, T: Serialize + Deserialize<'de>, C: Column> Deserialize<'de> for Encrypted<T, C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let value = T::deserialize(deserializer)?;
+        Self::new(value).map_err(serde::de::Error::custom)
+    }
+}
+
+impl<T, C> Type<Postgres> for Encrypted<T, C> {
+    fn type_info() -> PgTypeInfo {
+        <str as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <str as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl<T, C> Encode<'_, Postgres> for Encrypted<T, C> {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        <&str as Encode<Postgres>>::encode(self.sealed.as_str(), buf)
+    }
+}
+
+impl<'r, T: DeserializeOwned + Serialize, C: Column> Decode<'r, Postgres> for Encrypted<T, C> {
+    fn decode(value: PgValueRef<'r>) -> std::result::Result<Self, BoxDynError> {
+        let stored = <&str as Decode<Postgres>>::decode(value)?;
+        Ok(Self::open(stored)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::EnvelopeCipher;
+
+    struct MfaSecret;
+
+    impl Column for MfaSecret {
+        const TABLE: &'static str = "users";
+        const COLUMN: &'static str = "mfa_secret";
+    }
+
+    struct Metadata;
+
+    impl Column for Metadata {
+        const TABLE: &'static str = "documents";
+        const COLUMN: &'static str = "metadata";
+    }
+
+    #[test]
+    fn test_encrypted_values() {
+        envelope::install(EnvelopeCipher::new(3, &[9; 32]));
+
+        let secret = Encrypted::<_, MfaSecret>::new("JBSWY3DPEHPK3PXP".to_string()).unwrap();
+        assert_eq!(*secret, "JBSWY3DPEHPK3PXP");
+        assert_eq!(secret.key_version(), Some(3));
+        assert!(!secret.needs_reencryption());
+        assert!(!format!("{:?}", secret).contains("JBSWY3DPEHPK3PXP"));
+
+        let read = Encrypted::<String, MfaSecret>::open(&secret.sealed).unwrap();
+        assert_eq!(read.into_inner(), "JBSWY3DPEHPK3PXP");
+
+        // Sealed for one column, the value cannot be read from another
+        assert!(Encrypted::<String, Metadata>::open(&secret.sealed).is_err());
+
+        // Rows from before encryption still read, and are flagged
+        let legacy = Encrypted::<String, MfaSecret>::open("JBSWY3DPEHPK3PXP").unwrap();
+        assert_eq!(*legacy, "JBSWY3DPEHPK3PXP");
+        assert!(legacy.needs_reencryption());
+        assert_eq!(legacy.key_version(), None);
+        let resealed = Encrypted::<String, MfaSecret>::reseal("JBSWY3DPEHPK3PXP")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            *Encrypted::<String, MfaSecret>::open(&resealed).unwrap(),
+            "JBSWY3DPEHPK3PXP"
+        );
+        assert_eq!(
+            Encrypted::<String, MfaSecret>::reseal(&resealed).unwrap(),
+            None
+        );
+
+        let metadata = Encrypted::<serde_json::Value, Metadata>::open(r#"{"ssn":"123"}"#).unwrap();
+        assert_eq!(metadata["ssn"], "123");
+        assert_eq!(
+            serde_json::to_string(&metadata).unwrap(),
+            r#"{"ssn":"123"}"#
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use async_trait::async_trait;
+
+use crate::{Result, Secret, SecretError, SecretManager};
+
+/// Reads secrets from environment variables. `auth/jwt-secret` is looked up
+/// as `AUTH_JWT_SECRET`, behind an optional prefix. Read-only.
+#[derive(Debug, Clone, Default)]
+pub struct EnvSecretManager {
+    prefix: Option<String>,
+}
+
+impl EnvSecretManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only look at variables starting with `prefix`, e.g. `LOTA_`
+    pub fn with_prefix(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: Some(prefix.into()),
+        }
+    }
+
+    /// Uses `SECRETS_ENV_PREFIX` as the prefix when set
+    pub fn from_env() -> Self {
+        match std::env::var("SECRETS_ENV_PREFIX") {
+            Ok(prefix) if !prefix.is_empty() => Self::with_prefix(prefix),
+            _ => Self::new(),
+        }
+    }
+
+    pub fn var_name(&self, name: &str) -> String {
+        let name: String = name
+            .trim_matches('/')
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        format!("{}{}", self.prefix.as_deref().unwrap_or(""), name)
+    }
+}
+
+#[async_trait]
+impl SecretManager for EnvSecretManager {
+    async fn get_secret(&self, name: &str) -> Result<Secret> {
+        let var = self.var_name(name);
+        match std::env::var(&var) {
+            Ok(value) => Ok(Secret::new(value)),
+            Err(_) => Err(SecretError::NotFound(name.to_string())),
+        }
+    }
+
+    async fn create_secret(&self, _name: &str, _value: &str) -> Result<Secret> {
+        Err(SecretError::ReadOnly("env"))
+    }
+
+    async fn revoke_secret(&self, _name: &str) -> Result<()> {
+        Err(SecretError::ReadOnly("env"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_var_name() {
+        let manager = EnvSecretManager::new();
+        assert_eq!(manager.var_name("JWT_SECRET"), "JWT_SECRET");
+        assert_eq!(manager.var_name("auth/jwt-secret"), "AUTH_JWT_SECRET");
+        assert_eq!(manager.var_name("db/main#password"), "DB_MAIN_PASSWORD");
+
+        let manager = EnvSecretManager::with_prefix("LOTA_");
+        assert_eq!(manager.var_name("auth/jwt"), "LOTA_AUTH_JWT");
+    }
+
+    #[tokio::test]
+    async fn test_get_secret() {
+        std::env::set_var("LOTA_SECRETS_TEST_PRESENT", "from-env");
+        let manager = EnvSecretManager::with_prefix("LOTA_");
+
+        let secret = manager.get_secret("secrets-test/present").await.unwrap();
+        assert_eq!(secret.value, "from-env");
+        assert!(manager
+            .get_secret("secrets-test/missing")
+            .await
+            .unwrap_err()
+            .is_not_found());
+        assert!(matches!(
+            manager.create_secret("secrets-test/present", "x").await,
+            Err(SecretError::ReadOnly(_))
+        ));
+    }
+}
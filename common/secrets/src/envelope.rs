@@ -0,0 +1,417 @@
+//! Envelope encryption for sensitive values stored outside the secrets
+//! backend, such as database columns.
+//!
+//! Every value is encrypted with its own random data key, and the data key is
+//! wrapped with a key-encryption key (KEK) read from the secrets backend.
+//! Sealed values name the KEK version that wrapped them, so after the KEK is
+//! rotated old values still decrypt and [`EnvelopeCipher::reseal`] can move
+//! them to the current version by re-wrapping only the data key.
+//!
+//! A sealed value is the text `enc:v1:<kek version>:<base64>`, where the
+//! base64 holds the wrapping nonce, the wrapped data key, the data nonce and
+//! the ciphertext. The ciphertext is bound to a context, such as the column
+//! it is stored in, so a value copied elsewhere does not decrypt.
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use arc_swap::ArcSwapOption;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::RngCore;
+use std::{collections::BTreeMap, future::Future, sync::Arc};
+use tokio::task::JoinHandle;
+
+use crate::{watch, Result, SecretError, SecretManager, WatchOptions};
+
+const PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+/// A wrapped data key carries a 16 byte authentication tag
+const WRAPPED_KEY_LEN: usize = KEY_LEN + 16;
+
+static INSTALLED: ArcSwapOption<EnvelopeCipher> = ArcSwapOption::const_empty();
+
+/// Encrypts values under the current KEK and decrypts values sealed under
+/// any KEK version it was given
+pub struct EnvelopeCipher {
+    keks: BTreeMap<u64, Aes256Gcm>,
+    current: u64,
+}
+
+impl EnvelopeCipher {
+    pub fn new(version: u64, kek: &[u8; KEY_LEN]) -> Self {
+        Self {
+            keks: BTreeMap::from([(version, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(kek)))]),
+            current: version,
+        }
+    }
+
+    /// Also decrypt values wrapped by an earlier version of the KEK
+    pub fn with_previous(mut self, version: u64, kek: &[u8; KEY_LEN]) -> Self {
+        self.keks
+            .entry(version)
+            .or_insert_with(|| Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(kek)));
+        self
+    }
+
+    /// Reads the KEK `name`, a base64 encoded 32-byte key, and every earlier
+    /// version the backend still has. Backends without versions are treated
+    /// as version 1.
+    pub async fn load<M>(manager: &M, name: &str) -> Result<Self>
+    where
+        M: SecretManager + ?Sized,
+    {
+        let current = manager.get_secret(name).await?;
+        let version = current.version.unwrap_or(1);
+        let mut cipher = Self::new(version, &decode_kek(name, &current.value)?);
+
+        for previous in (1..version).rev() {
+            match manager.get_secret_version(name, previous).await {
+                Ok(secret) => {
+                    cipher = cipher.with_previous(previous, &decode_kek(name, &secret.value)?)
+                }
+                // Older versions are gone; values wrapped by them must have
+                // been resealed already
+                Err(e) if e.is_not_found() => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(cipher)
+    }
+
+    /// Version of the KEK new values are wrapped with
+    pub fn current_version(&self) -> u64 {
+        self.current
+    }
+
+    /// Seals `plaintext` for `context`, which has to be given again to
+    /// decrypt it
+    pub fn encrypt(&self, plaintext: &[u8], context: &[u8]) -> Result<String> {
+        let mut data_key = [0u8; KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut data_key);
+        let data_cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key));
+
+        let data_nonce = random_nonce();
+        let ciphertext = data_cipher
+            .encrypt(
+                Nonce::from_slice(&data_nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: context,
+                },
+            )
+            .map_err(|_| SecretError::Crypto("Failed to encrypt value".to_string()))?;
+
+        let mut body = self.wrap(self.current, &data_key)?;
+        body.extend_from_slice(&data_nonce);
+        body.extend_from_slice(&ciphertext);
+        Ok(format!(
+            "{}{}:{}",
+            PREFIX,
+            self.current,
+            STANDARD.encode(body)
+        ))
+    }
+
+    pub fn decrypt(&self, sealed: &str, context: &[u8]) -> Result<Vec<u8>> {
+        let (version, body) = parse(sealed)?;
+        let (wrapped, rest) = body.split_at(NONCE_LEN + WRAPPED_KEY_LEN);
+        let (data_nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let data_key = self.unwrap(version, wrapped)?;
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key))
+            .decrypt(
+                Nonce::from_slice(data_nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: context,
+                },
+            )
+            .map_err(|_| SecretError::Crypto("Value could not be decrypted".to_string()))
+    }
+
+    /// Re-wraps the data key of a value sealed under an older KEK with the
+    /// current one, leaving the ciphertext, and so its context, as is.
+    /// Returns `None` when the value is already current.
+    pub fn reseal(&self, sealed: &str) -> Result<Option<String>> {
+        let (version, mut body) = parse(sealed)?;
+        if version == self.current {
+            return Ok(None);
+        }
+
+        let data_key = self.unwrap(version, &body[..NONCE_LEN + WRAPPED_KEY_LEN])?;
+        body.splice(
+            ..NONCE_LEN + WRAPPED_KEY_LEN,
+            self.wrap(self.current, &data_key)?,
+        );
+        Ok(Some(format!(
+            "{}{}:{}",
+            PREFIX,
+            self.current,
+            STANDARD.encode(body)
+        )))
+    }
+
+    /// Wraps `data_key`, binding the KEK version so a tag cannot be swapped
+    fn wrap(&self, version: u64, data_key: &[u8]) -> Result<Vec<u8>> {
+        let nonce = random_nonce();
+        let wrapped = self
+            .kek(version)?
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: data_key,
+                    aad: version.to_string().as_bytes(),
+                },
+            )
+            .map_err(|_| SecretError::Crypto("Failed to wrap data key".to_string()))?;
+
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&wrapped);
+        Ok(out)
+    }
+
+    fn unwrap(&self, version: u64, wrapped: &[u8]) -> Result<Vec<u8>> {
+        let (nonce, wrapped) = wrapped.split_at(NONCE_LEN);
+        self.kek(version)?
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: wrapped,
+                    aad: version.to_string().as_bytes(),
+                },
+            )
+            .map_err(|_| SecretError::Crypto("Data key could not be unwrapped".to_string()))
+    }
+
+    fn kek(&self, version: u64) -> Result<&Aes256Gcm> {
+        self.keks.get(&version).ok_or_else(|| {
+            SecretError::Crypto(format!(
+                "Key-encryption key version {} is not loaded",
+                version
+            ))
+        })
+    }
+}
+
+/// Reloads the KEK `name` whenever the backend has a version other than
+/// `current`, and hands the new cipher to `on_change`, which is expected to
+/// install it and reseal the stored values. Failed reloads are logged and
+/// retried with the next change.
+pub fn follow<M, F, Fut>(
+    manager: Arc<M>,
+    name: &str,
+    current: u64,
+    options: WatchOptions,
+    mut on_change: F,
+) -> JoinHandle<()>
+where
+    M: SecretManager + ?Sized + 'static,
+    F: FnMut(EnvelopeCipher) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let name = name.to_string();
+    let mut values = watch(manager.clone(), &name, options);
+    tokio::spawn(async move {
+        let mut current = current;
+        while let Some(secret) = values.next().await {
+            if secret.version.unwrap_or(1) == current {
+                continue;
+            }
+            match EnvelopeCipher::load(&manager, &name).await {
+                Ok(cipher) => {
+                    tracing::info!(
+                        "Reloaded key-encryption key {} at version {}",
+                        name,
+                        cipher.current_version()
+                    );
+                    current = cipher.current_version();
+                    on_change(cipher).await;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to reload key-encryption key {}: {}", name, e)
+                }
+            }
+        }
+    })
+}
+
+/// Makes `cipher` the one used by [`installed`], e.g. by `Encrypted<T>`
+/// columns. Call again after the KEK has been rotated.
+pub fn install(cipher: EnvelopeCipher) {
+    INSTALLED.store(Some(Arc::new(cipher)));
+}
+
+/// The cipher set with [`install`]
+pub fn installed() -> Result<Arc<EnvelopeCipher>> {
+    INSTALLED
+        .load_full()
+        .ok_or_else(|| SecretError::Config("No envelope cipher has been installed".to_string()))
+}
+
+/// Whether `stored` is a sealed value rather than legacy plaintext
+pub fn is_sealed(stored: &str) -> bool {
+    stored.starts_with(PREFIX)
+}
+
+/// The KEK version that wrapped `sealed`
+pub fn key_version(sealed: &str) -> Option<u64> {
+    sealed.strip_prefix(PREFIX)?.split(':').next()?.parse().ok()
+}
+
+fn parse(sealed: &str) -> Result<(u64, Vec<u8>)> {
+    let corrupt = || SecretError::Crypto("Malformed encrypted value".to_string());
+
+    let (version, body) = sealed
+        .strip_prefix(PREFIX)
+        .and_then(|rest| rest.split_once(':'))
+        .ok_or_else(corrupt)?;
+    let version = version.parse().map_err(|_| corrupt())?;
+    let body = STANDARD.decode(body).map_err(|_| corrupt())?;
+    if body.len() < 2 * NONCE_LEN + WRAPPED_KEY_LEN {
+        return Err(corrupt());
+    }
+    Ok((version, body))
+}
+
+fn decode_kek(name: &str, value: &str) -> Result<[u8; KEY_LEN]> {
+    STANDARD
+        .decode(value.trim())
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| SecretError::Config(format!("{} must be 32 bytes, base64 encoded", name)))
+}
+
+fn random_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EncryptedFileSecretManager;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_round_trip_uses_fresh_data_keys() {
+        let cipher = EnvelopeCipher::new(1, &[1; 32]);
+        let a = cipher
+            .encrypt(b"JBSWY3DPEHPK3PXP", b"users.mfa_secret")
+            .unwrap();
+        let b = cipher
+            .encrypt(b"JBSWY3DPEHPK3PXP", b"users.mfa_secret")
+            .unwrap();
+
+        assert!(a.starts_with("enc:v1:1:"));
+        assert_ne!(a, b);
+        assert_eq!(
+            cipher.decrypt(&a, b"users.mfa_secret").unwrap(),
+            b"JBSWY3DPEHPK3PXP"
+        );
+        assert_eq!(key_version(&a), Some(1));
+
+        // Values copied to another column do not decrypt there
+        assert!(cipher.decrypt(&a, b"documents.metadata").is_err());
+
+        // Another KEK cannot unwrap the data key, even under the same version
+        let other = EnvelopeCipher::new(1, &[2; 32]);
+        assert!(other.decrypt(&a, b"users.mfa_secret").is_err());
+
+        // Re-tagging a value with a different version is detected
+        let retagged = a.replacen("enc:v1:1:", "enc:v1:2:", 1);
+        let both = EnvelopeCipher::new(2, &[1; 32]).with_previous(1, &[1; 32]);
+        assert!(both.decrypt(&retagged, b"users.mfa_secret").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reseal_after_kek_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = EncryptedFileSecretManager::new(dir.path().join("secrets.enc"), &[7; 32]);
+        manager
+            .create_secret("db/kek", &STANDARD.encode([1u8; 32]))
+            .await
+            .unwrap();
+
+        let old = EnvelopeCipher::load(&manager, "db/kek").await.unwrap();
+        let sealed = old
+            .encrypt(b"{\"ssn\":\"123\"}", b"documents.metadata")
+            .unwrap();
+        assert_eq!(old.reseal(&sealed).unwrap(), None);
+
+        manager
+            .create_secret("db/kek", &STANDARD.encode([2u8; 32]))
+            .await
+            .unwrap();
+        let cipher = EnvelopeCipher::load(&manager, "db/kek").await.unwrap();
+        assert_eq!(cipher.current_version(), 2);
+        assert_eq!(
+            cipher.decrypt(&sealed, b"documents.metadata").unwrap(),
+            b"{\"ssn\":\"123\"}"
+        );
+
+        let resealed = cipher.reseal(&sealed).unwrap().unwrap();
+        assert_eq!(key_version(&resealed), Some(2));
+        assert_eq!(
+            cipher.decrypt(&resealed, b"documents.metadata").unwrap(),
+            b"{\"ssn\":\"123\"}"
+        );
+        assert!(cipher.decrypt(&resealed, b"users.mfa_secret").is_err());
+        assert!(old.decrypt(&resealed, b"documents.metadata").is_err());
+        assert!(cipher.reseal("JBSWY3DPEHPK3PXP").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_follow_reseals_after_kek_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = Arc::new(EncryptedFileSecretManager::new(
+            dir.path().join("secrets.enc"),
+            &[7; 32],
+        ));
+        manager
+            .create_secret("db/kek", &STANDARD.encode([1u8; 32]))
+            .await
+            .unwrap();
+        let cipher = EnvelopeCipher::load(&manager, "db/kek").await.unwrap();
+        let mut stored = cipher
+            .encrypt(b"JBSWY3DPEHPK3PXP", b"users.mfa_secret")
+            .unwrap();
+
+        let (tx, mut rotated) = mpsc::unbounded_channel();
+        let options = WatchOptions {
+            interval: Duration::from_millis(10),
+            ..WatchOptions::default()
+        };
+        let follower = follow(manager.clone(), "db/kek", 1, options, move |cipher| {
+            let tx = tx.clone();
+            async move {
+                let _ = tx.send(cipher);
+            }
+        });
+
+        manager
+            .create_secret("db/kek", &STANDARD.encode([2u8; 32]))
+            .await
+            .unwrap();
+        let cipher = tokio::time::timeout(Duration::from_secs(5), rotated.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(cipher.current_version(), 2);
+        stored = cipher.reseal(&stored).unwrap().unwrap();
+        assert_eq!(key_version(&stored), Some(2));
+        assert_eq!(
+            cipher.decrypt(&stored, b"users.mfa_secret").unwrap(),
+            b"JBSWY3DPEHPK3PXP"
+        );
+
+        // Only changes are reported
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(rotated.try_recv().is_err());
+        follower.abort();
+    }
+}
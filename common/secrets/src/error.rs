@@ -0,0 +1,35 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error("Secret not found: {0}")]
+    NotFound(String),
+    #[error("Invalid secret name: {0}")]
+    InvalidName(String),
+    #[error("The {0} backend is read-only")]
+    ReadOnly(&'static str),
+    #[error("Not authorized: {0}")]
+    Unauthorized(String),
+    #[error("Secrets backend error: {0}")]
+    Backend(String),
+    #[error("Encryption error: {0}")]
+    Crypto(String),
+    #[error("Invalid secrets configuration: {0}")]
+    Config(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl SecretError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, SecretError::NotFound(_))
+    }
+}
+
+impl From<reqwest::Error> for SecretError {
+    fn from(err: reqwest::Error) -> Self {
+        SecretError::Backend(err.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, SecretError>;
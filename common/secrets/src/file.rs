@@ -0,0 +1,251 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Key, Nonce,
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use tokio::sync::Mutex;
+
+use crate::{Result, Secret, SecretError, SecretManager};
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoredSecret {
+    value: String,
+    version: u64,
+    /// Value of the version before, kept so rotations have a grace period
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous: Option<String>,
+}
+
+/// Keeps secrets in a local file encrypted with AES-256-GCM.
+///
+/// The file holds the base64 of a random nonce followed by the encrypted
+/// JSON map of names to values, and is rewritten atomically on every change.
+/// Intended for single hosts and development; writes from several processes
+/// are not coordinated.
+pub struct EncryptedFileSecretManager {
+    path: PathBuf,
+    cipher: Aes256Gcm,
+    // Serialises read-modify-write cycles within this process
+    lock: Mutex<()>,
+}
+
+impl EncryptedFileSecretManager {
+    pub fn new(path: impl Into<PathBuf>, key: &[u8; 32]) -> Self {
+        Self {
+            path: path.into(),
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Reads the file location from `SECRETS_FILE` and the base64 encoded
+    /// 32-byte key from `SECRETS_FILE_KEY`
+    pub fn from_env() -> Result<Self> {
+        let path = std::env::var("SECRETS_FILE")
+            .map_err(|_| SecretError::Config("SECRETS_FILE must be set".to_string()))?;
+        let key = std::env::var("SECRETS_FILE_KEY")
+            .map_err(|_| SecretError::Config("SECRETS_FILE_KEY must be set".to_string()))?;
+        let key: [u8; 32] = STANDARD
+            .decode(key.trim())
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| {
+                SecretError::Config("SECRETS_FILE_KEY must be 32 bytes, base64 encoded".to_string())
+            })?;
+
+        Ok(Self::new(path, &key))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    async fn load(&self) -> Result<BTreeMap<String, StoredSecret>> {
+        let encoded = match tokio::fs::read_to_string(&self.path).await {
+            Ok(encoded) => encoded,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let data = STANDARD
+            .decode(encoded.trim())
+            .map_err(|e| SecretError::Crypto(format!("Corrupt secrets file: {}", e)))?;
+        if data.len() < NONCE_LEN {
+            return Err(SecretError::Crypto("Corrupt secrets file".to_string()));
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| SecretError::Crypto("Secrets file could not be decrypted".to_string()))?;
+
+        serde_json::from_slice(&plaintext)
+            .map_err(|e| SecretError::Crypto(format!("Corrupt secrets file: {}", e)))
+    }
+
+    async fn store(&self, secrets: &BTreeMap<String, StoredSecret>) -> Result<()> {
+        let plaintext = serde_json::to_vec(secrets)
+            .map_err(|e| SecretError::Crypto(format!("Failed to encode secrets: {}", e)))?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| SecretError::Crypto("Failed to encrypt secrets".to_string()))?;
+
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+
+        // Write next to the target and rename so readers never see a
+        // partially written file
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, STANDARD.encode(data)).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SecretManager for EncryptedFileSecretManager {
+    async fn get_secret(&self, name: &str) -> Result<Secret> {
+        let secrets = self.load().await?;
+        secrets
+            .get(name)
+            .map(|stored| Secret {
+                value: stored.value.clone(),
+                version: Some(stored.version),
+                lease_duration: None,
+            })
+            .ok_or_else(|| SecretError::NotFound(name.to_string()))
+    }
+
+    async fn get_secret_version(&self, name: &str, version: u64) -> Result<Secret> {
+        let secrets = self.load().await?;
+        let value = secrets.get(name).and_then(|stored| {
+            if stored.version == version {
+                Some(stored.value.clone())
+            } else if stored.version == version + 1 {
+                stored.previous.clone()
+            } else {
+                None
+            }
+        });
+
+        value
+            .map(|value| Secret {
+                value,
+                version: Some(version),
+                lease_duration: None,
+            })
+            .ok_or_else(|| SecretError::NotFound(format!("{} version {}", name, version)))
+    }
+
+    async fn create_secret(&self, name: &str, value: &str) -> Result<Secret> {
+        let _guard = self.lock.lock().await;
+        let mut secrets = self.load().await?;
+
+        let stored = secrets.entry(name.to_string()).or_default();
+        if stored.version > 0 {
+            stored.previous = Some(std::mem::take(&mut stored.value));
+        }
+        stored.value = value.to_string();
+        stored.version += 1;
+        let secret = Secret {
+            value: stored.value.clone(),
+            version: Some(stored.version),
+            lease_duration: None,
+        };
+
+        self.store(&secrets).await?;
+        Ok(secret)
+    }
+
+    async fn revoke_secret(&self, name: &str) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut secrets = self.load().await?;
+        if secrets.remove(name).is_none() {
+            return Err(SecretError::NotFound(name.to_string()));
+        }
+        self.store(&secrets).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    #[tokio::test]
+    async fn test_round_trip_and_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.enc");
+        let manager = EncryptedFileSecretManager::new(&path, &KEY);
+
+        assert!(manager
+            .get_secret("auth/jwt")
+            .await
+            .unwrap_err()
+            .is_not_found());
+
+        let first = manager.create_secret("auth/jwt", "one").await.unwrap();
+        let second = manager.create_secret("auth/jwt", "two").await.unwrap();
+        assert_eq!(first.version, Some(1));
+        assert_eq!(second.version, Some(2));
+
+        // A fresh instance reads what the first one wrote
+        let reopened = EncryptedFileSecretManager::new(&path, &KEY);
+        assert_eq!(reopened.get_secret("auth/jwt").await.unwrap(), second);
+
+        // Only the version before the current one is kept
+        assert_eq!(
+            reopened.get_secret_version("auth/jwt", 1).await.unwrap(),
+            first
+        );
+        manager.create_secret("auth/jwt", "three").await.unwrap();
+        assert!(manager
+            .get_secret_version("auth/jwt", 1)
+            .await
+            .unwrap_err()
+            .is_not_found());
+
+        reopened.revoke_secret("auth/jwt").await.unwrap();
+        assert!(manager
+            .get_secret("auth/jwt")
+            .await
+            .unwrap_err()
+            .is_not_found());
+    }
+
+    #[tokio::test]
+    async fn test_file_is_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.enc");
+        let manager = EncryptedFileSecretManager::new(&path, &KEY);
+        manager
+            .create_secret("db/password", "plaintext-value")
+            .await
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("plaintext-value"));
+        assert!(!contents.contains("db/password"));
+
+        let wrong_key = EncryptedFileSecretManager::new(&path, &[8; 32]);
+        assert!(matches!(
+            wrong_key.get_secret("db/password").await,
+            Err(SecretError::Crypto(_))
+        ));
+    }
+}
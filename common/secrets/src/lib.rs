@@ -0,0 +1,177 @@
+//! Secrets management for LotaBots services.
+//!
+//! Every backend implements [`SecretManager`]. Services normally build a
+//! [`ChainedSecretManager`] with [`from_env`], which tries each configured
+//! backend in turn so that, for example, a value missing from Vault can still
+//! be supplied through the environment during development.
+
+use async_trait::async_trait;
+use std::{fmt, sync::Arc, time::Duration};
+
+mod chain;
+#[cfg(feature = "sqlx")]
+mod encrypted;
+mod env;
+pub mod envelope;
+mod error;
+mod file;
+mod rotation;
+pub mod vault;
+mod watch;
+
+pub use chain::ChainedSecretManager;
+#[cfg(feature = "sqlx")]
+pub use encrypted::{Column, Encrypted};
+pub use env::EnvSecretManager;
+pub use envelope::EnvelopeCipher;
+pub use error::{Result, SecretError};
+pub use file::EncryptedFileSecretManager;
+pub use rotation::{generate_secret, KeyRing, SecretChange, SecretRotator};
+pub use vault::{RetryPolicy, VaultAuth, VaultConfig, VaultSecretManager};
+pub use watch::{watch, SecretHandle, SecretStream, WatchOptions};
+
+/// A secret value as returned by a backend
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret {
+    pub value: String,
+    /// Version assigned by the backend, if it keeps versions
+    pub version: Option<u64>,
+    /// How long the backend says the value may be used before reading it
+    /// again, for secrets issued with a lease
+    pub lease_duration: Option<Duration>,
+}
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self {
+            value: value.into(),
+            version: None,
+            lease_duration: None,
+        }
+    }
+}
+
+// Keep values out of logs
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secret")
+            .field("value", &"<redacted>")
+            .field("version", &self.version)
+            .field("lease_duration", &self.lease_duration)
+            .finish()
+    }
+}
+
+/// Storage for named secrets.
+///
+/// Names are backend-neutral paths such as `auth/jwt`. Backends that store
+/// several values under one path accept `path#field`; the field defaults to
+/// `value`.
+#[async_trait]
+pub trait SecretManager: Send + Sync {
+    async fn get_secret(&self, name: &str) -> Result<Secret>;
+
+    /// Reads an earlier version of `name`. Backends that do not keep history
+    /// only find the current version.
+    async fn get_secret_version(&self, name: &str, version: u64) -> Result<Secret> {
+        let secret = self.get_secret(name).await?;
+        if secret.version == Some(version) {
+            Ok(secret)
+        } else {
+            Err(SecretError::NotFound(format!(
+                "{} version {}",
+                name, version
+            )))
+        }
+    }
+
+    /// Stores a new value under `name`, replacing the current one
+    async fn create_secret(&self, name: &str, value: &str) -> Result<Secret>;
+
+    /// Removes the current value of `name`
+    async fn revoke_secret(&self, name: &str) -> Result<()>;
+}
+
+#[async_trait]
+impl<T: SecretManager + ?Sized> SecretManager for Arc<T> {
+    async fn get_secret(&self, name: &str) -> Result<Secret> {
+        (**self).get_secret(name).await
+    }
+
+    async fn get_secret_version(&self, name: &str, version: u64) -> Result<Secret> {
+        (**self).get_secret_version(name, version).await
+    }
+
+    async fn create_secret(&self, name: &str, value: &str) -> Result<Secret> {
+        (**self).create_secret(name, value).await
+    }
+
+    async fn revoke_secret(&self, name: &str) -> Result<()> {
+        (**self).revoke_secret(name).await
+    }
+}
+
+/// Builds the provider chain named in `SECRETS_BACKENDS`, a comma separated
+/// list of `vault`, `file` and `env` in lookup order. Defaults to `env`.
+///
+/// When called inside a Tokio runtime the Vault backend's token renewal task
+/// is started as well.
+pub fn from_env() -> Result<ChainedSecretManager> {
+    let backends = std::env::var("SECRETS_BACKENDS").unwrap_or_else(|_| "env".to_string());
+
+    let mut chain = ChainedSecretManager::new();
+    for backend in backends.split(',').map(str::trim).filter(|b| !b.is_empty()) {
+        chain = match backend {
+            "vault" => {
+                let vault = VaultSecretManager::new(VaultConfig::from_env()?)?;
+                // Keep the token alive when called from within a runtime
+                if tokio::runtime::Handle::try_current().is_ok() {
+                    vault.start_renewal();
+                }
+                chain.with(vault)
+            }
+            "file" => chain.with(EncryptedFileSecretManager::from_env()?),
+            "env" => chain.with(EnvSecretManager::from_env()),
+            other => {
+                return Err(SecretError::Config(format!(
+                    "Unknown secrets backend '{}'",
+                    other
+                )))
+            }
+        };
+    }
+
+    Ok(chain)
+}
+
+/// Splits `path#field` into its parts, defaulting the field to `value`
+pub(crate) fn split_name(name: &str) -> Result<(&str, &str)> {
+    let (path, field) = name.split_once('#').unwrap_or((name, "value"));
+    let path = path.trim_matches('/');
+    if path.is_empty() || field.is_empty() {
+        return Err(SecretError::InvalidName(name.to_string()));
+    }
+    Ok((path, field))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_name() {
+        assert_eq!(split_name("auth/jwt").unwrap(), ("auth/jwt", "value"));
+        assert_eq!(
+            split_name("/db/main#password").unwrap(),
+            ("db/main", "password")
+        );
+        assert!(split_name("db#").is_err());
+        assert!(split_name("#field").is_err());
+    }
+
+    #[test]
+    fn test_secret_debug_is_redacted() {
+        let secret = Secret::new("hunter2");
+        assert!(!format!("{:?}", secret).contains("hunter2"));
+    }
+}
@@ -0,0 +1,302 @@
+//! Secret rotation with a grace period.
+//!
+//! Rotating a signing key must not invalidate everything signed with the old
+//! one. [`SecretRotator`] writes a new version and announces it as a
+//! [`SecretChange`]; it never revokes the old value. Consumers keep a
+//! [`KeyRing`] that signs with the current version and keeps verifying with
+//! the previous one until the grace period is over.
+
+use rand::RngCore;
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{sync::broadcast, task::JoinHandle};
+
+use crate::{
+    watch::{watch, SecretHandle, WatchOptions},
+    Result, Secret, SecretManager,
+};
+
+/// Announcement that a new version of a secret is current
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretChange {
+    pub name: String,
+    pub version: Option<u64>,
+    /// How long the version before it stays valid for verification
+    pub grace_period: Duration,
+}
+
+/// 256 bits of randomness, hex encoded
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Writes new versions of secrets and notifies subscribers
+pub struct SecretRotator<M> {
+    manager: M,
+    grace_period: Duration,
+    changes: broadcast::Sender<SecretChange>,
+}
+
+impl<M: SecretManager> SecretRotator<M> {
+    pub fn new(manager: M) -> Self {
+        let (changes, _) = broadcast::channel(16);
+        Self {
+            manager,
+            grace_period: Duration::from_secs(24 * 60 * 60),
+            changes,
+        }
+    }
+
+    /// How long the previous version keeps verifying after a rotation.
+    /// Should be at least the lifetime of anything signed with it.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    pub fn manager(&self) -> &M {
+        &self.manager
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SecretChange> {
+        self.changes.subscribe()
+    }
+
+    /// Stores a freshly generated value as the new version of `name`
+    pub async fn rotate(&self, name: &str) -> Result<Secret> {
+        let secret = self.manager.create_secret(name, &generate_secret()).await?;
+
+        // Nobody listening is fine; key rings also reload on startup
+        let _ = self.changes.send(SecretChange {
+            name: name.to_string(),
+            version: secret.version,
+            grace_period: self.grace_period,
+        });
+
+        tracing::info!("Rotated secret {} to version {:?}", name, secret.version);
+        Ok(secret)
+    }
+}
+
+/// The versions of one secret that are currently valid.
+///
+/// Cheap to clone; clones share the same keys, so a ring handed to request
+/// handlers picks up rotations applied through [`KeyRing::follow`] or
+/// [`KeyRing::watch`].
+#[derive(Clone)]
+pub struct KeyRing {
+    name: Arc<str>,
+    current: SecretHandle,
+    /// Previous version and when it stops verifying. Also serialises
+    /// rotations so the two never get out of step.
+    previous: Arc<RwLock<Option<(Secret, Instant)>>>,
+}
+
+impl KeyRing {
+    pub fn new(name: &str, current: Secret) -> Self {
+        Self {
+            name: name.into(),
+            current: SecretHandle::new(current),
+            previous: Arc::default(),
+        }
+    }
+
+    /// Loads the current version of `name` and, if the backend keeps
+    /// history, the version before it. Since the time of the last rotation is
+    /// not known the previous version is accepted for a full `grace_period`.
+    pub async fn load<M>(manager: &M, name: &str, grace_period: Duration) -> Result<Self>
+    where
+        M: SecretManager + ?Sized,
+    {
+        let current = manager.get_secret(name).await?;
+        let ring = Self::new(name, current.clone());
+
+        if let Some(version) = current.version.filter(|v| *v > 1) {
+            match manager.get_secret_version(name, version - 1).await {
+                Ok(previous) => {
+                    *ring.previous.write().unwrap() =
+                        Some((previous, Instant::now() + grace_period));
+                }
+                Err(e) if e.is_not_found() => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(ring)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The version to sign with
+    pub fn current(&self) -> Secret {
+        (*self.current.load()).clone()
+    }
+
+    /// Live view of the current version, for readers that need no grace
+    /// period
+    pub fn handle(&self) -> SecretHandle {
+        self.current.clone()
+    }
+
+    /// Every version that still verifies, current first
+    pub fn verification_keys(&self) -> Vec<Secret> {
+        let previous = self.previous.read().unwrap();
+        let mut keys = vec![self.current()];
+        if let Some((previous, until)) = previous.as_ref() {
+            if *until > Instant::now() {
+                keys.push(previous.clone());
+            }
+        }
+        keys
+    }
+
+    /// Makes `secret` current. The replaced version keeps verifying for
+    /// `grace_period`.
+    pub fn rotate_to(&self, secret: Secret, grace_period: Duration) {
+        let mut previous = self.previous.write().unwrap();
+        let current = self.current.load();
+        if *current == secret {
+            return;
+        }
+        *previous = Some(((*current).clone(), Instant::now() + grace_period));
+        self.current.store(secret);
+    }
+
+    /// Applies changes of this ring's secret as they are announced, reading
+    /// the new value from `manager`. The task ends when the sender is gone.
+    pub fn follow<M>(
+        &self,
+        manager: M,
+        mut changes: broadcast::Receiver<SecretChange>,
+    ) -> JoinHandle<()>
+    where
+        M: SecretManager + 'static,
+    {
+        let ring = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let grace_period = match changes.recv().await {
+                    Ok(change) if change.name == *ring.name => change.grace_period,
+                    Ok(_) => continue,
+                    // Missed some changes; the latest value is all that matters
+                    Err(broadcast::error::RecvError::Lagged(_)) => ring
+                        .previous
+                        .read()
+                        .unwrap()
+                        .as_ref()
+                        .map(|(_, until)| until.saturating_duration_since(Instant::now()))
+                        .unwrap_or_default(),
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+
+                match manager.get_secret(&ring.name).await {
+                    Ok(secret) => {
+                        tracing::info!(
+                            "Reloaded secret {} at version {:?}",
+                            ring.name,
+                            secret.version
+                        );
+                        ring.rotate_to(secret, grace_period);
+                    }
+                    Err(e) => tracing::error!("Failed to reload secret {}: {}", ring.name, e),
+                }
+            }
+        })
+    }
+
+    /// Applies changes picked up by polling `manager`, for services that do
+    /// not run the rotator themselves
+    pub fn watch<M>(
+        &self,
+        manager: M,
+        options: WatchOptions,
+        grace_period: Duration,
+    ) -> JoinHandle<()>
+    where
+        M: SecretManager + 'static,
+    {
+        let ring = self.clone();
+        let mut values = watch(manager, &self.name, options);
+        tokio::spawn(async move {
+            while let Some(secret) = values.next().await {
+                ring.rotate_to(secret, grace_period);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EncryptedFileSecretManager;
+
+    #[tokio::test]
+    async fn test_rotation_keeps_previous_version_during_grace_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = Arc::new(EncryptedFileSecretManager::new(
+            dir.path().join("secrets.enc"),
+            &[7; 32],
+        ));
+        manager.create_secret("jwt", "initial").await.unwrap();
+
+        let rotator =
+            SecretRotator::new(manager.clone()).with_grace_period(Duration::from_secs(60));
+        let ring = KeyRing::load(&manager, "jwt", Duration::from_secs(60))
+            .await
+            .unwrap();
+        let follower = ring.follow(manager.clone(), rotator.subscribe());
+
+        let rotated = rotator.rotate("jwt").await.unwrap();
+        assert_eq!(rotated.version, Some(2));
+        assert_eq!(rotated.value.len(), 64);
+
+        // Give the follower a chance to apply the change
+        for _ in 0..50 {
+            if ring.current() == rotated {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(ring.current(), rotated);
+        let values: Vec<_> = ring
+            .verification_keys()
+            .into_iter()
+            .map(|key| key.value)
+            .collect();
+        assert_eq!(values, vec![rotated.value.clone(), "initial".to_string()]);
+
+        // A ring loaded after the rotation still accepts the old version
+        let reloaded = KeyRing::load(&manager, "jwt", Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(reloaded.verification_keys().len(), 2);
+
+        drop(rotator);
+        follower.await.unwrap();
+    }
+
+    #[test]
+    fn test_previous_version_expires() {
+        let ring = KeyRing::new("jwt", Secret::new("old"));
+        ring.rotate_to(Secret::new("new"), Duration::ZERO);
+        assert_eq!(ring.verification_keys(), vec![Secret::new("new")]);
+
+        // Re-applying the current value does not drop the previous one
+        ring.rotate_to(Secret::new("newer"), Duration::from_secs(60));
+        ring.rotate_to(Secret::new("newer"), Duration::from_secs(60));
+        assert_eq!(
+            ring.verification_keys(),
+            vec![Secret::new("newer"), Secret::new("new")]
+        );
+    }
+}
@@ -0,0 +1,766 @@
+//! HashiCorp Vault KV version 2 backend
+
+use async_trait::async_trait;
+use rand::Rng;
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{Mutex, RwLock},
+    task::JoinHandle,
+};
+
+use crate::{split_name, Result, Secret, SecretError, SecretManager};
+
+/// Default location of the service account token in a Kubernetes pod
+pub const KUBERNETES_TOKEN_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+/// How the backend obtains its Vault token
+#[derive(Clone)]
+pub enum VaultAuth {
+    /// A token issued out of band. It is renewed if renewable but cannot be
+    /// replaced once it expires.
+    Token(String),
+    /// AppRole login with a role and secret id
+    AppRole {
+        role_id: String,
+        secret_id: String,
+        mount: String,
+    },
+    /// Login with the pod's service account JWT, read from `jwt_path` on
+    /// every login so projected tokens that rotate are picked up
+    Kubernetes {
+        role: String,
+        jwt_path: PathBuf,
+        mount: String,
+    },
+}
+
+impl VaultAuth {
+    pub fn app_role(role_id: impl Into<String>, secret_id: impl Into<String>) -> Self {
+        VaultAuth::AppRole {
+            role_id: role_id.into(),
+            secret_id: secret_id.into(),
+            mount: "approle".to_string(),
+        }
+    }
+
+    pub fn kubernetes(role: impl Into<String>) -> Self {
+        VaultAuth::Kubernetes {
+            role: role.into(),
+            jwt_path: PathBuf::from(KUBERNETES_TOKEN_PATH),
+            mount: "kubernetes".to_string(),
+        }
+    }
+
+    fn can_login(&self) -> bool {
+        !matches!(self, VaultAuth::Token(_))
+    }
+}
+
+// Keep credentials out of logs
+impl std::fmt::Debug for VaultAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VaultAuth::Token(_) => f.write_str("Token"),
+            VaultAuth::AppRole { mount, .. } => write!(f, "AppRole({})", mount),
+            VaultAuth::Kubernetes { role, mount, .. } => {
+                write!(f, "Kubernetes({}, role {})", mount, role)
+            }
+        }
+    }
+}
+
+/// Backoff for transient failures: connection errors, 429 and 5xx
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with up to 50% jitter so that many clients do not
+    /// retry in lockstep
+    fn backoff(&self, attempt: u32) -> Duration {
+        let base = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let jitter = rand::thread_rng().gen_range(0.0..0.5);
+        base.mul_f64(1.0 + jitter)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VaultConfig {
+    /// Base address, e.g. `https://vault.internal:8200`
+    pub address: String,
+    pub auth: VaultAuth,
+    /// Mount path of the KV v2 engine
+    pub mount: String,
+    /// Vault Enterprise namespace
+    pub namespace: Option<String>,
+    pub timeout: Duration,
+    pub retry: RetryPolicy,
+}
+
+impl VaultConfig {
+    pub fn new(address: impl Into<String>, token: impl Into<String>) -> Self {
+        Self::with_auth(address, VaultAuth::Token(token.into()))
+    }
+
+    pub fn with_auth(address: impl Into<String>, auth: VaultAuth) -> Self {
+        Self {
+            address: address.into(),
+            auth,
+            mount: "secret".to_string(),
+            namespace: None,
+            timeout: Duration::from_secs(10),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Reads `VAULT_ADDR`, `VAULT_KV_MOUNT`, `VAULT_NAMESPACE` and the
+    /// credentials for `VAULT_AUTH_METHOD`:
+    ///
+    /// - `token` (default): `VAULT_TOKEN`
+    /// - `approle`: `VAULT_ROLE_ID` and `VAULT_SECRET_ID`
+    /// - `kubernetes`: `VAULT_K8S_ROLE`, optionally `VAULT_K8S_TOKEN_PATH`
+    ///
+    /// `VAULT_AUTH_MOUNT` overrides the mount of the login method.
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| SecretError::Config(format!("{} must be set", name)))
+        };
+
+        let method = std::env::var("VAULT_AUTH_METHOD").unwrap_or_else(|_| "token".to_string());
+        let mut auth = match method.as_str() {
+            "token" => VaultAuth::Token(var("VAULT_TOKEN")?),
+            "approle" => VaultAuth::app_role(var("VAULT_ROLE_ID")?, var("VAULT_SECRET_ID")?),
+            "kubernetes" => VaultAuth::kubernetes(var("VAULT_K8S_ROLE")?),
+            other => {
+                return Err(SecretError::Config(format!(
+                    "Unknown VAULT_AUTH_METHOD '{}'",
+                    other
+                )))
+            }
+        };
+        if let VaultAuth::Kubernetes { jwt_path, .. } = &mut auth {
+            if let Ok(path) = std::env::var("VAULT_K8S_TOKEN_PATH") {
+                *jwt_path = PathBuf::from(path);
+            }
+        }
+        if let Ok(auth_mount) = std::env::var("VAULT_AUTH_MOUNT") {
+            match &mut auth {
+                VaultAuth::AppRole { mount, .. } | VaultAuth::Kubernetes { mount, .. } => {
+                    *mount = auth_mount
+                }
+                VaultAuth::Token(_) => {}
+            }
+        }
+
+        let mut config = Self::with_auth(var("VAULT_ADDR")?, auth);
+        if let Ok(mount) = std::env::var("VAULT_KV_MOUNT") {
+            config.mount = mount;
+        }
+        config.namespace = std::env::var("VAULT_NAMESPACE").ok();
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct KvReadResponse {
+    data: KvData,
+    /// Refresh hint in seconds. Zero unless the entry sets a `ttl`.
+    #[serde(default)]
+    lease_duration: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct KvData {
+    data: Option<Map<String, Value>>,
+    metadata: KvMetadata,
+    /// Copied from the enclosing response
+    #[serde(skip)]
+    lease_duration: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct KvMetadata {
+    version: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct KvWriteResponse {
+    data: KvMetadata,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthResponse {
+    auth: AuthInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthInfo {
+    client_token: String,
+    lease_duration: u64,
+    renewable: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct LookupResponse {
+    data: LookupData,
+}
+
+#[derive(Debug, Deserialize)]
+struct LookupData {
+    ttl: u64,
+    renewable: bool,
+}
+
+/// The current token and its lease
+#[derive(Clone)]
+struct TokenState {
+    token: String,
+    /// `None` for tokens that never expire
+    lease: Option<Duration>,
+    renewable: bool,
+    obtained_at: Instant,
+}
+
+impl TokenState {
+    fn new(token: String, lease_secs: u64, renewable: bool) -> Self {
+        Self {
+            token,
+            lease: (lease_secs > 0).then(|| Duration::from_secs(lease_secs)),
+            renewable,
+            obtained_at: Instant::now(),
+        }
+    }
+
+    /// Renew once two thirds of the lease have passed
+    fn renew_at(&self) -> Option<Instant> {
+        self.lease
+            .map(|lease| self.obtained_at + lease.mul_f64(2.0 / 3.0))
+    }
+}
+
+struct Inner {
+    client: Client,
+    config: VaultConfig,
+    token: RwLock<Option<TokenState>>,
+    // Only one login at a time; concurrent callers wait for its result
+    login_lock: Mutex<()>,
+}
+
+/// Reads and writes secrets in a KV v2 engine.
+///
+/// A secret name `path#field` addresses one field of the KV entry at `path`,
+/// so several related values (e.g. database user and password) can share an
+/// entry. Writing a field keeps the other fields and creates a new version.
+///
+/// With AppRole or Kubernetes auth the backend logs in on first use and
+/// again whenever Vault rejects the token. [`VaultSecretManager::start_renewal`]
+/// keeps the token's lease alive in the background. Cloning is cheap and
+/// clones share the token.
+#[derive(Clone)]
+pub struct VaultSecretManager {
+    inner: Arc<Inner>,
+}
+
+impl VaultSecretManager {
+    pub fn new(config: VaultConfig) -> Result<Self> {
+        let client = Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .map_err(|e| SecretError::Config(format!("Failed to build HTTP client: {}", e)))?;
+
+        let token = match &config.auth {
+            // Lease unknown until looked up; treated as non-expiring until then
+            VaultAuth::Token(token) => Some(TokenState::new(token.clone(), 0, false)),
+            _ => None,
+        };
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                client,
+                config: VaultConfig {
+                    address: config.address.trim_end_matches('/').to_string(),
+                    ..config
+                },
+                token: RwLock::new(token),
+                login_lock: Mutex::new(()),
+            }),
+        })
+    }
+
+    /// Logs in with the configured auth method, replacing the current token
+    pub async fn login(&self) -> Result<()> {
+        self.inner.login().await.map(|_| ())
+    }
+
+    /// Spawns a task that renews the token's lease before it runs out and
+    /// logs in again when renewal is refused. The task ends when every clone
+    /// of this manager has been dropped.
+    pub fn start_renewal(&self) -> JoinHandle<()> {
+        let weak = Arc::downgrade(&self.inner);
+        tokio::spawn(renewal_loop(weak))
+    }
+}
+
+impl Inner {
+    fn url(&self, path: &str) -> String {
+        format!("{}/v1/{}", self.config.address, path)
+    }
+
+    fn kv_url(&self, kind: &str, path: &str) -> String {
+        self.url(&format!("{}/{}/{}", self.config.mount, kind, path))
+    }
+
+    fn request(&self, method: Method, url: &str, token: Option<&str>) -> RequestBuilder {
+        let mut request = self.client.request(method, url);
+        if let Some(token) = token {
+            request = request.header("X-Vault-Token", token);
+        }
+        if let Some(namespace) = &self.config.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        request
+    }
+
+    /// Sends a request built by `build`, retrying transient failures with
+    /// backoff
+    async fn send_with_retry<F>(&self, build: F) -> Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let retry = &self.config.retry;
+        let mut attempt = 0;
+        loop {
+            let transient = match build().send().await {
+                Ok(response)
+                    if response.status() == StatusCode::TOO_MANY_REQUESTS
+                        || response.status().is_server_error() =>
+                {
+                    if attempt >= retry.max_retries {
+                        return Ok(response);
+                    }
+                    format!("Vault returned {}", response.status())
+                }
+                Ok(response) => return Ok(response),
+                Err(e) if attempt < retry.max_retries && (e.is_connect() || e.is_timeout()) => {
+                    e.to_string()
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let backoff = retry.backoff(attempt);
+            tracing::warn!(
+                "Transient Vault error ({}), retrying in {:?}",
+                transient,
+                backoff
+            );
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    async fn current_token(&self) -> Result<String> {
+        if let Some(state) = self.token.read().await.as_ref() {
+            return Ok(state.token.clone());
+        }
+        self.login_if_unchanged(None).await
+    }
+
+    /// Logs in unless another caller already replaced `stale` in the
+    /// meantime, in which case that token is used
+    async fn login_if_unchanged(&self, stale: Option<&str>) -> Result<String> {
+        let _guard = self.login_lock.lock().await;
+        if let Some(state) = self.token.read().await.as_ref() {
+            if Some(state.token.as_str()) != stale {
+                return Ok(state.token.clone());
+            }
+        }
+        self.login().await
+    }
+
+    async fn login(&self) -> Result<String> {
+        let (path, body) = match &self.config.auth {
+            VaultAuth::Token(_) => {
+                return Err(SecretError::Unauthorized(
+                    "Vault token expired and cannot be renewed".to_string(),
+                ))
+            }
+            VaultAuth::AppRole {
+                role_id,
+                secret_id,
+                mount,
+            } => (
+                format!("auth/{}/login", mount),
+                json!({ "role_id": role_id, "secret_id": secret_id }),
+            ),
+            VaultAuth::Kubernetes {
+                role,
+                jwt_path,
+                mount,
+            } => {
+                let jwt = tokio::fs::read_to_string(jwt_path).await.map_err(|e| {
+                    SecretError::Config(format!(
+                        "Failed to read service account token {}: {}",
+                        jwt_path.display(),
+                        e
+                    ))
+                })?;
+                (
+                    format!("auth/{}/login", mount),
+                    json!({ "role": role, "jwt": jwt.trim() }),
+                )
+            }
+        };
+
+        let url = self.url(&path);
+        let response = self
+            .send_with_retry(|| self.request(Method::POST, &url, None).json(&body))
+            .await?;
+        let auth = check("login", response)
+            .await?
+            .json::<AuthResponse>()
+            .await?
+            .auth;
+
+        tracing::info!(
+            "Logged in to Vault with {:?}, lease {}s",
+            self.config.auth,
+            auth.lease_duration
+        );
+        let state = TokenState::new(auth.client_token, auth.lease_duration, auth.renewable);
+        let token = state.token.clone();
+        *self.token.write().await = Some(state);
+        Ok(token)
+    }
+
+    /// Extends the current token's lease. Static tokens are looked up first
+    /// to learn whether they expire at all.
+    async fn renew(&self) -> Result<()> {
+        let Some(state) = self.token.read().await.clone() else {
+            return Ok(());
+        };
+
+        let url = self.url("auth/token/renew-self");
+        let response = self
+            .send_with_retry(|| {
+                self.request(Method::POST, &url, Some(&state.token))
+                    .json(&json!({}))
+            })
+            .await?;
+        let auth = check("token renewal", response)
+            .await?
+            .json::<AuthResponse>()
+            .await?
+            .auth;
+
+        tracing::debug!("Renewed Vault token, lease {}s", auth.lease_duration);
+        *self.token.write().await = Some(TokenState::new(
+            state.token,
+            auth.lease_duration,
+            auth.renewable,
+        ));
+        Ok(())
+    }
+
+    async fn lookup_static_token(&self) -> Result<()> {
+        let Some(state) = self.token.read().await.clone() else {
+            return Ok(());
+        };
+
+        let url = self.url("auth/token/lookup-self");
+        let response = self
+            .send_with_retry(|| self.request(Method::GET, &url, Some(&state.token)))
+            .await?;
+        let data = check("token lookup", response)
+            .await?
+            .json::<LookupResponse>()
+            .await?
+            .data;
+
+        *self.token.write().await = Some(TokenState::new(state.token, data.ttl, data.renewable));
+        Ok(())
+    }
+
+    /// Sends an authenticated request. If Vault rejects the token and the
+    /// auth method can log in, logs in again and retries once.
+    async fn send_authorized<F>(&self, name: &str, build: F) -> Result<Response>
+    where
+        F: Fn(&str) -> RequestBuilder,
+    {
+        let token = self.current_token().await?;
+        let response = self.send_with_retry(|| build(&token)).await?;
+
+        let rejected = matches!(
+            response.status(),
+            StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED
+        );
+        if !rejected || !self.config.auth.can_login() {
+            return check(name, response).await;
+        }
+
+        tracing::info!("Vault rejected the token, logging in again");
+        let token = self.login_if_unchanged(Some(&token)).await?;
+        let response = self.send_with_retry(|| build(&token)).await?;
+        check(name, response).await
+    }
+
+    /// Reads the whole entry at `path`, the latest version unless `version`
+    /// is given. Deleted versions have no data.
+    async fn read(&self, path: &str, version: Option<u64>) -> Result<Option<KvData>> {
+        let mut url = self.kv_url("data", path);
+        if let Some(version) = version {
+            url = format!("{}?version={}", url, version);
+        }
+        let result = self
+            .send_authorized(path, |token| self.request(Method::GET, &url, Some(token)))
+            .await;
+        match result {
+            Ok(response) => {
+                let response = response.json::<KvReadResponse>().await?;
+                Ok(Some(KvData {
+                    lease_duration: response.lease_duration,
+                    ..response.data
+                }))
+            }
+            Err(SecretError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes `data` as a new version. `cas` guards against concurrent
+    /// writers: the write fails if the current version has moved on.
+    async fn write(&self, path: &str, data: Map<String, Value>, cas: u64) -> Result<u64> {
+        let url = self.kv_url("data", path);
+        let body = json!({ "options": { "cas": cas }, "data": data });
+        let response = self
+            .send_authorized(path, |token| {
+                self.request(Method::POST, &url, Some(token)).json(&body)
+            })
+            .await?;
+        Ok(response.json::<KvWriteResponse>().await?.data.version)
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        let url = self.kv_url("data", path);
+        self.send_authorized(path, |token| {
+            self.request(Method::DELETE, &url, Some(token))
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+async fn check(name: &str, response: Response) -> Result<Response> {
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        StatusCode::NOT_FOUND => Err(SecretError::NotFound(name.to_string())),
+        StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => Err(SecretError::Unauthorized(
+            format!("Vault denied access to {}", name),
+        )),
+        status => {
+            let body = response.text().await.unwrap_or_default();
+            Err(SecretError::Backend(format!(
+                "Vault returned {}: {}",
+                status, body
+            )))
+        }
+    }
+}
+
+async fn renewal_loop(weak: Weak<Inner>) {
+    // Pause between attempts after a failed renewal or login
+    const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+    if let Some(inner) = weak.upgrade() {
+        if matches!(inner.config.auth, VaultAuth::Token(_)) {
+            if let Err(e) = inner.lookup_static_token().await {
+                tracing::warn!("Failed to look up Vault token: {}", e);
+            }
+        }
+    }
+
+    loop {
+        let renew_at = {
+            let Some(inner) = weak.upgrade() else { return };
+            let state = inner.token.read().await.clone();
+            match state {
+                None => Instant::now(),
+                Some(state) => match state.renew_at() {
+                    Some(at) => at,
+                    // Token never expires, nothing to do
+                    None => return,
+                },
+            }
+        };
+        tokio::time::sleep_until(renew_at.into()).await;
+
+        let Some(inner) = weak.upgrade() else { return };
+        let renewable = inner
+            .token
+            .read()
+            .await
+            .as_ref()
+            .is_some_and(|state| state.renewable);
+
+        let renewed = if renewable {
+            inner.renew().await
+        } else {
+            Err(SecretError::Unauthorized(
+                "Vault token is not renewable".to_string(),
+            ))
+        };
+
+        if let Err(e) = renewed {
+            if !inner.config.auth.can_login() {
+                tracing::error!("Failed to renew Vault token: {}", e);
+                return;
+            }
+            tracing::info!("Vault token not renewed ({}), logging in again", e);
+            let login = {
+                let _guard = inner.login_lock.lock().await;
+                inner.login().await
+            };
+            if let Err(e) = login {
+                tracing::warn!("Vault login failed: {}", e);
+                drop(inner);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+fn field_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+impl VaultSecretManager {
+    async fn get_field(&self, name: &str, version: Option<u64>) -> Result<Secret> {
+        let (path, field) = split_name(name)?;
+        let entry = self
+            .inner
+            .read(path, version)
+            .await?
+            .ok_or_else(|| SecretError::NotFound(name.to_string()))?;
+
+        entry
+            .data
+            .as_ref()
+            .and_then(|data| data.get(field))
+            .map(|value| Secret {
+                value: field_value(value),
+                version: Some(entry.metadata.version),
+                lease_duration: (entry.lease_duration > 0)
+                    .then(|| Duration::from_secs(entry.lease_duration)),
+            })
+            .ok_or_else(|| SecretError::NotFound(name.to_string()))
+    }
+}
+
+#[async_trait]
+impl SecretManager for VaultSecretManager {
+    async fn get_secret(&self, name: &str) -> Result<Secret> {
+        self.get_field(name, None).await
+    }
+
+    async fn get_secret_version(&self, name: &str, version: u64) -> Result<Secret> {
+        self.get_field(name, Some(version)).await
+    }
+
+    async fn create_secret(&self, name: &str, value: &str) -> Result<Secret> {
+        let (path, field) = split_name(name)?;
+        let (mut data, cas) = match self.inner.read(path, None).await? {
+            Some(entry) => (entry.data.unwrap_or_default(), entry.metadata.version),
+            None => (Map::new(), 0),
+        };
+
+        data.insert(field.to_string(), Value::String(value.to_string()));
+        let version = self.inner.write(path, data, cas).await?;
+
+        Ok(Secret {
+            value: value.to_string(),
+            version: Some(version),
+            lease_duration: None,
+        })
+    }
+
+    async fn revoke_secret(&self, name: &str) -> Result<()> {
+        let (path, field) = split_name(name)?;
+        let entry = self
+            .inner
+            .read(path, None)
+            .await?
+            .ok_or_else(|| SecretError::NotFound(name.to_string()))?;
+        let mut data = entry.data.unwrap_or_default();
+        if data.remove(field).is_none() {
+            return Err(SecretError::NotFound(name.to_string()));
+        }
+
+        if data.is_empty() {
+            // Soft delete: the version stays recoverable with `vault kv undelete`
+            self.inner.delete(path).await
+        } else {
+            self.inner
+                .write(path, data, entry.metadata.version)
+                .await
+                .map(|_| ())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+
+        let first = policy.backoff(0);
+        assert!(first >= Duration::from_millis(100) && first <= Duration::from_millis(150));
+        let third = policy.backoff(2);
+        assert!(third >= Duration::from_millis(400) && third <= Duration::from_millis(600));
+        let capped = policy.backoff(10);
+        assert!(capped >= Duration::from_secs(1) && capped <= Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn test_renewal_is_scheduled_before_expiry() {
+        let state = TokenState::new("t".to_string(), 3, true);
+        let renew_in = state.renew_at().unwrap() - state.obtained_at;
+        assert_eq!(renew_in, Duration::from_secs(2));
+
+        assert!(TokenState::new("root".to_string(), 0, false)
+            .renew_at()
+            .is_none());
+    }
+}
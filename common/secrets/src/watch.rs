@@ -0,0 +1,287 @@
+//! Watching secrets for changes.
+//!
+//! None of the backends push changes, so [`watch`] polls: on a fixed
+//! interval, sooner when the value was issued with a lease, and with
+//! exponential backoff while the backend is failing. [`SecretHandle`] keeps
+//! the latest value where request handlers can read it without locking.
+
+use arc_swap::ArcSwap;
+use futures_core::Stream;
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, watch as channel},
+    task::JoinHandle,
+};
+
+use crate::{Result, Secret, SecretManager};
+
+/// How often a watched secret is read again
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    /// Delay between reads while the backend is healthy
+    pub interval: Duration,
+    /// First delay after a failed read, doubled on every further failure
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl WatchOptions {
+    /// Reads the interval from `SECRETS_WATCH_INTERVAL_SECS`
+    pub fn from_env() -> Self {
+        let mut options = Self::default();
+        if let Some(secs) = std::env::var("SECRETS_WATCH_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            options.interval = Duration::from_secs(secs);
+        }
+        options
+    }
+
+    /// Delay before the next read of `secret`. Leased values are read again
+    /// once two thirds of the lease have passed.
+    fn next_poll(&self, secret: &Secret) -> Duration {
+        match secret.lease_duration {
+            Some(lease) => self.interval.min(lease.mul_f64(2.0 / 3.0)),
+            None => self.interval,
+        }
+    }
+}
+
+/// Values of a watched secret: the current one first, then every change.
+/// Polling stops when the stream is dropped.
+pub struct SecretStream {
+    values: mpsc::Receiver<Secret>,
+    task: JoinHandle<()>,
+}
+
+impl SecretStream {
+    /// The next value, without needing `StreamExt`
+    pub async fn next(&mut self) -> Option<Secret> {
+        self.values.recv().await
+    }
+}
+
+impl Stream for SecretStream {
+    type Item = Secret;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Secret>> {
+        self.values.poll_recv(cx)
+    }
+}
+
+impl Drop for SecretStream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Polls `name` and yields its value whenever it changes. Failed reads are
+/// logged and retried; the stream only ends when it is dropped.
+pub fn watch<M>(manager: M, name: &str, options: WatchOptions) -> SecretStream
+where
+    M: SecretManager + 'static,
+{
+    let (tx, values) = mpsc::channel(1);
+    let name = name.to_string();
+
+    let task = tokio::spawn(async move {
+        let mut last: Option<Secret> = None;
+        let mut backoff = options.initial_backoff;
+        loop {
+            let delay = match manager.get_secret(&name).await {
+                Ok(secret) => {
+                    backoff = options.initial_backoff;
+                    let delay = options.next_poll(&secret);
+                    if last.as_ref() != Some(&secret) {
+                        last = Some(secret.clone());
+                        if tx.send(secret).await.is_err() {
+                            return;
+                        }
+                    }
+                    delay
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to read secret {}, retrying in {:?}: {}",
+                        name,
+                        backoff,
+                        e
+                    );
+                    let delay = backoff;
+                    backoff = (backoff * 2).min(options.max_backoff);
+                    delay
+                }
+            };
+            tokio::time::sleep(delay).await;
+        }
+    });
+
+    SecretStream { values, task }
+}
+
+/// The live value of one secret.
+///
+/// Reads are lock-free and always see a complete value. Clones share the
+/// value, so a handle can be given to middleware, pools and services and
+/// updated in one place.
+#[derive(Clone)]
+pub struct SecretHandle {
+    current: Arc<ArcSwap<Secret>>,
+    changes: Arc<channel::Sender<()>>,
+}
+
+impl SecretHandle {
+    pub fn new(secret: Secret) -> Self {
+        let (changes, _) = channel::channel(());
+        Self {
+            current: Arc::new(ArcSwap::from_pointee(secret)),
+            changes: Arc::new(changes),
+        }
+    }
+
+    /// Reads `name` once and keeps the handle up to date in the background.
+    /// The watch stops once every clone of the handle has been dropped.
+    pub async fn watch<M>(manager: M, name: &str, options: WatchOptions) -> Result<Self>
+    where
+        M: SecretManager + 'static,
+    {
+        let handle = Self::new(manager.get_secret(name).await?);
+
+        let mut values = watch(manager, name, options);
+        let current = Arc::downgrade(&handle.current);
+        let changes = Arc::downgrade(&handle.changes);
+        tokio::spawn(async move {
+            while let Some(secret) = values.next().await {
+                let (Some(current), Some(changes)) = (current.upgrade(), changes.upgrade()) else {
+                    return;
+                };
+                Self::replace(&current, &changes, secret);
+            }
+        });
+
+        Ok(handle)
+    }
+
+    /// The current value
+    pub fn load(&self) -> Arc<Secret> {
+        self.current.load_full()
+    }
+
+    /// Replaces the value and notifies subscribers
+    pub fn store(&self, secret: Secret) {
+        Self::replace(&self.current, &self.changes, secret);
+    }
+
+    fn replace(current: &ArcSwap<Secret>, changes: &channel::Sender<()>, secret: Secret) {
+        if **current.load() == secret {
+            return;
+        }
+        current.store(Arc::new(secret));
+        changes.send_replace(());
+    }
+
+    /// A receiver that is notified whenever the value changes
+    pub fn subscribe(&self) -> channel::Receiver<()> {
+        self.changes.subscribe()
+    }
+}
+
+impl From<String> for SecretHandle {
+    fn from(value: String) -> Self {
+        Self::new(Secret::new(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EncryptedFileSecretManager;
+
+    fn fast() -> WatchOptions {
+        WatchOptions {
+            interval: Duration::from_millis(20),
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(40),
+        }
+    }
+
+    async fn next(stream: &mut SecretStream) -> Option<Secret> {
+        tokio::time::timeout(Duration::from_secs(2), stream.next())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn test_stream_yields_changes_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = Arc::new(EncryptedFileSecretManager::new(
+            dir.path().join("secrets.enc"),
+            &[7; 32],
+        ));
+
+        // Not there yet: the watch keeps retrying until it appears
+        let mut stream = watch(manager.clone(), "db/url", fast());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        manager.create_secret("db/url", "one").await.unwrap();
+        assert_eq!(next(&mut stream).await.unwrap().value, "one");
+
+        manager.create_secret("db/url", "two").await.unwrap();
+        let two = next(&mut stream).await.unwrap();
+        assert_eq!(two.value, "two");
+        assert_eq!(two.version, Some(2));
+
+        // Unchanged values are not repeated
+        let quiet = tokio::time::timeout(Duration::from_millis(100), stream.next()).await;
+        assert!(quiet.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_follows_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = Arc::new(EncryptedFileSecretManager::new(
+            dir.path().join("secrets.enc"),
+            &[7; 32],
+        ));
+        manager.create_secret("jwt", "first").await.unwrap();
+
+        let handle = SecretHandle::watch(manager.clone(), "jwt", fast())
+            .await
+            .unwrap();
+        let mut changes = handle.subscribe();
+        assert_eq!(handle.load().value, "first");
+
+        manager.create_secret("jwt", "second").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), changes.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(handle.clone().load().value, "second");
+    }
+
+    #[test]
+    fn test_leased_secrets_are_read_before_expiry() {
+        let options = WatchOptions::default();
+        let mut secret = Secret::new("value");
+        assert_eq!(options.next_poll(&secret), Duration::from_secs(30));
+
+        secret.lease_duration = Some(Duration::from_secs(15));
+        assert_eq!(options.next_poll(&secret), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,390 @@
+//! Runs the Vault backend against a small in-process stand-in for the KV v2
+//! and auth HTTP APIs
+
+use lotabots_secrets::{
+    RetryPolicy, SecretError, SecretManager, VaultAuth, VaultConfig, VaultSecretManager,
+};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use wiremock::{matchers::path_regex, Mock, MockServer, Request, Respond, ResponseTemplate};
+
+const TOKEN: &str = "test-root-token";
+const ROLE_ID: &str = "test-role";
+const SECRET_ID: &str = "test-secret-id";
+const K8S_JWT: &str = "service-account-jwt";
+
+#[derive(Default)]
+struct Entry {
+    versions: Vec<Option<Map<String, Value>>>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    /// Issued tokens and when they expire; `None` never expires
+    tokens: HashMap<String, Option<Instant>>,
+}
+
+/// Keeps KV v2 entries in memory and answers like Vault does, including
+/// check-and-set, soft deletes, logins with expiring tokens and renewal
+#[derive(Clone)]
+struct FakeVault {
+    state: Arc<Mutex<State>>,
+    lease_secs: u64,
+    logins: Arc<AtomicUsize>,
+    renewals: Arc<AtomicUsize>,
+    /// Requests still to be answered with 503
+    failures: Arc<AtomicUsize>,
+}
+
+impl FakeVault {
+    fn new(lease_secs: u64) -> Self {
+        let mut state = State::default();
+        state.tokens.insert(TOKEN.to_string(), None);
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            lease_secs,
+            logins: Arc::default(),
+            renewals: Arc::default(),
+            failures: Arc::default(),
+        }
+    }
+
+    fn lease(&self, state: &mut State, token: String) -> ResponseTemplate {
+        let expires_at = Instant::now() + Duration::from_secs(self.lease_secs);
+        state.tokens.insert(token.clone(), Some(expires_at));
+        ResponseTemplate::new(200).set_body_json(json!({
+            "auth": {
+                "client_token": token,
+                "lease_duration": self.lease_secs,
+                "renewable": true
+            }
+        }))
+    }
+
+    fn login(&self, state: &mut State) -> ResponseTemplate {
+        let n = self.logins.fetch_add(1, Ordering::SeqCst) + 1;
+        self.lease(state, format!("issued-{}", n))
+    }
+}
+
+impl Respond for FakeVault {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        if self
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return ResponseTemplate::new(503).set_body_json(json!({ "errors": ["sealed"] }));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let path = request.url.path().to_string();
+        let body: Value = serde_json::from_slice(&request.body).unwrap_or(Value::Null);
+
+        match path.as_str() {
+            "/v1/auth/approle/login" => {
+                if body["role_id"] != ROLE_ID || body["secret_id"] != SECRET_ID {
+                    return ResponseTemplate::new(400)
+                        .set_body_json(json!({ "errors": ["invalid role or secret ID"] }));
+                }
+                return self.login(&mut state);
+            }
+            "/v1/auth/kubernetes/login" => {
+                if body["role"] != "lotabots" || body["jwt"] != K8S_JWT {
+                    return ResponseTemplate::new(403)
+                        .set_body_json(json!({ "errors": ["permission denied"] }));
+                }
+                return self.login(&mut state);
+            }
+            _ => {}
+        }
+
+        let token = request
+            .headers
+            .iter()
+            .find(|(name, _)| name.as_str().eq_ignore_ascii_case("X-Vault-Token"))
+            .map(|(_, values)| values.last().as_str().to_string())
+            .unwrap_or_default();
+        let valid = match state.tokens.get(&token) {
+            Some(None) => true,
+            Some(Some(expires_at)) => *expires_at > Instant::now(),
+            None => false,
+        };
+        if !valid {
+            return ResponseTemplate::new(403)
+                .set_body_json(json!({ "errors": ["permission denied"] }));
+        }
+
+        if path == "/v1/auth/token/renew-self" {
+            self.renewals.fetch_add(1, Ordering::SeqCst);
+            return self.lease(&mut state, token);
+        }
+
+        let path = path.trim_start_matches("/v1/secret/data/").to_string();
+        match request.method.to_string().as_str() {
+            "GET" => match state.entries.get(&path).and_then(|e| {
+                let version = request
+                    .url
+                    .query_pairs()
+                    .find(|(key, _)| key == "version")
+                    .and_then(|(_, v)| v.parse::<usize>().ok())
+                    .unwrap_or(e.versions.len());
+                e.versions
+                    .get(version.checked_sub(1)?)
+                    .map(|data| (version, data))
+            }) {
+                Some((version, data)) => ResponseTemplate::new(200).set_body_json(json!({
+                    "data": { "data": data, "metadata": { "version": version } }
+                })),
+                None => ResponseTemplate::new(404).set_body_json(json!({ "errors": [] })),
+            },
+            "POST" => {
+                let entry = state.entries.entry(path).or_default();
+                let cas = body["options"]["cas"].as_u64();
+                if cas.is_some_and(|cas| cas != entry.versions.len() as u64) {
+                    return ResponseTemplate::new(400).set_body_json(
+                        json!({ "errors": ["check-and-set parameter did not match"] }),
+                    );
+                }
+                entry.versions.push(body["data"].as_object().cloned());
+                ResponseTemplate::new(200).set_body_json(json!({
+                    "data": { "version": entry.versions.len() }
+                }))
+            }
+            "DELETE" => {
+                if let Some(latest) = state
+                    .entries
+                    .get_mut(&path)
+                    .and_then(|e| e.versions.last_mut())
+                {
+                    *latest = None;
+                }
+                ResponseTemplate::new(204)
+            }
+            _ => ResponseTemplate::new(405),
+        }
+    }
+}
+
+/// Starts the stand-in, issuing login tokens that expire after `lease_secs`
+async fn start_vault(lease_secs: u64) -> (MockServer, FakeVault) {
+    let server = MockServer::start().await;
+    let vault = FakeVault::new(lease_secs);
+    Mock::given(path_regex("^/v1/.+"))
+        .respond_with(vault.clone())
+        .mount(&server)
+        .await;
+    (server, vault)
+}
+
+fn fast_retries(mut config: VaultConfig) -> VaultConfig {
+    config.retry = RetryPolicy {
+        max_retries: 3,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(50),
+    };
+    config
+}
+
+#[tokio::test]
+async fn test_vault_round_trip() {
+    let (server, _) = start_vault(60).await;
+    let manager = VaultSecretManager::new(VaultConfig::new(server.uri(), TOKEN)).unwrap();
+
+    assert!(manager
+        .get_secret("auth/jwt")
+        .await
+        .unwrap_err()
+        .is_not_found());
+
+    let created = manager.create_secret("auth/jwt", "first").await.unwrap();
+    assert_eq!(created.version, Some(1));
+    let updated = manager.create_secret("auth/jwt", "second").await.unwrap();
+    assert_eq!(updated.version, Some(2));
+
+    let secret = manager.get_secret("auth/jwt").await.unwrap();
+    assert_eq!(secret.value, "second");
+    assert_eq!(secret.version, Some(2));
+
+    manager.revoke_secret("auth/jwt").await.unwrap();
+    assert!(manager
+        .get_secret("auth/jwt")
+        .await
+        .unwrap_err()
+        .is_not_found());
+}
+
+#[tokio::test]
+async fn test_vault_reads_earlier_versions() {
+    let (server, _) = start_vault(60).await;
+    let manager = VaultSecretManager::new(VaultConfig::new(server.uri(), TOKEN)).unwrap();
+
+    manager.create_secret("auth/jwt", "first").await.unwrap();
+    manager.create_secret("auth/jwt", "second").await.unwrap();
+
+    let first = manager.get_secret_version("auth/jwt", 1).await.unwrap();
+    assert_eq!(first.value, "first");
+    assert_eq!(first.version, Some(1));
+    assert!(manager
+        .get_secret_version("auth/jwt", 3)
+        .await
+        .unwrap_err()
+        .is_not_found());
+}
+
+#[tokio::test]
+async fn test_vault_fields_share_an_entry() {
+    let (server, _) = start_vault(60).await;
+    let manager = VaultSecretManager::new(VaultConfig::new(server.uri(), TOKEN)).unwrap();
+
+    manager
+        .create_secret("db/main#username", "app")
+        .await
+        .unwrap();
+    manager
+        .create_secret("db/main#password", "s3cret")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        manager.get_secret("db/main#username").await.unwrap().value,
+        "app"
+    );
+    assert_eq!(
+        manager.get_secret("db/main#password").await.unwrap().value,
+        "s3cret"
+    );
+
+    // Revoking one field keeps the other
+    manager.revoke_secret("db/main#password").await.unwrap();
+    assert!(manager
+        .get_secret("db/main#password")
+        .await
+        .unwrap_err()
+        .is_not_found());
+    assert_eq!(
+        manager.get_secret("db/main#username").await.unwrap().value,
+        "app"
+    );
+}
+
+#[tokio::test]
+async fn test_vault_rejects_bad_token() {
+    let (server, _) = start_vault(60).await;
+    let manager = VaultSecretManager::new(VaultConfig::new(server.uri(), "wrong")).unwrap();
+
+    assert!(matches!(
+        manager.get_secret("auth/jwt").await,
+        Err(SecretError::Unauthorized(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_vault_unreachable() {
+    // Nothing listens on the discard port
+    let config = fast_retries(VaultConfig::new("http://127.0.0.1:9", TOKEN));
+    let manager = VaultSecretManager::new(config).unwrap();
+
+    assert!(matches!(
+        manager.get_secret("auth/jwt").await,
+        Err(SecretError::Backend(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_approle_logs_in_on_first_use() {
+    let (server, vault) = start_vault(60).await;
+    let config = VaultConfig::with_auth(server.uri(), VaultAuth::app_role(ROLE_ID, SECRET_ID));
+    let manager = VaultSecretManager::new(config).unwrap();
+
+    manager.create_secret("auth/jwt", "value").await.unwrap();
+    assert_eq!(manager.get_secret("auth/jwt").await.unwrap().value, "value");
+    assert_eq!(vault.logins.load(Ordering::SeqCst), 1);
+
+    let config = VaultConfig::with_auth(server.uri(), VaultAuth::app_role(ROLE_ID, "wrong"));
+    let manager = VaultSecretManager::new(config).unwrap();
+    assert!(manager.get_secret("auth/jwt").await.is_err());
+}
+
+#[tokio::test]
+async fn test_kubernetes_login_reads_service_account_token() {
+    let (server, vault) = start_vault(60).await;
+    let dir = tempfile::tempdir().unwrap();
+    let jwt_path = dir.path().join("token");
+    std::fs::write(&jwt_path, format!("{}\n", K8S_JWT)).unwrap();
+
+    let auth = VaultAuth::Kubernetes {
+        role: "lotabots".to_string(),
+        jwt_path,
+        mount: "kubernetes".to_string(),
+    };
+    let manager = VaultSecretManager::new(VaultConfig::with_auth(server.uri(), auth)).unwrap();
+
+    manager.login().await.unwrap();
+    manager.create_secret("auth/jwt", "value").await.unwrap();
+    assert_eq!(vault.logins.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_logs_in_again_after_token_expiry() {
+    let (server, vault) = start_vault(1).await;
+    let config = VaultConfig::with_auth(server.uri(), VaultAuth::app_role(ROLE_ID, SECRET_ID));
+    let manager = VaultSecretManager::new(config).unwrap();
+
+    manager.create_secret("auth/jwt", "value").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    // The expired token is rejected, so the backend logs in and retries
+    assert_eq!(manager.get_secret("auth/jwt").await.unwrap().value, "value");
+    assert_eq!(vault.logins.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_background_renewal_keeps_token_alive() {
+    let (server, vault) = start_vault(1).await;
+    let config = VaultConfig::with_auth(server.uri(), VaultAuth::app_role(ROLE_ID, SECRET_ID));
+    let manager = VaultSecretManager::new(config).unwrap();
+
+    manager.login().await.unwrap();
+    let renewal = manager.start_renewal();
+    manager.create_secret("auth/jwt", "value").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1600)).await;
+
+    assert!(vault.renewals.load(Ordering::SeqCst) >= 1);
+    assert_eq!(manager.get_secret("auth/jwt").await.unwrap().value, "value");
+    // Renewed rather than replaced
+    assert_eq!(vault.logins.load(Ordering::SeqCst), 1);
+
+    // The task stops once the manager is gone
+    drop(manager);
+    tokio::time::timeout(Duration::from_secs(2), renewal)
+        .await
+        .expect("renewal task did not stop")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_retries_transient_errors() {
+    let (server, vault) = start_vault(60).await;
+    let manager =
+        VaultSecretManager::new(fast_retries(VaultConfig::new(server.uri(), TOKEN))).unwrap();
+    manager.create_secret("auth/jwt", "value").await.unwrap();
+
+    vault.failures.store(2, Ordering::SeqCst);
+    assert_eq!(manager.get_secret("auth/jwt").await.unwrap().value, "value");
+
+    // Gives up once the retries are used up
+    vault.failures.store(10, Ordering::SeqCst);
+    assert!(matches!(
+        manager.get_secret("auth/jwt").await,
+        Err(SecretError::Backend(_))
+    ));
+}
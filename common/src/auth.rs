@@ -1,4 +1,5 @@
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use lotabots_api_keys::{is_api_key, ApiKeyError, ApiKeyVerifier, API_KEY_PREFIX};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use uuid::Uuid;
@@ -19,11 +20,14 @@ pub enum AuthError {
     TokenExpired,
     #[error("Missing authorization header")]
     MissingHeader,
+    #[error("Invalid API key")]
+    InvalidApiKey(#[from] ApiKeyError),
 }
 
 pub struct Auth {
     encoding_key: EncodingKey,
     decoding_key: DecodingKey,
+    api_keys: Option<ApiKeyVerifier>,
 }
 
 impl Auth {
@@ -31,9 +35,16 @@ impl Auth {
         Self {
             encoding_key: EncodingKey::from_secret(secret),
             decoding_key: DecodingKey::from_secret(secret),
+            api_keys: None,
         }
     }
 
+    /// Accept API keys in [`Auth::verify_credential`]
+    pub fn with_api_keys(mut self, verifier: ApiKeyVerifier) -> Self {
+        self.api_keys = Some(verifier);
+        self
+    }
+
     pub fn create_token(&self, claims: &Claims) -> Result<String, AuthError> {
         encode(&Header::default(), claims, &self.encoding_key).map_err(AuthError::InvalidToken)
     }
@@ -47,12 +58,35 @@ impl Auth {
                 _ => AuthError::InvalidToken(e),
             })
     }
+
+    /// Verifies either an API key or a JWT, including OAuth2 client access
+    /// tokens. API keys map to claims carrying the key's tenant and scopes.
+    pub async fn verify_credential(&self, credential: &str) -> Result<Claims, AuthError> {
+        if !is_api_key(credential) {
+            return self.verify_token(credential);
+        }
+
+        let verifier = self.api_keys.as_ref().ok_or(ApiKeyError::Unknown)?;
+        let key = verifier.verify(credential).await?;
+        Ok(Claims {
+            sub: format!("{}_{}", API_KEY_PREFIX, key.prefix),
+            exp: key
+                .expires_at
+                .map_or(usize::MAX, |expires_at| expires_at.timestamp() as usize),
+            tenant_id: key.tenant_id,
+            permissions: key.scopes,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use lotabots_api_keys::{ApiKeyRecord, InMemoryApiKeyStore};
+    use std::{
+        sync::Arc,
+        time::{SystemTime, UNIX_EPOCH},
+    };
 
     #[test]
     fn test_token_creation_and_verification() {
@@ -74,4 +108,31 @@ mod tests {
         assert_eq!(claims.sub, verified.sub);
         assert_eq!(claims.permissions, verified.permissions);
     }
+
+    #[test]
+    fn test_api_key_verification() {
+        let generated = lotabots_api_keys::generate_api_key();
+        let tenant_id = Uuid::new_v4();
+        let store = Arc::new(InMemoryApiKeyStore::new());
+        store.insert(ApiKeyRecord {
+            id: Uuid::new_v4(),
+            tenant_id,
+            name: "ci".to_string(),
+            prefix: generated.prefix,
+            secret_hash: generated.secret_hash,
+            scopes: vec!["products:read".to_string()],
+            expires_at: None,
+            last_used_at: None,
+            revoked_at: None,
+            created_at: chrono::Utc::now(),
+        });
+
+        let auth = Auth::new(b"test-secret");
+        assert!(tokio_test::block_on(auth.verify_credential(&generated.token)).is_err());
+
+        let auth = auth.with_api_keys(ApiKeyVerifier::new(store));
+        let claims = tokio_test::block_on(auth.verify_credential(&generated.token)).unwrap();
+        assert_eq!(claims.tenant_id, tenant_id);
+        assert_eq!(claims.permissions, vec!["products:read".to_string()]);
+    }
 }
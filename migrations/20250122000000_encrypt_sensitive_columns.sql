@@ -0,0 +1,5 @@
+-- MFA secrets and document metadata are stored envelope encrypted, as
+-- `enc:v1:<key version>:<base64>` text. Existing plaintext keeps working and
+-- is encrypted by UserService::reencrypt_mfa_secrets and
+-- Document::reencrypt_metadata.
+ALTER TABLE documents ALTER COLUMN metadata TYPE TEXT USING metadata::text;
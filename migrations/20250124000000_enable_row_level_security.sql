@@ -0,0 +1,30 @@
+-- Tenant-owned rows are only visible to connections bound to their tenant.
+-- Pools created with tenant isolation (DB_TENANT_ISOLATION=true) set
+-- app.tenant_id from the request's tenant on every checkout; without it
+-- the setting is empty and no rows match.
+--
+-- Policies are not forced, so the role owning the tables (and running
+-- migrations) still sees every row and deployments without isolation are
+-- unaffected. Isolated deployments connect as a role that does not own the
+-- tables, e.g.:
+--
+--   CREATE ROLE lotabots_app LOGIN PASSWORD '...';
+--   GRANT SELECT, INSERT, UPDATE, DELETE ON ALL TABLES IN SCHEMA public TO lotabots_app;
+
+ALTER TABLE users ENABLE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON users;
+CREATE POLICY tenant_isolation ON users
+    USING (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid)
+    WITH CHECK (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid);
+
+ALTER TABLE documents ENABLE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON documents;
+CREATE POLICY tenant_isolation ON documents
+    USING (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid)
+    WITH CHECK (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid);
+
+ALTER TABLE workflows ENABLE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON workflows;
+CREATE POLICY tenant_isolation ON workflows
+    USING (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid)
+    WITH CHECK (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid);
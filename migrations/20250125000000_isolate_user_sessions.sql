@@ -0,0 +1,16 @@
+-- Sessions belong to the tenant of their user and are isolated like the
+-- other tenant-owned tables.
+--
+-- tenants is left without a policy: TenantMiddleware reads it to resolve
+-- the tenant of a request, before there is a context to check against.
+
+ALTER TABLE user_sessions ADD COLUMN IF NOT EXISTS tenant_id UUID REFERENCES tenants(id);
+UPDATE user_sessions s SET tenant_id = u.tenant_id FROM users u
+    WHERE s.user_id = u.id AND s.tenant_id IS NULL;
+ALTER TABLE user_sessions ALTER COLUMN tenant_id SET NOT NULL;
+
+ALTER TABLE user_sessions ENABLE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON user_sessions;
+CREATE POLICY tenant_isolation ON user_sessions
+    USING (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid)
+    WITH CHECK (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid);
@@ -0,0 +1,20 @@
+-- Metered usage per tenant and route, rolled up per minute, hour and day.
+-- Counters are added to by the gateway's usage writer; latency_ms is the
+-- total over all requests of the period.
+CREATE TABLE IF NOT EXISTS usage_rollups (
+    tenant_id UUID NOT NULL,
+    granularity TEXT NOT NULL CHECK (granularity IN ('minute', 'hour', 'day')),
+    period_start TIMESTAMPTZ NOT NULL,
+    route TEXT NOT NULL,
+    requests BIGINT NOT NULL DEFAULT 0,
+    errors BIGINT NOT NULL DEFAULT 0,
+    request_bytes BIGINT NOT NULL DEFAULT 0,
+    response_bytes BIGINT NOT NULL DEFAULT 0,
+    tokens BIGINT NOT NULL DEFAULT 0,
+    latency_ms BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (tenant_id, granularity, period_start, route)
+);
+
+-- Pruning old minute and hour rollups
+CREATE INDEX IF NOT EXISTS usage_rollups_granularity_period_idx
+    ON usage_rollups (granularity, period_start);
@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS invoice_line_items;
+DROP TABLE IF EXISTS invoices;
+DROP TABLE IF EXISTS billing_accounts;
+DROP TABLE IF EXISTS billable_events;
@@ -0,0 +1,57 @@
+-- Billable usage not metered by the gateway itself, reported by services:
+-- storage snapshots and GPU time. Reports are deduplicated by their
+-- idempotency key, so services can retry them safely.
+CREATE TABLE IF NOT EXISTS billable_events (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    tenant_id UUID NOT NULL,
+    metric TEXT NOT NULL CHECK (metric IN ('storage_bytes', 'gpu_seconds')),
+    quantity BIGINT NOT NULL CHECK (quantity >= 0),
+    occurred_at TIMESTAMPTZ NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (tenant_id, idempotency_key)
+);
+
+CREATE INDEX IF NOT EXISTS billable_events_tenant_occurred_idx
+    ON billable_events (tenant_id, occurred_at);
+
+-- Where a tenant is billed in Stripe; subscription_items maps metrics to
+-- the subscription items their usage records are reported on
+CREATE TABLE IF NOT EXISTS billing_accounts (
+    tenant_id UUID PRIMARY KEY,
+    stripe_customer_id TEXT,
+    subscription_items JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- One invoice per tenant and period. Draft invoices are regenerated when
+-- billing runs again for their period; finalized ones are left alone.
+CREATE TABLE IF NOT EXISTS invoices (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    tenant_id UUID NOT NULL,
+    period_start TIMESTAMPTZ NOT NULL,
+    period_end TIMESTAMPTZ NOT NULL,
+    tier TEXT NOT NULL,
+    currency TEXT NOT NULL,
+    total_cents BIGINT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'draft' CHECK (status IN ('draft', 'finalized')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (tenant_id, period_start, period_end)
+);
+
+CREATE TABLE IF NOT EXISTS invoice_line_items (
+    invoice_id UUID NOT NULL REFERENCES invoices(id) ON DELETE CASCADE,
+    line INT NOT NULL,
+    -- NULL for the plan's base fee
+    metric TEXT,
+    description TEXT NOT NULL,
+    quantity BIGINT NOT NULL,
+    included BIGINT NOT NULL,
+    billable_units BIGINT NOT NULL,
+    unit_size BIGINT NOT NULL,
+    unit_amount_cents BIGINT NOT NULL,
+    amount_cents BIGINT NOT NULL,
+    PRIMARY KEY (invoice_id, line)
+);
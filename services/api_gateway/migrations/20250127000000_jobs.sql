@@ -0,0 +1,29 @@
+-- Durable background jobs. Workers claim runnable jobs with
+-- SELECT ... FOR UPDATE SKIP LOCKED and hold them for a lease; a job whose
+-- lease expires, e.g. because its worker died, is claimed again.
+CREATE TABLE IF NOT EXISTS jobs (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    job_type TEXT NOT NULL,
+    payload JSONB NOT NULL DEFAULT '{}',
+    -- NULL for system jobs
+    tenant_id UUID,
+    status TEXT NOT NULL DEFAULT 'queued'
+        CHECK (status IN ('queued', 'running', 'succeeded', 'dead', 'cancelled')),
+    attempts INT NOT NULL DEFAULT 0,
+    max_attempts INT NOT NULL DEFAULT 5 CHECK (max_attempts > 0),
+    -- Not run before; also when a failed job is retried
+    run_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- Jobs with the same type and key are only enqueued once
+    unique_key TEXT,
+    last_error TEXT,
+    locked_by TEXT,
+    locked_until TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at TIMESTAMPTZ,
+    UNIQUE (job_type, unique_key)
+);
+
+CREATE INDEX IF NOT EXISTS jobs_runnable_idx ON jobs (run_at) WHERE status = 'queued';
+CREATE INDEX IF NOT EXISTS jobs_leased_idx ON jobs (locked_until) WHERE status = 'running';
+CREATE INDEX IF NOT EXISTS jobs_status_created_idx ON jobs (status, created_at);
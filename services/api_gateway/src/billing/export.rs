@@ -0,0 +1,191 @@
+//! Invoice exports for billing systems
+
+use serde::Serialize;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use super::Invoice;
+
+const CSV_HEADER: &str = "invoice_id,tenant_id,period_start,period_end,tier,currency,status,\
+line,metric,description,quantity,included,billable_units,unit_size,unit_amount_cents,amount_cents";
+
+/// One row per line item
+pub fn to_csv(invoices: &[Invoice]) -> String {
+    let mut csv = format!("{}\n", CSV_HEADER);
+    for invoice in invoices {
+        for (line, item) in invoice.lines.iter().enumerate() {
+            let fields = [
+                invoice.id.to_string(),
+                invoice.tenant_id.to_string(),
+                invoice.period_start.to_rfc3339(),
+                invoice.period_end.to_rfc3339(),
+                invoice.tier.clone(),
+                invoice.currency.clone(),
+                invoice.status.clone(),
+                (line + 1).to_string(),
+                item.metric.map_or("base_fee", |m| m.as_str()).to_string(),
+                item.description.clone(),
+                item.quantity.to_string(),
+                item.included.to_string(),
+                item.billable_units.to_string(),
+                item.unit_size.to_string(),
+                item.unit_amount_cents.to_string(),
+                item.amount_cents.to_string(),
+            ];
+            let row: Vec<_> = fields.iter().map(|field| escape(field)).collect();
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+    }
+    csv
+}
+
+/// Quotes fields containing separators, quotes or line breaks (RFC 4180)
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// A usage record as accepted by Stripe's
+/// `POST /v1/subscription_items/{subscription_item}/usage_records`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StripeUsageRecord {
+    pub subscription_item: String,
+    /// Billable units, so the Stripe price per unit matches the price book
+    pub quantity: i64,
+    /// Unix time within the period, its last second
+    pub timestamp: i64,
+    /// Always `set`, so re-sending a record replaces rather than adds to it
+    pub action: &'static str,
+    /// To send as the `Idempotency-Key` header; stable across re-runs
+    pub idempotency_key: String,
+}
+
+/// Usage records of the metered line items of `invoices`, for tenants with
+/// a subscription item for the metric in `subscription_items`. Base fees
+/// are licensed prices in Stripe and have no usage records.
+pub fn to_stripe_usage_records(
+    invoices: &[Invoice],
+    subscription_items: &HashMap<Uuid, HashMap<String, String>>,
+) -> Vec<StripeUsageRecord> {
+    let mut records = Vec::new();
+    for invoice in invoices {
+        for item in &invoice.lines {
+            let Some(metric) = item.metric else {
+                continue;
+            };
+            let Some(subscription_item) = subscription_items
+                .get(&invoice.tenant_id)
+                .and_then(|items| items.get(metric.as_str()))
+            else {
+                tracing::warn!(
+                    "Tenant {} has no subscription item for {}, skipping its usage record",
+                    invoice.tenant_id,
+                    metric.as_str()
+                );
+                continue;
+            };
+
+            records.push(StripeUsageRecord {
+                subscription_item: subscription_item.clone(),
+                quantity: item.billable_units,
+                timestamp: invoice.period_end.timestamp() - 1,
+                action: "set",
+                idempotency_key: format!(
+                    "{}-{}-{}",
+                    invoice.tenant_id,
+                    invoice.period_start.format("%Y-%m-%d"),
+                    metric.as_str()
+                ),
+            });
+        }
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::billing::{BillingPeriod, LineItem, Metric};
+
+    fn invoice() -> Invoice {
+        let period: BillingPeriod = "2025-01".parse().unwrap();
+        Invoice {
+            id: Uuid::new_v4(),
+            tenant_id: Uuid::new_v4(),
+            period_start: period.start,
+            period_end: period.end,
+            tier: "professional".to_string(),
+            currency: "usd".to_string(),
+            total_cents: 5_000,
+            status: "draft".to_string(),
+            lines: vec![
+                LineItem {
+                    metric: None,
+                    description: "Professional plan".to_string(),
+                    quantity: 1,
+                    included: 0,
+                    billable_units: 1,
+                    unit_size: 1,
+                    unit_amount_cents: 4_900,
+                    amount_cents: 4_900,
+                },
+                LineItem {
+                    metric: Some(Metric::ApiRequests),
+                    description: "API requests, \"overage\"".to_string(),
+                    quantity: 300_001,
+                    included: 300_000,
+                    billable_units: 1,
+                    unit_size: 10_000,
+                    unit_amount_cents: 100,
+                    amount_cents: 100,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_csv_has_a_row_per_line_item() {
+        let invoice = invoice();
+        let csv = to_csv(std::slice::from_ref(&invoice));
+        let rows: Vec<_> = csv.lines().collect();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], CSV_HEADER);
+        assert!(rows[1].contains(",1,base_fee,Professional plan,1,0,1,1,4900,4900"));
+        assert!(rows[2].ends_with(
+            ",2,api_requests,\"API requests, \"\"overage\"\"\",300001,300000,1,10000,100,100"
+        ));
+        assert!(rows[2].starts_with(&format!(
+            "{},{},2025-01-01T00:00:00+00:00",
+            invoice.id, invoice.tenant_id
+        )));
+    }
+
+    #[test]
+    fn test_stripe_records_cover_mapped_metered_items() {
+        let invoice = invoice();
+        let items = HashMap::from([(
+            invoice.tenant_id,
+            HashMap::from([("api_requests".to_string(), "si_123".to_string())]),
+        )]);
+
+        let records = to_stripe_usage_records(std::slice::from_ref(&invoice), &items);
+        assert_eq!(
+            records,
+            vec![StripeUsageRecord {
+                subscription_item: "si_123".to_string(),
+                quantity: 1,
+                timestamp: invoice.period_end.timestamp() - 1,
+                action: "set",
+                idempotency_key: format!("{}-2025-01-01-api_requests", invoice.tenant_id),
+            }]
+        );
+
+        // Tenants without a billing account are skipped
+        assert!(to_stripe_usage_records(&[invoice], &HashMap::new()).is_empty());
+    }
+}
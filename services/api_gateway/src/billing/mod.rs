@@ -0,0 +1,244 @@
+//! Billing: turns metered usage into invoices.
+//!
+//! API requests and tokens come from the gateway's own metering
+//! (`usage_rollups`, see [`crate::usage`]); storage and GPU time are
+//! reported as billable events by the services that incur them. A billing
+//! run rates each tenant's usage over a [`BillingPeriod`] against the prices
+//! of its tier in the [`PriceBook`] and stores the result as an invoice with
+//! line items.
+//!
+//! Runs are idempotent: running a period again regenerates its draft
+//! invoices in place, with the same ids, and finalized invoices are never
+//! changed. Invoices are exported as CSV or as Stripe usage records, see
+//! [`export`].
+
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Utc};
+use lotabots_rate_limit::Tiers;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, str::FromStr};
+use uuid::Uuid;
+
+use crate::{
+    db::{self, DbPool},
+    error::ApiError,
+    jobs::{Job, JobFailure, JobHandler},
+    usage::{month_start, next_month_start},
+};
+
+pub mod export;
+mod price_book;
+
+pub use price_book::{Price, PriceBook, TierPrices};
+
+/// What is billed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    ApiRequests,
+    Tokens,
+    StorageBytes,
+    GpuSeconds,
+}
+
+impl Metric {
+    pub const ALL: [Self; 4] = [
+        Self::ApiRequests,
+        Self::Tokens,
+        Self::StorageBytes,
+        Self::GpuSeconds,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ApiRequests => "api_requests",
+            Self::Tokens => "tokens",
+            Self::StorageBytes => "storage_bytes",
+            Self::GpuSeconds => "gpu_seconds",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::ApiRequests => "API requests",
+            Self::Tokens => "Tokens",
+            Self::StorageBytes => "Storage (peak bytes)",
+            Self::GpuSeconds => "GPU time (seconds)",
+        }
+    }
+
+    /// Whether services report the metric as billable events; the others
+    /// are metered by the gateway
+    pub fn is_reported(&self) -> bool {
+        matches!(self, Self::StorageBytes | Self::GpuSeconds)
+    }
+}
+
+impl FromStr for Metric {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|metric| metric.as_str() == s)
+            .ok_or_else(|| ApiError::ValidationError(format!("Unknown metric '{}'", s)))
+    }
+}
+
+/// Usage of one tenant over a period. Storage is its peak, everything else
+/// the total.
+pub type Usage = BTreeMap<Metric, i64>;
+
+/// The calendar month invoices are generated for, in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BillingPeriod {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl BillingPeriod {
+    /// The month `at` falls in
+    pub fn containing(at: DateTime<Utc>) -> Self {
+        Self {
+            start: month_start(at),
+            end: next_month_start(at),
+        }
+    }
+}
+
+/// Parses `YYYY-MM`
+impl FromStr for BillingPeriod {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ApiError::ValidationError(format!("Invalid billing period '{}'", s));
+        let (year, month) = s.split_once('-').ok_or_else(invalid)?;
+        let year = year.parse().map_err(|_| invalid())?;
+        let month = month.parse().map_err(|_| invalid())?;
+        let start = chrono::NaiveDate::from_ymd_opt(year, month, 1)
+            .ok_or_else(invalid)?
+            .and_hms_opt(0, 0, 0)
+            .ok_or_else(invalid)?
+            .and_utc();
+        Ok(Self::containing(start))
+    }
+}
+
+impl fmt::Display for BillingPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}", self.start.year(), self.start.month())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineItem {
+    /// `None` for the plan's base fee
+    pub metric: Option<Metric>,
+    pub description: String,
+    pub quantity: i64,
+    pub included: i64,
+    pub billable_units: i64,
+    pub unit_size: i64,
+    pub unit_amount_cents: i64,
+    pub amount_cents: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invoice {
+    pub id: Uuid,
+    pub tenant_id: Uuid,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub tier: String,
+    pub currency: String,
+    pub total_cents: i64,
+    /// `draft` or `finalized`
+    pub status: String,
+    pub lines: Vec<LineItem>,
+}
+
+/// Outcome of a billing run
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    /// Draft invoices created or regenerated
+    pub generated: usize,
+    /// Tenants whose invoice was already finalized and left alone
+    pub finalized: usize,
+    /// Tenants on a tier without prices, who are not billed
+    pub unpriced: usize,
+}
+
+/// Generates the invoices of every tenant with usage in `period`. Safe to
+/// run again for the same period.
+pub async fn run(
+    pool: &DbPool,
+    period: BillingPeriod,
+    price_book: &PriceBook,
+    tiers: &Tiers,
+) -> Result<RunSummary, ApiError> {
+    let usage = db::billable_usage(pool, period.start, period.end)
+        .await
+        .map_err(|e| ApiError::InternalError(format!("Failed to read billable usage: {}", e)))?;
+
+    let mut summary = RunSummary::default();
+    for (tenant_id, usage) in usage {
+        let Some((tier, prices)) = price_book.prices_for(tenant_id, tiers) else {
+            tracing::warn!(
+                "Tenant {} is on a tier without prices, not billing it",
+                tenant_id
+            );
+            summary.unpriced += 1;
+            continue;
+        };
+
+        let lines = prices.rate(&usage);
+        let saved = db::save_invoice(pool, tenant_id, period, tier, &price_book.currency, &lines)
+            .await
+            .map_err(|e| {
+                ApiError::InternalError(format!(
+                    "Failed to save invoice of tenant {}: {}",
+                    tenant_id, e
+                ))
+            })?;
+        match saved {
+            Some(_) => summary.generated += 1,
+            None => summary.finalized += 1,
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Job type of billing runs, see [`RunJob`]
+pub const RUN_JOB: &str = "billing.run";
+
+#[derive(Debug, Deserialize)]
+struct RunJobPayload {
+    /// `YYYY-MM`
+    period: String,
+}
+
+/// Runs billing for the period in the job's payload, `{"period": "2025-01"}`,
+/// so runs can be scheduled and retried through the job queue
+pub struct RunJob {
+    pub pool: DbPool,
+    pub price_book: PriceBook,
+    pub tiers: Tiers,
+}
+
+#[async_trait]
+impl JobHandler for RunJob {
+    async fn run(&self, job: &Job) -> Result<(), JobFailure> {
+        let payload: RunJobPayload = job.payload()?;
+        let period: BillingPeriod = payload.period.parse()?;
+        let summary = run(&self.pool, period, &self.price_book, &self.tiers).await?;
+        tracing::info!(
+            "Billed {}: {} invoices generated, {} already finalized, {} tenants without prices",
+            period,
+            summary.generated,
+            summary.finalized,
+            summary.unpriced
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+use lotabots_rate_limit::Tiers;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, env, fs};
+use uuid::Uuid;
+
+use super::{LineItem, Metric, Usage};
+use crate::error::ApiError;
+
+const GIB: i64 = 1024 * 1024 * 1024;
+const HOUR: i64 = 60 * 60;
+
+/// Price of a metric: usage beyond what is included is billed in whole
+/// units of `unit_size`, e.g. $1 per 10,000 requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Price {
+    #[serde(default)]
+    pub included: i64,
+    pub unit_size: i64,
+    pub unit_amount_cents: i64,
+}
+
+impl Price {
+    pub fn new(included: i64, unit_size: i64, unit_amount_cents: i64) -> Self {
+        Self {
+            included,
+            unit_size: unit_size.max(1),
+            unit_amount_cents,
+        }
+    }
+
+    /// Billable units of `quantity` and what they cost
+    pub fn rate(&self, quantity: i64) -> (i64, i64) {
+        let over = (quantity - self.included).max(0);
+        let unit_size = self.unit_size.max(1);
+        let units = (over + unit_size - 1) / unit_size;
+        (units, units * self.unit_amount_cents)
+    }
+}
+
+/// What a plan costs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TierPrices {
+    /// Shown on invoices, e.g. "Professional"
+    pub name: String,
+    #[serde(default)]
+    pub base_fee_cents: i64,
+    /// Metrics without a price are not billed
+    #[serde(default)]
+    pub prices: HashMap<Metric, Price>,
+}
+
+impl TierPrices {
+    /// The base fee, if any, followed by a line per priced metric with usage
+    pub fn rate(&self, usage: &Usage) -> Vec<LineItem> {
+        let base_fee = (self.base_fee_cents > 0).then(|| LineItem {
+            metric: None,
+            description: format!("{} plan", self.name),
+            quantity: 1,
+            included: 0,
+            billable_units: 1,
+            unit_size: 1,
+            unit_amount_cents: self.base_fee_cents,
+            amount_cents: self.base_fee_cents,
+        });
+
+        let usage_lines = Metric::ALL.into_iter().filter_map(|metric| {
+            let quantity = usage.get(&metric).copied().filter(|q| *q > 0)?;
+            let price = self.prices.get(&metric)?;
+            let (billable_units, amount_cents) = price.rate(quantity);
+            Some(LineItem {
+                metric: Some(metric),
+                description: match price.included {
+                    0 => metric.label().to_string(),
+                    included => format!("{} ({} included)", metric.label(), included),
+                },
+                quantity,
+                included: price.included,
+                billable_units,
+                unit_size: price.unit_size,
+                unit_amount_cents: price.unit_amount_cents,
+                amount_cents,
+            })
+        });
+
+        base_fee.into_iter().chain(usage_lines).collect()
+    }
+}
+
+/// Prices per subscription tier, and for tenants on custom contracts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceBook {
+    /// ISO 4217 code, lowercase as Stripe expects
+    pub currency: String,
+    pub tiers: HashMap<String, TierPrices>,
+    /// Custom prices, which replace those of the tenant's tier
+    #[serde(default)]
+    pub tenants: HashMap<Uuid, TierPrices>,
+}
+
+impl PriceBook {
+    /// The default price book, or the JSON file named by
+    /// `BILLING_PRICE_BOOK`
+    pub fn from_env() -> Result<Self, ApiError> {
+        let Ok(path) = env::var("BILLING_PRICE_BOOK") else {
+            return Ok(Self::default());
+        };
+        let json = fs::read_to_string(&path).map_err(|e| {
+            ApiError::InternalError(format!("Failed to read price book {}: {}", path, e))
+        })?;
+        serde_json::from_str(&json)
+            .map_err(|e| ApiError::InternalError(format!("Invalid price book {}: {}", path, e)))
+    }
+
+    /// The tier the tenant is billed as and its prices: `custom` for
+    /// tenants with their own prices, else the tier `tiers` puts it on
+    pub fn prices_for<'a>(
+        &'a self,
+        tenant_id: Uuid,
+        tiers: &'a Tiers,
+    ) -> Option<(&'a str, &'a TierPrices)> {
+        if let Some(prices) = self.tenants.get(&tenant_id) {
+            return Some(("custom", prices));
+        }
+        let tier = tiers.tier_of(&tenant_id.to_string());
+        self.tiers.get(tier).map(|prices| (tier, prices))
+    }
+}
+
+/// The published plans. Custom contracts without their own prices are billed
+/// like enterprise.
+impl Default for PriceBook {
+    fn default() -> Self {
+        let enterprise = TierPrices {
+            name: "Enterprise".to_string(),
+            base_fee_cents: 49_900,
+            prices: HashMap::from([
+                (Metric::ApiRequests, Price::new(5_000_000, 10_000, 50)),
+                (Metric::Tokens, Price::new(500_000_000, 1_000_000, 100)),
+                (Metric::StorageBytes, Price::new(1024 * GIB, GIB, 5)),
+                (Metric::GpuSeconds, Price::new(0, HOUR, 200)),
+            ]),
+        };
+
+        Self {
+            currency: "usd".to_string(),
+            tiers: HashMap::from([
+                (
+                    "free".to_string(),
+                    TierPrices {
+                        name: "Free".to_string(),
+                        base_fee_cents: 0,
+                        // Usage is capped by quotas rather than billed
+                        prices: HashMap::new(),
+                    },
+                ),
+                (
+                    "professional".to_string(),
+                    TierPrices {
+                        name: "Professional".to_string(),
+                        base_fee_cents: 4_900,
+                        prices: HashMap::from([
+                            (Metric::ApiRequests, Price::new(300_000, 10_000, 100)),
+                            (Metric::Tokens, Price::new(20_000_000, 1_000_000, 200)),
+                            (Metric::StorageBytes, Price::new(100 * GIB, GIB, 10)),
+                            (Metric::GpuSeconds, Price::new(0, HOUR, 250)),
+                        ]),
+                    },
+                ),
+                (
+                    "custom".to_string(),
+                    TierPrices {
+                        name: "Custom".to_string(),
+                        ..enterprise.clone()
+                    },
+                ),
+                ("enterprise".to_string(), enterprise),
+            ]),
+            tenants: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lotabots_rate_limit::RateLimit;
+
+    #[test]
+    fn test_usage_beyond_the_plan_is_billed_in_whole_units() {
+        let book = PriceBook::default();
+        let professional = &book.tiers["professional"];
+        let usage = Usage::from([
+            (Metric::ApiRequests, 300_001),
+            (Metric::Tokens, 1_000),
+            (Metric::GpuSeconds, 90 * 60),
+        ]);
+
+        let lines = professional.rate(&usage);
+        let amounts: Vec<_> = lines
+            .iter()
+            .map(|line| (line.metric, line.billable_units, line.amount_cents))
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                (None, 1, 4_900),
+                (Some(Metric::ApiRequests), 1, 100),
+                (Some(Metric::Tokens), 0, 0),
+                (Some(Metric::GpuSeconds), 2, 500),
+            ]
+        );
+        assert_eq!(
+            lines[1].description,
+            "API requests (300000 included)".to_string()
+        );
+
+        // The free plan bills nothing
+        assert!(book.tiers["free"].rate(&usage).is_empty());
+    }
+
+    #[test]
+    fn test_custom_prices_replace_the_tier() {
+        let acme = Uuid::new_v4();
+        let tiers = Tiers::new("free", RateLimit::per_second(1))
+            .with_tier("professional", RateLimit::per_second(1))
+            .assign(&acme.to_string(), "professional");
+        let mut book = PriceBook::default();
+
+        assert_eq!(book.prices_for(acme, &tiers).unwrap().0, "professional");
+        assert_eq!(book.prices_for(Uuid::new_v4(), &tiers).unwrap().0, "free");
+
+        book.tenants.insert(
+            acme,
+            TierPrices {
+                name: "Acme contract".to_string(),
+                base_fee_cents: 100_000,
+                prices: HashMap::new(),
+            },
+        );
+        let (tier, prices) = book.prices_for(acme, &tiers).unwrap();
+        assert_eq!((tier, prices.base_fee_cents), ("custom", 100_000));
+
+        // Price books are read from JSON
+        let json = serde_json::to_string(&book).unwrap();
+        let parsed: PriceBook = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.tiers["professional"], book.tiers["professional"]);
+    }
+}
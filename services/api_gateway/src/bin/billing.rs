@@ -0,0 +1,81 @@
+//! Billing runs, meant for a scheduler:
+//!
+//! ```text
+//! billing run <YYYY-MM>               generate or regenerate draft invoices
+//! billing finalize <YYYY-MM>          freeze the period's invoices
+//! billing export <YYYY-MM> csv        line items as CSV
+//! billing export <YYYY-MM> stripe     Stripe usage records as JSON
+//! ```
+//!
+//! Reads `DATABASE_URL`, `BILLING_PRICE_BOOK` and the rate limit tier
+//! configuration from the environment.
+
+use api_gateway::{
+    billing::{self, export, BillingPeriod, PriceBook},
+    db, ApiError,
+};
+use lotabots_rate_limit::Tiers;
+use sqlx::PgPool;
+use std::{env, process::ExitCode};
+
+const USAGE: &str = "usage: billing run|finalize <YYYY-MM> | billing export <YYYY-MM> csv|stripe";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    dotenv::dotenv().ok();
+    tracing_subscriber::fmt::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    match execute(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn execute(args: &[String]) -> Result<(), ApiError> {
+    let usage = || ApiError::ValidationError(USAGE.to_string());
+    let (command, period) = match args {
+        [command, period, ..] => (command.as_str(), period.parse::<BillingPeriod>()?),
+        _ => return Err(usage()),
+    };
+
+    let database_url = env::var("DATABASE_URL")
+        .map_err(|_| ApiError::InternalError("DATABASE_URL must be set".to_string()))?;
+    let pool = PgPool::connect(&database_url).await?;
+
+    match (command, args.get(2).map(String::as_str)) {
+        ("run", None) => {
+            let price_book = PriceBook::from_env()?;
+            let tiers = Tiers::from_env().map_err(|e| {
+                ApiError::InternalError(format!("Invalid tier configuration: {}", e))
+            })?;
+            let summary = billing::run(&pool, period, &price_book, &tiers).await?;
+            println!(
+                "{}: {} invoices generated, {} already finalized, {} tenants without prices",
+                period, summary.generated, summary.finalized, summary.unpriced
+            );
+        }
+        ("finalize", None) => {
+            let finalized = db::finalize_invoices(&pool, period).await?;
+            println!("{}: {} invoices finalized", period, finalized);
+        }
+        ("export", Some("csv")) => {
+            let invoices = db::list_invoices(&pool, period, None).await?;
+            print!("{}", export::to_csv(&invoices));
+        }
+        ("export", Some("stripe")) => {
+            let invoices = db::list_invoices(&pool, period, None).await?;
+            let items = db::subscription_items(&pool).await?;
+            let records = export::to_stripe_usage_records(&invoices, &items);
+            let json = serde_json::to_string_pretty(&records)
+                .map_err(|e| ApiError::InternalError(e.to_string()))?;
+            println!("{}", json);
+        }
+        _ => return Err(usage()),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::{CacheStore, CachedResponse};
+use crate::error::ApiError;
+
+struct Entry {
+    response: CachedResponse,
+    expires_at: Instant,
+    size: usize,
+    /// Position in the recency order
+    used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    /// Keys by when they were last used, oldest first
+    order: BTreeMap<u64, String>,
+    clock: u64,
+    bytes: usize,
+}
+
+impl Lru {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+            self.bytes -= entry.size;
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.order.remove(&entry.used);
+            entry.used = clock;
+            self.order.insert(clock, key.to_string());
+        }
+    }
+}
+
+/// Keeps cached responses in the process, up to `max_bytes`; the least
+/// recently used are evicted to make room. Each replica caches, and
+/// invalidates, on its own, so use [`RedisCache`](super::RedisCache) when
+/// running more than one.
+pub struct MemoryCache {
+    max_bytes: usize,
+    lru: Mutex<Lru>,
+    generations: Mutex<HashMap<String, u64>>,
+}
+
+impl MemoryCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            lru: Mutex::default(),
+            generations: Mutex::default(),
+        }
+    }
+
+    fn lru(&self) -> std::sync::MutexGuard<'_, Lru> {
+        self.lru.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Number of cached responses, including expired ones not yet evicted
+    pub fn len(&self) -> usize {
+        self.lru().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryCache {
+    async fn get(&self, key: &str) -> Result<Option<CachedResponse>, ApiError> {
+        let mut lru = self.lru();
+        match lru.entries.get(key) {
+            None => return Ok(None),
+            Some(entry) if entry.expires_at <= Instant::now() => {
+                lru.remove(key);
+                return Ok(None);
+            }
+            Some(_) => {}
+        }
+        lru.touch(key);
+        Ok(lru.entries.get(key).map(|entry| entry.response.clone()))
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        response: CachedResponse,
+        ttl: Duration,
+    ) -> Result<(), ApiError> {
+        let size = key.len() + response.size();
+        if size > self.max_bytes {
+            return Ok(());
+        }
+
+        let mut lru = self.lru();
+        lru.remove(key);
+        while lru.bytes + size > self.max_bytes {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = lru.entries.remove(&oldest) {
+                lru.bytes -= entry.size;
+            }
+        }
+        lru.bytes += size;
+        lru.entries.insert(
+            key.to_string(),
+            Entry {
+                response,
+                expires_at: Instant::now() + ttl,
+                size,
+                used: 0,
+            },
+        );
+        lru.touch(key);
+        Ok(())
+    }
+
+    async fn generation(&self, resource: &str) -> Result<u64, ApiError> {
+        let generations = self.generations.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(generations.get(resource).copied().unwrap_or(0))
+    }
+
+    async fn invalidate(&self, resource: &str) -> Result<(), ApiError> {
+        let mut generations = self.generations.lock().unwrap_or_else(|e| e.into_inner());
+        *generations.entry(resource.to_string()).or_default() += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web::Bytes;
+    use chrono::Utc;
+
+    fn response(body: &str) -> CachedResponse {
+        CachedResponse {
+            status: 200,
+            headers: Vec::new(),
+            body: Bytes::from(body.to_string()),
+            etag: "\"1\"".to_string(),
+            stored_at: Utc::now(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_least_recently_used_are_evicted() {
+        // Room for two entries: key, body and etag take 3 bytes each
+        let cache = MemoryCache::new(20);
+        let ttl = Duration::from_secs(60);
+        cache.put("k:a", response("aaa"), ttl).await.unwrap();
+        cache.put("k:b", response("bbb"), ttl).await.unwrap();
+        assert!(cache.get("k:a").await.unwrap().is_some());
+
+        cache.put("k:c", response("ccc"), ttl).await.unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("k:b").await.unwrap().is_none());
+        let cached = cache.get("k:a").await.unwrap().unwrap();
+        assert_eq!(cached.body, Bytes::from_static(b"aaa"));
+
+        // Too large to cache at all
+        cache
+            .put("k:d", response(&"d".repeat(30)), ttl)
+            .await
+            .unwrap();
+        assert!(cache.get("k:d").await.unwrap().is_none());
+        assert_eq!(cache.len(), 2);
+
+        cache
+            .put("k:e", response("eee"), Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(cache.get("k:e").await.unwrap().is_none());
+
+        assert_eq!(cache.generation("products").await.unwrap(), 0);
+        cache.invalidate("products").await.unwrap();
+        assert_eq!(cache.generation("products").await.unwrap(), 1);
+    }
+}
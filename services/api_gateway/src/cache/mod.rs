@@ -0,0 +1,125 @@
+//! Responses of read-heavy routes, kept to answer repeated requests without
+//! running the handler again.
+//!
+//! Entries live in a [`CacheStore`]: in memory, bounded and evicting the
+//! least recently used ([`MemoryCache`]), or in Redis to share them between
+//! replicas ([`RedisCache`]). They are grouped by resource, whose
+//! generation is part of every key; a write to the resource moves it to a
+//! new generation, so what was cached for it before is no longer found and
+//! ages out. The HTTP side, `Cache-Control` and `ETag`, is up to the
+//! [`ResponseCache`](crate::middleware::ResponseCache) middleware.
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{env, sync::Arc, time::Duration};
+
+use crate::error::ApiError;
+
+pub mod memory;
+pub mod redis;
+
+pub use self::redis::RedisCache;
+pub use memory::MemoryCache;
+
+/// A response as it was sent, with the validator it is served under
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub body: Bytes,
+    pub etag: String,
+    pub stored_at: DateTime<Utc>,
+}
+
+impl CachedResponse {
+    /// Bytes the entry takes, roughly
+    pub fn size(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum();
+        self.body.len() + headers + self.etag.len()
+    }
+}
+
+fn to_base64<S: Serializer>(body: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(body))
+}
+
+fn from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    STANDARD
+        .decode(encoded)
+        .map(Bytes::from)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Where cached responses are kept
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<CachedResponse>, ApiError>;
+
+    /// Keeps `response` under `key` for `ttl`; stores may evict it sooner
+    async fn put(&self, key: &str, response: CachedResponse, ttl: Duration)
+        -> Result<(), ApiError>;
+
+    /// The current generation of `resource`, 0 until it is first
+    /// invalidated
+    async fn generation(&self, resource: &str) -> Result<u64, ApiError>;
+
+    /// Moves `resource` to a new generation, so nothing cached for it
+    /// before is found any more
+    async fn invalidate(&self, resource: &str) -> Result<(), ApiError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheOptions {
+    /// How long responses of routes that cache by default are kept when
+    /// they do not say themselves
+    pub default_ttl: Duration,
+    /// Larger responses are passed on without being cached
+    pub max_entry_bytes: usize,
+    /// Bound of the in-memory store
+    pub max_bytes: usize,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            default_ttl: Duration::from_secs(30),
+            max_entry_bytes: 1 << 20,
+            max_bytes: 64 << 20,
+        }
+    }
+}
+
+impl CacheOptions {
+    /// Defaults overridden by `CACHE_DEFAULT_TTL_SECS`,
+    /// `CACHE_MAX_ENTRY_BYTES` and `CACHE_MAX_BYTES`
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let var = |name| env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+
+        Self {
+            default_ttl: var("CACHE_DEFAULT_TTL_SECS")
+                .map_or(defaults.default_ttl, Duration::from_secs),
+            max_entry_bytes: var("CACHE_MAX_ENTRY_BYTES")
+                .map_or(defaults.max_entry_bytes, |v| v as usize),
+            max_bytes: var("CACHE_MAX_BYTES").map_or(defaults.max_bytes, |v| v as usize),
+        }
+    }
+}
+
+/// Uses Redis when `CACHE_REDIS_URL` is set, so replicas share cached
+/// responses and invalidations, and an in-memory store otherwise
+pub async fn from_env(options: &CacheOptions) -> Result<Arc<dyn CacheStore>, ApiError> {
+    if let Ok(url) = env::var("CACHE_REDIS_URL") {
+        return Ok(Arc::new(RedisCache::connect(&url).await?));
+    }
+    Ok(Arc::new(MemoryCache::new(options.max_bytes)))
+}
@@ -0,0 +1,79 @@
+use ::redis::{aio::ConnectionManager, AsyncCommands, Client, RedisError};
+use async_trait::async_trait;
+use std::time::Duration;
+
+use super::{CacheStore, CachedResponse};
+use crate::error::ApiError;
+
+const KEY_PREFIX: &str = "cache:";
+const GENERATION_PREFIX: &str = "cache:generation:";
+
+/// Keeps cached responses in Redis, shared by every replica, which also see
+/// each other's invalidations. Redis evicts them as its own `maxmemory`
+/// policy says.
+pub struct RedisCache {
+    connection: ConnectionManager,
+}
+
+impl RedisCache {
+    /// Connects to `url`. The connection is re-established automatically
+    /// after failures.
+    pub async fn connect(url: &str) -> Result<Self, ApiError> {
+        let client = Client::open(url).map_err(store_error)?;
+        let connection = ConnectionManager::new(client).await.map_err(store_error)?;
+        Ok(Self { connection })
+    }
+}
+
+fn store_error(e: RedisError) -> ApiError {
+    ApiError::InternalError(format!("Cache store error: {}", e))
+}
+
+#[async_trait]
+impl CacheStore for RedisCache {
+    async fn get(&self, key: &str) -> Result<Option<CachedResponse>, ApiError> {
+        let mut connection = self.connection.clone();
+        let cached: Option<String> = connection
+            .get(format!("{}{}", KEY_PREFIX, key))
+            .await
+            .map_err(store_error)?;
+        // Entries written by another version are treated as missing
+        Ok(cached.and_then(|cached| serde_json::from_str(&cached).ok()))
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        response: CachedResponse,
+        ttl: Duration,
+    ) -> Result<(), ApiError> {
+        let cached =
+            serde_json::to_string(&response).map_err(|e| ApiError::InternalError(e.to_string()))?;
+        let mut connection = self.connection.clone();
+        connection
+            .set_ex(
+                format!("{}{}", KEY_PREFIX, key),
+                cached,
+                ttl.as_secs().max(1),
+            )
+            .await
+            .map_err(store_error)
+    }
+
+    async fn generation(&self, resource: &str) -> Result<u64, ApiError> {
+        let mut connection = self.connection.clone();
+        let generation: Option<u64> = connection
+            .get(format!("{}{}", GENERATION_PREFIX, resource))
+            .await
+            .map_err(store_error)?;
+        Ok(generation.unwrap_or(0))
+    }
+
+    async fn invalidate(&self, resource: &str) -> Result<(), ApiError> {
+        let mut connection = self.connection.clone();
+        connection
+            .incr(format!("{}{}", GENERATION_PREFIX, resource), 1u64)
+            .await
+            .map_err(store_error)
+    }
+}
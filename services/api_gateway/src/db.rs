@@ -1,19 +1,46 @@
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
-use std::env;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use lotabots_api_keys::{ApiKeyError, ApiKeyRecord, ApiKeyStore, ClientRecord};
+use lotabots_secrets::SecretHandle;
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    Pool, Postgres,
+};
+use std::collections::{BTreeMap, HashMap};
 use uuid::Uuid;
-use crate::models::{User, Product};
+use crate::billing::{BillingPeriod, Invoice, LineItem, Metric, Usage};
+use crate::jobs::{Job, JobStatus, NewJob, DEFAULT_MAX_ATTEMPTS};
+use crate::models::{User, Product, RouteUsage, UsagePeriod};
 use crate::error::ApiError;
+use crate::usage::{Granularity, RollupKey, Totals};
 
 pub type DbPool = Pool<Postgres>;
 
-pub async fn create_pool() -> Result<DbPool, sqlx::Error> {
-    let database_url = env::var("DATABASE_URL")
-        .expect("DATABASE_URL must be set");
-
-    PgPoolOptions::new()
+/// Connects with the URL held in `database_url` and keeps following it, so
+/// rotated database credentials are used for new connections without a
+/// restart.
+pub async fn create_pool(database_url: &SecretHandle) -> Result<DbPool, sqlx::Error> {
+    let pool = PgPoolOptions::new()
         .max_connections(5)
-        .connect(&database_url)
-        .await
+        .connect(&database_url.load().value)
+        .await?;
+
+    let mut changes = database_url.subscribe();
+    let database_url = database_url.clone();
+    let follower = pool.clone();
+    tokio::spawn(async move {
+        while changes.changed().await.is_ok() && !follower.is_closed() {
+            match database_url.load().value.parse::<PgConnectOptions>() {
+                Ok(options) => {
+                    follower.set_connect_options(options);
+                    tracing::info!("Database credentials changed, reconnecting with new ones");
+                }
+                Err(e) => tracing::error!("Ignoring invalid DATABASE_URL: {}", e),
+            }
+        }
+    });
+
+    Ok(pool)
 }
 
 // User queries
@@ -291,3 +318,999 @@ pub async fn update_user_with_password(
 
     Ok(())
 }
+
+/// Replaces a stored password hash after a transparent rehash on login
+pub async fn update_password_hash(
+    pool: &DbPool,
+    user_id: Uuid,
+    password_hash: &str,
+) -> Result<(), ApiError> {
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET password_hash = $1
+        WHERE id = $2
+        "#,
+        password_hash,
+        user_id,
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| ApiError::InternalError(format!("Failed to update password hash: {}", e)))?;
+
+    Ok(())
+}
+
+// API key queries
+#[allow(clippy::too_many_arguments)]
+pub async fn create_api_key(
+    pool: &DbPool,
+    tenant_id: Uuid,
+    name: &str,
+    prefix: &str,
+    secret_hash: &str,
+    scopes: &[String],
+    created_by: Uuid,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<ApiKeyRecord, ApiError> {
+    sqlx::query_as!(
+        ApiKeyRecord,
+        r#"
+        INSERT INTO api_keys (tenant_id, name, prefix, secret_hash, scopes, created_by, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id, tenant_id, name, prefix, secret_hash, scopes, expires_at, last_used_at, revoked_at, created_at
+        "#,
+        tenant_id,
+        name,
+        prefix,
+        secret_hash,
+        scopes,
+        created_by,
+        expires_at,
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| ApiError::InternalError(format!("Failed to create API key: {}", e)))
+}
+
+pub async fn list_api_keys(pool: &DbPool, tenant_id: Uuid) -> Result<Vec<ApiKeyRecord>, ApiError> {
+    sqlx::query_as!(
+        ApiKeyRecord,
+        r#"
+        SELECT id, tenant_id, name, prefix, secret_hash, scopes, expires_at, last_used_at, revoked_at, created_at
+        FROM api_keys
+        WHERE tenant_id = $1
+        ORDER BY created_at DESC
+        "#,
+        tenant_id,
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| ApiError::InternalError(format!("Failed to list API keys: {}", e)))
+}
+
+/// Revokes a key of the tenant. Returns `false` if there is no such active key.
+pub async fn revoke_api_key(pool: &DbPool, tenant_id: Uuid, id: Uuid) -> Result<bool, ApiError> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE api_keys
+        SET revoked_at = NOW()
+        WHERE id = $1 AND tenant_id = $2 AND revoked_at IS NULL
+        "#,
+        id,
+        tenant_id,
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| ApiError::InternalError(format!("Failed to revoke API key: {}", e)))?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn find_api_key_by_prefix(
+    pool: &DbPool,
+    prefix: &str,
+) -> Result<Option<ApiKeyRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKeyRecord,
+        r#"
+        SELECT id, tenant_id, name, prefix, secret_hash, scopes, expires_at, last_used_at, revoked_at, created_at
+        FROM api_keys
+        WHERE prefix = $1
+        "#,
+        prefix,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn record_api_key_use(
+    pool: &DbPool,
+    id: Uuid,
+    at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE api_keys
+        SET last_used_at = $2
+        WHERE id = $1
+        "#,
+        id,
+        at,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// [`ApiKeyStore`] backed by the `api_keys` table
+#[derive(Clone)]
+pub struct PgApiKeyStore {
+    pool: DbPool,
+}
+
+impl PgApiKeyStore {
+    pub fn new(pool: DbPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for PgApiKeyStore {
+    async fn find_by_prefix(&self, prefix: &str) -> Result<Option<ApiKeyRecord>, ApiKeyError> {
+        find_api_key_by_prefix(&self.pool, prefix)
+            .await
+            .map_err(|e| ApiKeyError::Store(e.to_string()))
+    }
+
+    async fn record_use(&self, id: Uuid, at: DateTime<Utc>) -> Result<(), ApiKeyError> {
+        record_api_key_use(&self.pool, id, at)
+            .await
+            .map_err(|e| ApiKeyError::Store(e.to_string()))
+    }
+}
+
+// OAuth client queries
+pub async fn create_oauth_client(
+    pool: &DbPool,
+    tenant_id: Uuid,
+    name: &str,
+    client_id: &str,
+    secret_hash: &str,
+    scopes: &[String],
+    created_by: Uuid,
+) -> Result<ClientRecord, ApiError> {
+    sqlx::query_as!(
+        ClientRecord,
+        r#"
+        INSERT INTO oauth_clients (tenant_id, name, client_id, secret_hash, scopes, created_by)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, tenant_id, name, client_id, secret_hash, scopes, revoked_at, created_at
+        "#,
+        tenant_id,
+        name,
+        client_id,
+        secret_hash,
+        scopes,
+        created_by,
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| ApiError::InternalError(format!("Failed to create OAuth client: {}", e)))
+}
+
+pub async fn list_oauth_clients(pool: &DbPool, tenant_id: Uuid) -> Result<Vec<ClientRecord>, ApiError> {
+    sqlx::query_as!(
+        ClientRecord,
+        r#"
+        SELECT id, tenant_id, name, client_id, secret_hash, scopes, revoked_at, created_at
+        FROM oauth_clients
+        WHERE tenant_id = $1
+        ORDER BY created_at DESC
+        "#,
+        tenant_id,
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| ApiError::InternalError(format!("Failed to list OAuth clients: {}", e)))
+}
+
+/// Revokes a client of the tenant. Returns `false` if there is no such active client.
+pub async fn revoke_oauth_client(pool: &DbPool, tenant_id: Uuid, id: Uuid) -> Result<bool, ApiError> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE oauth_clients
+        SET revoked_at = NOW()
+        WHERE id = $1 AND tenant_id = $2 AND revoked_at IS NULL
+        "#,
+        id,
+        tenant_id,
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| ApiError::InternalError(format!("Failed to revoke OAuth client: {}", e)))?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn find_oauth_client(
+    pool: &DbPool,
+    client_id: &str,
+) -> Result<Option<ClientRecord>, ApiError> {
+    sqlx::query_as!(
+        ClientRecord,
+        r#"
+        SELECT id, tenant_id, name, client_id, secret_hash, scopes, revoked_at, created_at
+        FROM oauth_clients
+        WHERE client_id = $1
+        "#,
+        client_id,
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| ApiError::InternalError(format!("Failed to get OAuth client: {}", e)))
+}
+
+// Usage queries
+/// Adds the rollups to the stored counters, in one statement
+pub async fn record_usage(
+    pool: &DbPool,
+    rollups: &HashMap<RollupKey, Totals>,
+) -> Result<(), sqlx::Error> {
+    let mut tenant_ids = Vec::with_capacity(rollups.len());
+    let mut granularities = Vec::with_capacity(rollups.len());
+    let mut period_starts = Vec::with_capacity(rollups.len());
+    let mut routes = Vec::with_capacity(rollups.len());
+    let mut counters: [Vec<i64>; 6] = Default::default();
+    for (key, totals) in rollups {
+        tenant_ids.push(key.tenant_id);
+        granularities.push(key.granularity.as_str().to_string());
+        period_starts.push(key.period_start);
+        routes.push(key.route.clone());
+        for (column, value) in counters.iter_mut().zip([
+            totals.requests,
+            totals.errors,
+            totals.request_bytes,
+            totals.response_bytes,
+            totals.tokens,
+            totals.latency_ms,
+        ]) {
+            column.push(value);
+        }
+    }
+    let [requests, errors, request_bytes, response_bytes, tokens, latency_ms] = counters;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO usage_rollups AS u (
+            tenant_id, granularity, period_start, route,
+            requests, errors, request_bytes, response_bytes, tokens, latency_ms
+        )
+        SELECT * FROM UNNEST(
+            $1::uuid[], $2::text[], $3::timestamptz[], $4::text[],
+            $5::bigint[], $6::bigint[], $7::bigint[], $8::bigint[], $9::bigint[], $10::bigint[]
+        )
+        ON CONFLICT (tenant_id, granularity, period_start, route) DO UPDATE SET
+            requests = u.requests + EXCLUDED.requests,
+            errors = u.errors + EXCLUDED.errors,
+            request_bytes = u.request_bytes + EXCLUDED.request_bytes,
+            response_bytes = u.response_bytes + EXCLUDED.response_bytes,
+            tokens = u.tokens + EXCLUDED.tokens,
+            latency_ms = u.latency_ms + EXCLUDED.latency_ms
+        "#,
+        &tenant_ids,
+        &granularities,
+        &period_starts,
+        &routes,
+        &requests,
+        &errors,
+        &request_bytes,
+        &response_bytes,
+        &tokens,
+        &latency_ms,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// The tenant's usage over the whole days in `[from, to)`
+pub async fn usage_totals(
+    pool: &DbPool,
+    tenant_id: Uuid,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Totals, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT
+            COALESCE(SUM(requests), 0)::BIGINT AS "requests!",
+            COALESCE(SUM(errors), 0)::BIGINT AS "errors!",
+            COALESCE(SUM(request_bytes), 0)::BIGINT AS "request_bytes!",
+            COALESCE(SUM(response_bytes), 0)::BIGINT AS "response_bytes!",
+            COALESCE(SUM(tokens), 0)::BIGINT AS "tokens!",
+            COALESCE(SUM(latency_ms), 0)::BIGINT AS "latency_ms!"
+        FROM usage_rollups
+        WHERE tenant_id = $1 AND granularity = 'day' AND period_start >= $2 AND period_start < $3
+        "#,
+        tenant_id,
+        from,
+        to,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(Totals {
+        requests: row.requests,
+        errors: row.errors,
+        request_bytes: row.request_bytes,
+        response_bytes: row.response_bytes,
+        tokens: row.tokens,
+        latency_ms: row.latency_ms,
+    })
+}
+
+/// The tenant's usage per period in `[from, to)`, over all routes or only
+/// `route`
+pub async fn usage_periods(
+    pool: &DbPool,
+    tenant_id: Uuid,
+    granularity: Granularity,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    route: Option<&str>,
+) -> Result<Vec<UsagePeriod>, ApiError> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            period_start,
+            SUM(requests)::BIGINT AS "requests!",
+            SUM(errors)::BIGINT AS "errors!",
+            SUM(request_bytes)::BIGINT AS "request_bytes!",
+            SUM(response_bytes)::BIGINT AS "response_bytes!",
+            SUM(tokens)::BIGINT AS "tokens!",
+            SUM(latency_ms)::BIGINT AS "latency_ms!"
+        FROM usage_rollups
+        WHERE tenant_id = $1 AND granularity = $2 AND period_start >= $3 AND period_start < $4
+            AND ($5::text IS NULL OR route = $5)
+        GROUP BY period_start
+        ORDER BY period_start
+        "#,
+        tenant_id,
+        granularity.as_str(),
+        from,
+        to,
+        route,
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| ApiError::InternalError(format!("Failed to read usage: {}", e)))?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| UsagePeriod {
+            period_start: row.period_start,
+            usage: Totals {
+                requests: row.requests,
+                errors: row.errors,
+                request_bytes: row.request_bytes,
+                response_bytes: row.response_bytes,
+                tokens: row.tokens,
+                latency_ms: row.latency_ms,
+            },
+        })
+        .collect())
+}
+
+/// The tenant's usage per route over the whole days in `[from, to)`
+pub async fn usage_by_route(
+    pool: &DbPool,
+    tenant_id: Uuid,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<RouteUsage>, ApiError> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            route,
+            SUM(requests)::BIGINT AS "requests!",
+            SUM(errors)::BIGINT AS "errors!",
+            SUM(request_bytes)::BIGINT AS "request_bytes!",
+            SUM(response_bytes)::BIGINT AS "response_bytes!",
+            SUM(tokens)::BIGINT AS "tokens!",
+            SUM(latency_ms)::BIGINT AS "latency_ms!"
+        FROM usage_rollups
+        WHERE tenant_id = $1 AND granularity = 'day' AND period_start >= $2 AND period_start < $3
+        GROUP BY route
+        ORDER BY 2 DESC
+        "#,
+        tenant_id,
+        from,
+        to,
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| ApiError::InternalError(format!("Failed to read usage: {}", e)))?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| RouteUsage {
+            route: row.route,
+            usage: Totals {
+                requests: row.requests,
+                errors: row.errors,
+                request_bytes: row.request_bytes,
+                response_bytes: row.response_bytes,
+                tokens: row.tokens,
+                latency_ms: row.latency_ms,
+            },
+        })
+        .collect())
+}
+
+/// Deletes rollups of `granularity` for periods before `before`
+pub async fn prune_usage(
+    pool: &DbPool,
+    granularity: Granularity,
+    before: DateTime<Utc>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM usage_rollups
+        WHERE granularity = $1 AND period_start < $2
+        "#,
+        granularity.as_str(),
+        before,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+// Billing queries
+/// Stores a billable event reported by a service; returns `false` if an
+/// event with the same idempotency key was already recorded
+pub async fn record_billable_event(
+    pool: &DbPool,
+    tenant_id: Uuid,
+    metric: Metric,
+    quantity: i64,
+    occurred_at: DateTime<Utc>,
+    idempotency_key: &str,
+) -> Result<bool, ApiError> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO billable_events (tenant_id, metric, quantity, occurred_at, idempotency_key)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (tenant_id, idempotency_key) DO NOTHING
+        "#,
+        tenant_id,
+        metric.as_str(),
+        quantity,
+        occurred_at,
+        idempotency_key,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Billable usage per tenant in `[from, to)`: requests and tokens from the
+/// day rollups, GPU time summed and storage at its peak from the events
+pub async fn billable_usage(
+    pool: &DbPool,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<BTreeMap<Uuid, Usage>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT tenant_id AS "tenant_id!", metric AS "metric!", quantity AS "quantity!"
+        FROM (
+            SELECT tenant_id, 'api_requests' AS metric, SUM(requests)::BIGINT AS quantity
+            FROM usage_rollups
+            WHERE granularity = 'day' AND period_start >= $1 AND period_start < $2
+            GROUP BY tenant_id
+            UNION ALL
+            SELECT tenant_id, 'tokens', SUM(tokens)::BIGINT
+            FROM usage_rollups
+            WHERE granularity = 'day' AND period_start >= $1 AND period_start < $2
+            GROUP BY tenant_id
+            UNION ALL
+            SELECT tenant_id, metric,
+                CASE metric WHEN 'storage_bytes' THEN MAX(quantity) ELSE SUM(quantity)::BIGINT END
+            FROM billable_events
+            WHERE occurred_at >= $1 AND occurred_at < $2
+            GROUP BY tenant_id, metric
+        ) usage
+        "#,
+        from,
+        to,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut usage: BTreeMap<Uuid, Usage> = BTreeMap::new();
+    for row in rows {
+        match row.metric.parse::<Metric>() {
+            Ok(metric) => {
+                usage.entry(row.tenant_id).or_default().insert(metric, row.quantity);
+            }
+            Err(_) => tracing::warn!("Ignoring usage of unknown metric '{}'", row.metric),
+        }
+    }
+    Ok(usage)
+}
+
+/// Stores the tenant's draft invoice for `period`, replacing its line
+/// items if it exists. Returns `None`, changing nothing, if the invoice is
+/// finalized.
+pub async fn save_invoice(
+    pool: &DbPool,
+    tenant_id: Uuid,
+    period: BillingPeriod,
+    tier: &str,
+    currency: &str,
+    lines: &[LineItem],
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let total_cents: i64 = lines.iter().map(|line| line.amount_cents).sum();
+    let mut tx = pool.begin().await?;
+
+    let Some(invoice_id) = sqlx::query_scalar!(
+        r#"
+        INSERT INTO invoices (tenant_id, period_start, period_end, tier, currency, total_cents)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (tenant_id, period_start, period_end) DO UPDATE SET
+            tier = EXCLUDED.tier,
+            currency = EXCLUDED.currency,
+            total_cents = EXCLUDED.total_cents,
+            updated_at = NOW()
+        WHERE invoices.status = 'draft'
+        RETURNING id
+        "#,
+        tenant_id,
+        period.start,
+        period.end,
+        tier,
+        currency,
+        total_cents,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    sqlx::query!(
+        "DELETE FROM invoice_line_items WHERE invoice_id = $1",
+        invoice_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let numbers: Vec<i32> = (1..=lines.len() as i32).collect();
+    let metrics: Vec<Option<String>> = lines
+        .iter()
+        .map(|line| line.metric.map(|m| m.as_str().to_string()))
+        .collect();
+    let descriptions: Vec<String> = lines.iter().map(|line| line.description.clone()).collect();
+    let column = |f: fn(&LineItem) -> i64| lines.iter().map(f).collect::<Vec<i64>>();
+    sqlx::query!(
+        r#"
+        INSERT INTO invoice_line_items (
+            invoice_id, line, metric, description, quantity, included,
+            billable_units, unit_size, unit_amount_cents, amount_cents
+        )
+        SELECT $1, * FROM UNNEST(
+            $2::int[], $3::text[], $4::text[], $5::bigint[], $6::bigint[],
+            $7::bigint[], $8::bigint[], $9::bigint[], $10::bigint[]
+        )
+        "#,
+        invoice_id,
+        &numbers,
+        &metrics as &[Option<String>],
+        &descriptions,
+        &column(|line| line.quantity),
+        &column(|line| line.included),
+        &column(|line| line.billable_units),
+        &column(|line| line.unit_size),
+        &column(|line| line.unit_amount_cents),
+        &column(|line| line.amount_cents),
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(Some(invoice_id))
+}
+
+/// Invoices for `period`, of all tenants or only `tenant_id`, with their
+/// line items
+pub async fn list_invoices(
+    pool: &DbPool,
+    period: BillingPeriod,
+    tenant_id: Option<Uuid>,
+) -> Result<Vec<Invoice>, ApiError> {
+    let invoices = sqlx::query!(
+        r#"
+        SELECT id, tenant_id, period_start, period_end, tier, currency, total_cents, status
+        FROM invoices
+        WHERE period_start = $1 AND period_end = $2 AND ($3::uuid IS NULL OR tenant_id = $3)
+        ORDER BY tenant_id
+        "#,
+        period.start,
+        period.end,
+        tenant_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let ids: Vec<Uuid> = invoices.iter().map(|invoice| invoice.id).collect();
+    let rows = sqlx::query!(
+        r#"
+        SELECT invoice_id, metric, description, quantity, included,
+            billable_units, unit_size, unit_amount_cents, amount_cents
+        FROM invoice_line_items
+        WHERE invoice_id = ANY($1)
+        ORDER BY invoice_id, line
+        "#,
+        &ids,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut lines: HashMap<Uuid, Vec<LineItem>> = HashMap::new();
+    for row in rows {
+        lines.entry(row.invoice_id).or_default().push(LineItem {
+            metric: row.metric.and_then(|metric| metric.parse().ok()),
+            description: row.description,
+            quantity: row.quantity,
+            included: row.included,
+            billable_units: row.billable_units,
+            unit_size: row.unit_size,
+            unit_amount_cents: row.unit_amount_cents,
+            amount_cents: row.amount_cents,
+        });
+    }
+
+    Ok(invoices
+        .into_iter()
+        .map(|row| Invoice {
+            lines: lines.remove(&row.id).unwrap_or_default(),
+            id: row.id,
+            tenant_id: row.tenant_id,
+            period_start: row.period_start,
+            period_end: row.period_end,
+            tier: row.tier,
+            currency: row.currency,
+            total_cents: row.total_cents,
+            status: row.status,
+        })
+        .collect())
+}
+
+/// Finalizes the draft invoices of `period`, so later runs leave them alone
+pub async fn finalize_invoices(pool: &DbPool, period: BillingPeriod) -> Result<u64, ApiError> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE invoices SET status = 'finalized', updated_at = NOW()
+        WHERE period_start = $1 AND period_end = $2 AND status = 'draft'
+        "#,
+        period.start,
+        period.end,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Stripe subscription items per tenant, keyed by metric
+pub async fn subscription_items(
+    pool: &DbPool,
+) -> Result<HashMap<Uuid, HashMap<String, String>>, ApiError> {
+    let rows = sqlx::query!("SELECT tenant_id, subscription_items FROM billing_accounts")
+        .fetch_all(pool)
+        .await?;
+
+    rows.into_iter()
+        .map(|row| {
+            let items: HashMap<String, String> = serde_json::from_value(row.subscription_items)
+                .map_err(|e| {
+                    ApiError::InternalError(format!(
+                        "Invalid subscription items of tenant {}: {}",
+                        row.tenant_id, e
+                    ))
+                })?;
+            Ok((row.tenant_id, items))
+        })
+        .collect()
+}
+
+// Job queries
+struct JobRow {
+    id: Uuid,
+    job_type: String,
+    payload: serde_json::Value,
+    tenant_id: Option<Uuid>,
+    status: String,
+    attempts: i32,
+    max_attempts: i32,
+    run_at: DateTime<Utc>,
+    unique_key: Option<String>,
+    last_error: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<JobRow> for Job {
+    type Error = ApiError;
+
+    fn try_from(row: JobRow) -> Result<Self, Self::Error> {
+        Ok(Job {
+            id: row.id,
+            job_type: row.job_type,
+            payload: row.payload,
+            tenant_id: row.tenant_id,
+            status: row.status.parse()?,
+            attempts: row.attempts,
+            max_attempts: row.max_attempts,
+            run_at: row.run_at,
+            unique_key: row.unique_key,
+            last_error: row.last_error,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            finished_at: row.finished_at,
+        })
+    }
+}
+
+/// Enqueues the job and returns its id, and whether it is new: a job with
+/// the same type and unique key is only enqueued once
+pub async fn enqueue_job(pool: &DbPool, job: &NewJob) -> Result<(Uuid, bool), ApiError> {
+    let inserted = sqlx::query_scalar!(
+        r#"
+        INSERT INTO jobs (job_type, payload, tenant_id, run_at, max_attempts, unique_key)
+        VALUES ($1, $2, $3, COALESCE($4, NOW()), $5, $6)
+        ON CONFLICT (job_type, unique_key) DO NOTHING
+        RETURNING id
+        "#,
+        job.job_type,
+        job.payload,
+        job.tenant_id,
+        job.run_at,
+        job.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS),
+        job.unique_key,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(id) = inserted {
+        return Ok((id, true));
+    }
+
+    let existing = sqlx::query_scalar!(
+        "SELECT id FROM jobs WHERE job_type = $1 AND unique_key = $2",
+        job.job_type,
+        job.unique_key,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok((existing, false))
+}
+
+/// Claims up to `limit` runnable jobs of `job_types` for `worker` and
+/// counts an attempt for each. Jobs whose lease expired are runnable again
+/// while they have attempts left.
+pub async fn claim_jobs(
+    pool: &DbPool,
+    job_types: &[String],
+    limit: i64,
+    worker: &str,
+    lease: std::time::Duration,
+) -> Result<Vec<Job>, ApiError> {
+    let rows = sqlx::query_as!(
+        JobRow,
+        r#"
+        WITH runnable AS (
+            SELECT id FROM jobs
+            WHERE job_type = ANY($1)
+                AND ((status = 'queued' AND run_at <= NOW())
+                    OR (status = 'running' AND locked_until < NOW() AND attempts < max_attempts))
+            ORDER BY run_at
+            LIMIT $2
+            FOR UPDATE SKIP LOCKED
+        )
+        UPDATE jobs SET
+            status = 'running',
+            attempts = jobs.attempts + 1,
+            locked_by = $3,
+            locked_until = NOW() + make_interval(secs => $4),
+            updated_at = NOW()
+        FROM runnable
+        WHERE jobs.id = runnable.id
+        RETURNING jobs.id, jobs.job_type, jobs.payload, jobs.tenant_id, jobs.status,
+            jobs.attempts, jobs.max_attempts, jobs.run_at, jobs.unique_key, jobs.last_error,
+            jobs.created_at, jobs.updated_at, jobs.finished_at
+        "#,
+        job_types,
+        limit,
+        worker,
+        lease.as_secs_f64(),
+    )
+    .fetch_all(pool)
+    .await?;
+
+    rows.into_iter().map(Job::try_from).collect()
+}
+
+/// Marks a job `worker` holds as succeeded; `false` if it lost the lease
+pub async fn complete_job(pool: &DbPool, id: Uuid, worker: &str) -> Result<bool, ApiError> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE jobs SET
+            status = 'succeeded',
+            last_error = NULL,
+            locked_by = NULL,
+            locked_until = NULL,
+            updated_at = NOW(),
+            finished_at = NOW()
+        WHERE id = $1 AND locked_by = $2 AND status = 'running'
+        "#,
+        id,
+        worker,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/// Records a failed attempt of a job `worker` holds: it is queued again at
+/// `retry_at`, or dead if it has no attempts left. Returns the new status,
+/// or `None` if the worker lost the lease.
+pub async fn fail_job(
+    pool: &DbPool,
+    id: Uuid,
+    worker: &str,
+    error: &str,
+    retry_at: DateTime<Utc>,
+) -> Result<Option<JobStatus>, ApiError> {
+    let status = sqlx::query_scalar!(
+        r#"
+        UPDATE jobs SET
+            status = CASE WHEN attempts >= max_attempts THEN 'dead' ELSE 'queued' END,
+            run_at = CASE WHEN attempts >= max_attempts THEN run_at ELSE $4 END,
+            last_error = $3,
+            locked_by = NULL,
+            locked_until = NULL,
+            updated_at = NOW(),
+            finished_at = CASE WHEN attempts >= max_attempts THEN NOW() END
+        WHERE id = $1 AND locked_by = $2 AND status = 'running'
+        RETURNING status
+        "#,
+        id,
+        worker,
+        error,
+        retry_at,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    status.map(|status| status.parse()).transpose()
+}
+
+/// Moves jobs whose lease expired on their last attempt to the dead letters
+pub async fn bury_expired_jobs(pool: &DbPool) -> Result<u64, ApiError> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE jobs SET
+            status = 'dead',
+            last_error = 'Lease expired on the last attempt',
+            locked_by = NULL,
+            locked_until = NULL,
+            updated_at = NOW(),
+            finished_at = NOW()
+        WHERE status = 'running' AND locked_until < NOW() AND attempts >= max_attempts
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// The most recently created jobs, optionally of one status and type
+pub async fn list_jobs(
+    pool: &DbPool,
+    status: Option<JobStatus>,
+    job_type: Option<&str>,
+    limit: i64,
+) -> Result<Vec<Job>, ApiError> {
+    let rows = sqlx::query_as!(
+        JobRow,
+        r#"
+        SELECT id, job_type, payload, tenant_id, status, attempts, max_attempts, run_at,
+            unique_key, last_error, created_at, updated_at, finished_at
+        FROM jobs
+        WHERE ($1::text IS NULL OR status = $1) AND ($2::text IS NULL OR job_type = $2)
+        ORDER BY created_at DESC
+        LIMIT $3
+        "#,
+        status.map(|status| status.as_str()),
+        job_type,
+        limit,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    rows.into_iter().map(Job::try_from).collect()
+}
+
+pub async fn get_job(pool: &DbPool, id: Uuid) -> Result<Option<Job>, ApiError> {
+    let row = sqlx::query_as!(
+        JobRow,
+        r#"
+        SELECT id, job_type, payload, tenant_id, status, attempts, max_attempts, run_at,
+            unique_key, last_error, created_at, updated_at, finished_at
+        FROM jobs
+        WHERE id = $1
+        "#,
+        id,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    row.map(Job::try_from).transpose()
+}
+
+/// Queues a dead or cancelled job to run now, with its attempts reset.
+/// `None` if there is no such job in either state.
+pub async fn retry_job(pool: &DbPool, id: Uuid) -> Result<Option<Job>, ApiError> {
+    let row = sqlx::query_as!(
+        JobRow,
+        r#"
+        UPDATE jobs SET
+            status = 'queued',
+            attempts = 0,
+            run_at = NOW(),
+            updated_at = NOW(),
+            finished_at = NULL
+        WHERE id = $1 AND status IN ('dead', 'cancelled')
+        RETURNING id, job_type, payload, tenant_id, status, attempts, max_attempts, run_at,
+            unique_key, last_error, created_at, updated_at, finished_at
+        "#,
+        id,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    row.map(Job::try_from).transpose()
+}
+
+/// Cancels a queued job. `None` if there is no queued job with the id;
+/// running jobs cannot be cancelled.
+pub async fn cancel_job(pool: &DbPool, id: Uuid) -> Result<Option<Job>, ApiError> {
+    let row = sqlx::query_as!(
+        JobRow,
+        r#"
+        UPDATE jobs SET
+            status = 'cancelled',
+            updated_at = NOW(),
+            finished_at = NOW()
+        WHERE id = $1 AND status = 'queued'
+        RETURNING id, job_type, payload, tenant_id, status, attempts, max_attempts, run_at,
+            unique_key, last_error, created_at, updated_at, finished_at
+        "#,
+        id,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    row.map(Job::try_from).transpose()
+}
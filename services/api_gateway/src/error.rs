@@ -17,11 +17,23 @@ pub enum ApiError {
     #[error("Not found: {0}")]
     NotFoundError(String),
 
+    #[error("Method not allowed: {0}")]
+    MethodNotAllowed(String),
+
     #[error("Database error: {0}")]
     DatabaseError(#[from] sqlx::Error),
 
     #[error("Internal server error: {0}")]
     InternalError(String),
+
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+
+    #[error("Bad gateway: {0}")]
+    BadGateway(String),
+
+    #[error("Gateway timeout: {0}")]
+    GatewayTimeout(String),
 }
 
 #[derive(Serialize)]
@@ -44,8 +56,14 @@ impl ResponseError for ApiError {
             ApiError::AuthorizationError(_) => HttpResponse::Forbidden().json(error_response),
             ApiError::ValidationError(_) => HttpResponse::BadRequest().json(error_response),
             ApiError::NotFoundError(_) => HttpResponse::NotFound().json(error_response),
+            ApiError::MethodNotAllowed(_) => HttpResponse::MethodNotAllowed().json(error_response),
             ApiError::DatabaseError(_) => HttpResponse::InternalServerError().json(error_response),
             ApiError::InternalError(_) => HttpResponse::InternalServerError().json(error_response),
+            ApiError::ServiceUnavailable(_) => HttpResponse::ServiceUnavailable()
+                .insert_header(("Retry-After", "1"))
+                .json(error_response),
+            ApiError::BadGateway(_) => HttpResponse::BadGateway().json(error_response),
+            ApiError::GatewayTimeout(_) => HttpResponse::GatewayTimeout().json(error_response),
         }
     }
 }
@@ -56,8 +74,8 @@ impl From<ValidationErrors> for ApiError {
     }
 }
 
-impl From<bcrypt::BcryptError> for ApiError {
-    fn from(err: bcrypt::BcryptError) -> Self {
+impl From<lotabots_password::PasswordError> for ApiError {
+    fn from(err: lotabots_password::PasswordError) -> Self {
         ApiError::InternalError(err.to_string())
     }
 }
@@ -67,3 +85,18 @@ impl From<jsonwebtoken::errors::Error> for ApiError {
         ApiError::AuthenticationError(err.to_string())
     }
 }
+
+impl From<crate::worker::JobError> for ApiError {
+    fn from(err: crate::worker::JobError) -> Self {
+        use crate::worker::JobError;
+
+        match err {
+            // A job running out of time means the pool is overloaded, like
+            // a full queue, so the client is told to retry
+            JobError::Saturated(_) | JobError::TimedOut(_) | JobError::ShuttingDown => {
+                ApiError::ServiceUnavailable(err.to_string())
+            }
+            JobError::Panicked | JobError::Cancelled => ApiError::InternalError(err.to_string()),
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Durable background jobs, kept in Postgres.
+//!
+//! Jobs are enqueued with a type and a JSON payload, optionally delayed and
+//! under a unique key, and run by a [`JobRunner`] on any replica that has a
+//! [`JobHandler`] for their type. Runners claim jobs with
+//! `FOR UPDATE SKIP LOCKED`, so replicas never run the same job at once,
+//! and hold them for a lease. Failed jobs are retried with exponential
+//! backoff until they run out of attempts and become dead letters, which
+//! operators can retry through the admin API.
+//!
+//! Unlike the in-process [`WorkerPool`](crate::worker::WorkerPool), jobs
+//! survive restarts; handlers must therefore cope with running more than
+//! once, e.g. after a replica died mid-job.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, time::Duration};
+use uuid::Uuid;
+
+use crate::error::ApiError;
+
+mod runner;
+
+pub use runner::{JobRunner, RunnerHandle, RunnerOptions};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for `run_at`, also between retries
+    Queued,
+    Running,
+    Succeeded,
+    /// Failed on its last attempt
+    Dead,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub const ALL: [Self; 5] = [
+        Self::Queued,
+        Self::Running,
+        Self::Succeeded,
+        Self::Dead,
+        Self::Cancelled,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Succeeded => "succeeded",
+            Self::Dead => "dead",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|status| status.as_str() == s)
+            .ok_or_else(|| ApiError::ValidationError(format!("Unknown job status '{}'", s)))
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: Uuid,
+    pub job_type: String,
+    pub payload: serde_json::Value,
+    pub tenant_id: Option<Uuid>,
+    pub status: JobStatus,
+    /// Attempts started so far, including a running one
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub run_at: DateTime<Utc>,
+    pub unique_key: Option<String>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl Job {
+    /// The payload as `T`
+    pub fn payload<T: for<'de> Deserialize<'de>>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_value(self.payload.clone())
+    }
+}
+
+/// A job to enqueue
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewJob {
+    pub job_type: String,
+    #[serde(default)]
+    pub payload: serde_json::Value,
+    pub tenant_id: Option<Uuid>,
+    /// Defaults to now
+    pub run_at: Option<DateTime<Utc>>,
+    /// Defaults to [`DEFAULT_MAX_ATTEMPTS`]
+    pub max_attempts: Option<i32>,
+    pub unique_key: Option<String>,
+}
+
+pub const DEFAULT_MAX_ATTEMPTS: i32 = 5;
+
+impl NewJob {
+    pub fn new(job_type: &str, payload: impl Serialize) -> Result<Self, ApiError> {
+        let payload = serde_json::to_value(payload)
+            .map_err(|e| ApiError::InternalError(format!("Invalid job payload: {}", e)))?;
+        Ok(Self {
+            job_type: job_type.to_string(),
+            payload,
+            tenant_id: None,
+            run_at: None,
+            max_attempts: None,
+            unique_key: None,
+        })
+    }
+
+    pub fn for_tenant(mut self, tenant_id: Uuid) -> Self {
+        self.tenant_id = Some(tenant_id);
+        self
+    }
+
+    pub fn run_at(mut self, run_at: DateTime<Utc>) -> Self {
+        self.run_at = Some(run_at);
+        self
+    }
+
+    pub fn delay(self, delay: Duration) -> Self {
+        let delay = chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::MAX);
+        self.run_at(Utc::now() + delay)
+    }
+
+    pub fn max_attempts(mut self, max_attempts: i32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Enqueueing again with the same type and key returns the existing job
+    pub fn unique_key(mut self, key: impl Into<String>) -> Self {
+        self.unique_key = Some(key.into());
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.job_type.is_empty() || self.job_type.len() > 255 {
+            return Err(ApiError::ValidationError(
+                "Job type must be 1 to 255 characters".to_string(),
+            ));
+        }
+        if self.max_attempts.is_some_and(|n| n < 1) {
+            return Err(ApiError::ValidationError(
+                "max_attempts must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Delay before retrying a failed job: `base` doubled per failed attempt,
+/// at most `max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_secs(10),
+            max: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay after the `attempt`th attempt failed, counting from 1
+    pub fn delay(&self, attempt: i32) -> Duration {
+        let doublings = attempt.saturating_sub(1).clamp(0, 31) as u32;
+        self.base
+            .checked_mul(1 << doublings)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+pub type JobFailure = Box<dyn std::error::Error + Send + Sync>;
+
+/// Runs jobs of one type
+#[async_trait]
+pub trait JobHandler: Send + Sync + 'static {
+    /// An error fails the attempt; the job is retried if it has attempts
+    /// left
+    async fn run(&self, job: &Job) -> Result<(), JobFailure>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_maximum() {
+        let backoff = Backoff {
+            base: Duration::from_secs(10),
+            max: Duration::from_secs(60),
+        };
+        let delays: Vec<_> = (1..=5)
+            .map(|attempt| backoff.delay(attempt).as_secs())
+            .collect();
+        assert_eq!(delays, vec![10, 20, 40, 60, 60]);
+        assert_eq!(backoff.delay(1_000), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_new_jobs_are_validated() {
+        let job = NewJob::new("email.send", serde_json::json!({ "to": "a@example.com" }))
+            .unwrap()
+            .delay(Duration::from_secs(60))
+            .unique_key("welcome:42");
+        assert!(job.validate().is_ok());
+        assert!(job.run_at.unwrap() > Utc::now());
+
+        assert!(job.clone().max_attempts(0).validate().is_err());
+        assert!(NewJob::new("", ()).unwrap().validate().is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use chrono::Utc;
+use futures_util::FutureExt;
+use std::{collections::HashMap, env, panic::AssertUnwindSafe, sync::Arc, time::Duration};
+use tokio::{
+    sync::{watch, Semaphore},
+    task::JoinHandle,
+};
+use uuid::Uuid;
+
+use super::{Backoff, Job, JobHandler, JobStatus};
+use crate::{
+    db::{self, DbPool},
+    error::ApiError,
+};
+
+/// How jobs are claimed and run
+#[derive(Debug, Clone)]
+pub struct RunnerOptions {
+    /// Jobs run at once by this replica
+    pub concurrency: usize,
+    /// How often to look for jobs when there were none
+    pub poll_interval: Duration,
+    /// How long a claimed job is held; attempts running longer are failed,
+    /// and jobs of replicas that died are claimed again after it
+    pub lease: Duration,
+    pub backoff: Backoff,
+    /// Longest shutdown waits for running jobs
+    pub drain_timeout: Duration,
+}
+
+impl Default for RunnerOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            poll_interval: Duration::from_secs(1),
+            lease: Duration::from_secs(5 * 60),
+            backoff: Backoff::default(),
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RunnerOptions {
+    /// Defaults overridden by `JOB_CONCURRENCY`, `JOB_POLL_INTERVAL_SECS`
+    /// and `JOB_LEASE_SECS`
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let var = |name| env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+        Self {
+            concurrency: var("JOB_CONCURRENCY").map_or(defaults.concurrency, |v| v as usize),
+            poll_interval: var("JOB_POLL_INTERVAL_SECS")
+                .map_or(defaults.poll_interval, Duration::from_secs),
+            lease: var("JOB_LEASE_SECS").map_or(defaults.lease, Duration::from_secs),
+            ..defaults
+        }
+    }
+}
+
+/// Claims and runs the jobs it has handlers for
+pub struct JobRunner {
+    pool: DbPool,
+    options: RunnerOptions,
+    handlers: HashMap<String, Arc<dyn JobHandler>>,
+    /// Identifies this runner's claims
+    worker_id: String,
+}
+
+impl JobRunner {
+    pub fn new(pool: DbPool, options: RunnerOptions) -> Self {
+        Self {
+            pool,
+            options,
+            handlers: HashMap::new(),
+            worker_id: Uuid::new_v4().to_string(),
+        }
+    }
+
+    /// Runs jobs of `job_type` with `handler`
+    pub fn handle(mut self, job_type: &str, handler: impl JobHandler) -> Self {
+        self.handlers
+            .insert(job_type.to_string(), Arc::new(handler));
+        self
+    }
+
+    /// Claims runnable jobs, up to the concurrency, and runs them to the end.
+    /// Returns how many were run.
+    pub async fn run_pending(&self) -> Result<usize, ApiError> {
+        let jobs = self.claim(self.options.concurrency).await?;
+        let count = jobs.len();
+        futures_util::future::join_all(jobs.into_iter().map(|job| self.execute(job))).await;
+        Ok(count)
+    }
+
+    /// Runs jobs in the background until shut down
+    pub fn spawn(self) -> RunnerHandle {
+        let (stop, stopped) = watch::channel(false);
+        let drain_timeout = self.options.drain_timeout;
+        let task = tokio::spawn(Arc::new(self).poll(stopped));
+        RunnerHandle {
+            stop,
+            task,
+            drain_timeout,
+        }
+    }
+
+    async fn poll(self: Arc<Self>, mut stopped: watch::Receiver<bool>) {
+        let slots = Arc::new(Semaphore::new(self.options.concurrency.max(1)));
+
+        while !*stopped.borrow() {
+            // Jobs holding a lease they outlived without attempts left
+            match db::bury_expired_jobs(&self.pool).await {
+                Ok(0) => {}
+                Ok(buried) => {
+                    tracing::warn!("{} jobs lost their worker on the last attempt", buried)
+                }
+                Err(e) => tracing::error!("Failed to check for expired jobs: {}", e),
+            }
+
+            let free = slots.available_permits();
+            let claimed = match self.claim(free).await {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    tracing::error!("Failed to claim jobs: {}", e);
+                    Vec::new()
+                }
+            };
+            let idle = claimed.len() < free;
+            for job in claimed {
+                let slot = slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed");
+                let runner = self.clone();
+                tokio::spawn(async move {
+                    runner.execute(job).await;
+                    drop(slot);
+                });
+            }
+
+            // Look again right away while there is a backlog and capacity
+            let wait = if idle {
+                self.options.poll_interval
+            } else {
+                Duration::ZERO
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(wait), if !wait.is_zero() => {}
+                _ = slots.clone().acquire_owned(), if wait.is_zero() => {}
+                // Dropping the handle stops the runner too
+                changed = stopped.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Let running jobs finish; unfinished ones are claimed again once
+        // their lease expires
+        let all = self.options.concurrency.max(1) as u32;
+        let _ = slots.acquire_many(all).await;
+    }
+
+    async fn claim(&self, limit: usize) -> Result<Vec<Job>, ApiError> {
+        if limit == 0 || self.handlers.is_empty() {
+            return Ok(Vec::new());
+        }
+        let job_types: Vec<String> = self.handlers.keys().cloned().collect();
+        db::claim_jobs(
+            &self.pool,
+            &job_types,
+            limit as i64,
+            &self.worker_id,
+            self.options.lease,
+        )
+        .await
+    }
+
+    async fn execute(&self, job: Job) {
+        let Some(handler) = self.handlers.get(&job.job_type) else {
+            return;
+        };
+
+        let lease = self.options.lease;
+        let result =
+            match tokio::time::timeout(lease, AssertUnwindSafe(handler.run(&job)).catch_unwind())
+                .await
+            {
+                Ok(Ok(Ok(()))) => Ok(()),
+                Ok(Ok(Err(e))) => Err(e.to_string()),
+                Ok(Err(_)) => Err("Job panicked".to_string()),
+                Err(_) => Err(format!("Timed out after {:?}", lease)),
+            };
+
+        let saved = match result {
+            Ok(()) => db::complete_job(&self.pool, job.id, &self.worker_id)
+                .await
+                .map(|_| ()),
+            Err(error) => {
+                let delay = self.options.backoff.delay(job.attempts);
+                let retry_at =
+                    Utc::now() + chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::MAX);
+                match db::fail_job(&self.pool, job.id, &self.worker_id, &error, retry_at).await {
+                    Ok(Some(JobStatus::Dead)) => {
+                        tracing::error!(
+                            "Job {} ({}) failed on its last attempt: {}",
+                            job.id,
+                            job.job_type,
+                            error
+                        );
+                        Ok(())
+                    }
+                    Ok(_) => {
+                        tracing::warn!(
+                            "Job {} ({}) failed on attempt {}, retrying in {:?}: {}",
+                            job.id,
+                            job.job_type,
+                            job.attempts,
+                            delay,
+                            error
+                        );
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        if let Err(e) = saved {
+            tracing::error!("Failed to save the outcome of job {}: {}", job.id, e);
+        }
+    }
+}
+
+/// Stops a spawned [`JobRunner`]
+pub struct RunnerHandle {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+    drain_timeout: Duration,
+}
+
+impl RunnerHandle {
+    /// Stops claiming jobs and waits up to the drain timeout for running
+    /// ones
+    pub async fn shutdown(self) {
+        let _ = self.stop.send(true);
+        if tokio::time::timeout(self.drain_timeout, self.task)
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "Jobs still running after {:?} are left to their lease",
+                self.drain_timeout
+            );
+        }
+    }
+}
@@ -1,25 +1,38 @@
 use actix_cors::Cors;
-use actix_governor::{Governor, GovernorConfigBuilder};
 use actix_web::{
     http::header,
     middleware::{Compress, Logger, NormalizePath},
     web, App, HttpServer,
 };
+use lotabots_api_keys::ApiKeyVerifier;
+use lotabots_password::PasswordHasher;
+use lotabots_rate_limit::{actix::RateLimiter, Policy, RateLimit, Tiers};
+use lotabots_secrets::{KeyRing, SecretHandle, WatchOptions};
 use sqlx::migrate;
-use std::env;
+use std::{env, sync::Arc, time::Duration};
 use tracing::info;
 use tracing_subscriber::{prelude::*, EnvFilter};
 
+pub mod billing;
+pub mod cache;
 pub mod db;
 pub mod error;
+pub mod jobs;
 pub mod middleware;
 pub mod models;
+pub mod proxy;
 pub mod routes;
+pub mod usage;
 pub mod utils;
+pub mod worker;
 
 pub use error::ApiError;
 pub use models::{ApiResponse, CreateUserRequest, LoginResponse, User, UserLogin};
 
+/// How long tokens signed with the previous JWT secret are accepted after a
+/// rotation; matches the lifetime of user tokens
+const JWT_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub async fn run() -> std::io::Result<()> {
     dotenv::dotenv().ok();
 
@@ -36,37 +49,118 @@ pub async fn run() -> std::io::Result<()> {
     let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
     let bind_address = format!("{}:{}", host, port);
 
+    // Secrets are read through the configured backends and watched, so
+    // rotated values are picked up without a restart
+    let secrets = Arc::new(lotabots_secrets::from_env().expect("Invalid secrets configuration"));
+    let watch_options = WatchOptions::from_env();
+
     // Ensure JWT_SECRET is set
-    if env::var("JWT_SECRET").is_err() {
-        eprintln!("JWT_SECRET environment variable must be set. Generate one with:");
-        eprintln!("   openssl rand -hex 32");
-        std::process::exit(1);
-    }
+    let jwt_keys = match KeyRing::load(&secrets, "JWT_SECRET", JWT_GRACE_PERIOD).await {
+        Ok(jwt_keys) => jwt_keys,
+        Err(e) => {
+            eprintln!("JWT_SECRET could not be read ({}). Generate one with:", e);
+            eprintln!("   openssl rand -hex 32");
+            std::process::exit(1);
+        }
+    };
+    jwt_keys.watch(secrets.clone(), watch_options.clone(), JWT_GRACE_PERIOD);
 
     info!("Setting up database connection pool...");
-    let pool = db::create_pool()
+    let database_url = SecretHandle::watch(secrets.clone(), "DATABASE_URL", watch_options)
+        .await
+        .expect("DATABASE_URL must be set");
+    let pool = db::create_pool(&database_url)
         .await
         .expect("Failed to create database pool");
 
+    let password_hasher = web::Data::new(
+        PasswordHasher::from_env().expect("Invalid password hashing configuration"),
+    );
+
+    let api_keys = ApiKeyVerifier::new(Arc::new(db::PgApiKeyStore::new(pool.clone())));
+
     info!("Running database migrations...");
     migrate!()
         .run(&pool)
         .await
         .expect("Failed to run database migrations");
 
-    // Configure rate limiting
-    let governor_conf = GovernorConfigBuilder::default()
-        .per_second(2) // 2 requests per second
-        .burst_size(5) // Allow bursts of 5 requests
-        .finish()
-        .unwrap();
+    // Rate limits are kept in Redis when one is configured, so they hold
+    // across replicas
+    let rate_limits = lotabots_rate_limit::from_env()
+        .await
+        .expect("Invalid rate limit configuration");
+    let api_limit = RateLimit::per_second(2).with_burst(5);
+    // Stricter limits for auth endpoints
+    let auth_limit = RateLimit::per_second(1).with_burst(3);
+    // Authenticated requests are also counted against their tenant's tier
+    let tenant_policy = Policy::from_env().expect("Invalid rate limit configuration");
+
+    // Usage is metered per tenant for billing and checked against the
+    // monthly quota of its tier
+    let tiers = Tiers::from_env().expect("Invalid rate limit configuration");
+    let quotas =
+        usage::Quotas::from_env(tiers.clone()).expect("Invalid usage quota configuration");
+    let meter = usage::Meter::spawn(pool.clone(), quotas, usage::WriterOptions::from_env());
+
+    // Heavy work runs on a bounded pool off the request threads; handlers
+    // answer 503 when it is saturated
+    let workers = worker::WorkerPool::new(worker::WorkerOptions::from_env());
+    let app_workers = workers.clone();
+
+    // Durable jobs, such as billing runs, are claimed from Postgres by every
+    // replica; operators manage them through the admin API
+    let price_book = billing::PriceBook::from_env().expect("Invalid price book");
+    let job_runner = jobs::JobRunner::new(pool.clone(), jobs::RunnerOptions::from_env())
+        .handle(
+            billing::RUN_JOB,
+            billing::RunJob {
+                pool: pool.clone(),
+                price_book,
+                tiers,
+            },
+        )
+        .spawn();
+    let admins = middleware::Admins::from_env().expect("Invalid ADMIN_TENANT_IDS");
 
-    // Configure stricter rate limits for auth endpoints
-    let auth_governor_conf = GovernorConfigBuilder::default()
-        .per_second(1) // 1 request per second
-        .burst_size(3) // Allow bursts of 3 requests
-        .finish()
-        .unwrap();
+    // Backend services are reached through one pooled client
+    let upstream = proxy::Proxy::new(
+        proxy::ProxyOptions::from_env().expect("Invalid proxy configuration"),
+    )
+    .expect("Failed to create the proxy client");
+    // Services are read from UPSTREAMS_CONFIG, which is watched, and their
+    // instances health-checked in the background
+    let services = Arc::new(
+        proxy::ServiceRegistry::from_env().expect("Invalid upstreams configuration"),
+    );
+    services.spawn();
+    // Routes to those services declared in ROUTES_CONFIG, which is watched;
+    // they are matched before the gateway's own routes under /api/v1
+    let route_table = Arc::new(proxy::RouteTable::from_env().expect("Invalid route table"));
+    route_table
+        .check_upstreams(&services)
+        .expect("Invalid route table");
+    route_table.spawn(&services);
+    // Its gRPC routes are served on GRPC_PORT, over HTTP/2 with trailers,
+    // which actix-web does not send
+    if let Ok(grpc_port) = env::var("GRPC_PORT") {
+        let gate = middleware::RouteGate::new(route_table.clone(), jwt_keys.clone())
+            .with_api_keys(api_keys.clone())
+            .with_admins(admins.clone())
+            .with_rate_limits(rate_limits.clone());
+        let grpc_address = format!("{}:{}", host, grpc_port);
+        let listener = tokio::net::TcpListener::bind(&grpc_address).await?;
+        info!("Serving gRPC at http://{}", grpc_address);
+        tokio::spawn(proxy::GrpcGateway::new(gate, services.clone(), upstream.clone()).serve(listener));
+    }
+    let services = web::Data::from(services);
+
+    // Responses of read routes are cached per caller, in Redis when
+    // CACHE_REDIS_URL is set so replicas share them
+    let cache_options = cache::CacheOptions::from_env();
+    let response_cache = cache::from_env(&cache_options)
+        .await
+        .expect("Failed to connect to the cache store");
 
     info!("Starting server at http://{}", bind_address);
 
@@ -84,46 +178,173 @@ pub async fn run() -> std::io::Result<()> {
                     })
                     .unwrap_or(false)
             })
-            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
+            .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
             .allowed_headers(vec![
                 header::AUTHORIZATION,
                 header::ACCEPT,
                 header::CONTENT_TYPE,
+                header::HeaderName::from_static("x-api-key"),
             ])
             .max_age(3600);
 
+        let tenant_limiter =
+            middleware::tenant_rate_limiter(rate_limits.clone(), tenant_policy.clone());
+
         App::new()
             .app_data(web::Data::new(pool.clone()))
+            .app_data(password_hasher.clone())
+            .app_data(web::Data::new(jwt_keys.clone()))
+            .app_data(web::Data::new(meter.clone()))
+            .app_data(web::Data::new(app_workers.clone()))
+            .app_data(web::Data::new(upstream.clone()))
+            .app_data(services.clone())
             .wrap(cors)
             .wrap(Logger::default())
             .wrap(Compress::default())
             .wrap(NormalizePath::trim())
             .service(routes::health::health_check)
+            .service(
+                web::scope("/health/workers")
+                    .wrap(
+                        middleware::auth::AuthMiddleware::with_key_ring(jwt_keys.clone())
+                            .with_api_keys(api_keys.clone())
+                            .for_resource("workers")
+                            .for_admins(admins.clone()),
+                    )
+                    .wrap(RateLimiter::new(rate_limits.clone(), "api", api_limit))
+                    .service(routes::health::worker_stats),
+            )
+            .service(routes::health::metrics)
+            .service(
+                web::scope("")
+                    .guard(route_table.guard())
+                    .wrap(middleware::UsageMeter::new(meter.clone()))
+                    .wrap(
+                        middleware::RouteGate::new(route_table.clone(), jwt_keys.clone())
+                            .with_api_keys(api_keys.clone())
+                            .with_admins(admins.clone())
+                            .with_rate_limits(rate_limits.clone()),
+                    )
+                    .default_service(web::to(routes::proxy::table_route)),
+            )
             .service(
                 web::scope("/api/v1")
-                    .wrap(Governor::new(&governor_conf))
+                    .wrap(RateLimiter::new(rate_limits.clone(), "api", api_limit))
                     .service(
                         web::scope("/auth")
-                            .wrap(Governor::new(&auth_governor_conf))
+                            .wrap(RateLimiter::new(rate_limits.clone(), "auth", auth_limit))
                             .configure(routes::users::configure_auth),
                     )
+                    .service(
+                        web::scope("/oauth")
+                            .wrap(RateLimiter::new(rate_limits.clone(), "oauth", auth_limit))
+                            .configure(routes::oauth::configure),
+                    )
+                    .service(
+                        web::scope("/api-keys")
+                            .wrap(tenant_limiter.clone())
+                            .wrap(middleware::auth::AuthMiddleware::with_key_ring(jwt_keys.clone()))
+                            .configure(routes::api_keys::configure_api_keys),
+                    )
+                    .service(
+                        web::scope("/oauth-clients")
+                            .wrap(tenant_limiter.clone())
+                            .wrap(middleware::auth::AuthMiddleware::with_key_ring(jwt_keys.clone()))
+                            .configure(routes::api_keys::configure_clients),
+                    )
                     .service(
                         web::scope("/users")
-                            .wrap(middleware::auth::AuthMiddleware::new(
-                                utils::get_jwt_secret().expect("JWT_SECRET validation failed"),
-                            ))
+                            .wrap(middleware::UsageMeter::new(meter.clone()))
+                            .wrap(tenant_limiter.clone())
+                            .wrap(
+                                middleware::auth::AuthMiddleware::with_key_ring(jwt_keys.clone())
+                                .with_api_keys(api_keys.clone())
+                                .for_resource("users"),
+                            )
                             .configure(routes::users::configure_users),
                     )
                     .service(
                         web::scope("/products")
-                            .wrap(middleware::auth::AuthMiddleware::new(
-                                utils::get_jwt_secret().expect("JWT_SECRET validation failed"),
+                            .wrap(middleware::ResponseCache::new(
+                                response_cache.clone(),
+                                &cache_options,
                             ))
+                            .wrap(middleware::UsageMeter::new(meter.clone()))
+                            .wrap(tenant_limiter.clone())
+                            .wrap(
+                                middleware::auth::AuthMiddleware::with_key_ring(jwt_keys.clone())
+                                .with_api_keys(api_keys.clone())
+                                .for_resource("products"),
+                            )
                             .configure(routes::products::configure),
+                    )
+                    .service(
+                        web::scope("/usage")
+                            .wrap(tenant_limiter.clone())
+                            .wrap(
+                                middleware::auth::AuthMiddleware::with_key_ring(jwt_keys.clone())
+                                .with_api_keys(api_keys.clone())
+                                .for_resource("usage"),
+                            )
+                            .configure(routes::usage::configure),
+                    )
+                    .service(
+                        web::scope("/billing")
+                            .wrap(tenant_limiter.clone())
+                            .wrap(
+                                middleware::auth::AuthMiddleware::with_key_ring(jwt_keys.clone())
+                                .with_api_keys(api_keys.clone())
+                                .for_resource("billing"),
+                            )
+                            .configure(routes::billing::configure),
+                    )
+                    .service(
+                        web::scope("/services")
+                            // Proxied responses are only cached when they ask to be
+                            .wrap(
+                                middleware::ResponseCache::new(response_cache.clone(), &cache_options)
+                                    .resource_depth(4)
+                                    .default_ttl(Duration::ZERO),
+                            )
+                            .wrap(middleware::UsageMeter::new(meter.clone()))
+                            .wrap(tenant_limiter.clone())
+                            .wrap(
+                                middleware::auth::AuthMiddleware::with_key_ring(jwt_keys.clone())
+                                .with_api_keys(api_keys.clone())
+                                .for_resource("services"),
+                            )
+                            .configure(routes::proxy::configure),
                     ),
             )
+            .service(
+                web::scope("/admin/jobs")
+                    .wrap(
+                        middleware::auth::AuthMiddleware::with_key_ring(jwt_keys.clone())
+                            .with_api_keys(api_keys.clone())
+                            .for_resource("jobs")
+                            .for_admins(admins.clone()),
+                    )
+                    .wrap(RateLimiter::new(rate_limits.clone(), "api", api_limit))
+                    .configure(routes::jobs::configure),
+            )
+            .service(
+                web::scope("/admin/upstreams")
+                    .wrap(
+                        middleware::auth::AuthMiddleware::with_key_ring(jwt_keys.clone())
+                            .with_api_keys(api_keys.clone())
+                            .for_resource("upstreams")
+                            .for_admins(admins.clone()),
+                    )
+                    .wrap(RateLimiter::new(rate_limits.clone(), "api", api_limit))
+                    .configure(routes::upstreams::configure),
+            )
     })
     .bind(bind_address)?
     .run()
-    .await
+    .await?;
+
+    info!("Draining background jobs...");
+    job_runner.shutdown().await;
+    workers.shutdown().await;
+    Ok(())
 }
@@ -1,33 +1,163 @@
 use actix_web::{
+    body::EitherBody,
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpMessage,
+    http::{header, header::HeaderMap, Method},
+    Error, HttpMessage, HttpResponse,
 };
 use futures_util::future::LocalBoxFuture;
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use jsonwebtoken::{
+    decode, encode, errors::ErrorKind, DecodingKey, EncodingKey, Header, Validation,
+};
+use lotabots_api_keys::{
+    is_api_key, scope_allows, ApiKeyError, ApiKeyRecord, ApiKeyVerifier, API_KEY_PREFIX,
+};
+use lotabots_secrets::{KeyRing, Secret};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashSet,
+    env,
     future::{ready, Ready},
     rc::Rc,
+    sync::Arc,
 };
+use uuid::Uuid;
 
 use crate::error::ApiError;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Paths that are reachable without a token, e.g. for load balancer probes
+const PUBLIC_PATHS: &[&str] = &["/health"];
+
+/// Header carrying an API key, as an alternative to `Authorization: Bearer`
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+/// How the caller authenticated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Principal {
+    #[default]
+    User,
+    ApiKey,
+    Client,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    /// Tenant a machine client acts for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<Uuid>,
+    /// Scopes granted to a machine client. User sessions carry none and are
+    /// not restricted by scope.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub permissions: Vec<String>,
+    #[serde(default)]
+    pub principal: Principal,
+}
+
+impl Claims {
+    pub fn is_machine(&self) -> bool {
+        self.principal != Principal::User
+    }
+
+    /// Tenant the request acts for. Until users belong to an organisation,
+    /// each user is their own tenant.
+    pub fn tenant(&self) -> Option<Uuid> {
+        self.tenant_id.or_else(|| match self.principal {
+            Principal::User => Uuid::parse_str(&self.sub).ok(),
+            _ => None,
+        })
+    }
+
+    fn from_api_key(key: &ApiKeyRecord) -> Self {
+        Self {
+            sub: format!("{}_{}", API_KEY_PREFIX, key.prefix),
+            exp: key
+                .expires_at
+                .map_or(usize::MAX, |expires_at| expires_at.timestamp() as usize),
+            tenant_id: Some(key.tenant_id),
+            permissions: key.scopes.clone(),
+            principal: Principal::ApiKey,
+        }
+    }
 }
 
+/// Authenticates requests with a user JWT, an OAuth2 client access token or,
+/// when enabled, an API key.
 pub struct AuthMiddleware {
-    jwt_secret: Rc<String>,
+    jwt_keys: KeyRing,
+    api_keys: Option<ApiKeyVerifier>,
+    resource: Option<Rc<String>>,
+    admins: Option<Admins>,
 }
 
 impl AuthMiddleware {
     pub fn new(jwt_secret: String) -> Self {
+        Self::with_key_ring(KeyRing::new("JWT_SECRET", Secret::new(jwt_secret)))
+    }
+
+    /// Verifies JWTs against a live key ring, so rotated secrets are picked
+    /// up without a restart and tokens signed with the previous secret keep
+    /// working during the grace period
+    pub fn with_key_ring(jwt_keys: KeyRing) -> Self {
         Self {
-            jwt_secret: Rc::new(jwt_secret),
+            jwt_keys,
+            api_keys: None,
+            resource: None,
+            admins: None,
         }
     }
+
+    /// Also accept API keys, sent in `X-API-Key` or as a bearer token
+    pub fn with_api_keys(mut self, verifier: ApiKeyVerifier) -> Self {
+        self.api_keys = Some(verifier);
+        self
+    }
+
+    /// Require machine clients to hold `<resource>:read` for safe methods and
+    /// `<resource>:write` for everything else
+    pub fn for_resource(mut self, resource: &str) -> Self {
+        self.resource = Some(Rc::new(resource.to_string()));
+        self
+    }
+
+    /// Only let callers acting for one of the operator tenants in `admins`
+    /// through
+    pub fn for_admins(mut self, admins: Admins) -> Self {
+        self.admins = Some(admins);
+        self
+    }
+}
+
+/// Tenants whose users and machine clients operate the gateway
+#[derive(Debug, Clone, Default)]
+pub struct Admins(Arc<HashSet<Uuid>>);
+
+impl Admins {
+    pub fn new(tenants: impl IntoIterator<Item = Uuid>) -> Self {
+        Self(Arc::new(tenants.into_iter().collect()))
+    }
+
+    /// Tenants listed in `ADMIN_TENANT_IDS`, comma separated; none if unset
+    pub fn from_env() -> Result<Self, ApiError> {
+        let Ok(ids) = env::var("ADMIN_TENANT_IDS") else {
+            return Ok(Self::default());
+        };
+        ids.split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(|id| {
+                Uuid::parse_str(id).map_err(|_| {
+                    ApiError::ValidationError(format!("Invalid admin tenant id '{}'", id))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self::new)
+    }
+
+    pub fn contains(&self, claims: &Claims) -> bool {
+        claims.tenant().is_some_and(|tenant| self.0.contains(&tenant))
+    }
 }
 
 impl<S, B> Transform<S, ServiceRequest> for AuthMiddleware
@@ -36,7 +166,7 @@ where
     S::Future: 'static,
     B: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type InitError = ();
     type Transform = AuthMiddlewareService<S>;
@@ -45,14 +175,20 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(AuthMiddlewareService {
             service: Rc::new(service),
-            jwt_secret: self.jwt_secret.clone(),
+            jwt_keys: self.jwt_keys.clone(),
+            api_keys: self.api_keys.clone(),
+            resource: self.resource.clone(),
+            admins: self.admins.clone(),
         }))
     }
 }
 
 pub struct AuthMiddlewareService<S> {
     service: Rc<S>,
-    jwt_secret: Rc<String>,
+    jwt_keys: KeyRing,
+    api_keys: Option<ApiKeyVerifier>,
+    resource: Option<Rc<String>>,
+    admins: Option<Admins>,
 }
 
 impl<S, B> Service<ServiceRequest> for AuthMiddlewareService<S>
@@ -61,36 +197,129 @@ where
     S::Future: 'static,
     B: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let auth_header = req
-            .headers()
-            .get("Authorization")
-            .and_then(|h| h.to_str().ok())
-            .and_then(|h| h.strip_prefix("Bearer "))
-            .map(|s| s.to_string());
-
-        let jwt_secret = self.jwt_secret.clone();
+        if PUBLIC_PATHS.contains(&req.path()) {
+            let service = self.service.clone();
+            return Box::pin(
+                async move { service.call(req).await.map(|res| res.map_into_left_body()) },
+            );
+        }
+
+        let required_scope = self.resource.as_ref().map(|resource| {
+            let action = match *req.method() {
+                Method::GET | Method::HEAD | Method::OPTIONS => "read",
+                _ => "write",
+            };
+            format!("{}:{}", resource, action)
+        });
+
+        let jwt_keys = self.jwt_keys.clone();
+        let api_keys = self.api_keys.clone();
+        let admins = self.admins.clone();
         let service = self.service.clone();
 
         Box::pin(async move {
-            if let Some(token) = auth_header {
-                let key = DecodingKey::from_secret(jwt_secret.as_bytes());
-                match decode::<Claims>(&token, &key, &Validation::default()) {
-                    Ok(token_data) => {
-                        req.extensions_mut().insert(token_data.claims);
-                        service.call(req).await
+            let claims = authenticate(&jwt_keys, api_keys.as_ref(), req.headers()).await;
+
+            let error = match claims {
+                Ok(claims) => match required_scope {
+                    Some(scope)
+                        if claims.is_machine() && !scope_allows(&claims.permissions, &scope) =>
+                    {
+                        ApiError::AuthorizationError(format!("Missing scope {}", scope))
                     }
-                    Err(e) => Err(ApiError::AuthenticationError(e.to_string()).into()),
-                }
-            } else {
-                Err(ApiError::AuthenticationError("No authorization token provided".into()).into())
-            }
+                    _ if admins.as_ref().is_some_and(|admins| !admins.contains(&claims)) => {
+                        ApiError::AuthorizationError("Operators only".to_string())
+                    }
+                    _ => {
+                        req.extensions_mut().insert(claims);
+                        return service.call(req).await.map(|res| res.map_into_left_body());
+                    }
+                },
+                Err(error) => error,
+            };
+
+            // Answer with an error response rather than an error so outer
+            // middleware (logging, CORS) still sees the request complete
+            let (req, _) = req.into_parts();
+            Ok(ServiceResponse::new(req, HttpResponse::from_error(error)).map_into_right_body())
+        })
+    }
+}
+
+/// Claims of the JWT or API key `headers` carry; API keys are refused
+/// without a `verifier`
+pub async fn authenticate(
+    jwt_keys: &KeyRing,
+    api_keys: Option<&ApiKeyVerifier>,
+    headers: &HeaderMap,
+) -> Result<Claims, ApiError> {
+    let api_key_header = headers.get(API_KEY_HEADER).and_then(|h| h.to_str().ok());
+    let auth_header = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+
+    match (api_key_header, auth_header) {
+        (Some(key), _) => authenticate_api_key(api_keys, key).await,
+        (None, Some(token)) if is_api_key(token) => authenticate_api_key(api_keys, token).await,
+        (None, Some(token)) => decode_jwt(&jwt_keys.verification_keys(), token),
+        (None, None) => Err(ApiError::AuthenticationError(
+            "No authorization token provided".into(),
+        )),
+    }
+}
+
+async fn authenticate_api_key(
+    verifier: Option<&ApiKeyVerifier>,
+    token: &str,
+) -> Result<Claims, ApiError> {
+    let verifier = verifier
+        .ok_or_else(|| ApiError::AuthenticationError("API keys are not accepted here".into()))?;
+
+    verifier
+        .verify(token)
+        .await
+        .map(|key| Claims::from_api_key(&key))
+        .map_err(|e| match e {
+            ApiKeyError::Store(_) => ApiError::InternalError(e.to_string()),
+            _ => ApiError::AuthenticationError(e.to_string()),
         })
+}
+
+/// Verifies `token` against each valid key, current first
+fn decode_jwt(keys: &[Secret], token: &str) -> Result<Claims, ApiError> {
+    let mut error = None;
+    for key in keys {
+        let key = DecodingKey::from_secret(key.value.as_bytes());
+        match decode::<Claims>(token, &key, &Validation::default()) {
+            Ok(token_data) => return Ok(token_data.claims),
+            // A signature mismatch only means another key may match; any
+            // other failure (expiry, malformed token) is the real reason
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidSignature) => {
+                error.get_or_insert(e);
+            }
+            Err(e) => return Err(ApiError::AuthenticationError(e.to_string())),
+        }
     }
+
+    Err(ApiError::AuthenticationError(
+        error.map_or_else(|| "No signing key configured".to_string(), |e| e.to_string()),
+    ))
+}
+
+/// Signs `claims` with the current key, naming its version in `kid`
+pub fn sign_jwt(keys: &KeyRing, claims: &Claims) -> Result<String, jsonwebtoken::errors::Error> {
+    let key = keys.current();
+    let header = Header {
+        kid: key.version.map(|version| version.to_string()),
+        ..Header::default()
+    };
+    encode(&header, claims, &EncodingKey::from_secret(key.value.as_bytes()))
 }
@@ -0,0 +1,459 @@
+//! Answers repeated reads from the [`cache`](crate::cache), honouring
+//! `Cache-Control` and validating `ETag`s
+
+use actix_web::{
+    body::{to_bytes_limited, BodySize, EitherBody, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error::ErrorInternalServerError,
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        Method, StatusCode,
+    },
+    web::Bytes,
+    Error, HttpMessage, HttpResponse, HttpResponseBuilder,
+};
+use chrono::Utc;
+use futures_util::future::LocalBoxFuture;
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
+};
+
+use super::auth::Claims;
+use crate::{
+    cache::{CacheOptions, CacheStore, CachedResponse},
+    error::ApiError,
+    usage::TOKENS_HEADER,
+};
+
+const X_CACHE: &str = "x-cache";
+
+/// Headers that describe the connection or a single exchange rather than
+/// the response, and are not kept with it. Tokens are only metered for the
+/// request that used them.
+const UNCACHED_HEADERS: [&str; 6] = [
+    "connection",
+    "content-length",
+    "date",
+    "transfer-encoding",
+    TOKENS_HEADER,
+    X_CACHE,
+];
+
+/// Headers a `304 Not Modified` repeats from the response it stands for
+const NOT_MODIFIED_HEADERS: [&str; 5] = [
+    "cache-control",
+    "content-location",
+    "etag",
+    "expires",
+    "vary",
+];
+
+/// Caches `200` responses to `GET` requests and answers `If-None-Match`
+/// with `304 Not Modified`. Entries are kept per caller, so it must be
+/// wrapped before [`AuthMiddleware`](super::auth::AuthMiddleware); requests
+/// with credentials but no claims are never cached.
+///
+/// Responses are kept for their `s-maxage` or `max-age`, and for the
+/// default TTL if they carry no `Cache-Control` at all; `no-store`,
+/// `no-cache`, `Set-Cookie` and `Vary: *` keep them out. Requests may send
+/// `no-store` to bypass the cache or `no-cache` to skip the cached entry.
+/// A successful write to a resource, the first few segments of the path,
+/// invalidates everything cached for it.
+#[derive(Clone)]
+pub struct ResponseCache {
+    store: Arc<dyn CacheStore>,
+    default_ttl: Duration,
+    max_entry_bytes: usize,
+    resource_depth: usize,
+}
+
+impl ResponseCache {
+    pub fn new(store: Arc<dyn CacheStore>, options: &CacheOptions) -> Self {
+        Self {
+            store,
+            default_ttl: options.default_ttl,
+            max_entry_bytes: options.max_entry_bytes,
+            resource_depth: 3,
+        }
+    }
+
+    /// How long responses without `Cache-Control` are kept; zero caches
+    /// only those that ask to be
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// How many path segments name the resource, 3 by default, so that
+    /// `/api/v1/products/42` belongs to `/api/v1/products`
+    pub fn resource_depth(mut self, depth: usize) -> Self {
+        self.resource_depth = depth;
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ResponseCache
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ResponseCacheService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ResponseCacheService {
+            service: Rc::new(service),
+            cache: Rc::new(self.clone()),
+        }))
+    }
+}
+
+pub struct ResponseCacheService<S> {
+    service: Rc<S>,
+    cache: Rc<ResponseCache>,
+}
+
+impl<S, B> Service<ServiceRequest> for ResponseCacheService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let cache = self.cache.clone();
+
+        Box::pin(async move {
+            let resource = cache.resource(req.path());
+            if !req.method().is_safe() {
+                let res = service.call(req).await?;
+                if res.status().is_success() {
+                    if let Err(e) = cache.store.invalidate(&resource).await {
+                        tracing::warn!("Failed to invalidate cached {}: {}", resource, e);
+                    }
+                }
+                return Ok(res.map_into_left_body());
+            }
+
+            let directives = CacheControl::of(req.headers());
+            let Some(key) = cache.key(&req, &resource, &directives).await else {
+                return Ok(service.call(req).await?.map_into_left_body());
+            };
+
+            let if_none_match = req.headers().get(header::IF_NONE_MATCH).cloned();
+            if !directives.has("no-cache") && directives.seconds("max-age") != Some(0) {
+                match cache.store.get(&key).await {
+                    Ok(Some(cached)) => {
+                        let response = if etag_matches(if_none_match.as_ref(), &cached.etag) {
+                            not_modified(&cached)
+                        } else {
+                            let age = (Utc::now() - cached.stored_at).num_seconds().max(0);
+                            let mut response = full(&cached);
+                            if let Ok(age) = HeaderValue::from_str(&age.to_string()) {
+                                response.headers_mut().insert(header::AGE, age);
+                            }
+                            response
+                        };
+                        let response = with_x_cache(response, "HIT");
+                        return Ok(req.into_response(response).map_into_right_body());
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        tracing::warn!("Cache store unavailable, bypassing it: {}", e);
+                        return Ok(service.call(req).await?.map_into_left_body());
+                    }
+                }
+            }
+
+            let mut res = service.call(req).await?;
+            res.headers_mut().insert(
+                HeaderName::from_static(X_CACHE),
+                HeaderValue::from_static("MISS"),
+            );
+            let Some(ttl) = cache.ttl(&res) else {
+                return Ok(res.map_into_left_body());
+            };
+
+            let (http_req, res) = res.into_parts();
+            let (mut res, body) = res.into_parts();
+            let body = match to_bytes_limited(body, cache.max_entry_bytes).await {
+                Ok(Ok(body)) => body,
+                Ok(Err(e)) => {
+                    let e: Box<dyn std::error::Error> = e.into();
+                    return Err(ErrorInternalServerError(e.to_string()));
+                }
+                Err(_) => {
+                    return Err(ApiError::BadGateway(
+                        "Response body larger than announced".to_string(),
+                    )
+                    .into())
+                }
+            };
+
+            let headers = res.headers_mut();
+            if !headers.contains_key(header::CACHE_CONTROL) {
+                // Kept for the default TTL; clients revalidate with the ETag
+                headers.insert(
+                    header::CACHE_CONTROL,
+                    HeaderValue::from_static("private, no-cache"),
+                );
+            }
+            let etag = match headers.get(header::ETAG).and_then(|v| v.to_str().ok()) {
+                Some(etag) => etag.to_string(),
+                None => {
+                    let etag = format!("\"{:016x}\"", fnv1a(&body));
+                    if let Ok(value) = HeaderValue::from_str(&etag) {
+                        headers.insert(header::ETAG, value);
+                    }
+                    etag
+                }
+            };
+
+            let cached = CachedResponse {
+                status: StatusCode::OK.as_u16(),
+                headers: headers
+                    .iter()
+                    .filter(|(name, _)| {
+                        !UNCACHED_HEADERS
+                            .iter()
+                            .any(|uncached| name.as_str().eq_ignore_ascii_case(uncached))
+                    })
+                    .filter_map(|(name, value)| {
+                        Some((name.to_string(), value.to_str().ok()?.to_string()))
+                    })
+                    .collect(),
+                body: body.clone(),
+                etag,
+                stored_at: Utc::now(),
+            };
+            let response = if etag_matches(if_none_match.as_ref(), &cached.etag) {
+                with_x_cache(not_modified(&cached), "MISS")
+            } else {
+                res.set_body(body).map_into_boxed_body()
+            };
+            if let Err(e) = cache.store.put(&key, cached, ttl).await {
+                tracing::warn!("Failed to cache response: {}", e);
+            }
+            Ok(ServiceResponse::new(http_req, response).map_into_right_body())
+        })
+    }
+}
+
+impl ResponseCache {
+    /// The first `resource_depth` segments of `path`
+    fn resource(&self, path: &str) -> String {
+        let segments: Vec<_> = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .take(self.resource_depth)
+            .collect();
+        format!("/{}", segments.join("/"))
+    }
+
+    /// Where the response to `req` is cached, or `None` if it must not be:
+    /// per caller, per representation and per generation of `resource`
+    async fn key(
+        &self,
+        req: &ServiceRequest,
+        resource: &str,
+        directives: &CacheControl,
+    ) -> Option<String> {
+        // WebSocket handshakes are GETs too
+        if req.method() != Method::GET
+            || directives.has("no-store")
+            || req.headers().contains_key(header::UPGRADE)
+        {
+            return None;
+        }
+
+        let caller = match req.extensions().get::<Claims>() {
+            Some(claims) => format!(
+                "{}:{}",
+                claims.tenant().map(|t| t.to_string()).unwrap_or_default(),
+                claims.sub
+            ),
+            // Credentials the gateway did not check
+            None if req.headers().contains_key(header::AUTHORIZATION)
+                || req.headers().contains_key("x-api-key") =>
+            {
+                return None
+            }
+            None => "anonymous".to_string(),
+        };
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+        };
+        let representation = format!(
+            "{}|{}",
+            header(header::ACCEPT),
+            header(header::ACCEPT_ENCODING)
+        );
+
+        let generation = match self.store.generation(resource).await {
+            Ok(generation) => generation,
+            Err(e) => {
+                tracing::warn!("Cache store unavailable, bypassing it: {}", e);
+                return None;
+            }
+        };
+        Some(format!(
+            "{}|{}|{}|{}|{}",
+            resource,
+            generation,
+            caller,
+            req.uri()
+                .path_and_query()
+                .map_or(req.path(), |path| path.as_str()),
+            representation
+        ))
+    }
+
+    /// How long `res` may be kept, or `None` if it may not
+    fn ttl<B: MessageBody>(&self, res: &ServiceResponse<B>) -> Option<Duration> {
+        let headers = res.headers();
+        if res.status() != StatusCode::OK || headers.contains_key(header::SET_COOKIE) {
+            return None;
+        }
+        let varies_on_anything = headers
+            .get_all(header::VARY)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|v| v.trim() == "*");
+        if varies_on_anything {
+            return None;
+        }
+
+        let size = match res.response().body().size() {
+            BodySize::Sized(size) => Some(size),
+            // Streamed bodies, such as proxied ones, may announce their size
+            BodySize::Stream => headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.parse().ok()),
+            BodySize::None => Some(0),
+        };
+        if size.is_none_or(|size| size > self.max_entry_bytes as u64) {
+            return None;
+        }
+
+        let directives = CacheControl::of(headers);
+        if !directives.present {
+            return (!self.default_ttl.is_zero()).then_some(self.default_ttl);
+        }
+        if directives.has("no-store") || directives.has("no-cache") {
+            return None;
+        }
+        directives
+            .seconds("s-maxage")
+            .or_else(|| directives.seconds("max-age"))
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs)
+    }
+}
+
+/// The directives of a `Cache-Control` header
+struct CacheControl {
+    present: bool,
+    directives: Vec<(String, Option<String>)>,
+}
+
+impl CacheControl {
+    fn of(headers: &HeaderMap) -> Self {
+        let mut present = false;
+        let mut directives = Vec::new();
+        for value in headers.get_all(header::CACHE_CONTROL) {
+            present = true;
+            let Ok(value) = value.to_str() else { continue };
+            for directive in value.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+                let (name, argument) = match directive.split_once('=') {
+                    Some((name, argument)) => {
+                        (name, Some(argument.trim().trim_matches('"').to_string()))
+                    }
+                    None => (directive, None),
+                };
+                directives.push((name.trim().to_ascii_lowercase(), argument));
+            }
+        }
+        Self {
+            present,
+            directives,
+        }
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.directives
+            .iter()
+            .any(|(directive, _)| directive == name)
+    }
+
+    fn seconds(&self, name: &str) -> Option<u64> {
+        self.directives
+            .iter()
+            .find(|(directive, _)| directive == name)
+            .and_then(|(_, argument)| argument.as_deref()?.parse().ok())
+    }
+}
+
+/// Whether `If-None-Match` lists `etag`, compared weakly as it requires
+fn etag_matches(if_none_match: Option<&HeaderValue>, etag: &str) -> bool {
+    let Some(if_none_match) = if_none_match.and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+fn not_modified(cached: &CachedResponse) -> HttpResponse {
+    let mut response = HttpResponse::NotModified();
+    for (name, value) in &cached.headers {
+        if NOT_MODIFIED_HEADERS
+            .iter()
+            .any(|kept| name.eq_ignore_ascii_case(kept))
+        {
+            response.append_header((name.as_str(), value.as_str()));
+        }
+    }
+    response.finish()
+}
+
+fn full(cached: &CachedResponse) -> HttpResponse {
+    let status = StatusCode::from_u16(cached.status).unwrap_or(StatusCode::OK);
+    let mut response = HttpResponseBuilder::new(status);
+    for (name, value) in &cached.headers {
+        response.append_header((name.as_str(), value.as_str()));
+    }
+    response.body(cached.body.clone())
+}
+
+fn with_x_cache(mut response: HttpResponse, outcome: &'static str) -> HttpResponse {
+    response.headers_mut().insert(
+        HeaderName::from_static(X_CACHE),
+        HeaderValue::from_static(outcome),
+    );
+    response
+}
+
+/// 64-bit FNV-1a, for ETags of responses that have none
+fn fnv1a(body: &Bytes) -> u64 {
+    body.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -1,3 +1,11 @@
 pub mod auth;
+pub mod cache;
+pub mod rate_limit;
+pub mod route_table;
+pub mod usage;
 
-pub use auth::Claims;
+pub use auth::{sign_jwt, Admins, Claims, Principal};
+pub use cache::ResponseCache;
+pub use rate_limit::tenant_rate_limiter;
+pub use route_table::RouteGate;
+pub use usage::UsageMeter;
@@ -0,0 +1,47 @@
+//! Rate limits for authenticated routes, counted per tenant and caller with
+//! limits from the tenant's subscription tier
+
+use actix_web::{dev::ServiceRequest, HttpMessage};
+use lotabots_api_keys::API_KEY_PREFIX;
+use lotabots_rate_limit::{actix::RateLimiter, Policy, RateLimitStore, Subject};
+use std::sync::Arc;
+
+use super::auth::{Claims, Principal};
+
+/// Limits authenticated requests according to `policy`. Must be wrapped
+/// before [`AuthMiddleware`](super::auth::AuthMiddleware) so it runs after
+/// the claims are known; unauthenticated requests are left to the IP limit.
+pub fn tenant_rate_limiter(store: Arc<dyn RateLimitStore>, policy: Policy) -> RateLimiter {
+    RateLimiter::new(store, "tenant", policy).with_subject(subject)
+}
+
+/// Who a request is counted for: the tenant together with the user, API
+/// key or OAuth client that made it
+pub fn subject(req: &ServiceRequest) -> Option<Subject> {
+    let extensions = req.extensions();
+    let claims = extensions.get::<Claims>()?;
+    Some(subject_for(claims))
+}
+
+fn subject_for(claims: &Claims) -> Subject {
+    let subject = Subject {
+        tenant_id: claims.tenant().map(|id| id.to_string()),
+        ..Subject::default()
+    };
+
+    match claims.principal {
+        Principal::User => subject.with_user(&claims.sub),
+        Principal::ApiKey => {
+            let prefix = claims
+                .sub
+                .strip_prefix(API_KEY_PREFIX)
+                .and_then(|rest| rest.strip_prefix('_'))
+                .unwrap_or(&claims.sub);
+            subject.with_api_key(prefix)
+        }
+        Principal::Client => Subject {
+            client: Some(claims.sub.clone()),
+            ..subject
+        },
+    }
+}
@@ -0,0 +1,243 @@
+//! Enforces what the route table declares for each route: who may call it
+//! and how often
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{self, HeaderMap, HeaderName, HeaderValue},
+    Error, HttpMessage, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+use lotabots_api_keys::{scope_allows, ApiKeyVerifier};
+use lotabots_rate_limit::{actix::peer_ip, Decision, RateLimitStore};
+use lotabots_secrets::KeyRing;
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+    sync::Arc,
+};
+
+use super::auth::{self, Admins, Claims};
+use crate::{
+    error::ApiError,
+    proxy::{
+        route_table::{Access, LimitKey, Lookup, RouteLimit},
+        MatchedRoute, RouteTable,
+    },
+};
+
+/// Looks requests up in the [`RouteTable`], authenticates and rate limits
+/// them as their route declares and leaves the [`MatchedRoute`] and the
+/// caller's [`Claims`] in the request's extensions. Paths without a route
+/// are answered 404, and methods a path has no route for 405. Other
+/// middleware that needs the claims, such as the usage meter, must be
+/// wrapped before it. The gRPC listener applies the same checks through
+/// [`admit`](Self::admit) and [`count`](Self::count).
+#[derive(Clone)]
+pub struct RouteGate {
+    table: Arc<RouteTable>,
+    jwt_keys: KeyRing,
+    api_keys: Option<ApiKeyVerifier>,
+    admins: Admins,
+    rate_limits: Option<Arc<dyn RateLimitStore>>,
+}
+
+impl RouteGate {
+    pub fn new(table: Arc<RouteTable>, jwt_keys: KeyRing) -> Self {
+        Self {
+            table,
+            jwt_keys,
+            api_keys: None,
+            admins: Admins::default(),
+            rate_limits: None,
+        }
+    }
+
+    /// Also accept API keys, sent in `X-API-Key` or as a bearer token
+    pub fn with_api_keys(mut self, verifier: ApiKeyVerifier) -> Self {
+        self.api_keys = Some(verifier);
+        self
+    }
+
+    /// Operator tenants, for routes that require them
+    pub fn with_admins(mut self, admins: Admins) -> Self {
+        self.admins = admins;
+        self
+    }
+
+    /// Where the counts of rate limited routes are kept; without one, rate
+    /// limits are not applied
+    pub fn with_rate_limits(mut self, store: Arc<dyn RateLimitStore>) -> Self {
+        self.rate_limits = Some(store);
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RouteGate
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RouteGateService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RouteGateService {
+            service: Rc::new(service),
+            gate: Rc::new(self.clone()),
+        }))
+    }
+}
+
+pub struct RouteGateService<S> {
+    service: Rc<S>,
+    gate: Rc<RouteGate>,
+}
+
+impl<S, B> Service<ServiceRequest> for RouteGateService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let gate = self.gate.clone();
+
+        Box::pin(async move {
+            let matched = match gate.table.find(req.method(), req.path()) {
+                Lookup::Found(matched) => matched,
+                Lookup::MethodNotAllowed(allowed) => {
+                    let mut response = HttpResponse::from_error(ApiError::MethodNotAllowed(
+                        format!("{} is not routed for {}", req.path(), req.method()),
+                    ));
+                    let allowed: Vec<_> = allowed.iter().map(|m| m.as_str()).collect();
+                    if let Ok(allowed) = HeaderValue::from_str(&allowed.join(", ")) {
+                        response.headers_mut().insert(header::ALLOW, allowed);
+                    }
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+                Lookup::NotFound => {
+                    let error = ApiError::NotFoundError(format!("No route for {}", req.path()));
+                    let response = HttpResponse::from_error(error);
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            };
+
+            let claims = match gate.admit(&matched, req.headers()).await {
+                Ok(claims) => claims,
+                Err(error) => {
+                    let response = HttpResponse::from_error(error);
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            };
+
+            let decision = gate.count(&matched, claims.as_ref(), peer_ip(&req)).await;
+            if let Some((decision, limit)) = &decision {
+                if !decision.allowed {
+                    let mut response = HttpResponse::TooManyRequests()
+                        .json(serde_json::json!({ "error": "Rate limit exceeded" }));
+                    insert_headers(response.headers_mut(), decision, limit);
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            }
+
+            if let Some(claims) = claims {
+                req.extensions_mut().insert(claims);
+            }
+            req.extensions_mut().insert(matched);
+            let mut res = service.call(req).await?;
+            if let Some((decision, limit)) = &decision {
+                insert_headers(res.headers_mut(), decision, limit);
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+impl RouteGate {
+    /// The table requests are looked up in
+    pub fn table(&self) -> &Arc<RouteTable> {
+        &self.table
+    }
+
+    /// The caller's claims, if the route lets them through; public routes
+    /// are called without any
+    pub async fn admit(
+        &self,
+        matched: &MatchedRoute,
+        headers: &HeaderMap,
+    ) -> Result<Option<Claims>, ApiError> {
+        let route = &matched.route;
+        if route.access() == Access::Public {
+            return Ok(None);
+        }
+
+        let claims = auth::authenticate(&self.jwt_keys, self.api_keys.as_ref(), headers).await?;
+        if claims.is_machine() {
+            if let Some(missing) = route
+                .permissions()
+                .iter()
+                .find(|permission| !scope_allows(&claims.permissions, permission))
+            {
+                return Err(ApiError::AuthorizationError(format!(
+                    "Missing scope {}",
+                    missing
+                )));
+            }
+        }
+        if route.access() == Access::Admin && !self.admins.contains(&claims) {
+            return Err(ApiError::AuthorizationError("Operators only".to_string()));
+        }
+        Ok(Some(claims))
+    }
+
+    /// Counts a request from `peer` against its route's rate limit, if it
+    /// has one; requests are allowed when the store is unavailable
+    pub async fn count(
+        &self,
+        matched: &MatchedRoute,
+        claims: Option<&Claims>,
+        peer: Option<String>,
+    ) -> Option<(Decision, RouteLimit)> {
+        let (store, limit) = (self.rate_limits.as_ref()?, matched.route.limit()?);
+        let key = limit_key(limit, claims, peer);
+        match store.check(&key, &limit.limit, 1).await {
+            Ok(decision) => Some((decision, limit.clone())),
+            Err(e) => {
+                tracing::warn!("Rate limit store unavailable, allowing request: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Requests are counted per policy, so routes sharing one share its budget
+fn limit_key(limit: &RouteLimit, claims: Option<&Claims>, peer: Option<String>) -> String {
+    let tenant = match limit.per {
+        LimitKey::Tenant => claims.and_then(Claims::tenant),
+        LimitKey::Ip => None,
+    };
+    match tenant {
+        Some(tenant) => format!("routes:{}:t={}", limit.policy, tenant),
+        None => format!("routes:{}:c={}", limit.policy, peer.unwrap_or_default()),
+    }
+}
+
+fn insert_headers(headers: &mut HeaderMap, decision: &Decision, limit: &RouteLimit) {
+    for (name, value) in decision.headers(&limit.limit) {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Meters authenticated requests for billing and enforces monthly quotas
+
+use actix_web::{
+    body::{BodySize, EitherBody, MessageBody},
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    Error, HttpMessage, HttpResponse,
+};
+use chrono::Utc;
+use futures_util::{future::LocalBoxFuture, TryStreamExt};
+use std::{
+    cell::Cell,
+    future::{ready, Ready},
+    rc::Rc,
+    time::Instant,
+};
+
+use super::auth::Claims;
+use crate::{
+    proxy::MatchedRoute,
+    usage::{Meter, UsageEvent, TOKENS_HEADER},
+};
+
+/// Records a [`UsageEvent`] per request and answers `429 Too Many Requests`
+/// once the tenant has used up its monthly quota. Like the tenant rate
+/// limiter it must be wrapped before
+/// [`AuthMiddleware`](super::auth::AuthMiddleware); requests without a
+/// tenant are not metered.
+pub struct UsageMeter {
+    meter: Meter,
+}
+
+impl UsageMeter {
+    pub fn new(meter: Meter) -> Self {
+        Self { meter }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for UsageMeter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = UsageMeterService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(UsageMeterService {
+            service: Rc::new(service),
+            meter: self.meter.clone(),
+        }))
+    }
+}
+
+pub struct UsageMeterService<S> {
+    service: Rc<S>,
+    meter: Meter,
+}
+
+impl<S, B> Service<ServiceRequest> for UsageMeterService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let Some(tenant_id) = req.extensions().get::<Claims>().and_then(Claims::tenant) else {
+            return Box::pin(async move { Ok(service.call(req).await?.map_into_left_body()) });
+        };
+        let meter = self.meter.clone();
+        let started = Instant::now();
+
+        Box::pin(async move {
+            if let Err(exceeded) = meter.check(tenant_id).await {
+                let retry_after = (exceeded.resets_at - Utc::now()).num_seconds().max(1);
+                let response = HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                    .json(serde_json::json!({
+                        "error": "Usage quota exceeded",
+                        "metric": exceeded.metric,
+                        "limit": exceeded.limit,
+                        "resets_at": exceeded.resets_at,
+                    }));
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            // Bodies are counted as they are read, as chunked ones announce
+            // no length
+            let request_bytes = Rc::new(Cell::new(0u64));
+            let counter = request_bytes.clone();
+            let payload = req
+                .take_payload()
+                .inspect_ok(move |chunk| counter.set(counter.get() + chunk.len() as u64));
+            req.set_payload(Payload::Stream {
+                payload: Box::pin(payload),
+            });
+
+            let mut res = service.call(req).await?;
+            let tokens = res
+                .headers_mut()
+                .remove(TOKENS_HEADER)
+                .next()
+                .and_then(|h| h.to_str().ok().and_then(|h| h.parse().ok()))
+                .unwrap_or(0);
+            let response_bytes = match res.response().body().size() {
+                BodySize::Sized(size) => size,
+                // Streamed bodies, such as proxied ones, may announce their size
+                BodySize::Stream => res
+                    .headers()
+                    .get(header::CONTENT_LENGTH)
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(|h| h.parse().ok())
+                    .unwrap_or(0),
+                BodySize::None => 0,
+            };
+
+            meter.record(UsageEvent {
+                tenant_id,
+                // Routes of the route table are served by one catch-all
+                route: res
+                    .request()
+                    .extensions()
+                    .get::<MatchedRoute>()
+                    .map(|matched| matched.route.pattern().to_string())
+                    .or_else(|| res.request().match_pattern())
+                    .unwrap_or_else(|| "unmatched".to_string()),
+                status: res.status().as_u16(),
+                request_bytes: request_bytes.get(),
+                response_bytes,
+                latency: started.elapsed(),
+                tokens,
+                at: Utc::now(),
+            });
+
+            Ok(res.map_into_left_body())
+        })
+    }
+}
@@ -1,9 +1,14 @@
 use chrono::{DateTime, Utc};
+use lotabots_api_keys::{ApiKeyRecord, ClientRecord};
 use serde::{Deserialize, Serialize};
 use sqlx::types::BigDecimal;
 use uuid::Uuid;
 use validator::{Validate, ValidationError};
 
+use crate::billing::Metric;
+use crate::jobs::JobStatus;
+use crate::usage::{Granularity, Quota, Totals};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ApiResponse<T> {
     pub data: T,
@@ -79,3 +84,134 @@ pub struct Product {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct CreateApiKeyRequest {
+    #[validate(length(min = 1, max = 255))]
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Returned once when a key is created; `token` cannot be retrieved again
+#[derive(Debug, Serialize)]
+pub struct ApiKeyCreated {
+    pub key: ApiKeyRecord,
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct CreateOAuthClientRequest {
+    #[validate(length(min = 1, max = 255))]
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+/// Returned once when a client is created; `client_secret` cannot be
+/// retrieved again
+#[derive(Debug, Serialize)]
+pub struct OAuthClientCreated {
+    pub client: ClientRecord,
+    pub client_secret: String,
+}
+
+/// OAuth2 token request (RFC 6749, section 4.4)
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    pub scope: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    /// Defaults to `day`
+    pub granularity: Option<Granularity>,
+    /// Defaults to the start of the current month
+    pub from: Option<DateTime<Utc>>,
+    /// Defaults to now
+    pub to: Option<DateTime<Utc>>,
+    /// Only usage of this route pattern
+    pub route: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UsagePeriod {
+    pub period_start: DateTime<Utc>,
+    #[serde(flatten)]
+    pub usage: Totals,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RouteUsage {
+    pub route: String,
+    #[serde(flatten)]
+    pub usage: Totals,
+}
+
+/// Consumption this month against the tenant's quota
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuotaStatus {
+    pub period_start: DateTime<Utc>,
+    pub resets_at: DateTime<Utc>,
+    pub used: Totals,
+    pub limits: Quota,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub tenant_id: Uuid,
+    pub granularity: Granularity,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub periods: Vec<UsagePeriod>,
+    /// Per route over the whole days of the range
+    pub routes: Vec<RouteUsage>,
+    pub quota: QuotaStatus,
+}
+
+/// Billable usage reported by a service, see [`crate::billing`]
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct BillableEventRequest {
+    /// `storage_bytes` or `gpu_seconds`
+    pub metric: Metric,
+    /// Bytes stored at `occurred_at`, or GPU seconds used
+    #[validate(range(min = 0))]
+    pub quantity: i64,
+    /// Defaults to now
+    pub occurred_at: Option<DateTime<Utc>>,
+    /// Reports with a key already seen are ignored, so they can be retried
+    #[validate(length(min = 1, max = 255))]
+    pub idempotency_key: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BillableEventRecorded {
+    /// `false` if the event was a duplicate
+    pub recorded: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InvoiceQuery {
+    /// `YYYY-MM`; defaults to the current month
+    pub period: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JobQuery {
+    pub status: Option<JobStatus>,
+    pub job_type: Option<String>,
+    /// Defaults to 100, at most 1000
+    pub limit: Option<i64>,
+}
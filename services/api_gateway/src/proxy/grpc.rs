@@ -0,0 +1,358 @@
+//! gRPC passthrough.
+//!
+//! gRPC needs HTTP/2 and trailers, which actix-web does not send, so the
+//! gRPC routes of the [`RouteTable`](super::RouteTable) are served on a
+//! listener of their own, speaking HTTP/2 without TLS (h2c). Calls are
+//! checked by the same [`RouteGate`] as routes on the gateway's port and
+//! forwarded to an instance of the route's service, their messages and
+//! trailers streamed both ways. Calls the gateway refuses, or cannot
+//! forward, are answered with a gRPC status rather than an HTTP one.
+
+use actix_web::http::header::HeaderMap as ActixHeaderMap;
+use hyper::{
+    body::HttpBody,
+    client::HttpConnector,
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    server::conn::Http,
+    service::service_fn,
+    Body, Client, Method, Request, Response, StatusCode, Uri, Version,
+};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+use tokio::net::TcpListener;
+use uuid::Uuid;
+
+use super::{headers::HopByHop, is_failure, MatchedRoute, Proxy, ServiceRegistry, Upstream};
+use crate::{
+    error::ApiError,
+    middleware::{Claims, RouteGate},
+};
+
+// Status codes of the calls the gateway answers itself
+const INVALID_ARGUMENT: u16 = 3;
+const DEADLINE_EXCEEDED: u16 = 4;
+const PERMISSION_DENIED: u16 = 7;
+const RESOURCE_EXHAUSTED: u16 = 8;
+const UNIMPLEMENTED: u16 = 12;
+const INTERNAL: u16 = 13;
+const UNAVAILABLE: u16 = 14;
+const UNAUTHENTICATED: u16 = 16;
+
+/// Serves the gRPC routes of the gate's table; cheap to clone, clones share
+/// the connection pool
+#[derive(Clone)]
+pub struct GrpcGateway {
+    gate: RouteGate,
+    registry: Arc<ServiceRegistry>,
+    proxy: Proxy,
+    client: Client<HttpConnector>,
+}
+
+impl GrpcGateway {
+    /// Calls are checked by `gate`, forwarded to the services of
+    /// `registry` and reported to the metrics and circuit breakers of
+    /// `proxy`, whose timeouts they also wait for
+    pub fn new(gate: RouteGate, registry: Arc<ServiceRegistry>, proxy: Proxy) -> Self {
+        let mut connector = HttpConnector::new();
+        connector.set_connect_timeout(Some(proxy.options.connect_timeout));
+        connector.set_nodelay(true);
+        let client = Client::builder()
+            .http2_only(true)
+            .pool_idle_timeout(proxy.options.idle_timeout)
+            .build(connector);
+        Self {
+            gate,
+            registry,
+            proxy,
+            client,
+        }
+    }
+
+    /// Serves the connections accepted on `listener`, until the task is
+    /// dropped
+    pub async fn serve(self, listener: TcpListener) {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Failed to accept a gRPC connection: {}", e);
+                    continue;
+                }
+            };
+            let gateway = self.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req| {
+                    let gateway = gateway.clone();
+                    async move { Ok::<_, Infallible>(gateway.handle(req, peer).await) }
+                });
+                if let Err(e) = Http::new()
+                    .http2_only(true)
+                    .serve_connection(stream, service)
+                    .await
+                {
+                    tracing::debug!("gRPC connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, req: Request<Body>, peer: SocketAddr) -> Response<Relayed> {
+        let path = req.uri().path().to_string();
+        let Some(matched) = self.gate.table().find_grpc(&path) else {
+            return status(UNIMPLEMENTED, &format!("No route for {}", path));
+        };
+
+        let headers = ActixHeaderMap::from(req.headers().clone());
+        let claims = match self.gate.admit(&matched, &headers).await {
+            Ok(claims) => claims,
+            Err(error) => return error_status(&error),
+        };
+        let decision = self
+            .gate
+            .count(&matched, claims.as_ref(), Some(peer.ip().to_string()))
+            .await;
+        let mut response = match &decision {
+            Some((decision, _)) if !decision.allowed => {
+                status(RESOURCE_EXHAUSTED, "Rate limit exceeded")
+            }
+            _ => {
+                let tenant = claims.as_ref().and_then(Claims::tenant);
+                self.forward(&matched, tenant, req, peer)
+                    .await
+                    .unwrap_or_else(|error| error_status(&error))
+            }
+        };
+        if let Some((decision, limit)) = &decision {
+            for (name, value) in decision.headers(&limit.limit) {
+                if let Ok(value) = HeaderValue::from_str(&value) {
+                    response
+                        .headers_mut()
+                        .insert(HeaderName::from_static(name), value);
+                }
+            }
+        }
+        response
+    }
+
+    /// Sends the call to an instance of the route's service and relays its
+    /// response; the instance counts as busy until the response ends
+    async fn forward(
+        &self,
+        matched: &MatchedRoute,
+        tenant: Option<Uuid>,
+        req: Request<Body>,
+        peer: SocketAddr,
+    ) -> Result<Response<Relayed>, ApiError> {
+        let service = self.registry.get(matched.route.upstream())?;
+        let name = service.name();
+        if !service
+            .breaker()
+            .try_acquire(&service.config().circuit_breaker, Instant::now())
+        {
+            self.proxy.metrics.increment(
+                "gateway_circuit_breaker_rejections_total",
+                &[("service", name)],
+            );
+            return Err(ApiError::ServiceUnavailable(format!(
+                "Service {} is failing, try again later",
+                name
+            )));
+        }
+        let upstream = match service.pick(tenant) {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                self.proxy.record(&service, false);
+                return Err(e);
+            }
+        };
+
+        let (parts, body) = req.into_parts();
+        let path = matched.upstream_path(parts.uri.path(), parts.uri.query().unwrap_or_default());
+        let uri: Uri = format!("{}/{}", upstream.url(), path)
+            .parse()
+            .map_err(|_| ApiError::InternalError(format!("Invalid upstream URI for {}", name)))?;
+        let mut request = Request::new(body);
+        *request.method_mut() = Method::POST;
+        *request.uri_mut() = uri;
+        *request.version_mut() = Version::HTTP_2;
+        *request.headers_mut() = upstream_request(&parts.headers, peer);
+
+        let timeout = matched
+            .route
+            .timeout()
+            .unwrap_or_else(|| self.proxy.timeout_for(name));
+        let started = Instant::now();
+        let result = match tokio::time::timeout(timeout, self.client.request(request)).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => {
+                tracing::warn!("Failed to reach {} for {}: {}", name, path, e);
+                Err(ApiError::BadGateway(format!(
+                    "Service {} is unavailable",
+                    name
+                )))
+            }
+            Err(_) => {
+                tracing::warn!("{} did not respond within {:?} for {}", name, timeout, path);
+                Err(ApiError::GatewayTimeout(format!(
+                    "Service {} did not respond within {:?}",
+                    name, timeout
+                )))
+            }
+        };
+        let label = match &result {
+            Ok(response) => {
+                self.proxy.metrics.observe_latency(name, started.elapsed());
+                response.status().as_str().to_string()
+            }
+            Err(ApiError::GatewayTimeout(_)) => "timeout".to_string(),
+            Err(_) => "error".to_string(),
+        };
+        self.proxy.metrics.increment(
+            "gateway_upstream_requests_total",
+            &[("service", name), ("status", &label)],
+        );
+        let success = result
+            .as_ref()
+            .is_ok_and(|response| !is_failure(response.status()));
+        upstream.report(success);
+        self.proxy.record(&service, success);
+
+        let (mut parts, body) = result?.into_parts();
+        let hop_by_hop = HopByHop::of(parts.headers.get_all(header::CONNECTION).iter());
+        let headers = std::mem::take(&mut parts.headers);
+        parts.headers = filter(headers, |name| !hop_by_hop.contains(name));
+        Ok(Response::from_parts(
+            parts,
+            Relayed {
+                body,
+                upstream: Some(upstream),
+            },
+        ))
+    }
+}
+
+/// A response body with its trailers; the upstream it comes from, if any,
+/// counts as busy until it is sent
+struct Relayed {
+    body: Body,
+    upstream: Option<Upstream>,
+}
+
+impl HttpBody for Relayed {
+    type Data = hyper::body::Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let trailers = Pin::new(&mut self.body).poll_trailers(cx);
+        if trailers.is_ready() {
+            self.upstream = None;
+        }
+        trailers
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// The end-to-end headers of a call to send upstream, with the client in
+/// `X-Forwarded-For`
+fn upstream_request(headers: &HeaderMap, peer: SocketAddr) -> HeaderMap {
+    let hop_by_hop = HopByHop::of(headers.get_all(header::CONNECTION).iter());
+    let mut upstream = filter(headers.clone(), |name| {
+        name != header::HOST && !hop_by_hop.contains(name)
+    });
+
+    let mut forwarded_for: Vec<_> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(str::to_string)
+        .collect();
+    forwarded_for.push(peer.ip().to_string());
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for.join(", ")) {
+        upstream.insert("x-forwarded-for", value);
+    }
+    upstream.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+    // Required by gRPC servers, and hop-by-hop like the others
+    upstream.insert(header::TE, HeaderValue::from_static("trailers"));
+    upstream
+}
+
+fn filter(headers: HeaderMap, keep: impl Fn(&HeaderName) -> bool) -> HeaderMap {
+    let mut kept = HeaderMap::with_capacity(headers.len());
+    let mut current = None;
+    for (name, value) in headers {
+        // Names are only given for the first of several values
+        if name.is_some() {
+            current = name;
+        }
+        if let Some(name) = current.as_ref().filter(|name| keep(name)) {
+            kept.append(name.clone(), value);
+        }
+    }
+    kept
+}
+
+/// A call the gateway answers itself: headers only, carrying the status
+fn status(code: u16, message: &str) -> Response<Relayed> {
+    let mut response = Response::new(Relayed {
+        body: Body::empty(),
+        upstream: None,
+    });
+    *response.status_mut() = StatusCode::OK;
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    headers.insert("grpc-status", HeaderValue::from(code));
+    if let Ok(message) = HeaderValue::from_str(&percent_encode(message)) {
+        headers.insert("grpc-message", message);
+    }
+    response
+}
+
+fn error_status(error: &ApiError) -> Response<Relayed> {
+    let code = match error {
+        ApiError::AuthenticationError(_) => UNAUTHENTICATED,
+        ApiError::AuthorizationError(_) => PERMISSION_DENIED,
+        ApiError::ValidationError(_) => INVALID_ARGUMENT,
+        ApiError::NotFoundError(_) | ApiError::MethodNotAllowed(_) => UNIMPLEMENTED,
+        ApiError::ServiceUnavailable(_) | ApiError::BadGateway(_) => UNAVAILABLE,
+        ApiError::GatewayTimeout(_) => DEADLINE_EXCEEDED,
+        _ => INTERNAL,
+    };
+    status(code, &error.to_string())
+}
+
+/// `grpc-message` is percent-encoded, apart from printable ASCII
+fn percent_encode(message: &str) -> String {
+    message
+        .bytes()
+        .map(|byte| match byte {
+            b' '..=b'~' if byte != b'%' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
@@ -0,0 +1,209 @@
+//! Header hygiene between clients and upstreams
+
+use actix_web::{
+    http::header::{self, HeaderMap, HeaderName, HeaderValue},
+    HttpRequest,
+};
+use std::net::{IpAddr, SocketAddr};
+
+/// Headers that describe a single connection rather than the message
+/// (RFC 7230, section 6.1), and the non-standard `Proxy-Connection`
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Hop-by-hop headers of a message: the fixed ones and those its
+/// `Connection` header names
+pub struct HopByHop {
+    named: Vec<String>,
+}
+
+impl HopByHop {
+    pub fn of<'a>(connection: impl Iterator<Item = &'a HeaderValue>) -> Self {
+        let named = connection
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|token| token.trim().to_ascii_lowercase())
+            .filter(|token| !token.is_empty())
+            .collect();
+        Self { named }
+    }
+
+    pub fn contains(&self, name: &HeaderName) -> bool {
+        HOP_BY_HOP.contains(&name.as_str()) || self.named.iter().any(|n| n == name.as_str())
+    }
+}
+
+/// The end-to-end headers of `req` to send upstream, with the client
+/// described by `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host`
+/// and `Forwarded`
+pub fn upstream_request(req: &HttpRequest) -> reqwest::header::HeaderMap {
+    let hop_by_hop = HopByHop::of(req.headers().get_all(header::CONNECTION));
+    let mut headers = reqwest::header::HeaderMap::new();
+    for (name, value) in req.headers() {
+        // Host is set from the upstream URL
+        if name != header::HOST && !hop_by_hop.contains(name) {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+
+    let client = req.peer_addr();
+    let proto = if req.app_config().secure() {
+        "https"
+    } else {
+        "http"
+    };
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+        .map(str::to_string);
+
+    // Proxies in front of the gateway are kept in the chain
+    let forwarded_for = append(
+        req.headers(),
+        "x-forwarded-for",
+        &client.map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string()),
+    );
+    let mut element = format!("for={};proto={}", forwarded_node(client), proto);
+    if let Some(host) = &host {
+        element.push_str(&format!(";host={}", quote(host)));
+    }
+    let forwarded = append(req.headers(), "forwarded", &element);
+
+    insert(&mut headers, "x-forwarded-for", &forwarded_for);
+    insert(&mut headers, "forwarded", &forwarded);
+    insert(&mut headers, "x-forwarded-proto", proto);
+    if let Some(host) = &host {
+        insert(&mut headers, "x-forwarded-host", host);
+    }
+    headers
+}
+
+/// The `Upgrade` header of WebSocket handshakes. Other upgrades are
+/// dropped with the other hop-by-hop headers: actix-web only hands over the
+/// connection for WebSocket.
+pub fn upgrade(req: &HttpRequest) -> Option<HeaderValue> {
+    let hop_by_hop = HopByHop::of(req.headers().get_all(header::CONNECTION));
+    if !hop_by_hop.named.iter().any(|name| name == "upgrade") {
+        return None;
+    }
+    req.headers()
+        .get(header::UPGRADE)
+        .filter(|protocol| protocol.as_bytes().eq_ignore_ascii_case(b"websocket"))
+        .cloned()
+}
+
+/// The end-to-end headers of an upstream response, without
+/// `Content-Length`, which is set from the body
+pub fn client_response(
+    upstream: &reqwest::header::HeaderMap,
+) -> impl Iterator<Item = (&HeaderName, &HeaderValue)> {
+    let hop_by_hop = HopByHop::of(upstream.get_all(header::CONNECTION).iter());
+    upstream
+        .iter()
+        .filter(move |(name, _)| *name != header::CONTENT_LENGTH && !hop_by_hop.contains(name))
+}
+
+/// `value` appended to the comma separated list in `name`
+fn append(headers: &HeaderMap, name: &str, value: &str) -> String {
+    let existing: Vec<_> = headers
+        .get_all(name)
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    if existing.is_empty() {
+        value.to_string()
+    } else {
+        format!("{}, {}", existing.join(", "), value)
+    }
+}
+
+fn insert(headers: &mut reqwest::header::HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+/// A `Forwarded` node (RFC 7239, section 6); IPv6 addresses are bracketed
+/// and quoted
+fn forwarded_node(addr: Option<SocketAddr>) -> String {
+    match addr.map(|addr| addr.ip()) {
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Quotes values that are not a plain token, such as hosts with a port
+fn quote(value: &str) -> String {
+    if value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+    {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_hop_by_hop_headers_are_stripped() {
+        let req = TestRequest::default()
+            .insert_header(("connection", "keep-alive, X-Session"))
+            .insert_header(("keep-alive", "timeout=5"))
+            .insert_header(("transfer-encoding", "chunked"))
+            .insert_header(("te", "trailers"))
+            .insert_header(("upgrade", "h2c"))
+            .insert_header(("x-session", "abc"))
+            .insert_header(("authorization", "Bearer token"))
+            .insert_header(("host", "api.example.com"))
+            .to_http_request();
+
+        let headers = upstream_request(&req);
+        let mut names: Vec<_> = headers.keys().map(|name| name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            [
+                "authorization",
+                "forwarded",
+                "x-forwarded-for",
+                "x-forwarded-host",
+                "x-forwarded-proto"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forwarded_headers_extend_the_chain() {
+        let req = TestRequest::default()
+            .peer_addr("[2001:db8::1]:4711".parse().unwrap())
+            .insert_header(("host", "api.example.com:8443"))
+            .insert_header(("x-forwarded-for", "203.0.113.7"))
+            .insert_header(("forwarded", "for=203.0.113.7"))
+            .to_http_request();
+
+        let headers = upstream_request(&req);
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.7, 2001:db8::1");
+        assert_eq!(
+            headers["forwarded"],
+            "for=203.0.113.7, for=\"[2001:db8::1]\";proto=http;host=\"api.example.com:8443\""
+        );
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "api.example.com:8443");
+    }
+}
@@ -0,0 +1,213 @@
+//! Prometheus metrics of proxied requests, rendered in the text exposition
+//! format
+
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+use super::{resilience::Circuit, ServiceRegistry};
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Counters by name, with their help text
+const COUNTERS: [(&str, &str); 7] = [
+    (
+        "gateway_upstream_requests_total",
+        "Requests sent to upstream instances, by response status or error",
+    ),
+    (
+        "gateway_upstream_retries_total",
+        "Requests sent again after a failed attempt",
+    ),
+    (
+        "gateway_upstream_retry_budget_exhausted_total",
+        "Retries and hedged requests not sent for lack of retry budget",
+    ),
+    (
+        "gateway_upstream_hedged_requests_total",
+        "Second requests sent while the first had not answered",
+    ),
+    (
+        "gateway_circuit_breaker_rejections_total",
+        "Requests answered without calling the service because its circuit was open",
+    ),
+    (
+        "gateway_circuit_breaker_transitions_total",
+        "Times a circuit moved to a state",
+    ),
+    (
+        "gateway_upstream_fallbacks_total",
+        "Requests answered with the service's fallback response",
+    ),
+];
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Shared by all clones of a [`Proxy`](super::Proxy)
+#[derive(Debug, Default)]
+pub struct ProxyMetrics {
+    /// By counter name and rendered labels
+    counters: Mutex<BTreeMap<(&'static str, String), u64>>,
+    /// Time to response headers by service
+    latency: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl ProxyMetrics {
+    pub fn increment(&self, name: &'static str, labels: &[(&str, &str)]) {
+        debug_assert!(COUNTERS.iter().any(|(counter, _)| *counter == name));
+        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+        *counters.entry((name, render_labels(labels))).or_default() += 1;
+    }
+
+    pub fn observe_latency(&self, service: &str, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let mut histograms = self.latency.lock().unwrap_or_else(|e| e.into_inner());
+        let histogram = histograms.entry(service.to_string()).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    /// All metrics, with the circuits and instances of `registry` as gauges
+    pub fn render(&self, registry: &ServiceRegistry) -> String {
+        let mut out = String::new();
+
+        let counters = self
+            .counters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        for (name, help) in COUNTERS {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+            for ((_, labels), value) in counters.iter().filter(|((n, _), _)| *n == name) {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        }
+
+        let name = "gateway_upstream_request_duration_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} Time until upstreams sent their response headers\n# TYPE {} histogram",
+            name, name
+        );
+        let histograms = self
+            .latency
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        for (service, histogram) in &histograms {
+            for (count, bound) in histogram.buckets.iter().zip(BUCKETS) {
+                let labels = render_labels(&[("service", service), ("le", &bound.to_string())]);
+                let _ = writeln!(out, "{}_bucket{} {}", name, labels, count);
+            }
+            let labels = render_labels(&[("service", service), ("le", "+Inf")]);
+            let _ = writeln!(out, "{}_bucket{} {}", name, labels, histogram.count);
+            let labels = render_labels(&[("service", service)]);
+            let _ = writeln!(out, "{}_sum{} {}", name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.count);
+        }
+
+        let services = registry.status();
+        let _ = writeln!(
+            out,
+            "# HELP gateway_circuit_breaker_open Whether the service's circuit is open (1), \
+             half-open (0.5) or closed (0)\n# TYPE gateway_circuit_breaker_open gauge"
+        );
+        for service in &services {
+            let value = match service.circuit {
+                Circuit::Open => "1",
+                Circuit::HalfOpen => "0.5",
+                Circuit::Closed => "0",
+            };
+            let labels = render_labels(&[("service", &service.name)]);
+            let _ = writeln!(out, "gateway_circuit_breaker_open{} {}", labels, value);
+        }
+        let _ = writeln!(
+            out,
+            "# HELP gateway_upstream_instances Instances of the service by state\n\
+             # TYPE gateway_upstream_instances gauge"
+        );
+        for service in &services {
+            for state in ["healthy", "unhealthy", "ejected"] {
+                let count = service
+                    .instances
+                    .iter()
+                    .filter(|instance| instance.state == state)
+                    .count();
+                let labels = render_labels(&[("service", &service.name), ("state", state)]);
+                let _ = writeln!(out, "gateway_upstream_instances{} {}", labels, count);
+            }
+        }
+        out
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::RegistryConfig;
+
+    #[test]
+    fn test_metrics_render_in_the_text_format() {
+        let metrics = ProxyMetrics::default();
+        metrics.increment(
+            "gateway_upstream_requests_total",
+            &[("service", "document"), ("status", "200")],
+        );
+        metrics.increment(
+            "gateway_upstream_requests_total",
+            &[("service", "document"), ("status", "200")],
+        );
+        metrics.increment("gateway_upstream_retries_total", &[("service", "doc\"s")]);
+        metrics.observe_latency("document", Duration::from_millis(30));
+
+        let registry = ServiceRegistry::from_config(RegistryConfig::default())
+            .unwrap()
+            .with_service("document", "http://document:8080");
+        let text = metrics.render(&registry);
+        for line in [
+            "# TYPE gateway_upstream_requests_total counter",
+            "gateway_upstream_requests_total{service=\"document\",status=\"200\"} 2",
+            "gateway_upstream_retries_total{service=\"doc\\\"s\"} 1",
+            "gateway_upstream_request_duration_seconds_bucket{service=\"document\",le=\"0.025\"} 0",
+            "gateway_upstream_request_duration_seconds_bucket{service=\"document\",le=\"0.05\"} 1",
+            "gateway_upstream_request_duration_seconds_count{service=\"document\"} 1",
+            "gateway_circuit_breaker_open{service=\"document\"} 0",
+            "gateway_upstream_instances{service=\"document\",state=\"healthy\"} 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} missing from\n{}",
+                line,
+                text
+            );
+        }
+    }
+}
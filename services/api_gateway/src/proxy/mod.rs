@@ -0,0 +1,558 @@
+//! Forwarding of requests to backend services.
+//!
+//! Requests are sent upstream with every method, their bodies streamed in
+//! both directions, over one connection-pooled client shared by all
+//! workers. WebSocket handshakes become a tunnel relaying bytes both ways
+//! once the upstream agrees; gRPC is served on a listener of its own
+//! ([`grpc`]), since actix-web cannot send trailers. Hop-by-hop headers are
+//! dropped (RFC 7230, section 6.1) and the client is described to the
+//! upstream with `X-Forwarded-*` and `Forwarded` headers. Upstreams that
+//! cannot be reached answer 502, and those that do not answer within the
+//! route's timeout 504.
+//!
+//! Which instance of a service a request goes to is up to the
+//! [`ServiceRegistry`], which the outcome of every request is reported to.
+//! Per service, a circuit breaker answers at once while the service is
+//! failing, idempotent requests are retried within a retry budget and may
+//! be hedged, and a fallback response can stand in for errors.
+
+use actix_web::{
+    http::{
+        header::{self, HeaderValue},
+        Method, StatusCode,
+    },
+    web::{self, Bytes},
+    HttpRequest, HttpResponse,
+};
+use futures_util::StreamExt;
+use std::{
+    collections::HashMap,
+    env, io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+
+pub mod grpc;
+pub mod headers;
+pub mod metrics;
+pub mod registry;
+pub mod resilience;
+pub mod route_table;
+
+pub use grpc::GrpcGateway;
+pub use metrics::ProxyMetrics;
+pub use registry::{
+    RegistryConfig, ServiceConfig, ServiceHandle, ServiceRegistry, ServiceStatus, Strategy,
+    Upstream,
+};
+use resilience::{Circuit, Hedging};
+pub use route_table::{MatchedRoute, RouteTable, RouteTableConfig};
+
+/// Chunks of a request body buffered between the client and the upstream
+const BODY_BUFFER: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct ProxyOptions {
+    /// Longest wait for an upstream's response headers; bodies may stream
+    /// for longer
+    pub timeout: Duration,
+    /// Timeouts of routes that need another one, by route
+    pub route_timeouts: HashMap<String, Duration>,
+    pub connect_timeout: Duration,
+    /// Idle connections kept open per upstream host
+    pub max_idle_per_host: usize,
+    pub idle_timeout: Duration,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            route_timeouts: HashMap::new(),
+            connect_timeout: Duration::from_secs(5),
+            max_idle_per_host: 32,
+            idle_timeout: Duration::from_secs(90),
+        }
+    }
+}
+
+impl ProxyOptions {
+    /// Defaults overridden by `PROXY_TIMEOUT_SECS`,
+    /// `PROXY_CONNECT_TIMEOUT_SECS`, `PROXY_MAX_IDLE_PER_HOST` and
+    /// `PROXY_ROUTE_TIMEOUTS`, e.g. `document=120,auth=5`
+    pub fn from_env() -> Result<Self, ApiError> {
+        let defaults = Self::default();
+        let var = |name| env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+
+        let mut route_timeouts = HashMap::new();
+        for pair in env::var("PROXY_ROUTE_TIMEOUTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let (route, secs) = pair
+                .split_once('=')
+                .and_then(|(route, secs)| Some((route.trim(), secs.trim().parse().ok()?)))
+                .ok_or_else(|| {
+                    ApiError::ValidationError(format!(
+                        "Invalid PROXY_ROUTE_TIMEOUTS entry '{}'",
+                        pair
+                    ))
+                })?;
+            route_timeouts.insert(route.to_string(), Duration::from_secs(secs));
+        }
+
+        Ok(Self {
+            timeout: var("PROXY_TIMEOUT_SECS").map_or(defaults.timeout, Duration::from_secs),
+            route_timeouts,
+            connect_timeout: var("PROXY_CONNECT_TIMEOUT_SECS")
+                .map_or(defaults.connect_timeout, Duration::from_secs),
+            max_idle_per_host: var("PROXY_MAX_IDLE_PER_HOST")
+                .map_or(defaults.max_idle_per_host, |v| v as usize),
+            ..defaults
+        })
+    }
+}
+
+/// Sends requests upstream; cheap to clone, clones share the connection
+/// pool and metrics
+#[derive(Clone)]
+pub struct Proxy {
+    client: reqwest::Client,
+    options: ProxyOptions,
+    metrics: Arc<ProxyMetrics>,
+}
+
+/// Where and for whom a request is forwarded
+struct Target<'a> {
+    tenant: Option<Uuid>,
+    /// Path and query on the upstream, without the leading slash
+    path: &'a str,
+    timeout: Duration,
+    /// `Upgrade` header of a WebSocket handshake
+    upgrade: Option<HeaderValue>,
+}
+
+/// One request to one instance
+struct Attempt {
+    result: Result<(Upstream, reqwest::Response), ApiError>,
+    /// Whether the request went out; those that were not, because the
+    /// circuit is open or no instance is available, are not retried
+    sent: bool,
+}
+
+impl Attempt {
+    fn failed(&self) -> bool {
+        self.result
+            .as_ref()
+            .map_or(true, |(_, response)| is_failure(response.status()))
+    }
+}
+
+impl Proxy {
+    pub fn new(options: ProxyOptions) -> Result<Self, ApiError> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(options.connect_timeout)
+            .pool_max_idle_per_host(options.max_idle_per_host)
+            .pool_idle_timeout(options.idle_timeout)
+            // Redirects are for the client to follow
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| ApiError::InternalError(format!("Failed to build proxy client: {}", e)))?;
+        Ok(Self {
+            client,
+            options,
+            metrics: Arc::default(),
+        })
+    }
+
+    pub fn timeout_for(&self, route: &str) -> Duration {
+        self.options
+            .route_timeouts
+            .get(route)
+            .copied()
+            .unwrap_or(self.options.timeout)
+    }
+
+    pub fn metrics(&self) -> &ProxyMetrics {
+        &self.metrics
+    }
+
+    /// Forwards `req` with its body to `path` on an instance of `service`
+    /// and streams back the response, retrying, hedging and falling back
+    /// as the service is configured to. The instance counts as busy until
+    /// the response body is sent. Each attempt waits up to `timeout`, or
+    /// the service's timeout without one. WebSocket handshakes are
+    /// tunnelled instead, and neither retried nor hedged.
+    pub async fn forward(
+        &self,
+        service: ServiceHandle,
+        tenant: Option<Uuid>,
+        path: &str,
+        req: &HttpRequest,
+        payload: web::Payload,
+        timeout: Option<Duration>,
+    ) -> Result<HttpResponse, ApiError> {
+        let config = service.config();
+        let target = Target {
+            tenant,
+            path,
+            timeout: timeout.unwrap_or_else(|| self.timeout_for(service.name())),
+            upgrade: headers::upgrade(req),
+        };
+        let labels = [("service", service.name())];
+        service.retry_budget().record_request(Instant::now());
+        if target.upgrade.is_some() {
+            let (upstream, response) = self.attempt(&service, &target, req, None).await.result?;
+            if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+                return Ok(respond(upstream, response));
+            }
+            return Ok(tunnel(upstream, response, payload));
+        }
+        // Only requests that can be sent again as they were are retried
+        let mut body = has_body(req).then(|| stream_body(payload));
+        let replayable = body.is_none() && is_idempotent(req.method());
+
+        let mut retries = 0;
+        let attempt = loop {
+            let attempt = match &config.hedging {
+                Some(hedging) if replayable => self.hedged(&service, &target, req, hedging).await,
+                _ => self.attempt(&service, &target, req, body.take()).await,
+            };
+            if !attempt.failed()
+                || !attempt.sent
+                || !replayable
+                || retries >= config.retries.max_retries
+            {
+                break attempt;
+            }
+            if !service
+                .retry_budget()
+                .try_retry(&config.retries, Instant::now())
+            {
+                self.metrics
+                    .increment("gateway_upstream_retry_budget_exhausted_total", &labels);
+                break attempt;
+            }
+            tokio::time::sleep(config.retries.backoff(retries)).await;
+            retries += 1;
+            self.metrics
+                .increment("gateway_upstream_retries_total", &labels);
+        };
+
+        match attempt.result {
+            Ok((upstream, response)) => Ok(respond(upstream, response)),
+            Err(error) => match &config.fallback {
+                Some(fallback) => {
+                    self.metrics
+                        .increment("gateway_upstream_fallbacks_total", &labels);
+                    tracing::warn!(
+                        "Answering with the fallback of {}: {}",
+                        service.name(),
+                        error
+                    );
+                    Ok(fallback.respond(service.name()))
+                }
+                None => Err(error),
+            },
+        }
+    }
+
+    /// Sends a second request when the first has not answered within the
+    /// hedging delay, and takes the first answer that is not a failure
+    async fn hedged(
+        &self,
+        service: &ServiceHandle,
+        target: &Target<'_>,
+        req: &HttpRequest,
+        hedging: &Hedging,
+    ) -> Attempt {
+        let first = self.attempt(service, target, req, None);
+        tokio::pin!(first);
+        tokio::select! {
+            attempt = &mut first => return attempt,
+            _ = tokio::time::sleep(Duration::from_millis(hedging.delay_ms)) => {}
+        }
+
+        let labels = [("service", service.name())];
+        if !service
+            .retry_budget()
+            .try_retry(&service.config().retries, Instant::now())
+        {
+            self.metrics
+                .increment("gateway_upstream_retry_budget_exhausted_total", &labels);
+            return first.await;
+        }
+        self.metrics
+            .increment("gateway_upstream_hedged_requests_total", &labels);
+        let second = self.attempt(service, target, req, None);
+        tokio::pin!(second);
+
+        // The other request is dropped, and with it its connection
+        tokio::select! {
+            attempt = &mut first => if attempt.failed() { second.await } else { attempt },
+            attempt = &mut second => if attempt.failed() { first.await } else { attempt },
+        }
+    }
+
+    async fn attempt(
+        &self,
+        service: &ServiceHandle,
+        target: &Target<'_>,
+        req: &HttpRequest,
+        body: Option<reqwest::Body>,
+    ) -> Attempt {
+        let name = service.name();
+        let breaker = &service.config().circuit_breaker;
+        if !service.breaker().try_acquire(breaker, Instant::now()) {
+            self.metrics.increment(
+                "gateway_circuit_breaker_rejections_total",
+                &[("service", name)],
+            );
+            return Attempt {
+                result: Err(ApiError::ServiceUnavailable(format!(
+                    "Service {} is failing, try again later",
+                    name
+                ))),
+                sent: false,
+            };
+        }
+        let upstream = match service.pick(target.tenant) {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                self.record(service, false);
+                return Attempt {
+                    result: Err(e),
+                    sent: false,
+                };
+            }
+        };
+
+        let started = Instant::now();
+        let result = self.send(&upstream, target, req, body).await;
+        let status = match &result {
+            Ok(response) => {
+                self.metrics.observe_latency(name, started.elapsed());
+                response.status().as_str().to_string()
+            }
+            Err(ApiError::GatewayTimeout(_)) => "timeout".to_string(),
+            Err(_) => "error".to_string(),
+        };
+        self.metrics.increment(
+            "gateway_upstream_requests_total",
+            &[("service", name), ("status", &status)],
+        );
+
+        // Upstreams that are overloaded or cannot reach their own
+        // dependencies count as failing, like those that cannot be reached
+        let success = result
+            .as_ref()
+            .is_ok_and(|response| !is_failure(response.status()));
+        upstream.report(success);
+        self.record(service, success);
+        Attempt {
+            result: result.map(|response| (upstream, response)),
+            sent: true,
+        }
+    }
+
+    fn record(&self, service: &ServiceHandle, success: bool) {
+        let Some(circuit) =
+            service
+                .breaker()
+                .record(&service.config().circuit_breaker, success, Instant::now())
+        else {
+            return;
+        };
+        self.metrics.increment(
+            "gateway_circuit_breaker_transitions_total",
+            &[("service", service.name()), ("state", circuit.as_str())],
+        );
+        match circuit {
+            Circuit::Open => tracing::warn!("Opened the circuit of {}", service.name()),
+            _ => tracing::info!("The circuit of {} is {}", service.name(), circuit.as_str()),
+        }
+    }
+
+    async fn send(
+        &self,
+        upstream: &Upstream,
+        target: &Target<'_>,
+        req: &HttpRequest,
+        body: Option<reqwest::Body>,
+    ) -> Result<reqwest::Response, ApiError> {
+        let route = upstream.service();
+        let url = format!("{}/{}", upstream.url(), target.path);
+        let mut request = self
+            .client
+            .request(req.method().clone(), &url)
+            .headers(headers::upstream_request(req));
+        if let Some(protocol) = &target.upgrade {
+            request = request
+                .header(header::CONNECTION, "upgrade")
+                .header(header::UPGRADE, protocol.clone());
+        }
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+
+        let timeout = target.timeout;
+        match tokio::time::timeout(timeout, request.send()).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) if e.is_timeout() => {
+                tracing::warn!("Timed out connecting to {} for {}: {}", route, url, e);
+                Err(ApiError::GatewayTimeout(format!(
+                    "Service {} did not respond",
+                    route
+                )))
+            }
+            Ok(Err(e)) => {
+                tracing::warn!("Failed to reach {} for {}: {}", route, url, e);
+                Err(ApiError::BadGateway(format!(
+                    "Service {} is unavailable",
+                    route
+                )))
+            }
+            Err(_) => {
+                tracing::warn!("{} did not respond within {:?} for {}", route, timeout, url);
+                Err(ApiError::GatewayTimeout(format!(
+                    "Service {} did not respond within {:?}",
+                    route, timeout
+                )))
+            }
+        }
+    }
+}
+
+/// Streams the upstream's response to the client; `upstream` counts as
+/// busy until it is sent
+fn respond(upstream: Upstream, response: reqwest::Response) -> HttpResponse {
+    let mut builder = HttpResponse::build(response.status());
+    for (name, value) in headers::client_response(response.headers()) {
+        builder.append_header((name.clone(), value.clone()));
+    }
+    if let Some(length) = content_length(response.headers().get(header::CONTENT_LENGTH)) {
+        builder.no_chunking(length);
+    }
+    let body = response.bytes_stream().map(move |chunk| {
+        let _busy = &upstream;
+        chunk
+    });
+    builder.streaming(body)
+}
+
+/// Switches protocols with the client as the upstream did and relays bytes
+/// between the two until either closes; `upstream` counts as busy until
+/// then
+fn tunnel(
+    upstream: Upstream,
+    response: reqwest::Response,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    let mut builder = HttpResponse::build(StatusCode::SWITCHING_PROTOCOLS);
+    for (name, value) in headers::client_response(response.headers()) {
+        builder.append_header((name.clone(), value.clone()));
+    }
+    if let Some(protocol) = response
+        .headers()
+        .get(header::UPGRADE)
+        .and_then(|protocol| protocol.to_str().ok())
+    {
+        builder.upgrade(protocol);
+    }
+
+    let (tx, rx) = mpsc::channel::<io::Result<Bytes>>(BODY_BUFFER);
+    // The payload is bound to the worker it arrived on
+    actix_web::rt::spawn(async move {
+        let upgraded = match response.upgrade().await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to switch protocols with {}: {}",
+                    upstream.service(),
+                    e
+                );
+                return;
+            }
+        };
+        let (reader, mut writer) = tokio::io::split(upgraded);
+        let to_upstream = async {
+            while let Some(Ok(chunk)) = payload.next().await {
+                if writer.write_all(&chunk).await.is_err() {
+                    return;
+                }
+            }
+            let _ = writer.shutdown().await;
+        };
+        let to_client = async {
+            let mut reader = ReaderStream::new(reader);
+            while let Some(chunk) = reader.next().await {
+                if tx.send(chunk).await.is_err() {
+                    return;
+                }
+            }
+        };
+        // Either side closing ends the tunnel
+        tokio::select! {
+            _ = to_upstream => {}
+            _ = to_client => {}
+        }
+        drop(upstream);
+    });
+    builder.streaming(futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
+}
+
+/// Answers of upstreams that are overloaded or cannot reach their own
+/// dependencies
+fn is_failure(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 502..=504)
+}
+
+/// Methods that may be sent twice (RFC 7231, section 4.2.2)
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Whether the request announces a body; others are sent without one
+/// rather than with an empty chunked body
+fn has_body(req: &HttpRequest) -> bool {
+    req.headers().contains_key(header::TRANSFER_ENCODING)
+        || content_length(req.headers().get(header::CONTENT_LENGTH))
+            .is_some_and(|length| length > 0)
+}
+
+fn content_length(value: Option<&header::HeaderValue>) -> Option<u64> {
+    value
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// The request body as a stream reqwest can send from another thread;
+/// the payload itself is bound to the worker it arrived on
+fn stream_body(mut payload: web::Payload) -> reqwest::Body {
+    let (tx, rx) = mpsc::channel::<io::Result<Bytes>>(BODY_BUFFER);
+    actix_web::rt::spawn(async move {
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| io::Error::other(e.to_string()));
+            let failed = chunk.is_err();
+            // The upstream request was dropped
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    reqwest::Body::wrap_stream(futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
+}
@@ -0,0 +1,871 @@
+//! Backend services and their instances.
+//!
+//! The registry is loaded from a JSON file (`UPSTREAMS_CONFIG`), which is
+//! watched for changes, and can be changed at runtime through the admin
+//! API. Each service has one or more instances, picked per request by the
+//! service's load-balancing strategy among those that are available:
+//! passing their active health checks, if any, and not ejected by outlier
+//! detection after failing requests in a row.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+use super::resilience::{
+    self, Circuit, CircuitBreaker, CircuitBreakerConfig, Fallback, Hedging, RetryBudget,
+    RetryPolicy,
+};
+use crate::error::ApiError;
+
+/// Points per instance on the tenant hash ring
+const RING_POINTS: usize = 100;
+/// Ejections in a row lengthen the next one up to this many times
+const MAX_EJECTION_MULTIPLIER: u32 = 10;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RegistryConfig {
+    pub services: BTreeMap<String, ServiceConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceConfig {
+    /// Base URLs, e.g. `http://document-1:8080`
+    pub instances: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// Without one, instances are only taken out by outlier detection
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub outlier_detection: OutlierDetection,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub retries: RetryPolicy,
+    /// Off without one
+    #[serde(default)]
+    pub hedging: Option<Hedging>,
+    /// Errors are answered as such without one
+    #[serde(default)]
+    pub fallback: Option<Fallback>,
+}
+
+impl ServiceConfig {
+    pub fn new(instances: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            instances: instances.into_iter().map(Into::into).collect(),
+            strategy: Strategy::default(),
+            health_check: None,
+            outlier_detection: OutlierDetection::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            retries: RetryPolicy::default(),
+            hedging: None,
+            fallback: None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let invalid = |message: String| Err(ApiError::ValidationError(message));
+        if self.instances.is_empty() {
+            return invalid("A service needs at least one instance".to_string());
+        }
+        for url in &self.instances {
+            match reqwest::Url::parse(url) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+                _ => return invalid(format!("Invalid instance URL '{}'", url)),
+            }
+        }
+        if let Some(check) = &self.health_check {
+            if !check.path.starts_with('/')
+                || check.interval_secs == 0
+                || check.timeout_secs == 0
+                || check.healthy_threshold == 0
+                || check.unhealthy_threshold == 0
+            {
+                return invalid("Health check needs a path, intervals and thresholds".to_string());
+            }
+        }
+        let outliers = &self.outlier_detection;
+        if outliers.consecutive_failures == 0 || outliers.max_ejection_percent > 100 {
+            return invalid(
+                "Outlier detection needs a failure count and a percentage up to 100".to_string(),
+            );
+        }
+        resilience::validate(
+            &self.circuit_breaker,
+            &self.retries,
+            self.hedging.as_ref(),
+            self.fallback.as_ref(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    /// The instance with the fewest requests in flight
+    LeastConnections,
+    /// Requests of a tenant go to the same instance while it is available,
+    /// and to the next one on a hash ring while not; others round-robin
+    TenantHash,
+}
+
+/// `GET <instance><path>`, healthy on a 2xx answer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+    /// Passed checks in a row that bring an instance back
+    pub healthy_threshold: u32,
+    /// Failed checks in a row that take an instance out
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: "/health".to_string(),
+            interval_secs: 10,
+            timeout_secs: 2,
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+}
+
+/// Ejects instances whose requests fail in a row, i.e. cannot be sent,
+/// time out or are answered with 502, 503 or 504
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutlierDetection {
+    pub consecutive_failures: u32,
+    /// Doubled, tripled, ... for ejections without a success in between
+    pub ejection_secs: u64,
+    /// Instances of the service that may be ejected at once
+    pub max_ejection_percent: u32,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 5,
+            ejection_secs: 30,
+            max_ejection_percent: 50,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct InstanceState {
+    unhealthy: bool,
+    /// Active checks in a row with the same outcome
+    check_streak: u32,
+    last_checked_at: Option<DateTime<Utc>>,
+    last_check_error: Option<String>,
+    consecutive_failures: u32,
+    /// Ejections without a success in between
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+struct Instance {
+    url: String,
+    active: AtomicUsize,
+    state: Mutex<InstanceState>,
+}
+
+impl Instance {
+    fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            active: AtomicUsize::new(0),
+            state: Mutex::new(InstanceState::default()),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, InstanceState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn is_ejected(state: &InstanceState, now: Instant) -> bool {
+        state.ejected_until.is_some_and(|until| until > now)
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        let state = self.state();
+        !state.unhealthy && !Self::is_ejected(&state, now)
+    }
+}
+
+struct Service {
+    config: ServiceConfig,
+    instances: Vec<Arc<Instance>>,
+    /// Sorted points of the tenant hash ring, with their instance
+    ring: Vec<(u64, usize)>,
+    next: AtomicUsize,
+    next_check: Mutex<Instant>,
+    breaker: Arc<CircuitBreaker>,
+    retry_budget: Arc<RetryBudget>,
+}
+
+impl Service {
+    /// Keeps the circuit and retry budget of `previous`, and the state of
+    /// instances it has too
+    fn new(config: ServiceConfig, previous: Option<&Service>) -> Self {
+        let instances: Vec<_> = config
+            .instances
+            .iter()
+            .map(|url| {
+                let url = url.trim_end_matches('/');
+                previous
+                    .and_then(|service| service.instances.iter().find(|i| i.url == url))
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(Instance::new(url)))
+            })
+            .collect();
+
+        let mut ring: Vec<_> = instances
+            .iter()
+            .enumerate()
+            .flat_map(|(index, instance)| {
+                (0..RING_POINTS).map(move |point| {
+                    (
+                        hash(format!("{}#{}", instance.url, point).as_bytes()),
+                        index,
+                    )
+                })
+            })
+            .collect();
+        ring.sort_unstable();
+
+        Self {
+            config,
+            instances,
+            ring,
+            next: AtomicUsize::new(0),
+            next_check: Mutex::new(Instant::now()),
+            breaker: previous.map_or_else(Default::default, |service| service.breaker.clone()),
+            retry_budget: previous
+                .map_or_else(Default::default, |service| service.retry_budget.clone()),
+        }
+    }
+
+    fn pick(&self, tenant: Option<Uuid>, now: Instant) -> Option<usize> {
+        let available: Vec<usize> = (0..self.instances.len())
+            .filter(|&index| self.instances[index].is_available(now))
+            .collect();
+        if available.is_empty() {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+
+        match (self.config.strategy, tenant) {
+            (Strategy::TenantHash, Some(tenant)) => {
+                let key = hash(tenant.as_bytes());
+                let first = self.ring.partition_point(|(point, _)| *point < key);
+                self.ring[first..]
+                    .iter()
+                    .chain(&self.ring[..first])
+                    .map(|(_, index)| *index)
+                    .find(|index| available.contains(index))
+            }
+            (Strategy::LeastConnections, _) => (0..available.len())
+                .map(|offset| available[(start + offset) % available.len()])
+                .min_by_key(|&index| self.instances[index].active.load(Ordering::Relaxed)),
+            _ => Some(available[start % available.len()]),
+        }
+    }
+
+    /// Ejects `instance` if it failed too often in a row and the service
+    /// can spare it
+    fn record_failure(&self, instance: &Instance, now: Instant) {
+        let outliers = &self.config.outlier_detection;
+        let ejected = self
+            .instances
+            .iter()
+            .filter(|i| !std::ptr::eq(i.as_ref(), instance))
+            .filter(|i| Instance::is_ejected(&i.state(), now))
+            .count();
+
+        let mut state = instance.state();
+        state.consecutive_failures += 1;
+        if state.consecutive_failures < outliers.consecutive_failures
+            || Instance::is_ejected(&state, now)
+            || (ejected + 1) * 100 > self.instances.len() * outliers.max_ejection_percent as usize
+        {
+            return;
+        }
+
+        state.ejections += 1;
+        let duration = Duration::from_secs(outliers.ejection_secs)
+            * state.ejections.min(MAX_EJECTION_MULTIPLIER);
+        state.ejected_until = Some(now + duration);
+        state.consecutive_failures = 0;
+        tracing::warn!(
+            "Ejected {} for {:?} after {} failed requests in a row",
+            instance.url,
+            duration,
+            outliers.consecutive_failures
+        );
+    }
+
+    fn record_success(instance: &Instance) {
+        let mut state = instance.state();
+        state.consecutive_failures = 0;
+        state.ejections = 0;
+    }
+}
+
+/// FNV-1a, stable across builds so replicas agree on the ring
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// An instance picked for a request; counts as in flight until dropped
+pub struct Upstream {
+    service_name: String,
+    service: Arc<Service>,
+    instance: Arc<Instance>,
+}
+
+impl Upstream {
+    pub fn service(&self) -> &str {
+        &self.service_name
+    }
+
+    /// Base URL of the instance, without a trailing slash
+    pub fn url(&self) -> &str {
+        &self.instance.url
+    }
+
+    /// Feeds outlier detection with the outcome of the request
+    pub fn report(&self, success: bool) {
+        if success {
+            Service::record_success(&self.instance);
+        } else {
+            self.service.record_failure(&self.instance, Instant::now());
+        }
+    }
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        self.instance.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A service as configured when a request arrived
+#[derive(Clone)]
+pub struct ServiceHandle {
+    name: String,
+    service: Arc<Service>,
+}
+
+impl ServiceHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &ServiceConfig {
+        &self.service.config
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.service.breaker
+    }
+
+    pub fn retry_budget(&self) -> &RetryBudget {
+        &self.service.retry_budget
+    }
+
+    /// An available instance for a request of `tenant`
+    pub fn pick(&self, tenant: Option<Uuid>) -> Result<Upstream, ApiError> {
+        let index = self.service.pick(tenant, Instant::now()).ok_or_else(|| {
+            ApiError::ServiceUnavailable(format!("Service {} has no healthy instances", self.name))
+        })?;
+
+        let instance = self.service.instances[index].clone();
+        instance.active.fetch_add(1, Ordering::Relaxed);
+        Ok(Upstream {
+            service_name: self.name.clone(),
+            service: self.service.clone(),
+            instance,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceStatus {
+    pub name: String,
+    pub circuit: Circuit,
+    pub strategy: Strategy,
+    pub health_check: Option<HealthCheck>,
+    pub outlier_detection: OutlierDetection,
+    /// Instances requests can go to
+    pub available: usize,
+    pub instances: Vec<InstanceStatus>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InstanceStatus {
+    pub url: String,
+    /// `healthy`, `unhealthy` or `ejected`
+    pub state: &'static str,
+    pub active_requests: usize,
+    pub consecutive_failures: u32,
+    pub ejected_until: Option<DateTime<Utc>>,
+    pub last_checked_at: Option<DateTime<Utc>>,
+    pub last_check_error: Option<String>,
+}
+
+/// The services requests can be proxied to; shared by all workers
+pub struct ServiceRegistry {
+    services: RwLock<HashMap<String, Arc<Service>>>,
+    /// File the services were loaded from, watched for changes
+    source: Option<PathBuf>,
+    watch_interval: Duration,
+    checker: reqwest::Client,
+}
+
+impl ServiceRegistry {
+    /// The backend services of the default deployment, one instance each
+    pub fn new() -> Self {
+        let services = ["auth", "attestation", "document", "resource_management"]
+            .into_iter()
+            .map(|name| {
+                let config = ServiceConfig::new([format!("http://{}:8080", name)]);
+                (name.to_string(), config)
+            })
+            .collect();
+        Self::from_config(RegistryConfig { services }).expect("default services are valid")
+    }
+
+    pub fn from_config(config: RegistryConfig) -> Result<Self, ApiError> {
+        let checker = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| {
+                ApiError::InternalError(format!("Failed to build health check client: {}", e))
+            })?;
+        let registry = Self {
+            services: RwLock::new(HashMap::new()),
+            source: None,
+            watch_interval: Duration::from_secs(5),
+            checker,
+        };
+        registry.load(config)?;
+        Ok(registry)
+    }
+
+    /// Loads `UPSTREAMS_CONFIG`, watched every `UPSTREAMS_WATCH_INTERVAL_SECS`
+    /// once spawned; the default services without it
+    pub fn from_env() -> Result<Self, ApiError> {
+        let Ok(path) = env::var("UPSTREAMS_CONFIG") else {
+            return Ok(Self::new());
+        };
+        let registry = Self::from_file(path)?;
+        Ok(
+            match env::var("UPSTREAMS_WATCH_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+            {
+                Some(secs) => registry.watch_every(Duration::from_secs(secs)),
+                None => registry,
+            },
+        )
+    }
+
+    /// Loads the services of a JSON file, which is watched once spawned
+    pub fn from_file(path: impl Into<PathBuf>) -> Result<Self, ApiError> {
+        let path = path.into();
+        let mut registry = Self::from_config(read_config(&path)?)?;
+        registry.source = Some(path);
+        Ok(registry)
+    }
+
+    /// How often the file the services were loaded from is read again
+    pub fn watch_every(mut self, interval: Duration) -> Self {
+        self.watch_interval = interval;
+        self
+    }
+
+    /// Adds or replaces `service` with a single instance
+    pub fn with_service(self, service: &str, url: &str) -> Self {
+        self.set_service(service, ServiceConfig::new([url]))
+            .expect("invalid service URL");
+        self
+    }
+
+    /// Replaces all services; instances that stay keep their health
+    pub fn load(&self, config: RegistryConfig) -> Result<(), ApiError> {
+        for (name, service) in &config.services {
+            service
+                .validate()
+                .map_err(|e| ApiError::ValidationError(format!("Service {}: {}", name, e)))?;
+        }
+        let mut services = self.services.write().unwrap_or_else(|e| e.into_inner());
+        *services = config
+            .services
+            .into_iter()
+            .map(|(name, config)| {
+                let service = Service::new(config, services.get(&name).map(Arc::as_ref));
+                (name, Arc::new(service))
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Adds or replaces one service
+    pub fn set_service(&self, name: &str, config: ServiceConfig) -> Result<(), ApiError> {
+        config.validate()?;
+        let mut services = self.services.write().unwrap_or_else(|e| e.into_inner());
+        let service = Service::new(config, services.get(name).map(Arc::as_ref));
+        services.insert(name.to_string(), Arc::new(service));
+        Ok(())
+    }
+
+    pub fn remove_service(&self, name: &str) -> bool {
+        self.services
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(name)
+            .is_some()
+    }
+
+    fn service(&self, name: &str) -> Option<Arc<Service>> {
+        self.services
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(name)
+            .cloned()
+    }
+
+    pub fn get(&self, name: &str) -> Result<ServiceHandle, ApiError> {
+        let service = self
+            .service(name)
+            .ok_or_else(|| ApiError::NotFoundError(format!("Unknown service '{}'", name)))?;
+        Ok(ServiceHandle {
+            name: name.to_string(),
+            service,
+        })
+    }
+
+    /// An available instance of `service` for a request of `tenant`
+    pub fn pick(&self, service: &str, tenant: Option<Uuid>) -> Result<Upstream, ApiError> {
+        self.get(service)?.pick(tenant)
+    }
+
+    /// Services by name, with the state of their instances
+    pub fn status(&self) -> Vec<ServiceStatus> {
+        let services = self.services.read().unwrap_or_else(|e| e.into_inner());
+        let mut status: Vec<_> = services
+            .iter()
+            .map(|(name, service)| service_status(name, service))
+            .collect();
+        status.sort_by(|a, b| a.name.cmp(&b.name));
+        status
+    }
+
+    pub fn service_status(&self, name: &str) -> Option<ServiceStatus> {
+        self.service(name)
+            .map(|service| service_status(name, &service))
+    }
+
+    /// Runs the health checks of every service that has them
+    pub async fn check_health(&self) {
+        self.check(None).await;
+    }
+
+    /// Checks services whose interval is up, or all without `now`
+    async fn check(&self, now: Option<Instant>) {
+        let due: Vec<_> = {
+            let services = self.services.read().unwrap_or_else(|e| e.into_inner());
+            services
+                .values()
+                .filter_map(|service| {
+                    let check = service.config.health_check.clone()?;
+                    let mut next_check =
+                        service.next_check.lock().unwrap_or_else(|e| e.into_inner());
+                    if let Some(now) = now {
+                        if *next_check > now {
+                            return None;
+                        }
+                        *next_check = now + Duration::from_secs(check.interval_secs);
+                    }
+                    Some((service.clone(), check))
+                })
+                .collect()
+        };
+
+        let checks = due.iter().flat_map(|(service, check)| {
+            service
+                .instances
+                .iter()
+                .map(move |instance| self.check_instance(instance, check))
+        });
+        futures_util::future::join_all(checks).await;
+    }
+
+    async fn check_instance(&self, instance: &Instance, check: &HealthCheck) {
+        let result = self
+            .checker
+            .get(format!("{}{}", instance.url, check.path))
+            .timeout(Duration::from_secs(check.timeout_secs))
+            .send()
+            .await;
+        let error = match result {
+            Ok(response) if response.status().is_success() => None,
+            Ok(response) => Some(format!("Answered {}", response.status())),
+            Err(e) => Some(e.to_string()),
+        };
+
+        let mut state = instance.state();
+        state.last_checked_at = Some(Utc::now());
+        let passed = error.is_none();
+        // The streak counts checks that disagree with the current state
+        if passed == state.unhealthy {
+            state.check_streak += 1;
+        } else {
+            state.check_streak = 0;
+        }
+        if state.unhealthy && state.check_streak >= check.healthy_threshold {
+            state.unhealthy = false;
+            state.check_streak = 0;
+            tracing::info!("{} passes its health checks again", instance.url);
+        } else if !state.unhealthy && state.check_streak >= check.unhealthy_threshold {
+            state.unhealthy = true;
+            state.check_streak = 0;
+            tracing::warn!(
+                "{} failed {} health checks in a row: {}",
+                instance.url,
+                check.unhealthy_threshold,
+                error.as_deref().unwrap_or_default()
+            );
+        }
+        state.last_check_error = error;
+    }
+
+    /// Runs health checks, and reloads the source file when it changes,
+    /// until the registry is dropped
+    pub fn spawn(self: &Arc<Self>) {
+        let registry = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                ticks.tick().await;
+                let Some(registry) = registry.upgrade() else {
+                    break;
+                };
+                registry.check(Some(Instant::now())).await;
+            }
+        });
+
+        if let Some(path) = self.source.clone() {
+            tokio::spawn(watch(Arc::downgrade(self), path, self.watch_interval));
+        }
+    }
+}
+
+impl Default for ServiceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn service_status(name: &str, service: &Service) -> ServiceStatus {
+    let now = Instant::now();
+    let instances: Vec<_> = service
+        .instances
+        .iter()
+        .map(|instance| {
+            let state = instance.state();
+            let ejected = Instance::is_ejected(&state, now);
+            InstanceStatus {
+                url: instance.url.clone(),
+                state: if state.unhealthy {
+                    "unhealthy"
+                } else if ejected {
+                    "ejected"
+                } else {
+                    "healthy"
+                },
+                active_requests: instance.active.load(Ordering::Relaxed),
+                consecutive_failures: state.consecutive_failures,
+                ejected_until: state.ejected_until.filter(|_| ejected).map(|until| {
+                    Utc::now()
+                        + chrono::Duration::from_std(until - now).unwrap_or(chrono::Duration::MAX)
+                }),
+                last_checked_at: state.last_checked_at,
+                last_check_error: state.last_check_error.clone(),
+            }
+        })
+        .collect();
+
+    ServiceStatus {
+        name: name.to_string(),
+        circuit: service.breaker.circuit(),
+        strategy: service.config.strategy,
+        health_check: service.config.health_check.clone(),
+        outlier_detection: service.config.outlier_detection.clone(),
+        available: instances.iter().filter(|i| i.state == "healthy").count(),
+        instances,
+    }
+}
+
+fn read_config(path: &PathBuf) -> Result<RegistryConfig, ApiError> {
+    let json = fs::read_to_string(path).map_err(|e| {
+        ApiError::InternalError(format!("Failed to read {}: {}", path.display(), e))
+    })?;
+    serde_json::from_str(&json)
+        .map_err(|e| ApiError::ValidationError(format!("Invalid {}: {}", path.display(), e)))
+}
+
+/// Reloads `path` when its contents change; invalid contents are logged
+/// and the services kept
+async fn watch(registry: Weak<ServiceRegistry>, path: PathBuf, interval: Duration) {
+    let mut loaded = read_config(&path).ok();
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(registry) = registry.upgrade() else {
+            break;
+        };
+        match read_config(&path) {
+            Ok(config) if loaded.as_ref() == Some(&config) => {}
+            Ok(config) => match registry.load(config.clone()) {
+                Ok(()) => {
+                    tracing::info!("Reloaded upstreams from {}", path.display());
+                    loaded = Some(config);
+                }
+                Err(e) => tracing::error!("Keeping the current upstreams: {}", e),
+            },
+            Err(e) => tracing::error!("Keeping the current upstreams: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn registry(strategy: Strategy, instances: usize) -> ServiceRegistry {
+        let urls = (0..instances).map(|i| format!("http://doc-{}:8080", i));
+        let config = ServiceConfig {
+            strategy,
+            ..ServiceConfig::new(urls)
+        };
+        let registry = ServiceRegistry::from_config(RegistryConfig::default()).unwrap();
+        registry.set_service("document", config).unwrap();
+        registry
+    }
+
+    fn pick(registry: &ServiceRegistry, tenant: Option<Uuid>) -> String {
+        registry.pick("document", tenant).unwrap().url().to_string()
+    }
+
+    #[test]
+    fn test_strategies_spread_requests() {
+        let registry = registry(Strategy::RoundRobin, 3);
+        let picked: Vec<_> = (0..6).map(|_| pick(&registry, None)).collect();
+        assert_eq!(picked[..3], picked[3..]);
+        assert_eq!(picked[..3].iter().collect::<HashSet<_>>().len(), 3);
+
+        // Instances with requests in flight are avoided
+        let held: Vec<_> = (0..2)
+            .map(|_| registry.pick("document", None).unwrap())
+            .collect();
+        let config = registry.service_status("document").unwrap();
+        registry
+            .set_service(
+                "document",
+                ServiceConfig {
+                    strategy: Strategy::LeastConnections,
+                    ..ServiceConfig::new(config.instances.iter().map(|i| i.url.clone()))
+                },
+            )
+            .unwrap();
+        let idle = pick(&registry, None);
+        assert!(held.iter().all(|upstream| upstream.url() != idle));
+
+        let registry = self::registry(Strategy::TenantHash, 3);
+        let tenant = Uuid::new_v4();
+        let first = pick(&registry, Some(tenant));
+        assert!((0..10).all(|_| pick(&registry, Some(tenant)) == first));
+    }
+
+    #[test]
+    fn test_failing_instances_are_ejected_and_tenants_move_on() {
+        let registry = registry(Strategy::TenantHash, 4);
+        let tenant = Uuid::new_v4();
+        let home = pick(&registry, Some(tenant));
+
+        for _ in 0..OutlierDetection::default().consecutive_failures {
+            registry
+                .pick("document", Some(tenant))
+                .unwrap()
+                .report(false);
+        }
+        let status = registry.service_status("document").unwrap();
+        assert_eq!(status.available, 3);
+        let ejected = status
+            .instances
+            .iter()
+            .find(|i| i.state == "ejected")
+            .unwrap();
+        assert_eq!(ejected.url, home);
+        assert!(ejected.ejected_until.is_some());
+
+        let moved = pick(&registry, Some(tenant));
+        assert_ne!(moved, home);
+        assert!((0..10).all(|_| pick(&registry, Some(tenant)) == moved));
+    }
+
+    #[test]
+    fn test_the_last_instances_are_not_ejected() {
+        let registry = registry(Strategy::RoundRobin, 1);
+        for _ in 0..20 {
+            registry.pick("document", None).unwrap().report(false);
+        }
+        assert_eq!(registry.service_status("document").unwrap().available, 1);
+        assert!(registry.pick("document", None).is_ok());
+    }
+
+    #[test]
+    fn test_reloading_keeps_the_state_of_remaining_instances() {
+        let registry = registry(Strategy::RoundRobin, 2);
+        let held = registry.pick("document", None).unwrap();
+        let url = held.url().to_string();
+
+        let mut config = RegistryConfig::default();
+        config.services.insert(
+            "document".to_string(),
+            ServiceConfig::new([url.clone(), "http://doc-9:8080".to_string()]),
+        );
+        registry.load(config).unwrap();
+
+        let status = registry.service_status("document").unwrap();
+        let kept = status.instances.iter().find(|i| i.url == url).unwrap();
+        assert_eq!(kept.active_requests, 1);
+        assert!(matches!(
+            registry.pick("search", None),
+            Err(ApiError::NotFoundError(_))
+        ));
+        assert!(ServiceConfig::new(["ftp://doc"]).validate().is_err());
+    }
+}
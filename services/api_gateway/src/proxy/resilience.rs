@@ -0,0 +1,390 @@
+//! Protection of clients from failing upstreams, and of upstreams from
+//! their clients: circuit breakers, retry budgets, hedging and fallbacks
+
+use actix_web::{http::StatusCode, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::error::ApiError;
+
+/// Period retry budgets are counted over
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Opens when too many requests to a service fail, so they are answered
+/// at once instead of waiting on it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Share of failed requests in a window that opens the circuit
+    pub failure_rate: f64,
+    /// Requests in a window before the failure rate counts
+    pub minimum_requests: u32,
+    pub window_secs: u64,
+    /// How long the circuit stays open before probing the service
+    pub open_secs: u64,
+    /// Probes let through while half-open; all must succeed to close
+    pub half_open_requests: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_rate: 0.5,
+            minimum_requests: 20,
+            window_secs: 10,
+            open_secs: 30,
+            half_open_requests: 3,
+        }
+    }
+}
+
+/// Retries of idempotent requests without a body, on another instance
+/// when there is one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Before the first retry, doubled for each further one
+    pub backoff_ms: u64,
+    /// Retries, and hedged requests, allowed per request to the service
+    pub budget_ratio: f64,
+    /// Retries allowed per second regardless of traffic
+    pub min_retries_per_sec: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            backoff_ms: 25,
+            budget_ratio: 0.2,
+            min_retries_per_sec: 10,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn backoff(&self, retry: u32) -> Duration {
+        Duration::from_millis(self.backoff_ms.saturating_mul(1 << retry.min(16)))
+    }
+}
+
+/// Sends a second request to another instance when the first has not
+/// answered in time, and uses whichever answers first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hedging {
+    pub delay_ms: u64,
+}
+
+/// Answered instead of an error when the service cannot be reached or its
+/// circuit is open
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fallback {
+    #[serde(default = "Fallback::default_status")]
+    pub status: u16,
+    #[serde(default = "Fallback::default_content_type")]
+    pub content_type: String,
+    #[serde(default)]
+    pub body: String,
+}
+
+impl Fallback {
+    fn default_status() -> u16 {
+        503
+    }
+
+    fn default_content_type() -> String {
+        "application/json".to_string()
+    }
+
+    pub fn respond(&self, service: &str) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+        HttpResponse::build(status)
+            .content_type(self.content_type.as_str())
+            .insert_header(("x-gateway-fallback", service))
+            .body(self.body.clone())
+    }
+}
+
+pub(super) fn validate(
+    breaker: &CircuitBreakerConfig,
+    retries: &RetryPolicy,
+    hedging: Option<&Hedging>,
+    fallback: Option<&Fallback>,
+) -> Result<(), ApiError> {
+    let invalid = |message: &str| Err(ApiError::ValidationError(message.to_string()));
+    if breaker.failure_rate.is_nan()
+        || breaker.failure_rate <= 0.0
+        || breaker.failure_rate > 1.0
+        || breaker.minimum_requests == 0
+        || breaker.window_secs == 0
+        || breaker.half_open_requests == 0
+    {
+        return invalid("Circuit breaker needs a failure rate up to 1, a window and probes");
+    }
+    if retries.budget_ratio.is_nan() || retries.budget_ratio < 0.0 {
+        return invalid("Retry budget ratio must not be negative");
+    }
+    if hedging.is_some_and(|hedging| hedging.delay_ms == 0) {
+        return invalid("Hedging needs a delay");
+    }
+    if fallback.is_some_and(|fallback| StatusCode::from_u16(fallback.status).is_err()) {
+        return invalid("Fallback needs a valid status");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Circuit {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl Circuit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    circuit: Circuit,
+    window_started: Instant,
+    requests: u32,
+    failures: u32,
+    opened_at: Instant,
+    /// Probes let through since half-opening
+    probes: u32,
+    probe_successes: u32,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            state: Mutex::new(BreakerState {
+                circuit: Circuit::Closed,
+                window_started: now,
+                requests: 0,
+                failures: 0,
+                opened_at: now,
+                probes: 0,
+                probe_successes: 0,
+            }),
+        }
+    }
+}
+
+impl CircuitBreaker {
+    fn state(&self) -> std::sync::MutexGuard<'_, BreakerState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn circuit(&self) -> Circuit {
+        self.state().circuit
+    }
+
+    /// Whether a request may be sent; half-opens the circuit once it was
+    /// open long enough
+    pub fn try_acquire(&self, config: &CircuitBreakerConfig, now: Instant) -> bool {
+        let mut state = self.state();
+        let waited = now >= state.opened_at + Duration::from_secs(config.open_secs);
+        match state.circuit {
+            Circuit::Closed => true,
+            Circuit::Open if !waited => false,
+            // Probes that never report back, such as hedged requests that
+            // lost, would otherwise keep the circuit half-open for good
+            Circuit::HalfOpen if state.probes >= config.half_open_requests && !waited => false,
+            Circuit::Open | Circuit::HalfOpen => {
+                if state.circuit == Circuit::Open || state.probes >= config.half_open_requests {
+                    state.circuit = Circuit::HalfOpen;
+                    state.opened_at = now;
+                    state.probes = 0;
+                    state.probe_successes = 0;
+                }
+                state.probes += 1;
+                true
+            }
+        }
+    }
+
+    /// Records the outcome of a request; returns the circuit it moved to,
+    /// if any
+    pub fn record(
+        &self,
+        config: &CircuitBreakerConfig,
+        success: bool,
+        now: Instant,
+    ) -> Option<Circuit> {
+        let mut state = self.state();
+        match state.circuit {
+            Circuit::Closed => {
+                if now >= state.window_started + Duration::from_secs(config.window_secs) {
+                    state.window_started = now;
+                    state.requests = 0;
+                    state.failures = 0;
+                }
+                state.requests += 1;
+                if !success {
+                    state.failures += 1;
+                }
+                let rate = f64::from(state.failures) / f64::from(state.requests);
+                if state.requests >= config.minimum_requests && rate >= config.failure_rate {
+                    state.circuit = Circuit::Open;
+                    state.opened_at = now;
+                    return Some(Circuit::Open);
+                }
+                None
+            }
+            Circuit::HalfOpen if !success => {
+                state.circuit = Circuit::Open;
+                state.opened_at = now;
+                Some(Circuit::Open)
+            }
+            Circuit::HalfOpen => {
+                state.probe_successes += 1;
+                if state.probe_successes < config.half_open_requests {
+                    return None;
+                }
+                state.circuit = Circuit::Closed;
+                state.window_started = now;
+                state.requests = 0;
+                state.failures = 0;
+                Some(Circuit::Closed)
+            }
+            // Requests sent before the circuit opened
+            Circuit::Open => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BudgetState {
+    window_started: Instant,
+    requests: u32,
+    retries: u32,
+}
+
+/// Caps retries at a share of the requests, so retries cannot multiply
+/// the load on a struggling service
+#[derive(Debug)]
+pub struct RetryBudget {
+    state: Mutex<BudgetState>,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(BudgetState {
+                window_started: Instant::now(),
+                requests: 0,
+                retries: 0,
+            }),
+        }
+    }
+}
+
+impl RetryBudget {
+    fn state(&self, now: Instant) -> std::sync::MutexGuard<'_, BudgetState> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if now >= state.window_started + BUDGET_WINDOW {
+            *state = BudgetState {
+                window_started: now,
+                requests: 0,
+                retries: 0,
+            };
+        }
+        state
+    }
+
+    pub fn record_request(&self, now: Instant) {
+        self.state(now).requests += 1;
+    }
+
+    /// Takes a retry from the budget, if it has one left
+    pub fn try_retry(&self, policy: &RetryPolicy, now: Instant) -> bool {
+        let mut state = self.state(now);
+        let by_ratio = (f64::from(state.requests) * policy.budget_ratio) as u32;
+        let minimum = policy
+            .min_retries_per_sec
+            .saturating_mul(BUDGET_WINDOW.as_secs() as u32);
+        if state.retries >= by_ratio.max(minimum) {
+            return false;
+        }
+        state.retries += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_opens_half_opens_and_closes() {
+        let config = CircuitBreakerConfig {
+            minimum_requests: 4,
+            half_open_requests: 2,
+            ..CircuitBreakerConfig::default()
+        };
+        let breaker = CircuitBreaker::default();
+        let start = Instant::now();
+
+        for success in [true, false, true] {
+            assert!(breaker.try_acquire(&config, start));
+            assert_eq!(breaker.record(&config, success, start), None);
+        }
+        assert_eq!(breaker.record(&config, false, start), Some(Circuit::Open));
+        assert!(!breaker.try_acquire(&config, start + Duration::from_secs(29)));
+
+        // Probes fail and open it again
+        let later = start + Duration::from_secs(30);
+        assert!(breaker.try_acquire(&config, later));
+        assert_eq!(breaker.circuit(), Circuit::HalfOpen);
+        assert_eq!(breaker.record(&config, false, later), Some(Circuit::Open));
+
+        let later = later + Duration::from_secs(30);
+        assert!(breaker.try_acquire(&config, later));
+        assert!(breaker.try_acquire(&config, later));
+        // No more probes than configured
+        assert!(!breaker.try_acquire(&config, later));
+        assert_eq!(breaker.record(&config, true, later), None);
+        assert_eq!(breaker.record(&config, true, later), Some(Circuit::Closed));
+        assert!(breaker.try_acquire(&config, later));
+    }
+
+    #[test]
+    fn test_retry_budget_is_a_share_of_requests() {
+        let policy = RetryPolicy {
+            budget_ratio: 0.1,
+            min_retries_per_sec: 0,
+            ..RetryPolicy::default()
+        };
+        let budget = RetryBudget::default();
+        let now = Instant::now();
+        for _ in 0..50 {
+            budget.record_request(now);
+        }
+        let retries = (0..50).filter(|_| budget.try_retry(&policy, now)).count();
+        assert_eq!(retries, 5);
+
+        // A new window starts empty
+        assert!(!budget.try_retry(&policy, now + BUDGET_WINDOW));
+        assert_eq!(policy.backoff(0), Duration::from_millis(25));
+        assert_eq!(policy.backoff(2), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,743 @@
+//! Routes to backend services declared in a file rather than in code.
+//!
+//! The table is read from a TOML or YAML file (`ROUTES_CONFIG`), validated
+//! as a whole and watched for changes, so operators can expose a service,
+//! or change how it is reached, without a new build of the gateway. Each
+//! route names the methods and path pattern it answers, the service it is
+//! forwarded to and how: authentication, required permissions, a rate
+//! limit policy, a timeout and a rewrite of the path. gRPC routes are
+//! served on the gRPC listener instead, the others on the gateway's port.
+
+use actix_web::{guard, http::Method};
+use lotabots_rate_limit::RateLimit;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, Weak},
+    time::Duration,
+};
+
+use super::ServiceRegistry;
+use crate::error::ApiError;
+
+/// Paths the gateway keeps to itself
+const RESERVED_PREFIXES: [&str; 3] = ["/admin", "/health", "/metrics"];
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteTableConfig {
+    /// Rate limit policies routes refer to by name
+    #[serde(default)]
+    pub rate_limits: BTreeMap<String, RateLimitPolicy>,
+    /// Tried in order; the first whose pattern matches the path is used
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitPolicy {
+    /// `<requests>/<period>[:<burst>]`, e.g. `100/s:200`
+    pub limit: String,
+    #[serde(default)]
+    pub per: LimitKey,
+}
+
+/// Who a rate limit counts requests for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitKey {
+    /// The tenant the request acts for, or the peer IP without one
+    #[default]
+    Tenant,
+    Ip,
+}
+
+/// Who may use a route
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    /// Anyone, without credentials
+    Public,
+    /// Users, API keys and OAuth clients
+    #[default]
+    Authenticated,
+    /// Callers acting for an operator tenant
+    Admin,
+}
+
+/// What a route speaks to its clients and upstream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// HTTP/1, WebSocket upgrades included
+    #[default]
+    Http,
+    /// gRPC over HTTP/2 without TLS, trailers included
+    Grpc,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub name: String,
+    /// Segments are literals, `{name}` for one segment or, last,
+    /// `{name*}` for the rest of the path, e.g. `/api/v1/documents/{rest*}`
+    pub path: String,
+    /// All methods when empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Service in the registry requests are forwarded to
+    pub upstream: String,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub auth: Access,
+    /// Scopes API keys and OAuth clients must hold, e.g. `documents:read`;
+    /// users are not restricted by scope
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    /// Name of a policy in `rate_limits`; unlimited without one
+    #[serde(default)]
+    pub rate_limit: Option<String>,
+    /// Instead of the service's timeout
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Path on the upstream, with the pattern's parameters filled in, e.g.
+    /// `/v2/{rest}`; the request's path without one
+    #[serde(default)]
+    pub rewrite: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+/// A rate limit policy ready to be checked
+#[derive(Debug, Clone)]
+pub struct RouteLimit {
+    pub policy: String,
+    pub limit: RateLimit,
+    pub per: LimitKey,
+}
+
+/// A validated route
+#[derive(Debug)]
+pub struct Route {
+    config: RouteConfig,
+    pattern: Vec<Segment>,
+    methods: Vec<Method>,
+    limit: Option<RouteLimit>,
+}
+
+impl Route {
+    fn compile(
+        config: RouteConfig,
+        policies: &BTreeMap<String, RouteLimit>,
+    ) -> Result<Self, ApiError> {
+        let invalid = |message: String| {
+            ApiError::ValidationError(format!("Route {}: {}", config.name, message))
+        };
+
+        let pattern = parse_pattern(&config.path).map_err(invalid)?;
+        if RESERVED_PREFIXES
+            .iter()
+            .any(|prefix| matches_prefix(&pattern, prefix))
+        {
+            return Err(invalid(format!("{} is reserved", config.path)));
+        }
+        let methods = config
+            .methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.to_uppercase().as_bytes())
+                    .map_err(|_| invalid(format!("Invalid method '{}'", method)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if config.upstream.is_empty() {
+            return Err(invalid("Needs an upstream".to_string()));
+        }
+        // gRPC calls are always POSTs
+        if config.protocol == Protocol::Grpc && !config.methods.is_empty() {
+            return Err(invalid("gRPC routes cannot declare methods".to_string()));
+        }
+        if config.auth == Access::Public && !config.permissions.is_empty() {
+            return Err(invalid(
+                "Public routes cannot require permissions".to_string(),
+            ));
+        }
+        let limit = match &config.rate_limit {
+            Some(policy) => Some(
+                policies
+                    .get(policy)
+                    .cloned()
+                    .ok_or_else(|| invalid(format!("Unknown rate limit '{}'", policy)))?,
+            ),
+            None => None,
+        };
+        if config.timeout_secs == Some(0) {
+            return Err(invalid("Timeout must be positive".to_string()));
+        }
+        if let Some(rewrite) = &config.rewrite {
+            check_rewrite(rewrite, &pattern).map_err(invalid)?;
+        }
+
+        Ok(Self {
+            config,
+            pattern,
+            methods,
+            limit,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// The pattern as configured, e.g. `/api/v1/documents/{rest*}`
+    pub fn pattern(&self) -> &str {
+        &self.config.path
+    }
+
+    pub fn upstream(&self) -> &str {
+        &self.config.upstream
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.config.protocol
+    }
+
+    pub fn access(&self) -> Access {
+        self.config.auth
+    }
+
+    pub fn permissions(&self) -> &[String] {
+        &self.config.permissions
+    }
+
+    pub fn limit(&self) -> Option<&RouteLimit> {
+        self.limit.as_ref()
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.config.timeout_secs.map(Duration::from_secs)
+    }
+
+    fn allows(&self, method: &Method) -> bool {
+        self.methods.is_empty() || self.methods.contains(method)
+    }
+
+    /// Parameters of `path` by name, if it matches the pattern
+    fn match_path(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut rest = path.strip_prefix('/')?;
+        let mut params = Vec::new();
+        for segment in &self.pattern {
+            if let Segment::Rest(name) = segment {
+                params.push((name.clone(), rest.to_string()));
+                return Some(params);
+            }
+            let (value, remaining) = rest.split_once('/').unwrap_or((rest, ""));
+            match segment {
+                Segment::Literal(literal) if literal == value => {}
+                Segment::Param(name) if !value.is_empty() => {
+                    params.push((name.clone(), value.to_string()));
+                }
+                _ => return None,
+            }
+            rest = remaining;
+        }
+        rest.is_empty().then_some(params)
+    }
+}
+
+/// A request's route with the parameters of its path; kept in the
+/// request's extensions for the handler and the usage meter
+#[derive(Debug, Clone)]
+pub struct MatchedRoute {
+    pub route: Arc<Route>,
+    pub params: Vec<(String, String)>,
+}
+
+impl MatchedRoute {
+    /// Path and query to request from the upstream, without the leading
+    /// slash
+    pub fn upstream_path(&self, path: &str, query: &str) -> String {
+        let mut upstream = match &self.route.config.rewrite {
+            Some(rewrite) => self
+                .params
+                .iter()
+                .fold(rewrite.clone(), |rewritten, (name, value)| {
+                    rewritten.replace(&format!("{{{}}}", name), value)
+                }),
+            None => path.to_string(),
+        };
+        if !query.is_empty() {
+            upstream.push('?');
+            upstream.push_str(query);
+        }
+        upstream.trim_start_matches('/').to_string()
+    }
+}
+
+/// How a request fits the table
+#[derive(Debug)]
+pub enum Lookup {
+    Found(MatchedRoute),
+    /// The path matches routes, none of them for this method
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
+
+/// The declared routes; shared by all workers and replaced as a whole when
+/// the file changes
+pub struct RouteTable {
+    routes: RwLock<Arc<Vec<Arc<Route>>>>,
+    /// File the routes were loaded from, watched for changes
+    source: Option<PathBuf>,
+    watch_interval: Duration,
+}
+
+impl RouteTable {
+    /// A table without routes
+    pub fn new() -> Self {
+        Self {
+            routes: RwLock::default(),
+            source: None,
+            watch_interval: Duration::from_secs(5),
+        }
+    }
+
+    pub fn from_config(config: RouteTableConfig) -> Result<Self, ApiError> {
+        let table = Self::new();
+        table.load(config)?;
+        Ok(table)
+    }
+
+    /// Loads `ROUTES_CONFIG`, watched every `ROUTES_WATCH_INTERVAL_SECS`
+    /// once spawned; no routes without it
+    pub fn from_env() -> Result<Self, ApiError> {
+        let Ok(path) = env::var("ROUTES_CONFIG") else {
+            return Ok(Self::new());
+        };
+        let table = Self::from_file(path)?;
+        Ok(
+            match env::var("ROUTES_WATCH_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+            {
+                Some(secs) => table.watch_every(Duration::from_secs(secs)),
+                None => table,
+            },
+        )
+    }
+
+    /// Loads the routes of a `.toml`, `.yaml` or `.yml` file, which is
+    /// watched once spawned
+    pub fn from_file(path: impl Into<PathBuf>) -> Result<Self, ApiError> {
+        let path = path.into();
+        let mut table = Self::from_config(read_config(&path)?)?;
+        table.source = Some(path);
+        Ok(table)
+    }
+
+    /// How often the file the routes were loaded from is read again
+    pub fn watch_every(mut self, interval: Duration) -> Self {
+        self.watch_interval = interval;
+        self
+    }
+
+    /// Replaces all routes, if every one of them is valid
+    pub fn load(&self, config: RouteTableConfig) -> Result<(), ApiError> {
+        let policies = config
+            .rate_limits
+            .into_iter()
+            .map(|(policy, config)| {
+                let limit = config.limit.parse().map_err(|e| {
+                    ApiError::ValidationError(format!("Rate limit {}: {}", policy, e))
+                })?;
+                let limit = RouteLimit {
+                    policy: policy.clone(),
+                    limit,
+                    per: config.per,
+                };
+                Ok((policy, limit))
+            })
+            .collect::<Result<BTreeMap<_, _>, ApiError>>()?;
+
+        let mut names = HashSet::new();
+        let routes = config
+            .routes
+            .into_iter()
+            .map(|route| {
+                if !names.insert(route.name.clone()) {
+                    return Err(ApiError::ValidationError(format!(
+                        "Route {} is declared twice",
+                        route.name
+                    )));
+                }
+                Route::compile(route, &policies).map(Arc::new)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        *self.routes.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(routes);
+        Ok(())
+    }
+
+    fn routes(&self) -> Arc<Vec<Arc<Route>>> {
+        self.routes
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes().is_empty()
+    }
+
+    /// Whether any HTTP route matches `path`, whatever its method
+    pub fn matches(&self, path: &str) -> bool {
+        self.routes()
+            .iter()
+            .any(|route| route.protocol() == Protocol::Http && route.match_path(path).is_some())
+    }
+
+    /// The first HTTP route for `method` whose pattern matches `path`
+    pub fn find(&self, method: &Method, path: &str) -> Lookup {
+        self.lookup(Protocol::Http, method, path)
+    }
+
+    /// The first gRPC route whose pattern matches `path`, which names the
+    /// service and method, e.g. `/agent.v1.Agent/Chat`
+    pub fn find_grpc(&self, path: &str) -> Option<MatchedRoute> {
+        match self.lookup(Protocol::Grpc, &Method::POST, path) {
+            Lookup::Found(matched) => Some(matched),
+            _ => None,
+        }
+    }
+
+    fn lookup(&self, protocol: Protocol, method: &Method, path: &str) -> Lookup {
+        let mut allowed = Vec::new();
+        for route in self.routes().iter() {
+            if route.protocol() != protocol {
+                continue;
+            }
+            let Some(params) = route.match_path(path) else {
+                continue;
+            };
+            if route.allows(method) {
+                return Lookup::Found(MatchedRoute {
+                    route: route.clone(),
+                    params,
+                });
+            }
+            for method in &route.methods {
+                if !allowed.contains(method) {
+                    allowed.push(method.clone());
+                }
+            }
+        }
+        if allowed.is_empty() {
+            Lookup::NotFound
+        } else {
+            Lookup::MethodNotAllowed(allowed)
+        }
+    }
+
+    /// Fails if a route names a service `registry` does not have
+    pub fn check_upstreams(&self, registry: &ServiceRegistry) -> Result<(), ApiError> {
+        match self
+            .routes()
+            .iter()
+            .find(|route| registry.get(route.upstream()).is_err())
+        {
+            Some(route) => Err(ApiError::ValidationError(format!(
+                "Route {}: Unknown upstream '{}'",
+                route.name(),
+                route.upstream()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Admits requests to the scope serving the table only when a route
+    /// matches their path, so other paths fall through to the gateway's
+    /// own routes
+    pub fn guard(self: &Arc<Self>) -> impl guard::Guard {
+        let table = self.clone();
+        guard::fn_guard(move |ctx| table.matches(ctx.head().uri.path()))
+    }
+
+    /// Reloads the source file when it changes until the table is dropped;
+    /// routes to services `registry` does not have are refused
+    pub fn spawn(self: &Arc<Self>, registry: &Arc<ServiceRegistry>) {
+        if let Some(path) = self.source.clone() {
+            tokio::spawn(watch(
+                Arc::downgrade(self),
+                Arc::downgrade(registry),
+                path,
+                self.watch_interval,
+            ));
+        }
+    }
+}
+
+impl Default for RouteTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn parse_pattern(path: &str) -> Result<Vec<Segment>, String> {
+    let Some(rest) = path.strip_prefix('/') else {
+        return Err(format!("Path '{}' must start with /", path));
+    };
+    let mut names = HashSet::new();
+    let mut pattern = Vec::new();
+    let segments: Vec<_> = rest.split('/').filter(|s| !s.is_empty()).collect();
+    for (i, segment) in segments.iter().enumerate() {
+        let Some(name) = segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) else {
+            if segment.contains(['{', '}']) {
+                return Err(format!("Invalid segment '{}'", segment));
+            }
+            pattern.push(Segment::Literal(segment.to_string()));
+            continue;
+        };
+        let (name, rest) = match name.strip_suffix('*') {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("Invalid parameter '{}'", segment));
+        }
+        if !names.insert(name) {
+            return Err(format!("Parameter '{}' is used twice", name));
+        }
+        if rest && i + 1 != segments.len() {
+            return Err(format!("'{}' must be the last segment", segment));
+        }
+        pattern.push(if rest {
+            Segment::Rest(name.to_string())
+        } else {
+            Segment::Param(name.to_string())
+        });
+    }
+    Ok(pattern)
+}
+
+/// Whether paths under `prefix` can match `pattern`
+fn matches_prefix(pattern: &[Segment], prefix: &str) -> bool {
+    let prefix: Vec<_> = prefix.split('/').filter(|s| !s.is_empty()).collect();
+    for (i, segment) in pattern.iter().enumerate() {
+        match (segment, prefix.get(i)) {
+            (_, None) | (Segment::Rest(_), _) => return true,
+            (Segment::Literal(literal), Some(expected)) if literal != expected => return false,
+            _ => {}
+        }
+    }
+    pattern.len() >= prefix.len()
+}
+
+fn check_rewrite(rewrite: &str, pattern: &[Segment]) -> Result<(), String> {
+    if !rewrite.starts_with('/') {
+        return Err(format!("Rewrite '{}' must start with /", rewrite));
+    }
+    let mut rest = rewrite;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            return Err(format!("Unclosed parameter in '{}'", rewrite));
+        };
+        let name = &rest[start + 1..start + end];
+        let known = pattern.iter().any(|segment| match segment {
+            Segment::Param(param) | Segment::Rest(param) => param == name,
+            Segment::Literal(_) => false,
+        });
+        if !known {
+            return Err(format!("Rewrite uses unknown parameter '{}'", name));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+fn read_config(path: &Path) -> Result<RouteTableConfig, ApiError> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        ApiError::InternalError(format!("Failed to read {}: {}", path.display(), e))
+    })?;
+    let invalid =
+        |e: String| ApiError::ValidationError(format!("Invalid {}: {}", path.display(), e));
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&contents).map_err(|e| invalid(e.to_string())),
+        Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(|e| invalid(e.to_string())),
+        _ => Err(invalid(
+            "Routes must be a .toml, .yaml or .yml file".to_string(),
+        )),
+    }
+}
+
+/// Reloads `path` when its contents change; invalid contents, or routes to
+/// unknown services, are logged and the routes kept
+async fn watch(
+    table: Weak<RouteTable>,
+    registry: Weak<ServiceRegistry>,
+    path: PathBuf,
+    interval: Duration,
+) {
+    let mut loaded = read_config(&path).ok();
+    loop {
+        tokio::time::sleep(interval).await;
+        let (Some(table), Some(registry)) = (table.upgrade(), registry.upgrade()) else {
+            break;
+        };
+        let config = match read_config(&path) {
+            Ok(config) if loaded.as_ref() == Some(&config) => continue,
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!("Keeping the current routes: {}", e);
+                continue;
+            }
+        };
+        // Checked on a copy, so the served table never holds invalid routes
+        let result = RouteTable::from_config(config.clone())
+            .and_then(|candidate| candidate.check_upstreams(&registry))
+            .and_then(|()| table.load(config.clone()));
+        match result {
+            Ok(()) => {
+                tracing::info!("Reloaded routes from {}", path.display());
+                loaded = Some(config);
+            }
+            Err(e) => tracing::error!("Keeping the current routes: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(toml: &str) -> Result<RouteTable, ApiError> {
+        RouteTable::from_config(toml::from_str(toml).unwrap())
+    }
+
+    #[test]
+    fn test_routes_match_in_order_with_their_parameters() {
+        let table = table(
+            r#"
+            [[routes]]
+            name = "document-files"
+            path = "/api/v1/documents/{id}/files/{rest*}"
+            methods = ["get"]
+            upstream = "document"
+            rewrite = "/v2/files/{id}/{rest}"
+
+            [[routes]]
+            name = "documents"
+            path = "/api/v1/documents/{rest*}"
+            methods = ["GET", "POST"]
+            upstream = "document"
+            "#,
+        )
+        .unwrap();
+
+        let Lookup::Found(matched) = table.find(&Method::GET, "/api/v1/documents/7/files/a/b")
+        else {
+            panic!("no route");
+        };
+        assert_eq!(matched.route.name(), "document-files");
+        assert_eq!(
+            matched.upstream_path("/api/v1/documents/7/files/a/b", "v=1"),
+            "v2/files/7/a/b?v=1"
+        );
+
+        let Lookup::Found(matched) = table.find(&Method::POST, "/api/v1/documents/7/files/a")
+        else {
+            panic!("no route");
+        };
+        assert_eq!(matched.route.name(), "documents");
+        assert_eq!(
+            matched.upstream_path("/api/v1/documents/7/files/a", ""),
+            "api/v1/documents/7/files/a"
+        );
+
+        // The rest may be empty, other segments may not
+        assert!(table.matches("/api/v1/documents"));
+        assert!(!table.matches("/api/v1/documentsx"));
+        assert!(matches!(
+            table.find(&Method::DELETE, "/api/v1/documents/7"),
+            Lookup::MethodNotAllowed(methods) if methods == [Method::GET, Method::POST]
+        ));
+        assert!(matches!(
+            table.find(&Method::GET, "/api/v2/documents"),
+            Lookup::NotFound
+        ));
+    }
+
+    #[test]
+    fn test_grpc_routes_are_kept_apart() {
+        let table = table(
+            r#"
+            [[routes]]
+            name = "agent"
+            path = "/agent.v1.Agent/{method}"
+            upstream = "agent"
+            protocol = "grpc"
+            "#,
+        )
+        .unwrap();
+
+        let matched = table.find_grpc("/agent.v1.Agent/Chat").unwrap();
+        assert_eq!(matched.params, [("method".to_string(), "Chat".to_string())]);
+        assert!(table.find_grpc("/agent.v1.Other/Chat").is_none());
+        assert!(!table.matches("/agent.v1.Agent/Chat"));
+        assert!(matches!(
+            table.find(&Method::POST, "/agent.v1.Agent/Chat"),
+            Lookup::NotFound
+        ));
+    }
+
+    #[test]
+    fn test_invalid_tables_are_refused() {
+        let route = |extra: &str| {
+            format!(
+                "[rate_limits.standard]\nlimit = \"10/s\"\n\n[[routes]]\nname = \"r\"\nupstream = \"document\"\n{}",
+                extra
+            )
+        };
+        assert!(table(&route(
+            "path = \"/api/v1/documents/{id}\"\nrate_limit = \"standard\""
+        ))
+        .is_ok());
+
+        for extra in [
+            "path = \"api/v1\"",
+            "path = \"/api/{rest*}/files\"",
+            "path = \"/api/{id}/{id}\"",
+            "path = \"/admin/{rest*}\"",
+            "path = \"/{rest*}\"",
+            "path = \"/api/v1\"\nmethods = [\"G T\"]",
+            "path = \"/api/v1\"\nrate_limit = \"unknown\"",
+            "path = \"/api/v1\"\nrewrite = \"/v2/{id}\"",
+            "path = \"/api/v1\"\ntimeout_secs = 0",
+            "path = \"/api/v1\"\nauth = \"public\"\npermissions = [\"documents:read\"]",
+            "path = \"/a.B/{method}\"\nprotocol = \"grpc\"\nmethods = [\"POST\"]",
+        ] {
+            assert!(table(&route(extra)).is_err(), "{} was accepted", extra);
+        }
+
+        assert!(table(
+            "[[routes]]\nname = \"r\"\npath = \"/a\"\nupstream = \"s\"\n\n\
+             [[routes]]\nname = \"r\"\npath = \"/b\"\nupstream = \"s\""
+        )
+        .is_err());
+
+        let yaml: RouteTableConfig = serde_yaml::from_str(
+            "routes:\n  - name: documents\n    path: /api/v1/documents/{rest*}\n    upstream: document\n    auth: admin\n",
+        )
+        .unwrap();
+        assert_eq!(yaml.routes[0].auth, Access::Admin);
+    }
+}
@@ -0,0 +1,148 @@
+use actix_web::{web, HttpResponse};
+use lotabots_api_keys::{generate_api_key, generate_client, validate_scopes};
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    db::{self, DbPool},
+    error::ApiError,
+    middleware::Claims,
+    models::{
+        ApiKeyCreated, ApiResponse, CreateApiKeyRequest, CreateOAuthClientRequest,
+        OAuthClientCreated,
+    },
+};
+
+pub fn configure_api_keys(cfg: &mut web::ServiceConfig) {
+    cfg.route("", web::post().to(create_api_key))
+        .route("", web::get().to(list_api_keys))
+        .route("/{id}", web::delete().to(revoke_api_key));
+}
+
+pub fn configure_clients(cfg: &mut web::ServiceConfig) {
+    cfg.route("", web::post().to(create_client))
+        .route("", web::get().to(list_clients))
+        .route("/{id}", web::delete().to(revoke_client));
+}
+
+/// Resolves the tenant and user managing credentials. Machine clients cannot
+/// mint further credentials.
+fn tenant_of(claims: &Claims) -> Result<(Uuid, Uuid), ApiError> {
+    if claims.is_machine() {
+        return Err(ApiError::AuthorizationError(
+            "Machine clients cannot manage credentials".to_string(),
+        ));
+    }
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| ApiError::AuthenticationError("Invalid subject".to_string()))?;
+
+    Ok((claims.tenant().unwrap_or(user_id), user_id))
+}
+
+fn check_scopes(scopes: &[String]) -> Result<(), ApiError> {
+    validate_scopes(scopes).map_err(|e| ApiError::ValidationError(e.to_string()))
+}
+
+async fn create_api_key(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<Claims>,
+    req: web::Json<CreateApiKeyRequest>,
+) -> Result<HttpResponse, ApiError> {
+    req.validate()?;
+    check_scopes(&req.scopes)?;
+    let (tenant_id, user_id) = tenant_of(&claims)?;
+
+    let generated = generate_api_key();
+    let key = db::create_api_key(
+        &pool,
+        tenant_id,
+        &req.name,
+        &generated.prefix,
+        &generated.secret_hash,
+        &req.scopes,
+        user_id,
+        req.expires_at,
+    )
+    .await?;
+
+    Ok(
+        HttpResponse::Created().json(ApiResponse::success(ApiKeyCreated {
+            key,
+            token: generated.token,
+        })),
+    )
+}
+
+async fn list_api_keys(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, ApiError> {
+    let (tenant_id, _) = tenant_of(&claims)?;
+    let keys = db::list_api_keys(&pool, tenant_id).await?;
+    Ok(HttpResponse::Ok().json(ApiResponse::success(keys)))
+}
+
+async fn revoke_api_key(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let (tenant_id, _) = tenant_of(&claims)?;
+    if !db::revoke_api_key(&pool, tenant_id, id.into_inner()).await? {
+        return Err(ApiError::NotFoundError("API key not found".to_string()));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn create_client(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<Claims>,
+    req: web::Json<CreateOAuthClientRequest>,
+) -> Result<HttpResponse, ApiError> {
+    req.validate()?;
+    check_scopes(&req.scopes)?;
+    let (tenant_id, user_id) = tenant_of(&claims)?;
+
+    let generated = generate_client();
+    let client = db::create_oauth_client(
+        &pool,
+        tenant_id,
+        &req.name,
+        &generated.client_id,
+        &generated.secret_hash,
+        &req.scopes,
+        user_id,
+    )
+    .await?;
+
+    Ok(
+        HttpResponse::Created().json(ApiResponse::success(OAuthClientCreated {
+            client,
+            client_secret: generated.client_secret,
+        })),
+    )
+}
+
+async fn list_clients(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, ApiError> {
+    let (tenant_id, _) = tenant_of(&claims)?;
+    let clients = db::list_oauth_clients(&pool, tenant_id).await?;
+    Ok(HttpResponse::Ok().json(ApiResponse::success(clients)))
+}
+
+async fn revoke_client(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let (tenant_id, _) = tenant_of(&claims)?;
+    if !db::revoke_oauth_client(&pool, tenant_id, id.into_inner()).await? {
+        return Err(ApiError::NotFoundError(
+            "OAuth client not found".to_string(),
+        ));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
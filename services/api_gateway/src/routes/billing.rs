@@ -0,0 +1,69 @@
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use validator::Validate;
+
+use crate::{
+    billing::BillingPeriod,
+    db::{self, DbPool},
+    error::ApiError,
+    middleware::Claims,
+    models::{ApiResponse, BillableEventRecorded, BillableEventRequest, InvoiceQuery},
+};
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/events", web::post().to(record_event))
+        .route("/invoices", web::get().to(list_invoices));
+}
+
+/// Records storage or GPU usage of the caller's tenant
+async fn record_event(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<Claims>,
+    req: web::Json<BillableEventRequest>,
+) -> Result<HttpResponse, ApiError> {
+    req.validate()?;
+    if !req.metric.is_reported() {
+        return Err(ApiError::ValidationError(format!(
+            "'{}' is metered by the gateway and cannot be reported",
+            req.metric.as_str()
+        )));
+    }
+    let tenant_id = claims
+        .tenant()
+        .ok_or_else(|| ApiError::AuthenticationError("Invalid subject".to_string()))?;
+
+    let recorded = db::record_billable_event(
+        &pool,
+        tenant_id,
+        req.metric,
+        req.quantity,
+        req.occurred_at.unwrap_or_else(Utc::now),
+        &req.idempotency_key,
+    )
+    .await?;
+
+    let mut response = if recorded {
+        HttpResponse::Created()
+    } else {
+        HttpResponse::Ok()
+    };
+    Ok(response.json(ApiResponse::success(BillableEventRecorded { recorded })))
+}
+
+/// The caller's invoice for a period, if one was generated
+async fn list_invoices(
+    pool: web::Data<DbPool>,
+    claims: web::ReqData<Claims>,
+    query: web::Query<InvoiceQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let tenant_id = claims
+        .tenant()
+        .ok_or_else(|| ApiError::AuthenticationError("Invalid subject".to_string()))?;
+    let period = match &query.period {
+        Some(period) => period.parse()?,
+        None => BillingPeriod::containing(Utc::now()),
+    };
+
+    let invoices = db::list_invoices(&pool, period, Some(tenant_id)).await?;
+    Ok(HttpResponse::Ok().json(ApiResponse::success(invoices)))
+}
@@ -1,7 +1,25 @@
-use actix_web::{get, HttpResponse};
+use actix_web::{get, web, HttpResponse};
 use crate::models::ApiResponse;
+use crate::proxy::{Proxy, ServiceRegistry};
+use crate::worker::WorkerPool;
 
 #[get("/health")]
 pub async fn health_check() -> HttpResponse {
     HttpResponse::Ok().json(ApiResponse::success("OK"))
 }
+
+/// Queue depth, load and job latency of the background worker pool. Mounted
+/// at `/health/workers`, for operators only.
+#[get("")]
+pub async fn worker_stats(workers: web::Data<WorkerPool>) -> HttpResponse {
+    HttpResponse::Ok().json(ApiResponse::success(workers.stats()))
+}
+
+/// Metrics of proxied requests, circuits and upstream instances in the
+/// Prometheus text format
+#[get("/metrics")]
+pub async fn metrics(proxy: web::Data<Proxy>, registry: web::Data<ServiceRegistry>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(proxy.metrics().render(&registry))
+}
@@ -0,0 +1,88 @@
+use actix_web::{web, HttpResponse};
+use uuid::Uuid;
+
+use crate::{
+    db::{self, DbPool},
+    error::ApiError,
+    jobs::{Job, NewJob},
+    models::{ApiResponse, JobQuery},
+};
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1_000;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("", web::get().to(list_jobs))
+        .route("", web::post().to(enqueue_job))
+        .route("/{id}", web::get().to(get_job))
+        .route("/{id}/retry", web::post().to(retry_job))
+        .route("/{id}/cancel", web::post().to(cancel_job));
+}
+
+async fn list_jobs(
+    pool: web::Data<DbPool>,
+    query: web::Query<JobQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let jobs = db::list_jobs(&pool, query.status, query.job_type.as_deref(), limit).await?;
+    Ok(HttpResponse::Ok().json(ApiResponse::success(jobs)))
+}
+
+/// Enqueues a job; a job with the same type and unique key is returned
+/// instead if there is one
+async fn enqueue_job(
+    pool: web::Data<DbPool>,
+    job: web::Json<NewJob>,
+) -> Result<HttpResponse, ApiError> {
+    job.validate()?;
+    let (id, created) = db::enqueue_job(&pool, &job).await?;
+    let job = find_job(&pool, id).await?;
+
+    let mut response = if created {
+        HttpResponse::Created()
+    } else {
+        HttpResponse::Ok()
+    };
+    Ok(response.json(ApiResponse::success(job)))
+}
+
+async fn get_job(pool: web::Data<DbPool>, id: web::Path<Uuid>) -> Result<HttpResponse, ApiError> {
+    let job = find_job(&pool, id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(ApiResponse::success(job)))
+}
+
+/// Runs a dead or cancelled job again, with a fresh set of attempts
+async fn retry_job(pool: web::Data<DbPool>, id: web::Path<Uuid>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    match db::retry_job(&pool, id).await? {
+        Some(job) => Ok(HttpResponse::Ok().json(ApiResponse::success(job))),
+        None => Err(not_in_state(
+            find_job(&pool, id).await?,
+            "Only dead or cancelled jobs can be retried",
+        )),
+    }
+}
+
+async fn cancel_job(
+    pool: web::Data<DbPool>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    match db::cancel_job(&pool, id).await? {
+        Some(job) => Ok(HttpResponse::Ok().json(ApiResponse::success(job))),
+        None => Err(not_in_state(
+            find_job(&pool, id).await?,
+            "Only queued jobs can be cancelled",
+        )),
+    }
+}
+
+async fn find_job(pool: &DbPool, id: Uuid) -> Result<Job, ApiError> {
+    db::get_job(pool, id)
+        .await?
+        .ok_or_else(|| ApiError::NotFoundError("Job not found".to_string()))
+}
+
+fn not_in_state(job: Job, message: &str) -> ApiError {
+    ApiError::ValidationError(format!("{}; the job is {}", message, job.status))
+}
@@ -1,3 +1,10 @@
+pub mod api_keys;
+pub mod billing;
 pub mod health;
+pub mod jobs;
+pub mod oauth;
 pub mod products;
+pub mod proxy;
+pub mod upstreams;
+pub mod usage;
 pub mod users;
@@ -0,0 +1,112 @@
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lotabots_api_keys::requested_scopes;
+use lotabots_secrets::KeyRing;
+use serde_json::json;
+
+use crate::{
+    db::{self, DbPool},
+    error::ApiError,
+    middleware::{sign_jwt, Claims, Principal},
+    models::{TokenRequest, TokenResponse},
+};
+
+/// Lifetime of access tokens issued to machine clients
+const ACCESS_TOKEN_TTL_SECS: i64 = 3600;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/token", web::post().to(token));
+}
+
+/// OAuth2 error response (RFC 6749, section 5.2)
+fn oauth_error(status: StatusCode, error: &str, description: &str) -> HttpResponse {
+    HttpResponse::build(status).json(json!({
+        "error": error,
+        "error_description": description,
+    }))
+}
+
+fn invalid_client() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header(("WWW-Authenticate", "Basic realm=\"oauth\""))
+        .json(json!({
+            "error": "invalid_client",
+            "error_description": "Client authentication failed",
+        }))
+}
+
+/// Client credentials from HTTP Basic authentication, falling back to the
+/// request body
+fn client_credentials(req: &HttpRequest, form: &TokenRequest) -> Option<(String, String)> {
+    let basic = req
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|encoded| STANDARD.decode(encoded).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|decoded| {
+            decoded
+                .split_once(':')
+                .map(|(id, secret)| (id.to_string(), secret.to_string()))
+        });
+
+    basic.or_else(|| form.client_id.clone().zip(form.client_secret.clone()))
+}
+
+async fn token(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    jwt_keys: web::Data<KeyRing>,
+    form: web::Form<TokenRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if form.grant_type != "client_credentials" {
+        return Ok(oauth_error(
+            StatusCode::BAD_REQUEST,
+            "unsupported_grant_type",
+            "Only the client_credentials grant is supported",
+        ));
+    }
+
+    let Some((client_id, client_secret)) = client_credentials(&req, &form) else {
+        return Ok(invalid_client());
+    };
+
+    let Some(client) = db::find_oauth_client(&pool, &client_id).await? else {
+        return Ok(invalid_client());
+    };
+    if client.check(&client_secret).is_err() {
+        return Ok(invalid_client());
+    }
+
+    let scopes = match requested_scopes(form.scope.as_deref(), &client.scopes) {
+        Ok(scopes) => scopes,
+        Err(e) => {
+            return Ok(oauth_error(
+                StatusCode::BAD_REQUEST,
+                "invalid_scope",
+                &e.to_string(),
+            ))
+        }
+    };
+
+    let claims = Claims {
+        sub: client.client_id,
+        exp: (chrono::Utc::now() + chrono::Duration::seconds(ACCESS_TOKEN_TTL_SECS)).timestamp()
+            as usize,
+        tenant_id: Some(client.tenant_id),
+        permissions: scopes.clone(),
+        principal: Principal::Client,
+    };
+
+    let access_token = sign_jwt(&jwt_keys, &claims)?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(TokenResponse {
+            access_token,
+            token_type: "Bearer",
+            expires_in: ACCESS_TOKEN_TTL_SECS,
+            scope: scopes.join(" "),
+        }))
+}
@@ -1,85 +1,63 @@
-use actix_web::{
-    route,
-    web,
-    HttpRequest,
-    HttpResponse,
-    Error,
-};
-use reqwest::Client;
-use std::collections::HashMap;
-
-pub struct ServiceRegistry {
-    services: HashMap<String, String>,
-}
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
 
-impl ServiceRegistry {
-    pub fn new() -> Self {
-        let mut services = HashMap::new();
-        services.insert("auth".to_string(), "http://auth:8080".to_string());
-        services.insert("attestation".to_string(), "http://attestation:8080".to_string());
-        services.insert("document".to_string(), "http://document:8080".to_string());
-        services.insert(
-            "resource_management".to_string(),
-            "http://resource_management:8080".to_string(),
-        );
-        Self { services }
-    }
+use crate::{
+    error::ApiError,
+    middleware::Claims,
+    proxy::{MatchedRoute, Proxy, ServiceRegistry},
+};
 
-    pub fn get_service_url(&self, service: &str) -> Option<&String> {
-        self.services.get(service)
-    }
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/{service}/{path:.*}", web::route().to(proxy_route));
 }
 
-#[route("/{service}/{path:.*}", method = "GET", method = "POST")]
+/// Forwards any method to `path` on an instance of `service`
 pub async fn proxy_route(
     path: web::Path<(String, String)>,
     req: HttpRequest,
-    body: web::Bytes,
+    payload: web::Payload,
     registry: web::Data<ServiceRegistry>,
-) -> Result<HttpResponse, Error> {
-    let (service, path) = path.into_inner();
-
-    // Special handling for test service
-    if service == "test" && path == "test" {
-        return Ok(HttpResponse::Ok().finish());
+    proxy: web::Data<Proxy>,
+) -> Result<HttpResponse, ApiError> {
+    let (service, mut path) = path.into_inner();
+
+    let tenant = req.extensions().get::<Claims>().and_then(Claims::tenant);
+    let service = registry.get(&service)?;
+    if !req.query_string().is_empty() {
+        path.push('?');
+        path.push_str(req.query_string());
     }
 
-    // Check if the service exists in the registry
-    if let Some(base_url) = registry.get_service_url(&service) {
-        let client = Client::new();
-        let url = format!("{}/{}", base_url, path);
-
-        let mut forward_req = client.request(
-            req.method().clone(),
-            &url,
-        );
-
-        // Forward headers
-        for (key, value) in req.headers() {
-            if key != "host" {
-                forward_req = forward_req.header(key, value);
-            }
-        }
-
-        // Forward body for POST requests
-        if req.method() == reqwest::Method::POST {
-            forward_req = forward_req.body(body);
-        }
-
-        match forward_req.send().await {
-            Ok(resp) => {
-                let mut builder = HttpResponse::build(resp.status());
-
-                // Forward response headers
-                for (key, value) in resp.headers() {
-                    builder.append_header((key, value));
-                }
+    proxy
+        .forward(service, tenant, &path, &req, payload, None)
+        .await
+}
 
-                Ok(builder.body(resp.bytes().await.unwrap_or_default()))
-            }
-            Err(_) => Ok(HttpResponse::InternalServerError().finish())
-        }
-    } else {
-        Ok(HttpResponse::NotFound().finish())
-    }
+/// Forwards a request the [`RouteGate`](crate::middleware::RouteGate) let
+/// through to the upstream of its route
+pub async fn table_route(
+    req: HttpRequest,
+    payload: web::Payload,
+    registry: web::Data<ServiceRegistry>,
+    proxy: web::Data<Proxy>,
+) -> Result<HttpResponse, ApiError> {
+    let (matched, tenant) = {
+        let extensions = req.extensions();
+        let matched = extensions.get::<MatchedRoute>().cloned().ok_or_else(|| {
+            ApiError::InternalError("Table route without a matched route".to_string())
+        })?;
+        (matched, extensions.get::<Claims>().and_then(Claims::tenant))
+    };
+
+    let service = registry.get(matched.route.upstream())?;
+    let path = matched.upstream_path(req.path(), req.query_string());
+    proxy
+        .forward(
+            service,
+            tenant,
+            &path,
+            &req,
+            payload,
+            matched.route.timeout(),
+        )
+        .await
 }
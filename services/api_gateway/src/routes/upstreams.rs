@@ -0,0 +1,61 @@
+use actix_web::{web, HttpResponse};
+
+use crate::{
+    error::ApiError,
+    models::ApiResponse,
+    proxy::{ServiceConfig, ServiceRegistry, ServiceStatus},
+};
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("", web::get().to(list_upstreams))
+        .route("/{service}", web::get().to(get_upstream))
+        .route("/{service}", web::put().to(put_upstream))
+        .route("/{service}", web::delete().to(delete_upstream));
+}
+
+/// Services with the state of their instances
+async fn list_upstreams(registry: web::Data<ServiceRegistry>) -> HttpResponse {
+    HttpResponse::Ok().json(ApiResponse::success(registry.status()))
+}
+
+async fn get_upstream(
+    registry: web::Data<ServiceRegistry>,
+    service: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let status = find_service(&registry, &service)?;
+    Ok(HttpResponse::Ok().json(ApiResponse::success(status)))
+}
+
+/// Adds or replaces a service on this replica, until the upstreams file
+/// changes
+async fn put_upstream(
+    registry: web::Data<ServiceRegistry>,
+    service: web::Path<String>,
+    config: web::Json<ServiceConfig>,
+) -> Result<HttpResponse, ApiError> {
+    registry.set_service(&service, config.into_inner())?;
+    tracing::info!("Service {} was changed through the admin API", service);
+    let status = find_service(&registry, &service)?;
+    Ok(HttpResponse::Ok().json(ApiResponse::success(status)))
+}
+
+async fn delete_upstream(
+    registry: web::Data<ServiceRegistry>,
+    service: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    if !registry.remove_service(&service) {
+        return Err(unknown(&service));
+    }
+    tracing::info!("Service {} was removed through the admin API", service);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn find_service(registry: &ServiceRegistry, service: &str) -> Result<ServiceStatus, ApiError> {
+    registry
+        .service_status(service)
+        .ok_or_else(|| unknown(service))
+}
+
+fn unknown(service: &str) -> ApiError {
+    ApiError::NotFoundError(format!("Unknown service '{}'", service))
+}
@@ -0,0 +1,79 @@
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+
+use crate::{
+    db::{self, DbPool},
+    error::ApiError,
+    middleware::Claims,
+    models::{ApiResponse, QuotaStatus, UsageQuery, UsageReport},
+    usage::{month_start, next_month_start, Granularity, Meter},
+};
+
+/// Most periods returned at once, e.g. a day of minutes
+const MAX_PERIODS: i64 = 1_440;
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("", web::get().to(get_usage));
+}
+
+/// Consumption of the caller's tenant per period, per route and against its
+/// monthly quota
+async fn get_usage(
+    pool: web::Data<DbPool>,
+    meter: web::Data<Meter>,
+    claims: web::ReqData<Claims>,
+    query: web::Query<UsageQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let tenant_id = claims
+        .tenant()
+        .ok_or_else(|| ApiError::AuthenticationError("Invalid subject".to_string()))?;
+
+    let now = Utc::now();
+    let granularity = query.granularity.unwrap_or(Granularity::Day);
+    let from = granularity.truncate(query.from.unwrap_or_else(|| month_start(now)));
+    let to = query.to.unwrap_or(now);
+    if to <= from {
+        return Err(ApiError::ValidationError(
+            "'to' must be after 'from'".to_string(),
+        ));
+    }
+    if (to - from).num_seconds() / granularity.duration().num_seconds() > MAX_PERIODS {
+        return Err(ApiError::ValidationError(format!(
+            "At most {} periods can be requested; use a coarser granularity",
+            MAX_PERIODS
+        )));
+    }
+
+    let periods = db::usage_periods(
+        &pool,
+        tenant_id,
+        granularity,
+        from,
+        to,
+        query.route.as_deref(),
+    )
+    .await?;
+    let routes = db::usage_by_route(
+        &pool,
+        tenant_id,
+        Granularity::Day.truncate(from),
+        Granularity::Day.truncate(to) + Granularity::Day.duration(),
+    )
+    .await?;
+    let (used, limits) = meter.month_to_date(tenant_id).await;
+
+    Ok(HttpResponse::Ok().json(ApiResponse::success(UsageReport {
+        tenant_id,
+        granularity,
+        from,
+        to,
+        periods,
+        routes,
+        quota: QuotaStatus {
+            period_start: month_start(now),
+            resets_at: next_month_start(now),
+            used,
+            limits,
+        },
+    })))
+}
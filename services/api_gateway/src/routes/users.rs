@@ -1,15 +1,16 @@
 use actix_web::{web, HttpResponse};
-use bcrypt::{hash, verify, DEFAULT_COST};
-use jsonwebtoken::{encode, EncodingKey, Header};
+use lotabots_password::{PasswordHasher, Verification};
+use lotabots_secrets::KeyRing;
 use uuid::Uuid;
 use validator::Validate;
 
 use crate::{
     db::{self, DbPool},
     error::ApiError,
-    middleware::Claims,
+    middleware::{sign_jwt, Claims, Principal},
     models::{ApiResponse, CreateUserRequest, LoginResponse, User, UserLogin},
     utils::validate_password,
+    worker::{Priority, WorkerPool},
 };
 
 pub fn configure_auth(cfg: &mut web::ServiceConfig) {
@@ -30,8 +31,35 @@ pub fn configure_users(cfg: &mut web::ServiceConfig) {
         .route("/{id}", web::delete().to(delete_user));
 }
 
+/// Hashes on the worker pool, keeping the deliberately slow hashing off the
+/// request threads
+async fn hash_password(
+    workers: &WorkerPool,
+    hasher: &web::Data<PasswordHasher>,
+    password: &str,
+) -> Result<String, ApiError> {
+    let (hasher, password) = (hasher.clone(), password.to_string());
+    Ok(workers
+        .submit_blocking(Priority::High, move || hasher.hash(&password))?
+        .await??)
+}
+
+async fn verify_password(
+    workers: &WorkerPool,
+    hasher: &web::Data<PasswordHasher>,
+    password: &str,
+    stored: &str,
+) -> Result<Verification, ApiError> {
+    let (hasher, password, stored) = (hasher.clone(), password.to_string(), stored.to_string());
+    Ok(workers
+        .submit_blocking(Priority::High, move || hasher.verify(&password, &stored))?
+        .await??)
+}
+
 pub async fn create_user(
     pool: web::Data<DbPool>,
+    hasher: web::Data<PasswordHasher>,
+    workers: web::Data<WorkerPool>,
     user_data: web::Json<CreateUserRequest>,
 ) -> Result<HttpResponse, ApiError> {
     // Validate request
@@ -41,7 +69,7 @@ pub async fn create_user(
     validate_password(&user_data.password, &[&user_data.username, &user_data.email])?;
 
     // Hash password
-    let hashed_password = hash(user_data.password.as_bytes(), DEFAULT_COST)?;
+    let hashed_password = hash_password(&workers, &hasher, &user_data.password).await?;
 
     // Create user in database
     let user_id = db::create_user(
@@ -78,6 +106,8 @@ pub async fn get_user(
 
 pub async fn update_user(
     pool: web::Data<DbPool>,
+    hasher: web::Data<PasswordHasher>,
+    workers: web::Data<WorkerPool>,
     id: web::Path<Uuid>,
     user_data: web::Json<User>,
 ) -> Result<HttpResponse, ApiError> {
@@ -93,7 +123,7 @@ pub async fn update_user(
         validate_password(password, &[&existing_user.username, &existing_user.email])?;
 
         // Hash new password
-        let hashed_password = hash(password.as_bytes(), DEFAULT_COST)?;
+        let hashed_password = hash_password(&workers, &hasher, password).await?;
 
         // Update user with new password
         db::update_user_with_password(
@@ -133,6 +163,9 @@ pub async fn delete_user(
 
 pub async fn login(
     pool: web::Data<DbPool>,
+    hasher: web::Data<PasswordHasher>,
+    workers: web::Data<WorkerPool>,
+    jwt_keys: web::Data<KeyRing>,
     credentials: web::Json<UserLogin>,
 ) -> Result<HttpResponse, ApiError> {
     // Validate request
@@ -148,23 +181,31 @@ pub async fn login(
         .await?
         .ok_or_else(|| ApiError::AuthenticationError("Invalid credentials".to_string()))?;
 
-    // Verify password
-    if !verify(credentials.password.as_bytes(), &password_hash)? {
-        return Err(ApiError::AuthenticationError("Invalid credentials".to_string()));
+    // Verify password, upgrading the stored hash if it is outdated
+    match verify_password(&workers, &hasher, &credentials.password, &password_hash).await? {
+        Verification::Invalid => {
+            return Err(ApiError::AuthenticationError("Invalid credentials".to_string()));
+        }
+        Verification::NeedsRehash(new_hash) => {
+            // The login already succeeded; a failed upgrade is retried on
+            // the next one
+            if let Err(e) = db::update_password_hash(&pool, user_id, &new_hash).await {
+                tracing::warn!("Failed to upgrade password hash: {}", e);
+            }
+        }
+        Verification::Valid => {}
     }
 
     // Generate JWT token
     let claims = Claims {
         sub: user_id.to_string(),
         exp: (chrono::Utc::now() + chrono::Duration::hours(24)).timestamp() as usize,
+        tenant_id: None,
+        permissions: Vec::new(),
+        principal: Principal::User,
     };
 
-    let jwt_secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(jwt_secret.as_bytes()),
-    )?;
+    let token = sign_jwt(&jwt_keys, &claims)?;
 
     // Get user data
     let user = db::get_user_by_id(&pool, user_id)
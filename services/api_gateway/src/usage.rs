@@ -0,0 +1,702 @@
+//! Usage metering for billing.
+//!
+//! [`UsageMeter`](crate::middleware::UsageMeter) records a [`UsageEvent`] for
+//! every authenticated request on a bounded channel, so requests never wait
+//! on the database. A background writer rolls the events up per tenant,
+//! route and minute, hour and day, and adds the rollups to `usage_rollups`
+//! in batches. Monthly quotas per subscription tier are checked against the
+//! stored totals plus the usage not written yet; the stored totals of a
+//! tenant are read the first time it is checked each month, so usage from
+//! before the gateway started counts too.
+
+use chrono::{DateTime, Datelike, DurationRound, TimeZone, Utc};
+use lotabots_rate_limit::Tiers;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use uuid::Uuid;
+
+use crate::{
+    db::{self, DbPool},
+    error::ApiError,
+};
+
+/// Response header upstream services report the model tokens a request
+/// consumed in; removed before the response reaches the client
+pub const TOKENS_HEADER: &str = "X-Usage-Tokens";
+
+/// Minute rollups are pruned after this long
+const MINUTE_RETENTION: chrono::Duration = chrono::Duration::days(7);
+/// Hour rollups are pruned after this long; day rollups are kept for billing
+const HOUR_RETENTION: chrono::Duration = chrono::Duration::days(90);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// One metered request
+#[derive(Debug, Clone)]
+pub struct UsageEvent {
+    pub tenant_id: Uuid,
+    /// Route pattern, e.g. `/api/v1/products/{id}`
+    pub route: String,
+    pub status: u16,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    pub latency: Duration,
+    pub tokens: u64,
+    pub at: DateTime<Utc>,
+}
+
+/// Usage summed over requests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Totals {
+    pub requests: i64,
+    /// Requests answered with a 4xx or 5xx status
+    pub errors: i64,
+    pub request_bytes: i64,
+    pub response_bytes: i64,
+    pub tokens: i64,
+    /// Total over all requests
+    pub latency_ms: i64,
+}
+
+impl Totals {
+    /// Bytes transferred in either direction
+    pub fn bytes(&self) -> i64 {
+        self.request_bytes + self.response_bytes
+    }
+
+    fn add(&mut self, other: &Totals) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.request_bytes += other.request_bytes;
+        self.response_bytes += other.response_bytes;
+        self.tokens += other.tokens;
+        self.latency_ms += other.latency_ms;
+    }
+
+    fn subtract(&mut self, other: &Totals) {
+        self.requests -= other.requests;
+        self.errors -= other.errors;
+        self.request_bytes -= other.request_bytes;
+        self.response_bytes -= other.response_bytes;
+        self.tokens -= other.tokens;
+        self.latency_ms -= other.latency_ms;
+    }
+}
+
+impl From<&UsageEvent> for Totals {
+    fn from(event: &UsageEvent) -> Self {
+        Self {
+            requests: 1,
+            errors: i64::from(event.status >= 400),
+            request_bytes: event.request_bytes as i64,
+            response_bytes: event.response_bytes as i64,
+            tokens: event.tokens as i64,
+            latency_ms: event.latency.as_millis() as i64,
+        }
+    }
+}
+
+/// Length of the periods usage is rolled up in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    pub const ALL: [Self; 3] = [Self::Minute, Self::Hour, Self::Day];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Minute => "minute",
+            Self::Hour => "hour",
+            Self::Day => "day",
+        }
+    }
+
+    pub fn duration(&self) -> chrono::Duration {
+        match self {
+            Self::Minute => chrono::Duration::minutes(1),
+            Self::Hour => chrono::Duration::hours(1),
+            Self::Day => chrono::Duration::days(1),
+        }
+    }
+
+    /// Start of the period `at` falls in
+    pub fn truncate(&self, at: DateTime<Utc>) -> DateTime<Utc> {
+        at.duration_trunc(self.duration()).unwrap_or(at)
+    }
+}
+
+/// Start of the calendar month `at` falls in, which quotas are counted over
+pub fn month_start(at: DateTime<Utc>) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(at.year(), at.month(), 1, 0, 0, 0)
+        .single()
+        .unwrap_or(at)
+}
+
+/// Start of the calendar month after the one `at` falls in
+pub fn next_month_start(at: DateTime<Utc>) -> DateTime<Utc> {
+    let (year, month) = match at.month() {
+        12 => (at.year() + 1, 1),
+        month => (at.year(), month + 1),
+    };
+    Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0)
+        .single()
+        .unwrap_or(at)
+}
+
+/// A stored counter row
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RollupKey {
+    pub tenant_id: Uuid,
+    pub granularity: Granularity,
+    pub period_start: DateTime<Utc>,
+    pub route: String,
+}
+
+/// Adds `event` to its minute, hour and day rollups
+fn roll_up(rollups: &mut HashMap<RollupKey, Totals>, event: &UsageEvent) {
+    let totals = Totals::from(event);
+    for granularity in Granularity::ALL {
+        let key = RollupKey {
+            tenant_id: event.tenant_id,
+            granularity,
+            period_start: granularity.truncate(event.at),
+            route: event.route.clone(),
+        };
+        rollups.entry(key).or_default().add(&totals);
+    }
+}
+
+/// Monthly allowance of a tier; `None` is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub requests: Option<i64>,
+    pub tokens: Option<i64>,
+    /// Request and response bytes
+    pub bytes: Option<i64>,
+}
+
+impl Quota {
+    /// The first allowance `used` has reached, and its size
+    pub fn exceeded(&self, used: &Totals) -> Option<(&'static str, i64)> {
+        [
+            ("requests", self.requests, used.requests),
+            ("tokens", self.tokens, used.tokens),
+            ("bytes", self.bytes, used.bytes()),
+        ]
+        .into_iter()
+        .find_map(|(metric, limit, used)| {
+            limit
+                .filter(|limit| used >= *limit)
+                .map(|limit| (metric, limit))
+        })
+    }
+}
+
+/// Monthly quotas per subscription tier. Tenants are put on tiers as for
+/// rate limits, see [`Tiers::from_env`].
+#[derive(Debug, Clone)]
+pub struct Quotas {
+    tiers: Tiers,
+    quotas: HashMap<String, Quota>,
+}
+
+impl Quotas {
+    /// No quota on any tier
+    pub fn unlimited(tiers: Tiers) -> Self {
+        Self {
+            tiers,
+            quotas: HashMap::new(),
+        }
+    }
+
+    /// The default quotas of the free and professional tiers; enterprise is
+    /// unlimited
+    pub fn new(tiers: Tiers) -> Self {
+        const GB: i64 = 1024 * 1024 * 1024;
+        Self::unlimited(tiers)
+            .with_quota(
+                "free",
+                Quota {
+                    requests: Some(30_000),
+                    tokens: Some(1_000_000),
+                    bytes: Some(10 * GB),
+                },
+            )
+            .with_quota(
+                "professional",
+                Quota {
+                    requests: Some(300_000),
+                    tokens: Some(20_000_000),
+                    bytes: Some(100 * GB),
+                },
+            )
+    }
+
+    /// Adds or replaces the quota of a tier
+    pub fn with_quota(mut self, tier: &str, quota: Quota) -> Self {
+        self.quotas.insert(tier.to_string(), quota);
+        self
+    }
+
+    /// Starts from [`Quotas::new`] and applies `USAGE_QUOTAS`:
+    /// `<tier>.<requests|tokens|bytes>=<amount|unlimited>` pairs, e.g.
+    /// `free.tokens=500000,professional.bytes=unlimited`
+    pub fn from_env(tiers: Tiers) -> Result<Self, ApiError> {
+        let mut quotas = Self::new(tiers);
+        let Ok(value) = env::var("USAGE_QUOTAS") else {
+            return Ok(quotas);
+        };
+
+        for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let invalid =
+                || ApiError::InternalError(format!("Invalid USAGE_QUOTAS entry '{}'", pair));
+            let (name, amount) = pair.split_once('=').ok_or_else(invalid)?;
+            let (tier, metric) = name.trim().split_once('.').ok_or_else(invalid)?;
+            let amount = match amount.trim() {
+                "unlimited" => None,
+                amount => Some(amount.parse().map_err(|_| invalid())?),
+            };
+
+            let quota = quotas.quotas.entry(tier.to_string()).or_default();
+            match metric {
+                "requests" => quota.requests = amount,
+                "tokens" => quota.tokens = amount,
+                "bytes" => quota.bytes = amount,
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(quotas)
+    }
+
+    /// The quota of the tenant's tier
+    pub fn quota_for(&self, tenant_id: Uuid) -> Quota {
+        let tier = self.tiers.tier_of(&tenant_id.to_string());
+        self.quotas.get(tier).copied().unwrap_or_default()
+    }
+}
+
+/// A tenant has used up a monthly allowance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub metric: &'static str,
+    pub limit: i64,
+    pub resets_at: DateTime<Utc>,
+}
+
+/// What each tenant has used this month
+#[derive(Debug, Default)]
+struct TenantUsage {
+    month: Option<DateTime<Utc>>,
+    /// Whether `stored` has been read from the database this month
+    loaded: bool,
+    /// Written to the database, as of the last flush
+    stored: Totals,
+    /// Recorded but not written yet
+    pending: Totals,
+}
+
+struct Ledger {
+    quotas: Quotas,
+    tenants: Mutex<HashMap<Uuid, TenantUsage>>,
+}
+
+impl Ledger {
+    /// Runs `f` on the tenant's usage of the month `at` falls in, starting
+    /// over when a new month has begun
+    fn with<T>(
+        &self,
+        tenant_id: Uuid,
+        at: DateTime<Utc>,
+        f: impl FnOnce(&mut TenantUsage) -> T,
+    ) -> T {
+        let month = month_start(at);
+        let mut tenants = self.tenants.lock().unwrap();
+        let usage = tenants.entry(tenant_id).or_default();
+        if usage.month != Some(month) {
+            *usage = TenantUsage {
+                month: Some(month),
+                ..TenantUsage::default()
+            };
+        }
+        f(usage)
+    }
+
+    fn used(&self, tenant_id: Uuid, now: DateTime<Utc>) -> Totals {
+        self.with(tenant_id, now, |usage| {
+            let mut used = usage.stored;
+            used.add(&usage.pending);
+            used
+        })
+    }
+
+    /// Moves `written` from pending to stored usage, taking the stored
+    /// totals from the database when they could be read, as other replicas
+    /// add to them too
+    fn settle(
+        &self,
+        tenant_id: Uuid,
+        month: DateTime<Utc>,
+        written: &Totals,
+        stored: Option<Totals>,
+    ) {
+        self.with(tenant_id, month, |usage| {
+            usage.pending.subtract(written);
+            match stored {
+                Some(stored) => {
+                    usage.stored = stored;
+                    usage.loaded = true;
+                }
+                None => usage.stored.add(written),
+            }
+        });
+    }
+
+    /// Takes the stored totals read from the database, unless a flush has
+    /// settled more recent ones in the meantime
+    fn load(&self, tenant_id: Uuid, month: DateTime<Utc>, stored: Totals) {
+        self.with(tenant_id, month, |usage| {
+            if !usage.loaded {
+                usage.stored = stored;
+                usage.loaded = true;
+            }
+        });
+    }
+}
+
+/// Records usage and checks quotas. Cheap to clone; all clones share the
+/// same writer and counters.
+#[derive(Clone)]
+pub struct Meter {
+    events: mpsc::Sender<UsageEvent>,
+    ledger: Arc<Ledger>,
+    /// Where stored usage is read from; `None` for meters from [`Meter::new`]
+    pool: Option<DbPool>,
+}
+
+impl Meter {
+    /// A meter delivering its events to the returned receiver, for at most
+    /// `capacity` events at a time. [`Meter::spawn`] writes them to Postgres.
+    /// Quotas only count the usage recorded by this meter.
+    pub fn new(quotas: Quotas, capacity: usize) -> (Self, mpsc::Receiver<UsageEvent>) {
+        let (events, receiver) = mpsc::channel(capacity.max(1));
+        let meter = Self {
+            events,
+            ledger: Arc::new(Ledger {
+                quotas,
+                tenants: Mutex::new(HashMap::new()),
+            }),
+            pool: None,
+        };
+        (meter, receiver)
+    }
+
+    /// A meter whose events are written to `pool` by a background task, and
+    /// whose quotas also count the usage stored there
+    pub fn spawn(pool: DbPool, quotas: Quotas, options: WriterOptions) -> Self {
+        let (mut meter, events) = Self::new(quotas, options.capacity);
+        tokio::spawn(write(pool.clone(), events, meter.ledger.clone(), options));
+        meter.pool = Some(pool);
+        meter
+    }
+
+    /// The tenant's usage this month, reading what is stored the first time
+    /// the tenant is seen. When that fails only the usage recorded here is
+    /// counted, and the read is tried again next time.
+    async fn used(&self, tenant_id: Uuid, now: DateTime<Utc>) -> Totals {
+        if let Some(pool) = &self.pool {
+            if !self.ledger.with(tenant_id, now, |usage| usage.loaded) {
+                let month = month_start(now);
+                match db::usage_totals(pool, tenant_id, month, next_month_start(month)).await {
+                    Ok(stored) => self.ledger.load(tenant_id, month, stored),
+                    Err(e) => {
+                        tracing::warn!("Failed to read usage of tenant {}: {}", tenant_id, e)
+                    }
+                }
+            }
+        }
+        self.ledger.used(tenant_id, now)
+    }
+
+    /// Queues the event for writing. Events are dropped rather than slowing
+    /// requests down when the writer falls behind.
+    pub fn record(&self, event: UsageEvent) {
+        let (tenant_id, at, totals) = (event.tenant_id, event.at, Totals::from(&event));
+        match self.events.try_send(event) {
+            Ok(()) => self
+                .ledger
+                .with(tenant_id, at, |usage| usage.pending.add(&totals)),
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!(
+                    "Usage queue is full, dropping event for tenant {}",
+                    tenant_id
+                )
+            }
+            Err(TrySendError::Closed(_)) => {
+                tracing::error!(
+                    "Usage writer has stopped, dropping event for tenant {}",
+                    tenant_id
+                )
+            }
+        }
+    }
+
+    /// Fails once the tenant has used up an allowance of its monthly quota.
+    /// Usage recorded by other replicas is seen after their next flush.
+    pub async fn check(&self, tenant_id: Uuid) -> Result<(), QuotaExceeded> {
+        let now = Utc::now();
+        let used = self.used(tenant_id, now).await;
+        match self.ledger.quotas.quota_for(tenant_id).exceeded(&used) {
+            Some((metric, limit)) => Err(QuotaExceeded {
+                metric,
+                limit,
+                resets_at: next_month_start(now),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// The tenant's usage this month as counted for its quota, and the quota
+    pub async fn month_to_date(&self, tenant_id: Uuid) -> (Totals, Quota) {
+        (
+            self.used(tenant_id, Utc::now()).await,
+            self.ledger.quotas.quota_for(tenant_id),
+        )
+    }
+}
+
+/// How usage is written
+#[derive(Debug, Clone)]
+pub struct WriterOptions {
+    /// Events queued at most before new ones are dropped
+    pub capacity: usize,
+    /// Longest time events wait before being written
+    pub flush_interval: Duration,
+    /// Events after which a batch is written without waiting
+    pub max_batch: usize,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            flush_interval: Duration::from_secs(5),
+            max_batch: 1_000,
+        }
+    }
+}
+
+impl WriterOptions {
+    /// Defaults overridden by `USAGE_QUEUE_CAPACITY` and
+    /// `USAGE_FLUSH_INTERVAL_SECS`
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let var = |name| env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+        Self {
+            capacity: var("USAGE_QUEUE_CAPACITY").map_or(defaults.capacity, |v| v as usize),
+            flush_interval: var("USAGE_FLUSH_INTERVAL_SECS")
+                .map_or(defaults.flush_interval, Duration::from_secs),
+            ..defaults
+        }
+    }
+}
+
+/// Events received since the last write, rolled up
+#[derive(Default)]
+struct Batch {
+    rollups: HashMap<RollupKey, Totals>,
+    /// Per tenant and month, to settle the ledger with
+    written: HashMap<(Uuid, DateTime<Utc>), Totals>,
+    events: usize,
+}
+
+impl Batch {
+    fn add(&mut self, event: &UsageEvent) {
+        roll_up(&mut self.rollups, event);
+        self.written
+            .entry((event.tenant_id, month_start(event.at)))
+            .or_default()
+            .add(&Totals::from(event));
+        self.events += 1;
+    }
+
+    /// Writes the batch. On failure it is kept and retried with the next one.
+    async fn flush(&mut self, pool: &DbPool, ledger: &Ledger) {
+        if self.rollups.is_empty() {
+            return;
+        }
+        if let Err(e) = db::record_usage(pool, &self.rollups).await {
+            tracing::error!("Failed to write usage, retrying with the next batch: {}", e);
+            self.events = 0;
+            return;
+        }
+
+        for ((tenant_id, month), written) in self.written.drain() {
+            let stored = db::usage_totals(pool, tenant_id, month, next_month_start(month))
+                .await
+                .map_err(|e| tracing::warn!("Failed to read usage of tenant {}: {}", tenant_id, e))
+                .ok();
+            ledger.settle(tenant_id, month, &written, stored);
+        }
+        self.rollups.clear();
+        self.events = 0;
+    }
+}
+
+async fn write(
+    pool: DbPool,
+    mut events: mpsc::Receiver<UsageEvent>,
+    ledger: Arc<Ledger>,
+    options: WriterOptions,
+) {
+    let mut batch = Batch::default();
+    let mut ticker = tokio::time::interval(options.flush_interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut last_prune: Option<Instant> = None;
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => {
+                    batch.add(&event);
+                    if batch.events < options.max_batch {
+                        continue;
+                    }
+                }
+                None => {
+                    batch.flush(&pool, &ledger).await;
+                    return;
+                }
+            },
+            _ = ticker.tick() => {}
+        }
+        batch.flush(&pool, &ledger).await;
+
+        if last_prune.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+            last_prune = Some(Instant::now());
+            let now = Utc::now();
+            for (granularity, retention) in [
+                (Granularity::Minute, MINUTE_RETENTION),
+                (Granularity::Hour, HOUR_RETENTION),
+            ] {
+                if let Err(e) = db::prune_usage(&pool, granularity, now - retention).await {
+                    tracing::warn!(
+                        "Failed to prune {} usage rollups: {}",
+                        granularity.as_str(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lotabots_rate_limit::RateLimit;
+
+    fn event(tenant_id: Uuid, at: DateTime<Utc>, tokens: u64) -> UsageEvent {
+        UsageEvent {
+            tenant_id,
+            route: "/api/v1/products".to_string(),
+            status: 200,
+            request_bytes: 100,
+            response_bytes: 400,
+            latency: Duration::from_millis(20),
+            tokens,
+            at,
+        }
+    }
+
+    #[test]
+    fn test_events_roll_up_per_period() {
+        let tenant = Uuid::new_v4();
+        let at = Utc.with_ymd_and_hms(2025, 1, 31, 23, 59, 30).unwrap();
+        let mut batch = Batch::default();
+        batch.add(&event(tenant, at, 10));
+        batch.add(&event(tenant, at + chrono::Duration::seconds(20), 5));
+        batch.add(&UsageEvent {
+            status: 503,
+            ..event(tenant, at + chrono::Duration::seconds(40), 0)
+        });
+
+        let rollup = |granularity, period_start| {
+            batch.rollups[&RollupKey {
+                tenant_id: tenant,
+                granularity,
+                period_start,
+                route: "/api/v1/products".to_string(),
+            }]
+        };
+
+        // The first two fall into the last minute of January, the third into February
+        let minute = rollup(
+            Granularity::Minute,
+            Utc.with_ymd_and_hms(2025, 1, 31, 23, 59, 0).unwrap(),
+        );
+        assert_eq!(minute.requests, 2);
+        assert_eq!(minute.tokens, 15);
+        assert_eq!(minute.bytes(), 1000);
+        assert_eq!(minute.latency_ms, 40);
+        let day = rollup(
+            Granularity::Day,
+            Utc.with_ymd_and_hms(2025, 2, 1, 0, 0, 0).unwrap(),
+        );
+        assert_eq!((day.requests, day.errors), (1, 1));
+        assert_eq!(batch.rollups.len(), 6);
+
+        assert_eq!(batch.written.len(), 2);
+        assert_eq!(
+            next_month_start(Utc.with_ymd_and_hms(2025, 12, 15, 8, 0, 0).unwrap()),
+            Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quotas_count_unwritten_usage() {
+        let tenant = Uuid::new_v4();
+        let tiers = Tiers::new("free", RateLimit::per_second(10))
+            .with_tier("enterprise", RateLimit::per_second(10))
+            .assign(&Uuid::nil().to_string(), "enterprise");
+        let quotas = Quotas::unlimited(tiers).with_quota(
+            "free",
+            Quota {
+                tokens: Some(100),
+                ..Quota::default()
+            },
+        );
+        let (meter, mut events) = Meter::new(quotas, 16);
+
+        meter.record(event(tenant, Utc::now(), 60));
+        assert!(meter.check(tenant).await.is_ok());
+        meter.record(event(tenant, Utc::now(), 40));
+        let exceeded = meter.check(tenant).await.unwrap_err();
+        assert_eq!((exceeded.metric, exceeded.limit), ("tokens", 100));
+
+        // Writing the events moves them to the stored totals
+        let mut batch = Batch::default();
+        while let Ok(event) = events.try_recv() {
+            batch.add(&event);
+        }
+        for ((tenant_id, month), written) in batch.written.drain() {
+            meter.ledger.settle(tenant_id, month, &written, None);
+        }
+        assert_eq!(meter.month_to_date(tenant).await.0.tokens, 100);
+        assert!(meter.check(tenant).await.is_err());
+
+        // Other tiers have their own quota
+        meter.record(event(Uuid::nil(), Utc::now(), 1_000));
+        assert!(meter.check(Uuid::nil()).await.is_ok());
+    }
+}
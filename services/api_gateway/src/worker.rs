@@ -0,0 +1,570 @@
+//! Background jobs.
+//!
+//! A [`WorkerPool`] runs jobs off the request path on a fixed number of
+//! workers. Jobs wait in one bounded queue per [`Priority`] and workers
+//! always take the most urgent one first. When a queue is full, submitting
+//! fails with [`JobError::Saturated`], which handlers answer with 503 rather
+//! than piling up more work. Every job runs under a timeout, past which it
+//! fails with [`JobError::TimedOut`] and is answered with 503 as well. On
+//! shutdown the pool stops accepting jobs and drains its queues within a
+//! deadline.
+//!
+//! Workers are tasks on the runtime the pool was created on. CPU-bound or
+//! blocking work goes through [`WorkerPool::submit_blocking`], so it runs on
+//! tokio's blocking threads while still counting against the pool's
+//! concurrency.
+
+use futures_util::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    env,
+    future::Future,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::{
+    sync::{oneshot, Notify},
+    task::JoinHandle,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Work a client is waiting for
+    High,
+    Normal,
+    /// Work nobody waits for, e.g. notifications
+    Low,
+}
+
+impl Priority {
+    /// Index of the priority's queue, most urgent first
+    fn lane(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum JobError {
+    #[error("The {0:?} priority queue is full")]
+    Saturated(Priority),
+
+    #[error("The worker pool is shutting down")]
+    ShuttingDown,
+
+    #[error("Job timed out after {0:?}")]
+    TimedOut(Duration),
+
+    #[error("Job panicked")]
+    Panicked,
+
+    /// Dropped from the queue or aborted when draining took too long
+    #[error("Job was cancelled")]
+    Cancelled,
+}
+
+/// How the pool runs jobs
+#[derive(Debug, Clone)]
+pub struct WorkerOptions {
+    /// Jobs run at once
+    pub workers: usize,
+    /// Jobs waiting at most per priority
+    pub queue_capacity: usize,
+    /// Longest a job may run unless submitted with its own timeout
+    pub job_timeout: Duration,
+    /// Longest shutdown waits for queued and running jobs
+    pub drain_timeout: Duration,
+}
+
+impl Default for WorkerOptions {
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            queue_capacity: 1_000,
+            job_timeout: Duration::from_secs(30),
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl WorkerOptions {
+    /// Defaults overridden by `WORKER_POOL_SIZE`, `WORKER_QUEUE_CAPACITY`,
+    /// `WORKER_JOB_TIMEOUT_SECS` and `WORKER_DRAIN_TIMEOUT_SECS`
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let var = |name| env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+        Self {
+            workers: var("WORKER_POOL_SIZE").map_or(defaults.workers, |v| v as usize),
+            queue_capacity: var("WORKER_QUEUE_CAPACITY")
+                .map_or(defaults.queue_capacity, |v| v as usize),
+            job_timeout: var("WORKER_JOB_TIMEOUT_SECS")
+                .map_or(defaults.job_timeout, Duration::from_secs),
+            drain_timeout: var("WORKER_DRAIN_TIMEOUT_SECS")
+                .map_or(defaults.drain_timeout, Duration::from_secs),
+        }
+    }
+}
+
+/// Jobs waiting per priority
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueDepth {
+    pub high: usize,
+    pub normal: usize,
+    pub low: usize,
+}
+
+/// Snapshot of the pool's load and of its jobs since it started
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerStats {
+    pub workers: usize,
+    /// Workers running a job
+    pub busy: usize,
+    pub queued: QueueDepth,
+    pub queue_capacity: usize,
+    pub submitted: u64,
+    /// Turned away because their queue was full
+    pub rejected: u64,
+    pub completed: u64,
+    pub timed_out: u64,
+    pub panicked: u64,
+    /// Time from submission until a worker started the job
+    pub mean_wait_ms: u64,
+    pub max_wait_ms: u64,
+    /// Time from start until the job finished or timed out
+    pub mean_run_ms: u64,
+    pub max_run_ms: u64,
+}
+
+/// Result of a submitted job. Dropping the handle does not cancel the job.
+pub struct JobHandle<T> {
+    result: oneshot::Receiver<Result<T, JobError>>,
+}
+
+impl<T> Future for JobHandle<T> {
+    type Output = Result<T, JobError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.result)
+            .poll(cx)
+            .map(|result| result.unwrap_or(Err(JobError::Cancelled)))
+    }
+}
+
+enum Outcome {
+    Completed,
+    TimedOut,
+    Panicked,
+}
+
+struct Queued {
+    job: Pin<Box<dyn Future<Output = Outcome> + Send>>,
+    enqueued: Instant,
+}
+
+#[derive(Default)]
+struct Queues {
+    lanes: [VecDeque<Queued>; 3],
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Counters {
+    busy: AtomicUsize,
+    submitted: AtomicU64,
+    rejected: AtomicU64,
+    completed: AtomicU64,
+    timed_out: AtomicU64,
+    panicked: AtomicU64,
+    started: AtomicU64,
+    wait_us: AtomicU64,
+    max_wait_us: AtomicU64,
+    finished: AtomicU64,
+    run_us: AtomicU64,
+    max_run_us: AtomicU64,
+}
+
+impl Counters {
+    fn started(&self, wait: Duration) {
+        let us = wait.as_micros() as u64;
+        self.started.fetch_add(1, Ordering::Relaxed);
+        self.wait_us.fetch_add(us, Ordering::Relaxed);
+        self.max_wait_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    fn finished(&self, outcome: Outcome, run: Duration) {
+        let us = run.as_micros() as u64;
+        self.finished.fetch_add(1, Ordering::Relaxed);
+        self.run_us.fetch_add(us, Ordering::Relaxed);
+        self.max_run_us.fetch_max(us, Ordering::Relaxed);
+        let counter = match outcome {
+            Outcome::Completed => &self.completed,
+            Outcome::TimedOut => &self.timed_out,
+            Outcome::Panicked => &self.panicked,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    /// Signalled when a job is queued or the pool closes
+    available: Notify,
+    counters: Counters,
+}
+
+impl Shared {
+    /// The most urgent job, or `None` once the pool is closed and drained
+    async fn next(&self) -> Option<Queued> {
+        loop {
+            let available = self.available.notified();
+            {
+                let mut queues = self.queues.lock().unwrap();
+                if let Some(queued) = queues.lanes.iter_mut().find_map(VecDeque::pop_front) {
+                    return Some(queued);
+                }
+                if queues.closed {
+                    return None;
+                }
+            }
+            available.await;
+        }
+    }
+}
+
+/// Runs background jobs; cheap to clone
+#[derive(Clone)]
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+    options: WorkerOptions,
+    workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl WorkerPool {
+    /// Starts the workers on the current runtime
+    pub fn new(options: WorkerOptions) -> Self {
+        let shared = Arc::new(Shared {
+            queues: Mutex::default(),
+            available: Notify::new(),
+            counters: Counters::default(),
+        });
+        let workers = (0..options.workers.max(1))
+            .map(|_| tokio::spawn(work(shared.clone())))
+            .collect();
+
+        Self {
+            shared,
+            options,
+            workers: Arc::new(Mutex::new(workers)),
+        }
+    }
+
+    /// Queues `job` with the default timeout
+    pub fn submit<F>(&self, priority: Priority, job: F) -> Result<JobHandle<F::Output>, JobError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.submit_with_timeout(priority, self.options.job_timeout, job)
+    }
+
+    /// Queues `job`, which is abandoned if it runs longer than `timeout`
+    pub fn submit_with_timeout<F>(
+        &self,
+        priority: Priority,
+        timeout: Duration,
+        job: F,
+    ) -> Result<JobHandle<F::Output>, JobError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (sender, result) = oneshot::channel();
+        let job = async move {
+            let (outcome, result) =
+                match tokio::time::timeout(timeout, AssertUnwindSafe(job).catch_unwind()).await {
+                    Ok(Ok(output)) => (Outcome::Completed, Ok(output)),
+                    Ok(Err(_)) => (Outcome::Panicked, Err(JobError::Panicked)),
+                    Err(_) => (Outcome::TimedOut, Err(JobError::TimedOut(timeout))),
+                };
+            let _ = sender.send(result);
+            outcome
+        };
+
+        {
+            let mut queues = self.shared.queues.lock().unwrap();
+            if queues.closed {
+                return Err(JobError::ShuttingDown);
+            }
+            let lane = &mut queues.lanes[priority.lane()];
+            if lane.len() >= self.options.queue_capacity {
+                self.shared
+                    .counters
+                    .rejected
+                    .fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    "Worker pool {:?} priority queue is full, rejecting job",
+                    priority
+                );
+                return Err(JobError::Saturated(priority));
+            }
+            lane.push_back(Queued {
+                job: Box::pin(job),
+                enqueued: Instant::now(),
+            });
+        }
+        self.shared
+            .counters
+            .submitted
+            .fetch_add(1, Ordering::Relaxed);
+        self.shared.available.notify_one();
+
+        Ok(JobHandle { result })
+    }
+
+    /// Queues blocking or CPU-bound `job` to run on a blocking thread. A
+    /// job that times out is reported as such but cannot be stopped.
+    pub fn submit_blocking<F, T>(
+        &self,
+        priority: Priority,
+        job: F,
+    ) -> Result<JobHandle<T>, JobError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit(priority, async move {
+            match tokio::task::spawn_blocking(job).await {
+                Ok(output) => output,
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            }
+        })
+    }
+
+    pub fn stats(&self) -> WorkerStats {
+        let queued = {
+            let queues = self.shared.queues.lock().unwrap();
+            QueueDepth {
+                high: queues.lanes[Priority::High.lane()].len(),
+                normal: queues.lanes[Priority::Normal.lane()].len(),
+                low: queues.lanes[Priority::Low.lane()].len(),
+            }
+        };
+        let counters = &self.shared.counters;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mean_ms = |total: &AtomicU64, count: &AtomicU64| {
+            load(total).checked_div(load(count)).unwrap_or(0) / 1_000
+        };
+
+        WorkerStats {
+            workers: self.options.workers.max(1),
+            busy: counters.busy.load(Ordering::Relaxed),
+            queued,
+            queue_capacity: self.options.queue_capacity,
+            submitted: load(&counters.submitted),
+            rejected: load(&counters.rejected),
+            completed: load(&counters.completed),
+            timed_out: load(&counters.timed_out),
+            panicked: load(&counters.panicked),
+            mean_wait_ms: mean_ms(&counters.wait_us, &counters.started),
+            max_wait_ms: load(&counters.max_wait_us) / 1_000,
+            mean_run_ms: mean_ms(&counters.run_us, &counters.finished),
+            max_run_ms: load(&counters.max_run_us) / 1_000,
+        }
+    }
+
+    /// Stops accepting jobs and waits up to the drain timeout for queued
+    /// and running ones. Jobs still left after that are cancelled.
+    pub async fn shutdown(&self) {
+        self.shared.queues.lock().unwrap().closed = true;
+        self.shared.available.notify_waiters();
+
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        let aborts: Vec<_> = workers.iter().map(JoinHandle::abort_handle).collect();
+        let drained = tokio::time::timeout(
+            self.options.drain_timeout,
+            futures_util::future::join_all(workers),
+        )
+        .await;
+
+        if drained.is_err() {
+            aborts.iter().for_each(|worker| worker.abort());
+            let dropped: usize = {
+                let mut queues = self.shared.queues.lock().unwrap();
+                queues
+                    .lanes
+                    .iter_mut()
+                    .map(|lane| lane.drain(..).count())
+                    .sum()
+            };
+            tracing::warn!(
+                "Worker pool did not drain within {:?}; cancelled running jobs and {} queued",
+                self.options.drain_timeout,
+                dropped
+            );
+        }
+    }
+}
+
+async fn work(shared: Arc<Shared>) {
+    while let Some(queued) = shared.next().await {
+        let started = Instant::now();
+        shared.counters.started(started - queued.enqueued);
+        shared.counters.busy.fetch_add(1, Ordering::Relaxed);
+        let outcome = queued.job.await;
+        shared.counters.busy.fetch_sub(1, Ordering::Relaxed);
+        shared.counters.finished(outcome, started.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ApiError;
+    use actix_web::{http::StatusCode, ResponseError};
+    use tokio::sync::Semaphore;
+
+    fn pool(workers: usize, queue_capacity: usize) -> WorkerPool {
+        WorkerPool::new(WorkerOptions {
+            workers,
+            queue_capacity,
+            job_timeout: Duration::from_secs(5),
+            drain_timeout: Duration::from_secs(5),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_urgent_jobs_run_first_and_full_queues_reject() {
+        let pool = pool(1, 2);
+        // Keep the only worker busy until the queues are filled
+        let gate = Arc::new(Semaphore::new(0));
+        let blocker = {
+            let gate = gate.clone();
+            pool.submit(Priority::High, async move {
+                let _ = gate.acquire().await;
+            })
+            .unwrap()
+        };
+        while pool.stats().busy == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let record = |priority| {
+            let order = order.clone();
+            pool.submit(
+                priority,
+                async move { order.lock().unwrap().push(priority) },
+            )
+        };
+        let low = record(Priority::Low).unwrap();
+        let normal = record(Priority::Normal).unwrap();
+        let high = record(Priority::High).unwrap();
+        record(Priority::Low).unwrap();
+        assert_eq!(
+            record(Priority::Low).err(),
+            Some(JobError::Saturated(Priority::Low))
+        );
+        assert_eq!(
+            pool.stats().queued,
+            QueueDepth {
+                high: 1,
+                normal: 1,
+                low: 2
+            }
+        );
+
+        gate.add_permits(1);
+        blocker.await.unwrap();
+        low.await.unwrap();
+        normal.await.unwrap();
+        high.await.unwrap();
+        assert_eq!(
+            order.lock().unwrap()[..3],
+            [Priority::High, Priority::Normal, Priority::Low]
+        );
+
+        let stats = pool.stats();
+        assert_eq!((stats.submitted, stats.rejected), (5, 1));
+    }
+
+    #[tokio::test]
+    async fn test_jobs_time_out_and_panics_are_contained() {
+        let pool = pool(1, 10);
+
+        let slow = pool
+            .submit_with_timeout(Priority::Normal, Duration::from_millis(10), async {
+                tokio::time::sleep(Duration::from_secs(60)).await
+            })
+            .unwrap();
+        let timed_out = slow.await.unwrap_err();
+        assert_eq!(timed_out, JobError::TimedOut(Duration::from_millis(10)));
+        // Clients are told to retry, as when the queue is full
+        assert_eq!(
+            ApiError::from(timed_out).error_response().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let panicking = pool
+            .submit_blocking(Priority::Normal, || -> u32 { panic!("boom") })
+            .unwrap();
+        assert_eq!(panicking.await, Err(JobError::Panicked));
+
+        // The worker survives both
+        let answer = pool.submit_blocking(Priority::Normal, || 6 * 7).unwrap();
+        assert_eq!(answer.await, Ok(42));
+
+        let stats = pool.stats();
+        assert_eq!(
+            (stats.completed, stats.timed_out, stats.panicked),
+            (1, 1, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_queued_jobs() {
+        let pool = pool(2, 100);
+        let handles: Vec<_> = (0..10u64)
+            .map(|i| {
+                pool.submit(Priority::Low, async move {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    i
+                })
+                .unwrap()
+            })
+            .collect();
+
+        pool.shutdown().await;
+        assert!(matches!(
+            pool.submit(Priority::High, async {}),
+            Err(JobError::ShuttingDown)
+        ));
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.await, Ok(i as u64));
+        }
+
+        // Jobs still running at the deadline are cancelled
+        let pool = WorkerPool::new(WorkerOptions {
+            workers: 1,
+            drain_timeout: Duration::from_millis(10),
+            ..WorkerOptions::default()
+        });
+        let stuck = pool
+            .submit(
+                Priority::Normal,
+                tokio::time::sleep(Duration::from_secs(60)),
+            )
+            .unwrap();
+        let queued = pool.submit(Priority::Normal, async {}).unwrap();
+        pool.shutdown().await;
+        assert_eq!(stuck.await, Err(JobError::Cancelled));
+        assert_eq!(queued.await, Err(JobError::Cancelled));
+    }
+}
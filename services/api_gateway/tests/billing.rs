@@ -0,0 +1,191 @@
+use actix_web::{test, web, App};
+use api_gateway::{
+    billing::{self, BillingPeriod, Metric, PriceBook},
+    db,
+    middleware::{auth::AuthMiddleware, sign_jwt, Claims, Principal},
+    routes,
+    usage::{Granularity, RollupKey, Totals},
+};
+use chrono::{TimeZone, Utc};
+use lotabots_rate_limit::{RateLimit, Tiers};
+use lotabots_secrets::{KeyRing, Secret};
+use serde_json::json;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+const SECRET: &str = "test_secret";
+const GIB: i64 = 1024 * 1024 * 1024;
+
+fn token_for(user_id: Uuid) -> String {
+    let claims = Claims {
+        sub: user_id.to_string(),
+        exp: (Utc::now().timestamp() + 3600) as usize,
+        tenant_id: None,
+        permissions: Vec::new(),
+        principal: Principal::User,
+    };
+    sign_jwt(&KeyRing::new("JWT_SECRET", Secret::new(SECRET)), &claims).unwrap()
+}
+
+async fn record_requests(pool: &PgPool, tenant_id: Uuid, requests: i64) {
+    let key = RollupKey {
+        tenant_id,
+        granularity: Granularity::Day,
+        period_start: Utc.with_ymd_and_hms(2025, 1, 15, 0, 0, 0).unwrap(),
+        route: "/api/v1/products".to_string(),
+    };
+    let totals = Totals {
+        requests,
+        tokens: 1_000,
+        ..Totals::default()
+    };
+    db::record_usage(pool, &HashMap::from([(key, totals)]))
+        .await
+        .unwrap();
+}
+
+/// Needs `TEST_DATABASE_URL`; skipped otherwise
+#[actix_rt::test]
+async fn test_billing_runs_are_idempotent() {
+    let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL is not set; skipping");
+        return;
+    };
+    let pool = PgPool::connect(&url).await.unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+
+    let tenant = Uuid::new_v4();
+    let tiers = Tiers::new("free", RateLimit::per_second(1))
+        .with_tier("professional", RateLimit::per_second(1))
+        .assign(&tenant.to_string(), "professional");
+    let price_book = PriceBook::default();
+    let period: BillingPeriod = "2025-01".parse().unwrap();
+
+    // Storage and GPU time are reported through the API
+    let app = test::init_service(
+        App::new().app_data(web::Data::new(pool.clone())).service(
+            web::scope("/api/v1/billing")
+                .wrap(AuthMiddleware::new(SECRET.to_string()))
+                .configure(routes::billing::configure),
+        ),
+    )
+    .await;
+    let token = token_for(tenant);
+    let report = |event: serde_json::Value| {
+        test::TestRequest::post()
+            .uri("/api/v1/billing/events")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(event)
+            .to_request()
+    };
+
+    let gpu = json!({
+        "metric": "gpu_seconds",
+        "quantity": 5_400,
+        "occurred_at": "2025-01-10T12:00:00Z",
+        "idempotency_key": "job-1",
+    });
+    assert_eq!(
+        test::call_service(&app, report(gpu.clone()))
+            .await
+            .status()
+            .as_u16(),
+        201
+    );
+    // Retried reports are ignored
+    let resp = test::call_service(&app, report(gpu)).await;
+    assert_eq!(resp.status().as_u16(), 200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["data"]["recorded"], false);
+    for (key, gib, day) in [("snapshot-1", 50, 5), ("snapshot-2", 200, 20)] {
+        let storage = json!({
+            "metric": "storage_bytes",
+            "quantity": gib * GIB,
+            "occurred_at": format!("2025-01-{:02}T00:00:00Z", day),
+            "idempotency_key": key,
+        });
+        assert_eq!(
+            test::call_service(&app, report(storage))
+                .await
+                .status()
+                .as_u16(),
+            201
+        );
+    }
+    // Requests are metered by the gateway
+    let requests = json!({
+        "metric": "api_requests",
+        "quantity": 1,
+        "idempotency_key": "requests-1",
+    });
+    assert_eq!(
+        test::call_service(&app, report(requests))
+            .await
+            .status()
+            .as_u16(),
+        400
+    );
+    record_requests(&pool, tenant, 310_000).await;
+
+    billing::run(&pool, period, &price_book, &tiers)
+        .await
+        .unwrap();
+    let invoices = db::list_invoices(&pool, period, Some(tenant))
+        .await
+        .unwrap();
+    assert_eq!(invoices.len(), 1);
+    let invoice = &invoices[0];
+    assert_eq!(invoice.tier, "professional");
+    let amounts: Vec<_> = invoice
+        .lines
+        .iter()
+        .map(|line| (line.metric, line.quantity, line.amount_cents))
+        .collect();
+    assert_eq!(
+        amounts,
+        vec![
+            (None, 1, 4_900),
+            (Some(Metric::ApiRequests), 310_000, 100),
+            (Some(Metric::Tokens), 1_000, 0),
+            // Billed at its peak, 100 GiB over the included 100 GiB
+            (Some(Metric::StorageBytes), 200 * GIB, 1_000),
+            (Some(Metric::GpuSeconds), 5_400, 500),
+        ]
+    );
+    assert_eq!(invoice.total_cents, 6_500);
+
+    // Running again regenerates the same invoice
+    record_requests(&pool, tenant, 10_000).await;
+    billing::run(&pool, period, &price_book, &tiers)
+        .await
+        .unwrap();
+    let rerun = db::list_invoices(&pool, period, Some(tenant))
+        .await
+        .unwrap();
+    assert_eq!(rerun.len(), 1);
+    assert_eq!(rerun[0].id, invoice.id);
+    assert_eq!(rerun[0].lines.len(), invoice.lines.len());
+    assert_eq!(rerun[0].total_cents, 6_600);
+
+    // Finalized invoices are left alone
+    db::finalize_invoices(&pool, period).await.unwrap();
+    record_requests(&pool, tenant, 10_000).await;
+    let summary = billing::run(&pool, period, &price_book, &tiers)
+        .await
+        .unwrap();
+    assert!(summary.finalized >= 1);
+    let finalized = db::list_invoices(&pool, period, Some(tenant))
+        .await
+        .unwrap();
+    assert_eq!(finalized[0].status, "finalized");
+    assert_eq!(finalized[0].total_cents, 6_600);
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/billing/invoices?period=2025-01")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"][0]["id"], invoice.id.to_string());
+    assert_eq!(body["data"][0]["lines"].as_array().unwrap().len(), 5);
+}
@@ -0,0 +1,149 @@
+use actix_web::{
+    dev::{Service, ServiceResponse},
+    http::header,
+    test, web, App, HttpResponse,
+};
+use api_gateway::{
+    cache::{CacheOptions, MemoryCache},
+    middleware::{auth::AuthMiddleware, sign_jwt, Claims, Principal, ResponseCache},
+};
+use chrono::Utc;
+use lotabots_secrets::{KeyRing, Secret};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use uuid::Uuid;
+
+fn keys() -> KeyRing {
+    KeyRing::new("JWT_SECRET", Secret::new("test_secret"))
+}
+
+fn token(user: Uuid) -> String {
+    let claims = Claims {
+        sub: user.to_string(),
+        exp: (Utc::now().timestamp() + 3600) as usize,
+        tenant_id: None,
+        permissions: Vec::new(),
+        principal: Principal::User,
+    };
+    format!("Bearer {}", sign_jwt(&keys(), &claims).unwrap())
+}
+
+/// Counts the requests that reach it, and answers with the count
+async fn counted(calls: web::Data<AtomicUsize>) -> HttpResponse {
+    let calls = calls.fetch_add(1, Ordering::SeqCst) + 1;
+    HttpResponse::Ok().body(calls.to_string())
+}
+
+async fn uncacheable(calls: web::Data<AtomicUsize>) -> HttpResponse {
+    calls.fetch_add(1, Ordering::SeqCst);
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .finish()
+}
+
+fn header(res: &ServiceResponse, name: &str) -> String {
+    res.headers()
+        .get(name)
+        .map(|v| v.to_str().unwrap().to_string())
+        .unwrap_or_default()
+}
+
+#[actix_rt::test]
+async fn test_responses_are_cached_per_caller_and_invalidated_by_writes() {
+    let calls = web::Data::new(AtomicUsize::new(0));
+    let app = test::init_service(
+        App::new().app_data(calls.clone()).service(
+            web::scope("/api/v1/products")
+                .wrap(ResponseCache::new(
+                    Arc::new(MemoryCache::new(1 << 20)),
+                    &CacheOptions::default(),
+                ))
+                .wrap(AuthMiddleware::with_key_ring(keys()))
+                .route("/live", web::get().to(uncacheable))
+                .route("/{id}", web::get().to(counted))
+                .route("/{id}", web::put().to(counted)),
+        ),
+    )
+    .await;
+    let alice = token(Uuid::new_v4());
+    let bob = token(Uuid::new_v4());
+    let get = |token: &str| {
+        test::TestRequest::get()
+            .uri("/api/v1/products/1")
+            .insert_header((header::AUTHORIZATION, token))
+    };
+
+    let res = app.call(get(&alice).to_request()).await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(header(&res, "x-cache"), "MISS");
+    assert_eq!(header(&res, "cache-control"), "private, no-cache");
+    let etag = header(&res, "etag");
+    assert!(!etag.is_empty());
+    assert_eq!(test::read_body(res).await, "1");
+
+    let res = app.call(get(&alice).to_request()).await.unwrap();
+    assert_eq!(header(&res, "x-cache"), "HIT");
+    assert_eq!(header(&res, "etag"), etag);
+    assert!(res.headers().contains_key(header::AGE));
+    assert_eq!(test::read_body(res).await, "1");
+
+    let res = app
+        .call(
+            get(&alice)
+                .insert_header((header::IF_NONE_MATCH, format!("W/{}", etag)))
+                .to_request(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 304);
+    assert_eq!(header(&res, "etag"), etag);
+    assert!(test::read_body(res).await.is_empty());
+
+    // Others have their own entries
+    let res = app.call(get(&bob).to_request()).await.unwrap();
+    assert_eq!(header(&res, "x-cache"), "MISS");
+    assert_eq!(test::read_body(res).await, "2");
+
+    // A client asking to skip the cache reaches the handler
+    let res = app
+        .call(
+            get(&alice)
+                .insert_header((header::CACHE_CONTROL, "no-cache"))
+                .to_request(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(test::read_body(res).await, "3");
+
+    // Writes invalidate the whole resource
+    let res = app
+        .call(
+            test::TestRequest::put()
+                .uri("/api/v1/products/2")
+                .insert_header((header::AUTHORIZATION, alice.as_str()))
+                .to_request(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let res = app.call(get(&bob).to_request()).await.unwrap();
+    assert_eq!(header(&res, "x-cache"), "MISS");
+    assert_eq!(test::read_body(res).await, "5");
+
+    // Responses that forbid it are not stored
+    for _ in 0..2 {
+        let res = app
+            .call(
+                test::TestRequest::get()
+                    .uri("/api/v1/products/live")
+                    .insert_header((header::AUTHORIZATION, alice.as_str()))
+                    .to_request(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(header(&res, "x-cache"), "MISS");
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 7);
+}
@@ -0,0 +1,265 @@
+use actix_web::{test, web, App};
+use api_gateway::{
+    db,
+    jobs::{Backoff, Job, JobFailure, JobHandler, JobRunner, JobStatus, NewJob, RunnerOptions},
+    middleware::{auth::AuthMiddleware, sign_jwt, Admins, Claims, Principal},
+    routes,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use lotabots_secrets::{KeyRing, Secret};
+use serde_json::json;
+use sqlx::PgPool;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use uuid::Uuid;
+
+const SECRET: &str = "test_secret";
+
+fn token_for(user_id: Uuid) -> String {
+    let claims = Claims {
+        sub: user_id.to_string(),
+        exp: (Utc::now().timestamp() + 3600) as usize,
+        tenant_id: None,
+        permissions: Vec::new(),
+        principal: Principal::User,
+    };
+    sign_jwt(&KeyRing::new("JWT_SECRET", Secret::new(SECRET)), &claims).unwrap()
+}
+
+async fn connect() -> Option<PgPool> {
+    let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL is not set; skipping");
+        return None;
+    };
+    let pool = PgPool::connect(&url).await.unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    Some(pool)
+}
+
+/// Fails its first `failures` runs
+struct Flaky {
+    runs: Arc<AtomicUsize>,
+    failures: usize,
+}
+
+#[async_trait]
+impl JobHandler for Flaky {
+    async fn run(&self, _job: &Job) -> Result<(), JobFailure> {
+        if self.runs.fetch_add(1, Ordering::SeqCst) < self.failures {
+            return Err("upstream unavailable".into());
+        }
+        Ok(())
+    }
+}
+
+fn runner(pool: &PgPool, job_type: &str, handler: Flaky) -> JobRunner {
+    JobRunner::new(
+        pool.clone(),
+        RunnerOptions {
+            backoff: Backoff {
+                base: Duration::ZERO,
+                max: Duration::ZERO,
+            },
+            ..RunnerOptions::default()
+        },
+    )
+    .handle(job_type, handler)
+}
+
+/// Needs `TEST_DATABASE_URL`; skipped otherwise
+#[actix_rt::test]
+async fn test_jobs_are_claimed_once_and_retried_until_dead() {
+    let Some(pool) = connect().await else {
+        return;
+    };
+    let job_type = format!("test.{}", Uuid::new_v4());
+    let job = |key: &str| {
+        NewJob::new(&job_type, json!({ "key": key }))
+            .unwrap()
+            .unique_key(key)
+            .max_attempts(2)
+    };
+
+    // Unique keys are enqueued once
+    let (first, created) = db::enqueue_job(&pool, &job("a")).await.unwrap();
+    assert!(created);
+    assert_eq!(
+        db::enqueue_job(&pool, &job("a")).await.unwrap(),
+        (first, false)
+    );
+    db::enqueue_job(&pool, &job("b")).await.unwrap();
+    db::enqueue_job(&pool, &job("c")).await.unwrap();
+    // Delayed jobs wait for their time
+    db::enqueue_job(&pool, &job("later").delay(Duration::from_secs(3600)))
+        .await
+        .unwrap();
+
+    // Concurrent workers never claim the same job
+    let types = vec![job_type.clone()];
+    let lease = Duration::from_secs(60);
+    let (one, two) = tokio::join!(
+        db::claim_jobs(&pool, &types, 2, "one", lease),
+        db::claim_jobs(&pool, &types, 2, "two", lease),
+    );
+    let mut claimed: Vec<_> = one.unwrap().into_iter().chain(two.unwrap()).collect();
+    claimed.sort_by_key(|job| job.id);
+    claimed.dedup_by_key(|job| job.id);
+    assert_eq!(claimed.len(), 3);
+    assert!(claimed
+        .iter()
+        .all(|job| job.status == JobStatus::Running && job.attempts == 1));
+    for job in &claimed {
+        assert!(
+            db::complete_job(&pool, job.id, "one").await.unwrap()
+                || db::complete_job(&pool, job.id, "two").await.unwrap()
+        );
+    }
+
+    // A failing job is retried, then dead lettered
+    let runs = Arc::new(AtomicUsize::new(0));
+    let runner = runner(
+        &pool,
+        &job_type,
+        Flaky {
+            runs: runs.clone(),
+            failures: usize::MAX,
+        },
+    );
+    let (id, _) = db::enqueue_job(&pool, &job("flaky")).await.unwrap();
+    assert_eq!(runner.run_pending().await.unwrap(), 1);
+    let retried = db::get_job(&pool, id).await.unwrap().unwrap();
+    assert_eq!((retried.status, retried.attempts), (JobStatus::Queued, 1));
+    assert_eq!(retried.last_error.as_deref(), Some("upstream unavailable"));
+
+    assert_eq!(runner.run_pending().await.unwrap(), 1);
+    let dead = db::get_job(&pool, id).await.unwrap().unwrap();
+    assert_eq!((dead.status, dead.attempts), (JobStatus::Dead, 2));
+    assert!(dead.finished_at.is_some());
+    assert_eq!(runner.run_pending().await.unwrap(), 0);
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+    // Retrying a dead job gives it a fresh set of attempts
+    db::retry_job(&pool, id).await.unwrap().unwrap();
+    let runner = self::runner(
+        &pool,
+        &job_type,
+        Flaky {
+            runs: Arc::new(AtomicUsize::new(0)),
+            failures: 0,
+        },
+    );
+    assert_eq!(runner.run_pending().await.unwrap(), 1);
+    let succeeded = db::get_job(&pool, id).await.unwrap().unwrap();
+    assert_eq!(
+        (succeeded.status, succeeded.attempts),
+        (JobStatus::Succeeded, 1)
+    );
+
+    // Spawned runners poll for jobs until shut down
+    let runs = Arc::new(AtomicUsize::new(0));
+    let handle = JobRunner::new(
+        pool.clone(),
+        RunnerOptions {
+            poll_interval: Duration::from_millis(10),
+            ..RunnerOptions::default()
+        },
+    )
+    .handle(
+        &job_type,
+        Flaky {
+            runs: runs.clone(),
+            failures: 0,
+        },
+    )
+    .spawn();
+    let (id, _) = db::enqueue_job(&pool, &job("spawned")).await.unwrap();
+    for _ in 0..200 {
+        if runs.load(Ordering::SeqCst) == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    handle.shutdown().await;
+    let job = db::get_job(&pool, id).await.unwrap().unwrap();
+    assert_eq!(job.status, JobStatus::Succeeded);
+}
+
+/// Needs `TEST_DATABASE_URL`; skipped otherwise
+#[actix_rt::test]
+async fn test_operators_manage_jobs_through_the_admin_api() {
+    let Some(pool) = connect().await else {
+        return;
+    };
+    let operator = Uuid::new_v4();
+    let app = test::init_service(
+        App::new().app_data(web::Data::new(pool.clone())).service(
+            web::scope("/admin/jobs")
+                .wrap(AuthMiddleware::new(SECRET.to_string()).for_admins(Admins::new([operator])))
+                .configure(routes::jobs::configure),
+        ),
+    )
+    .await;
+    let call = |req: test::TestRequest, user: Uuid| {
+        req.insert_header(("Authorization", format!("Bearer {}", token_for(user))))
+            .to_request()
+    };
+    let job_type = format!("test.{}", Uuid::new_v4());
+
+    // Tenants cannot see other tenants' jobs
+    let resp = test::call_service(
+        &app,
+        call(test::TestRequest::get().uri("/admin/jobs"), Uuid::new_v4()),
+    )
+    .await;
+    assert_eq!(resp.status().as_u16(), 403);
+
+    let enqueue = test::TestRequest::post()
+        .uri("/admin/jobs")
+        .set_json(json!({
+            "job_type": job_type,
+            "payload": { "period": "2025-01" },
+            "run_at": "2100-01-01T00:00:00Z",
+        }));
+    let resp = test::call_service(&app, call(enqueue, operator)).await;
+    assert_eq!(resp.status().as_u16(), 201);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let id = body["data"]["id"].as_str().unwrap().to_string();
+    assert_eq!(body["data"]["status"], "queued");
+
+    let list =
+        test::TestRequest::get().uri(&format!("/admin/jobs?status=queued&job_type={}", job_type));
+    let body: serde_json::Value = test::call_and_read_body_json(&app, call(list, operator)).await;
+    assert_eq!(body["data"].as_array().unwrap().len(), 1);
+
+    // Queued jobs can be cancelled, and cancelled ones retried
+    let cancel = test::TestRequest::post().uri(&format!("/admin/jobs/{}/cancel", id));
+    let body: serde_json::Value = test::call_and_read_body_json(&app, call(cancel, operator)).await;
+    assert_eq!(body["data"]["status"], "cancelled");
+    let cancel = test::TestRequest::post().uri(&format!("/admin/jobs/{}/cancel", id));
+    assert_eq!(
+        test::call_service(&app, call(cancel, operator))
+            .await
+            .status()
+            .as_u16(),
+        400
+    );
+
+    let retry = test::TestRequest::post().uri(&format!("/admin/jobs/{}/retry", id));
+    let body: serde_json::Value = test::call_and_read_body_json(&app, call(retry, operator)).await;
+    assert_eq!(body["data"]["status"], "queued");
+
+    let missing = test::TestRequest::get().uri(&format!("/admin/jobs/{}", Uuid::new_v4()));
+    assert_eq!(
+        test::call_service(&app, call(missing, operator))
+            .await
+            .status()
+            .as_u16(),
+        404
+    );
+}
@@ -0,0 +1,289 @@
+use actix_codec::{Decoder, Encoder};
+use actix_web::{
+    http::{header, StatusCode},
+    web::{self, Bytes, BytesMut},
+    App, HttpRequest, HttpResponse,
+};
+use api_gateway::{
+    middleware::{sign_jwt, Claims, Principal, RouteGate},
+    proxy::{GrpcGateway, Proxy, ProxyOptions, RouteTable, RouteTableConfig, ServiceRegistry},
+    routes,
+};
+use awc::{
+    error::WsClientError,
+    ws::{Codec, Frame, Message},
+};
+use chrono::Utc;
+use futures_util::{SinkExt, StreamExt};
+use hyper::{body::HttpBody, server::conn::Http, service::service_fn, Body, Request, Response};
+use lotabots_rate_limit::MemoryStore;
+use lotabots_secrets::{KeyRing, Secret};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::{net::TcpListener, sync::mpsc};
+use uuid::Uuid;
+
+const ROUTES: &str = r#"
+[rate_limits.chats]
+limit = "2/min"
+
+[rate_limits.calls]
+limit = "2/min"
+
+[[routes]]
+name = "chat"
+path = "/api/v1/chat"
+methods = ["GET"]
+upstream = "agent"
+rate_limit = "chats"
+
+[[routes]]
+name = "echo"
+path = "/echo.Echo/{method}"
+upstream = "echo"
+protocol = "grpc"
+permissions = ["echo:call"]
+rate_limit = "calls"
+"#;
+
+fn keys() -> KeyRing {
+    KeyRing::new("JWT_SECRET", Secret::new("test_secret"))
+}
+
+fn token(tenant: Uuid, permissions: &[&str]) -> String {
+    let claims = Claims {
+        sub: "client".to_string(),
+        exp: (Utc::now().timestamp() + 3600) as usize,
+        tenant_id: Some(tenant),
+        permissions: permissions.iter().map(|p| p.to_string()).collect(),
+        principal: Principal::Client,
+    };
+    format!("Bearer {}", sign_jwt(&keys(), &claims).unwrap())
+}
+
+fn gate(table: &Arc<RouteTable>) -> RouteGate {
+    RouteGate::new(table.clone(), keys()).with_rate_limits(Arc::new(MemoryStore::new()))
+}
+
+fn table() -> Arc<RouteTable> {
+    let config: RouteTableConfig = toml::from_str(ROUTES).unwrap();
+    Arc::new(RouteTable::from_config(config).unwrap())
+}
+
+/// A WebSocket server sending back every message it receives
+async fn websocket_echo(
+    req: HttpRequest,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let key = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_KEY)
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Not a handshake"))?;
+    let accept = actix_http::ws::hash_key(key.as_bytes());
+
+    let (tx, rx) = mpsc::unbounded_channel::<Result<Bytes, actix_web::Error>>();
+    actix_web::rt::spawn(async move {
+        let mut codec = Codec::new();
+        let mut buffer = BytesMut::new();
+        while let Some(Ok(chunk)) = payload.next().await {
+            buffer.extend_from_slice(&chunk);
+            while let Some(frame) = codec.decode(&mut buffer).unwrap() {
+                let message = match frame {
+                    Frame::Text(text) => {
+                        Message::Text(String::from_utf8(text.to_vec()).unwrap().into())
+                    }
+                    Frame::Binary(data) => Message::Binary(data),
+                    Frame::Close(reason) => Message::Close(reason),
+                    _ => continue,
+                };
+                let mut encoded = BytesMut::new();
+                codec.encode(message, &mut encoded).unwrap();
+                let _ = tx.send(Ok(encoded.freeze()));
+            }
+        }
+    });
+
+    Ok(HttpResponse::SwitchingProtocols()
+        .upgrade("websocket")
+        .insert_header((header::SEC_WEBSOCKET_ACCEPT, &accept[..]))
+        .streaming(futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|chunk| (chunk, rx))
+        })))
+}
+
+/// A gRPC server sending back the messages of each call, with trailers
+async fn grpc_echo(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let path = req.uri().path().to_string();
+    let messages = hyper::body::to_bytes(req.into_body()).await.unwrap();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        sender.send_data(messages).await.unwrap();
+        let mut trailers = hyper::HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        trailers.insert("x-echo-path", path.parse().unwrap());
+        sender.send_trailers(trailers).await.unwrap();
+    });
+    Ok(Response::builder()
+        .header("content-type", "application/grpc")
+        .body(body)
+        .unwrap())
+}
+
+async fn grpc_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(
+                Http::new()
+                    .http2_only(true)
+                    .serve_connection(stream, service_fn(grpc_echo)),
+            );
+        }
+    });
+    address
+}
+
+#[actix_rt::test]
+async fn test_websockets_are_tunnelled_through_the_gate() {
+    let upstream =
+        actix_test::start(|| App::new().route("/api/v1/chat", web::get().to(websocket_echo)));
+    let registry = Arc::new(ServiceRegistry::default().with_service("agent", &upstream.url("")));
+    let table = table();
+    let gate = gate(&table);
+    let registry = web::Data::from(registry);
+    let proxy = web::Data::new(Proxy::new(ProxyOptions::default()).unwrap());
+    let gateway = actix_test::start(move || {
+        App::new()
+            .app_data(registry.clone())
+            .app_data(proxy.clone())
+            .service(
+                web::scope("")
+                    .guard(table.guard())
+                    .wrap(gate.clone())
+                    .default_service(web::to(routes::proxy::table_route)),
+            )
+    });
+    let client = awc::Client::new();
+    let url = gateway.url("/api/v1/chat");
+
+    let refused = client.ws(&url).connect().await.err();
+    assert!(matches!(
+        refused,
+        Some(WsClientError::InvalidResponseStatus(
+            StatusCode::UNAUTHORIZED
+        ))
+    ));
+
+    let tenant = Uuid::new_v4();
+    let (res, mut connection) = client
+        .ws(&url)
+        .header(header::AUTHORIZATION, token(tenant, &[]))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+    for text in ["hello", "again"] {
+        connection.send(Message::Text(text.into())).await.unwrap();
+        let frame = connection.next().await.unwrap().unwrap();
+        assert_eq!(frame, Frame::Text(Bytes::from(text)));
+    }
+    connection
+        .send(Message::Binary(Bytes::from_static(&[0, 1, 2])))
+        .await
+        .unwrap();
+    let frame = connection.next().await.unwrap().unwrap();
+    assert_eq!(frame, Frame::Binary(Bytes::from_static(&[0, 1, 2])));
+    connection.send(Message::Close(None)).await.unwrap();
+    assert_eq!(
+        connection.next().await.unwrap().unwrap(),
+        Frame::Close(None)
+    );
+
+    // Handshakes count against the route's rate limit
+    let connect = || {
+        client
+            .ws(&url)
+            .header(header::AUTHORIZATION, token(tenant, &[]))
+            .connect()
+    };
+    assert!(connect().await.is_ok());
+    assert!(matches!(
+        connect().await.err(),
+        Some(WsClientError::InvalidResponseStatus(
+            StatusCode::TOO_MANY_REQUESTS
+        ))
+    ));
+}
+
+#[actix_rt::test]
+async fn test_grpc_calls_are_relayed_with_trailers() {
+    let upstream = grpc_server().await;
+    let registry =
+        Arc::new(ServiceRegistry::default().with_service("echo", &format!("http://{}", upstream)));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let table = table();
+    let gateway = GrpcGateway::new(
+        gate(&table),
+        registry,
+        Proxy::new(ProxyOptions::default()).unwrap(),
+    );
+    tokio::spawn(gateway.serve(listener));
+
+    let client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<Body>();
+    let tenant = Uuid::new_v4();
+    // One length-prefixed message
+    let message = Bytes::from_static(b"\x00\x00\x00\x00\x05hello");
+    let call = |path: &str, token: Option<String>| {
+        let mut request = Request::post(format!("http://{}{}", address, path))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers");
+        if let Some(token) = token {
+            request = request.header("authorization", token);
+        }
+        client.request(request.body(Body::from(message.clone())).unwrap())
+    };
+    let status = |res: &Response<Body>| {
+        res.headers()
+            .get("grpc-status")
+            .map(|status| status.to_str().unwrap().to_string())
+    };
+
+    let res = call("/echo.Echo/Say", None).await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(status(&res).as_deref(), Some("16"));
+    let res = call("/echo.Echo/Say", Some(token(tenant, &["other:call"])))
+        .await
+        .unwrap();
+    assert_eq!(status(&res).as_deref(), Some("7"));
+    let res = call("/other.Other/Say", Some(token(tenant, &["echo:call"])))
+        .await
+        .unwrap();
+    assert_eq!(status(&res).as_deref(), Some("12"));
+
+    for _ in 0..2 {
+        let res = call("/echo.Echo/Say", Some(token(tenant, &["echo:call"])))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(status(&res), None);
+        assert!(res.headers().contains_key("ratelimit-remaining"));
+        let mut body = res.into_body();
+        let mut received = Vec::new();
+        while let Some(chunk) = body.data().await {
+            received.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(received, message);
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers["x-echo-path"], "/echo.Echo/Say");
+    }
+
+    let res = call("/echo.Echo/Say", Some(token(tenant, &["echo:call"])))
+        .await
+        .unwrap();
+    assert_eq!(status(&res).as_deref(), Some("8"));
+}
@@ -0,0 +1,517 @@
+use actix_web::{web, App, HttpRequest, HttpResponse};
+use api_gateway::{
+    proxy::{
+        registry::HealthCheck,
+        resilience::{CircuitBreakerConfig, Fallback, Hedging, RetryPolicy},
+        Proxy, ProxyOptions, RegistryConfig, ServiceConfig, ServiceRegistry,
+    },
+    routes,
+};
+use futures_util::{stream, StreamExt};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// Answers with what it received
+async fn echo(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    let headers: HashMap<_, _> = req
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_str().unwrap_or("").to_string()))
+        .collect();
+    HttpResponse::Ok().json(json!({
+        "method": req.method().as_str(),
+        "path": req.path(),
+        "query": req.query_string(),
+        "headers": headers,
+        "body_len": body.len(),
+    }))
+}
+
+async fn slow() -> HttpResponse {
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    HttpResponse::Ok().finish()
+}
+
+/// A body sent in chunks as they are produced
+async fn download() -> HttpResponse {
+    let chunks = stream::iter(0..64).then(|i| async move {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        Ok::<_, actix_web::Error>(web::Bytes::from(vec![i as u8; 1024]))
+    });
+    HttpResponse::Ok()
+        .insert_header(("keep-alive", "timeout=5"))
+        .streaming(chunks)
+}
+
+fn upstream() -> actix_test::TestServer {
+    actix_test::start(|| {
+        App::new()
+            .app_data(web::PayloadConfig::new(4 << 20))
+            .route("/slow", web::get().to(slow))
+            .route("/download", web::get().to(download))
+            .default_service(web::to(echo))
+    })
+}
+
+/// What a [`faulty`] upstream does next
+#[derive(Default)]
+struct Faults {
+    /// Requests still to answer with 503
+    failures: AtomicUsize,
+    delay_ms: AtomicU64,
+    requests: AtomicUsize,
+}
+
+impl Faults {
+    fn fail(&self, requests: usize) {
+        self.failures.store(requests, Ordering::SeqCst);
+    }
+
+    fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
+
+async fn faulty_handler(faults: web::Data<Faults>) -> HttpResponse {
+    faults.requests.fetch_add(1, Ordering::SeqCst);
+    let delay = faults.delay_ms.load(Ordering::SeqCst);
+    if delay > 0 {
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+    }
+    let failing = faults
+        .failures
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok();
+    if failing {
+        HttpResponse::ServiceUnavailable().finish()
+    } else {
+        HttpResponse::Ok().body("ok")
+    }
+}
+
+/// An upstream that fails and slows down on demand
+fn faulty() -> (actix_test::TestServer, Arc<Faults>) {
+    let faults = Arc::new(Faults::default());
+    let data = web::Data::from(faults.clone());
+    let server = actix_test::start(move || {
+        App::new()
+            .app_data(data.clone())
+            .default_service(web::to(faulty_handler))
+    });
+    (server, faults)
+}
+
+/// A gateway with `config` as the service `flaky`
+fn resilient_gateway(config: ServiceConfig) -> actix_test::TestServer {
+    let registry = ServiceRegistry::from_config(RegistryConfig::default()).unwrap();
+    registry.set_service("flaky", config).unwrap();
+    gateway(registry, ProxyOptions::default())
+}
+
+async fn metrics(client: &reqwest::Client, gateway: &actix_test::TestServer) -> String {
+    client
+        .get(gateway.url("/metrics"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap()
+}
+
+/// The stub as `echo`, and `down`, which nothing listens for
+fn registry(upstream: &actix_test::TestServer) -> ServiceRegistry {
+    ServiceRegistry::new()
+        .with_service("echo", &upstream.url(""))
+        // The discard port
+        .with_service("down", "http://127.0.0.1:9")
+}
+
+fn gateway(
+    registry: impl Into<Arc<ServiceRegistry>>,
+    options: ProxyOptions,
+) -> actix_test::TestServer {
+    let registry = web::Data::from(registry.into());
+    let proxy = web::Data::new(Proxy::new(options).unwrap());
+    actix_test::start(move || {
+        App::new()
+            .app_data(registry.clone())
+            .app_data(proxy.clone())
+            .service(web::scope("/services").configure(routes::proxy::configure))
+            .service(web::scope("/admin/upstreams").configure(routes::upstreams::configure))
+            .service(routes::health::metrics)
+    })
+}
+
+#[actix_rt::test]
+async fn test_every_method_is_forwarded_with_its_body() {
+    let upstream = upstream();
+    let gateway = gateway(registry(&upstream), ProxyOptions::default());
+    let client = reqwest::Client::new();
+
+    for method in ["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"] {
+        let body = if method == "GET" { "" } else { "payload" };
+        let res = client
+            .request(
+                method.parse().unwrap(),
+                gateway.url("/services/echo/items/42?expand=owner"),
+            )
+            .header("x-request-id", "abc")
+            .header("connection", "keep-alive, x-session")
+            .header("x-session", "secret")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200, "{}", method);
+
+        let echoed: Value = res.json().await.unwrap();
+        assert_eq!(echoed["method"], method);
+        assert_eq!(echoed["path"], "/items/42");
+        assert_eq!(echoed["query"], "expand=owner");
+        assert_eq!(echoed["body_len"], body.len());
+        let headers = &echoed["headers"];
+        assert_eq!(headers["x-request-id"], "abc");
+        assert!(headers.get("x-session").is_none(), "{}", method);
+        assert_eq!(headers["x-forwarded-for"], "127.0.0.1");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert!(headers["forwarded"]
+            .as_str()
+            .unwrap()
+            .starts_with("for=127.0.0.1;proto=http;host="));
+    }
+}
+
+#[actix_rt::test]
+async fn test_bodies_are_streamed_both_ways() {
+    let upstream = upstream();
+    let gateway = gateway(registry(&upstream), ProxyOptions::default());
+    let client = reqwest::Client::new();
+
+    // A chunked upload of unknown length
+    let chunks = stream::iter(0..256).map(|_| Ok::<_, std::io::Error>(vec![7u8; 4096]));
+    let res = client
+        .post(gateway.url("/services/echo/upload"))
+        .body(reqwest::Body::wrap_stream(chunks))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let echoed: Value = res.json().await.unwrap();
+    assert_eq!(echoed["body_len"], 256 * 4096);
+
+    let res = client
+        .get(gateway.url("/services/echo/download"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert!(res.headers().get("keep-alive").is_none());
+    let body = res.bytes().await.unwrap();
+    assert_eq!(body.len(), 64 * 1024);
+    assert!(body
+        .chunks(1024)
+        .enumerate()
+        .all(|(i, chunk)| chunk[0] == i as u8));
+}
+
+#[actix_rt::test]
+async fn test_upstream_failures_map_to_gateway_errors() {
+    let upstream = upstream();
+    let gateway = gateway(
+        registry(&upstream),
+        ProxyOptions {
+            route_timeouts: HashMap::from([("echo".to_string(), Duration::from_millis(200))]),
+            ..ProxyOptions::default()
+        },
+    );
+    let client = reqwest::Client::new();
+
+    let res = client
+        .get(gateway.url("/services/echo/slow"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 504);
+
+    let res = client
+        .get(gateway.url("/services/down/anything"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 502);
+
+    let res = client
+        .get(gateway.url("/services/unknown/anything"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+}
+
+#[actix_rt::test]
+async fn test_requests_avoid_unhealthy_instances() {
+    let healthy = upstream();
+    let sick =
+        actix_test::start(|| App::new().default_service(web::to(HttpResponse::ServiceUnavailable)));
+    let config = ServiceConfig {
+        health_check: Some(HealthCheck {
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
+            ..HealthCheck::default()
+        }),
+        ..ServiceConfig::new([healthy.url(""), sick.url("")])
+    };
+    let registry = Arc::new(ServiceRegistry::from_config(RegistryConfig::default()).unwrap());
+    registry.set_service("echo", config).unwrap();
+    registry.check_health().await;
+
+    let gateway = gateway(registry.clone(), ProxyOptions::default());
+    let client = reqwest::Client::new();
+    for _ in 0..6 {
+        let res = client
+            .get(gateway.url("/services/echo/items"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+    }
+
+    let status: Value = client
+        .get(gateway.url("/admin/upstreams/echo"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let instances = &status["data"]["instances"];
+    assert_eq!(status["data"]["available"], 1);
+    assert_eq!(instances[1]["state"], "unhealthy");
+    assert_eq!(
+        instances[1]["last_check_error"],
+        "Answered 503 Service Unavailable"
+    );
+    assert_eq!(instances[0]["state"], "healthy");
+}
+
+#[actix_rt::test]
+async fn test_upstreams_change_at_runtime() {
+    let upstream = upstream();
+    let path = std::env::temp_dir().join(format!("upstreams-{}.json", Uuid::new_v4()));
+    let write = |instances: Vec<String>| {
+        let config = json!({ "services": { "echo": { "instances": instances, "strategy": "tenant_hash" } } });
+        std::fs::write(&path, config.to_string()).unwrap();
+    };
+    write(vec!["http://127.0.0.1:9".to_string()]);
+
+    let registry = Arc::new(
+        ServiceRegistry::from_file(&path)
+            .unwrap()
+            .watch_every(Duration::from_millis(20)),
+    );
+    registry.spawn();
+    let gateway = gateway(registry.clone(), ProxyOptions::default());
+    let client = reqwest::Client::new();
+    let get = || client.get(gateway.url("/services/echo/items")).send();
+    assert_eq!(get().await.unwrap().status(), 502);
+
+    // The file is watched
+    write(vec![upstream.url("")]);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(get().await.unwrap().status(), 200);
+    std::fs::remove_file(&path).unwrap();
+
+    // Operators can change services through the admin API
+    let res = client
+        .put(gateway.url("/admin/upstreams/echo"))
+        .json(&json!({ "instances": ["ftp://elsewhere"] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 400);
+    let res = client
+        .put(gateway.url("/admin/upstreams/search"))
+        .json(&json!({ "instances": [upstream.url("")], "strategy": "least_connections" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let res = client
+        .get(gateway.url("/services/search/items"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .delete(gateway.url("/admin/upstreams/echo"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 204);
+    assert_eq!(get().await.unwrap().status(), 404);
+
+    let services: Value = client
+        .get(gateway.url("/admin/upstreams"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(services["data"][0]["name"], "search");
+    assert_eq!(services["data"][0]["strategy"], "least_connections");
+}
+
+#[actix_rt::test]
+async fn test_idempotent_requests_are_retried_within_the_budget() {
+    let (upstream, faults) = faulty();
+    let gateway = resilient_gateway(ServiceConfig::new([upstream.url("")]));
+    let client = reqwest::Client::new();
+
+    faults.fail(2);
+    let res = client
+        .get(gateway.url("/services/flaky/items"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(faults.requests(), 3);
+
+    // Bodies are streamed once, so requests with one are not sent again
+    faults.fail(1);
+    let res = client
+        .put(gateway.url("/services/flaky/items/1"))
+        .body("{}")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 503);
+    assert_eq!(faults.requests(), 4);
+
+    let text = metrics(&client, &gateway).await;
+    assert!(text.contains("gateway_upstream_retries_total{service=\"flaky\"} 2"));
+    assert!(text.contains("gateway_upstream_requests_total{service=\"flaky\",status=\"503\"} 3"));
+
+    // Without budget there are no retries
+    let (upstream, faults) = faulty();
+    let gateway = resilient_gateway(ServiceConfig {
+        retries: RetryPolicy {
+            budget_ratio: 0.0,
+            min_retries_per_sec: 0,
+            ..RetryPolicy::default()
+        },
+        ..ServiceConfig::new([upstream.url("")])
+    });
+    faults.fail(1);
+    let res = client
+        .get(gateway.url("/services/flaky/items"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 503);
+    assert_eq!(faults.requests(), 1);
+    assert!(metrics(&client, &gateway)
+        .await
+        .contains("gateway_upstream_retry_budget_exhausted_total{service=\"flaky\"} 1"));
+}
+
+#[actix_rt::test]
+async fn test_open_circuits_answer_with_the_fallback() {
+    let (upstream, faults) = faulty();
+    let gateway = resilient_gateway(ServiceConfig {
+        circuit_breaker: CircuitBreakerConfig {
+            minimum_requests: 4,
+            open_secs: 1,
+            half_open_requests: 1,
+            ..CircuitBreakerConfig::default()
+        },
+        retries: RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        },
+        fallback: Some(Fallback {
+            status: 200,
+            content_type: "application/json".to_string(),
+            body: r#"{"items":[]}"#.to_string(),
+        }),
+        ..ServiceConfig::new([upstream.url("")])
+    });
+    let client = reqwest::Client::new();
+    let get = || client.get(gateway.url("/services/flaky/items")).send();
+
+    // Answers of the upstream are passed on, failures or not
+    faults.fail(usize::MAX);
+    for _ in 0..4 {
+        assert_eq!(get().await.unwrap().status(), 503);
+    }
+    let res = get().await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-gateway-fallback"], "flaky");
+    assert_eq!(res.text().await.unwrap(), r#"{"items":[]}"#);
+    assert_eq!(faults.requests(), 4);
+
+    let status: Value = client
+        .get(gateway.url("/admin/upstreams/flaky"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(status["data"]["circuit"], "open");
+
+    // A successful probe closes it again
+    faults.fail(0);
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let res = get().await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.text().await.unwrap(), "ok");
+
+    let text = metrics(&client, &gateway).await;
+    for line in [
+        "gateway_circuit_breaker_transitions_total{service=\"flaky\",state=\"open\"} 1",
+        "gateway_circuit_breaker_transitions_total{service=\"flaky\",state=\"closed\"} 1",
+        "gateway_circuit_breaker_rejections_total{service=\"flaky\"} 1",
+        "gateway_upstream_fallbacks_total{service=\"flaky\"} 1",
+        "gateway_circuit_breaker_open{service=\"flaky\"} 0",
+    ] {
+        assert!(text.contains(line), "{} missing from\n{}", line, text);
+    }
+}
+
+#[actix_rt::test]
+async fn test_slow_requests_are_hedged() {
+    let (slow, slow_faults) = faulty();
+    slow_faults.delay_ms.store(2_000, Ordering::SeqCst);
+    let (fast, _) = faulty();
+    let gateway = resilient_gateway(ServiceConfig {
+        hedging: Some(Hedging { delay_ms: 50 }),
+        ..ServiceConfig::new([slow.url(""), fast.url("")])
+    });
+    let client = reqwest::Client::new();
+
+    for _ in 0..4 {
+        let started = Instant::now();
+        let res = client
+            .get(gateway.url("/services/flaky/items"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+    assert!(slow_faults.requests() >= 1);
+    assert!(metrics(&client, &gateway)
+        .await
+        .contains("gateway_upstream_hedged_requests_total{service=\"flaky\"}"));
+}
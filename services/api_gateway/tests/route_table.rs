@@ -0,0 +1,261 @@
+use actix_web::{web, App, HttpRequest, HttpResponse};
+use api_gateway::{
+    middleware::{sign_jwt, Admins, Claims, Principal, RouteGate},
+    proxy::{Proxy, ProxyOptions, RouteTable, ServiceRegistry},
+    routes,
+};
+use chrono::Utc;
+use lotabots_rate_limit::MemoryStore;
+use lotabots_secrets::{KeyRing, Secret};
+use serde_json::{json, Value};
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
+use uuid::Uuid;
+
+const SECRET: &str = "test_secret";
+
+const ROUTES: &str = r#"
+[rate_limits.scarce]
+limit = "1/min"
+
+[[routes]]
+name = "document-files"
+path = "/api/v1/docs/{id}/files/{rest*}"
+methods = ["GET"]
+upstream = "document"
+permissions = ["documents:read"]
+rewrite = "/v2/documents/{id}/files/{rest}"
+
+[[routes]]
+name = "status"
+path = "/api/v1/status"
+upstream = "document"
+auth = "public"
+rate_limit = "scarce"
+
+[[routes]]
+name = "slow"
+path = "/api/v1/slow"
+methods = ["POST"]
+upstream = "document"
+timeout_secs = 1
+
+[[routes]]
+name = "operations"
+path = "/api/v1/operations/{rest*}"
+upstream = "document"
+auth = "admin"
+"#;
+
+fn keys() -> KeyRing {
+    KeyRing::new("JWT_SECRET", Secret::new(SECRET))
+}
+
+fn token(principal: Principal, tenant: Uuid, permissions: &[&str]) -> String {
+    let claims = Claims {
+        sub: match principal {
+            Principal::User => tenant.to_string(),
+            _ => "client".to_string(),
+        },
+        exp: (Utc::now().timestamp() + 3600) as usize,
+        tenant_id: (principal != Principal::User).then_some(tenant),
+        permissions: permissions.iter().map(|p| p.to_string()).collect(),
+        principal,
+    };
+    format!("Bearer {}", sign_jwt(&keys(), &claims).unwrap())
+}
+
+async fn echo(req: HttpRequest) -> HttpResponse {
+    if req.path() == "/api/v1/slow" {
+        tokio::time::sleep(Duration::from_secs(3)).await;
+    }
+    HttpResponse::Ok().json(json!({
+        "method": req.method().as_str(),
+        "path": req.path(),
+        "query": req.query_string(),
+    }))
+}
+
+fn routes_file(contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("routes-{}.toml", Uuid::new_v4()));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+/// A gateway serving the route table in `path`, with its own routes under
+/// `/api/v1/local`
+fn gateway(
+    upstream: &actix_test::TestServer,
+    path: &PathBuf,
+    admin: Uuid,
+) -> (actix_test::TestServer, Arc<RouteTable>) {
+    let registry = Arc::new(ServiceRegistry::default().with_service("document", &upstream.url("")));
+    let table = Arc::new(
+        RouteTable::from_file(path)
+            .unwrap()
+            .watch_every(Duration::from_millis(50)),
+    );
+    table.check_upstreams(&registry).unwrap();
+    table.spawn(&registry);
+
+    let registry = web::Data::from(registry);
+    let proxy = web::Data::new(Proxy::new(ProxyOptions::default()).unwrap());
+    let store = Arc::new(MemoryStore::new());
+    let served = table.clone();
+    let server = actix_test::start(move || {
+        App::new()
+            .app_data(registry.clone())
+            .app_data(proxy.clone())
+            .service(
+                web::scope("")
+                    .guard(served.guard())
+                    .wrap(
+                        RouteGate::new(served.clone(), keys())
+                            .with_admins(Admins::new([admin]))
+                            .with_rate_limits(store.clone()),
+                    )
+                    .default_service(web::to(routes::proxy::table_route)),
+            )
+            .route("/api/v1/local", web::get().to(|| async { "local" }))
+    });
+    (server, table)
+}
+
+#[actix_rt::test]
+async fn test_routes_are_served_as_declared() {
+    let upstream = actix_test::start(|| App::new().default_service(web::to(echo)));
+    let admin = Uuid::new_v4();
+    let (gateway, _table) = gateway(&upstream, &routes_file(ROUTES), admin);
+    let client = reqwest::Client::new();
+    let tenant = Uuid::new_v4();
+
+    // Rewritten, with the query kept
+    let res = client
+        .get(gateway.url("/api/v1/docs/7/files/a/b.pdf?v=2"))
+        .header("Authorization", token(Principal::User, tenant, &[]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["path"], "/v2/documents/7/files/a/b.pdf");
+    assert_eq!(body["query"], "v=2");
+
+    let files = gateway.url("/api/v1/docs/7/files/a");
+    assert_eq!(client.get(&files).send().await.unwrap().status(), 401);
+    let res = client
+        .get(&files)
+        .header(
+            "Authorization",
+            token(Principal::Client, tenant, &["users:read"]),
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 403);
+    let res = client
+        .get(&files)
+        .header(
+            "Authorization",
+            token(Principal::Client, tenant, &["documents:read"]),
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .delete(&files)
+        .header("Authorization", token(Principal::User, tenant, &[]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 405);
+    assert_eq!(res.headers()["allow"], "GET");
+
+    // Operators only
+    let operations = gateway.url("/api/v1/operations/drain");
+    let res = client
+        .post(&operations)
+        .header("Authorization", token(Principal::User, tenant, &[]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 403);
+    let res = client
+        .post(&operations)
+        .header("Authorization", token(Principal::User, admin, &[]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    // Public and rate limited
+    let status = gateway.url("/api/v1/status");
+    let res = client.get(&status).send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["ratelimit-remaining"], "0");
+    let res = client.get(&status).send().await.unwrap();
+    assert_eq!(res.status(), 429);
+    assert!(res.headers().contains_key("retry-after"));
+
+    // The route's own timeout
+    let res = client
+        .post(gateway.url("/api/v1/slow"))
+        .header("Authorization", token(Principal::User, tenant, &[]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 504);
+
+    // Other paths are left to the gateway's own routes
+    let res = client
+        .get(gateway.url("/api/v1/local"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.text().await.unwrap(), "local");
+    let res = client
+        .get(gateway.url("/api/v1/other"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+}
+
+#[actix_rt::test]
+async fn test_routes_are_reloaded_when_valid() {
+    let upstream = actix_test::start(|| App::new().default_service(web::to(echo)));
+    let path = routes_file(ROUTES);
+    let (gateway, table) = gateway(&upstream, &path, Uuid::new_v4());
+    let client = reqwest::Client::new();
+    let reports = gateway.url("/api/v1/reports/monthly");
+    assert_eq!(client.get(&reports).send().await.unwrap().status(), 404);
+
+    let reports_route = r#"
+[[routes]]
+name = "reports"
+path = "/api/v1/reports/{rest*}"
+upstream = "document"
+auth = "public"
+rewrite = "/reports/{rest}"
+"#;
+    fs::write(&path, format!("{}{}", ROUTES, reports_route)).unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let res = client.get(&reports).send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["path"], "/reports/monthly");
+
+    // Invalid tables and routes to unknown services are not applied
+    for invalid in [
+        "[[routes]]\nname = \"broken\"\npath = \"no-slash\"\nupstream = \"document\"\n",
+        "[[routes]]\nname = \"missing\"\npath = \"/api/v1/reports\"\nupstream = \"billing\"\n",
+        "routes = 3",
+    ] {
+        fs::write(&path, invalid).unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(client.get(&reports).send().await.unwrap().status(), 200);
+    }
+    assert!(!table.is_empty());
+    fs::remove_file(&path).unwrap();
+}
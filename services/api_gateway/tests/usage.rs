@@ -0,0 +1,250 @@
+use actix_web::{http::header, test, web, App, HttpResponse};
+use api_gateway::{
+    db,
+    middleware::{auth::AuthMiddleware, sign_jwt, Claims, Principal, UsageMeter},
+    routes,
+    usage::{
+        month_start, Granularity, Meter, Quota, Quotas, RollupKey, Totals, WriterOptions,
+        TOKENS_HEADER,
+    },
+};
+use chrono::Utc;
+use lotabots_rate_limit::{RateLimit, Tiers};
+use lotabots_secrets::{KeyRing, Secret};
+use sqlx::PgPool;
+use std::{collections::HashMap, time::Duration};
+use uuid::Uuid;
+
+const SECRET: &str = "test_secret";
+
+fn token_for(user_id: Uuid) -> String {
+    let claims = Claims {
+        sub: user_id.to_string(),
+        exp: (Utc::now().timestamp() + 3600) as usize,
+        tenant_id: None,
+        permissions: Vec::new(),
+        principal: Principal::User,
+    };
+    sign_jwt(&KeyRing::new("JWT_SECRET", Secret::new(SECRET)), &claims).unwrap()
+}
+
+fn quotas(tokens: i64) -> Quotas {
+    Quotas::unlimited(Tiers::new("free", RateLimit::per_second(100))).with_quota(
+        "free",
+        Quota {
+            tokens: Some(tokens),
+            ..Quota::default()
+        },
+    )
+}
+
+async fn completion(_prompt: web::Bytes) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((TOKENS_HEADER, "40"))
+        .body("generated text")
+}
+
+#[actix_rt::test]
+async fn test_requests_are_metered_until_the_quota_is_used_up() {
+    let (meter, mut events) = Meter::new(quotas(100), 16);
+    let app = test::init_service(
+        App::new().service(
+            web::scope("/api/v1/completions")
+                .wrap(UsageMeter::new(meter.clone()))
+                .wrap(AuthMiddleware::new(SECRET.to_string()))
+                .route("/{model}", web::post().to(completion)),
+        ),
+    )
+    .await;
+    let tenant = Uuid::new_v4();
+
+    let call = |body: &'static str| {
+        test::TestRequest::post()
+            .uri("/api/v1/completions/small")
+            .insert_header(("Authorization", format!("Bearer {}", token_for(tenant))))
+            .set_payload(body)
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, call("prompt")).await;
+    assert_eq!(resp.status().as_u16(), 200);
+    // The upstream's token count is internal
+    assert!(resp.headers().get(TOKENS_HEADER).is_none());
+
+    let event = events.try_recv().unwrap();
+    assert_eq!(event.tenant_id, tenant);
+    assert_eq!(event.route, "/api/v1/completions/{model}");
+    assert_eq!(event.status, 200);
+    assert_eq!(event.request_bytes, 6);
+    assert_eq!(event.response_bytes, 14);
+    assert_eq!(event.tokens, 40);
+
+    // Chunked bodies announce no length and are counted as they are read
+    let mut chunked = call("a longer prompt");
+    chunked.headers_mut().remove(header::CONTENT_LENGTH);
+    assert_eq!(
+        test::call_service(&app, chunked).await.status().as_u16(),
+        200
+    );
+    assert_eq!(events.try_recv().unwrap().request_bytes, 15);
+
+    // 80 of 100 tokens used, so one more request is allowed
+    assert_eq!(
+        test::call_service(&app, call("p")).await.status().as_u16(),
+        200
+    );
+    let resp = test::call_service(&app, call("p")).await;
+    assert_eq!(resp.status().as_u16(), 429);
+    assert!(resp.headers().contains_key("retry-after"));
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body["metric"], "tokens");
+    assert_eq!(body["limit"], 100);
+
+    // Rejected requests are not metered, and other tenants are unaffected
+    assert_eq!(meter.month_to_date(tenant).await.0.requests, 3);
+    let other = test::TestRequest::post()
+        .uri("/api/v1/completions/small")
+        .insert_header((
+            "Authorization",
+            format!("Bearer {}", token_for(Uuid::new_v4())),
+        ))
+        .to_request();
+    assert_eq!(test::call_service(&app, other).await.status().as_u16(), 200);
+}
+
+/// The migrated test database, or `None` when `TEST_DATABASE_URL` is not set
+async fn connect() -> Option<PgPool> {
+    let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL is not set; skipping");
+        return None;
+    };
+    let pool = PgPool::connect(&url).await.unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    Some(pool)
+}
+
+#[actix_rt::test]
+async fn test_usage_is_written_and_reported() {
+    let Some(pool) = connect().await else {
+        return;
+    };
+
+    let meter = Meter::spawn(
+        pool.clone(),
+        quotas(1_000),
+        WriterOptions {
+            flush_interval: Duration::from_millis(50),
+            ..WriterOptions::default()
+        },
+    );
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(meter.clone()))
+            .service(
+                web::scope("/api/v1/completions")
+                    .wrap(UsageMeter::new(meter.clone()))
+                    .wrap(AuthMiddleware::new(SECRET.to_string()))
+                    .route("/{model}", web::post().to(completion)),
+            )
+            .service(
+                web::scope("/api/v1/usage")
+                    .wrap(AuthMiddleware::new(SECRET.to_string()))
+                    .configure(routes::usage::configure),
+            ),
+    )
+    .await;
+    let tenant = Uuid::new_v4();
+    let token = token_for(tenant);
+
+    for _ in 0..3 {
+        let req = test::TestRequest::post()
+            .uri("/api/v1/completions/small")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status().as_u16(), 200);
+    }
+
+    // Wait for the writer
+    let now = Utc::now();
+    let mut stored = Default::default();
+    for _ in 0..100 {
+        stored = db::usage_totals(
+            &pool,
+            tenant,
+            month_start(now),
+            now + chrono::Duration::days(1),
+        )
+        .await
+        .unwrap();
+        if stored.requests == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(stored.requests, 3);
+    assert_eq!(stored.tokens, 120);
+
+    let minutes = db::usage_periods(
+        &pool,
+        tenant,
+        Granularity::Minute,
+        now - chrono::Duration::hours(1),
+        now + chrono::Duration::hours(1),
+        Some("/api/v1/completions/{model}"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(minutes.iter().map(|p| p.usage.requests).sum::<i64>(), 3);
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/usage?granularity=hour")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status().as_u16(), 200);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    let report = &body["data"];
+    assert_eq!(report["tenant_id"], tenant.to_string());
+    assert_eq!(report["granularity"], "hour");
+    assert_eq!(report["routes"][0]["route"], "/api/v1/completions/{model}");
+    assert_eq!(report["routes"][0]["tokens"], 120);
+    assert_eq!(report["quota"]["used"]["tokens"], 120);
+    assert_eq!(report["quota"]["limits"]["tokens"], 1_000);
+
+    let req = test::TestRequest::get()
+        .uri("/api/v1/usage?granularity=minute&from=2025-01-01T00:00:00Z&to=2025-02-01T00:00:00Z")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status().as_u16(), 400);
+}
+
+#[actix_rt::test]
+async fn test_quotas_count_usage_stored_before_start() {
+    let Some(pool) = connect().await else {
+        return;
+    };
+    let tenant = Uuid::new_v4();
+
+    // Written before this gateway started, or by another replica
+    let now = Utc::now();
+    let key = RollupKey {
+        tenant_id: tenant,
+        granularity: Granularity::Day,
+        period_start: Granularity::Day.truncate(now),
+        route: "/api/v1/completions/{model}".to_string(),
+    };
+    let stored = Totals {
+        requests: 3,
+        tokens: 100,
+        ..Totals::default()
+    };
+    db::record_usage(&pool, &HashMap::from([(key, stored)]))
+        .await
+        .unwrap();
+
+    let meter = Meter::spawn(pool, quotas(100), WriterOptions::default());
+    let exceeded = meter.check(tenant).await.unwrap_err();
+    assert_eq!((exceeded.metric, exceeded.limit), ("tokens", 100));
+    assert_eq!(meter.month_to_date(tenant).await.0, stored);
+}
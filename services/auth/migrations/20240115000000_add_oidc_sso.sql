@@ -0,0 +1,37 @@
+-- Per-tenant OpenID Connect identity providers
+CREATE TABLE IF NOT EXISTS oidc_providers (
+    tenant VARCHAR(64) PRIMARY KEY,
+    issuer VARCHAR(512) NOT NULL,
+    client_id VARCHAR(255) NOT NULL,
+    client_secret VARCHAR(512),
+    redirect_uri VARCHAR(512) NOT NULL,
+    scopes VARCHAR(512) NOT NULL DEFAULT 'openid email profile',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Logins that were sent to an identity provider and await the callback
+CREATE TABLE IF NOT EXISTS oidc_login_states (
+    state VARCHAR(64) PRIMARY KEY,
+    tenant VARCHAR(64) NOT NULL REFERENCES oidc_providers(tenant) ON DELETE CASCADE,
+    nonce VARCHAR(64) NOT NULL,
+    code_verifier VARCHAR(128) NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- External identities linked to local users
+CREATE TABLE IF NOT EXISTS user_identities (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    issuer VARCHAR(512) NOT NULL,
+    subject VARCHAR(255) NOT NULL,
+    email VARCHAR(255),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_login_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (issuer, subject)
+);
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS oidc_login_states_expires_at_idx ON oidc_login_states (expires_at);
+CREATE INDEX IF NOT EXISTS user_identities_user_id_idx ON user_identities (user_id);
@@ -0,0 +1,13 @@
+-- Tenant whose identity provider created the account, if any
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS tenant VARCHAR(64);
+
+-- Email domains the tenant has proven it owns. Its identity provider may
+-- sign in existing accounts with addresses on these domains.
+ALTER TABLE oidc_providers
+    ADD COLUMN IF NOT EXISTS verified_domains TEXT[] NOT NULL DEFAULT '{}';
+
+-- Client secrets are stored envelope encrypted, as
+-- `enc:v1:<key version>:<base64>` text
+ALTER TABLE oidc_providers
+    ALTER COLUMN client_secret TYPE TEXT;
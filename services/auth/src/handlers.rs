@@ -1,5 +1,9 @@
-use actix_web::{web, HttpResponse, Responder};
-use crate::{AuthResponse, LoginRequest, RegisterRequest, service::AuthService};
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Responder};
+use crate::{
+    models::UserResponse, oidc::OidcError, service::AuthService, AuthResponse,
+    EmailVerificationRequest, ForgotPasswordRequest, LoginRequest, OidcCallbackQuery,
+    PasswordResetRequest, RegisterRequest, VerifyEmailRequest,
+};
 
 pub async fn register(
     req: web::Json<RegisterRequest>,
@@ -21,11 +25,119 @@ pub async fn login(
     }
 }
 
+/// The signed-in user. Tokens issued before the user's last password
+/// change are refused.
+pub async fn me(req: HttpRequest, service: web::Data<AuthService>) -> impl Responder {
+    let Some(token) = bearer_token(&req) else {
+        return HttpResponse::Unauthorized().json("Missing bearer token");
+    };
+    match service.authenticate(token).await {
+        Ok(user) => HttpResponse::Ok().json(UserResponse::from(user)),
+        Err(e) => HttpResponse::Unauthorized().json(e),
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+pub async fn forgot_password(
+    req: web::Json<ForgotPasswordRequest>,
+    service: web::Data<AuthService>,
+) -> impl Responder {
+    match service.request_password_reset(req.into_inner()).await {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(e) => HttpResponse::BadRequest().json(e),
+    }
+}
+
+pub async fn reset_password(
+    req: web::Json<PasswordResetRequest>,
+    service: web::Data<AuthService>,
+) -> impl Responder {
+    match service.reset_password(req.into_inner()).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => HttpResponse::BadRequest().json(e),
+    }
+}
+
+pub async fn request_email_verification(
+    req: web::Json<EmailVerificationRequest>,
+    service: web::Data<AuthService>,
+) -> impl Responder {
+    match service.request_email_verification(req.into_inner()).await {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(e) => HttpResponse::BadRequest().json(e),
+    }
+}
+
+pub async fn verify_email(
+    req: web::Json<VerifyEmailRequest>,
+    service: web::Data<AuthService>,
+) -> impl Responder {
+    match service.verify_email(req.into_inner()).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => HttpResponse::BadRequest().json(e),
+    }
+}
+
+pub async fn oidc_authorize(
+    tenant: web::Path<String>,
+    service: web::Data<AuthService>,
+) -> impl Responder {
+    match service.begin_oidc_login(&tenant).await {
+        Ok(url) => HttpResponse::Found()
+            .insert_header((header::LOCATION, url))
+            .finish(),
+        Err(e) => oidc_error_response(e),
+    }
+}
+
+pub async fn oidc_callback(
+    query: web::Query<OidcCallbackQuery>,
+    service: web::Data<AuthService>,
+) -> impl Responder {
+    match service.complete_oidc_login(query.into_inner()).await {
+        Ok(token) => HttpResponse::Ok().json(AuthResponse { token }),
+        Err(e) => oidc_error_response(e),
+    }
+}
+
+fn oidc_error_response(e: OidcError) -> HttpResponse {
+    match e {
+        OidcError::UnknownProvider(_) => HttpResponse::NotFound().json(e.to_string()),
+        OidcError::InvalidState => HttpResponse::BadRequest().json(e.to_string()),
+        OidcError::Provider(_)
+        | OidcError::InvalidIdToken(_)
+        | OidcError::EmailNotVerified => HttpResponse::Unauthorized().json(e.to_string()),
+        OidcError::AccountConflict => HttpResponse::Conflict().json(e.to_string()),
+        OidcError::Discovery(_) | OidcError::Exchange(_) => {
+            tracing::error!("Identity provider unavailable: {}", e);
+            HttpResponse::BadGateway().json(e.to_string())
+        }
+        OidcError::Internal(_) => {
+            tracing::error!("SSO login failed: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg
         .service(
             web::scope("/auth")
                 .route("/register", web::post().to(register))
                 .route("/login", web::post().to(login))
+                .route("/me", web::get().to(me))
+                .route("/password/forgot", web::post().to(forgot_password))
+                .route("/password/reset", web::post().to(reset_password))
+                .route("/email/verification", web::post().to(request_email_verification))
+                .route("/email/verify", web::post().to(verify_email))
+                .route("/oidc/{tenant}/authorize", web::get().to(oidc_authorize))
+                .route("/oidc/callback", web::get().to(oidc_callback))
         );
 }
@@ -1,44 +1,70 @@
-use actix_web::{web, HttpResponse};
 use serde::{Deserialize, Serialize};
-use shared::models::User;
-use thiserror::Error;
+use validator::Validate;
 
-#[derive(Debug, Error)]
-pub enum AuthError {
-    #[error("Invalid credentials")]
-    InvalidCredentials,
-    #[error("User not found")]
-    UserNotFound,
-    #[error("Database error")]
-    DatabaseError(#[from] sqlx::Error),
+pub mod config;
+pub mod handlers;
+pub mod mailer;
+pub mod models;
+pub mod monitoring;
+pub mod oidc;
+pub mod repository;
+pub mod secret_rotation;
+pub mod secrets;
+pub mod service;
+pub mod tokens;
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct RegisterRequest {
+    #[validate(length(min = 3, max = 50))]
+    pub username: String,
+    #[validate(email)]
+    pub email: String,
+    #[validate(length(min = 8, max = 128))]
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthResponse {
     pub token: String,
-    pub user: User,
-}
-
-pub async fn login() -> Result<HttpResponse, AuthError> {
-    // Implementation here
-    Ok(HttpResponse::Ok().json(AuthResponse {
-        token: "dummy_token".to_string(),
-        user: User {
-            id: 1,
-            email: "test@example.com".to_string(),
-        },
-    }))
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use actix_web::test;
-
-    #[tokio::test]
-    async fn test_login() {
-        let req = test::TestRequest::default().to_http_request();
-        let resp = login().await;
-        assert!(resp.is_ok());
-    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct ForgotPasswordRequest {
+    #[validate(email)]
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct PasswordResetRequest {
+    #[validate(length(min = 1, max = 1024))]
+    pub token: String,
+    #[validate(length(min = 8, max = 128))]
+    pub new_password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct EmailVerificationRequest {
+    #[validate(email)]
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub struct VerifyEmailRequest {
+    #[validate(length(min = 1, max = 1024))]
+    pub token: String,
+}
+
+/// Query parameters of the redirect back from an identity provider
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcCallbackQuery {
+    pub state: String,
+    pub code: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
 }
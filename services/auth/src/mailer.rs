@@ -0,0 +1,294 @@
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum MailerError {
+    #[error("Invalid address: {0}")]
+    InvalidAddress(String),
+    #[error("Failed to build message: {0}")]
+    Message(String),
+    #[error("Transport error: {0}")]
+    Transport(String),
+    #[error("Configuration error: {0}")]
+    Config(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    pub fn password_reset(to: &str, link: &str) -> Self {
+        Self {
+            to: to.to_string(),
+            subject: "Reset your LotaBots password".to_string(),
+            body: format!(
+                "A password reset was requested for your LotaBots account.\n\n\
+                 Follow this link to choose a new password:\n{}\n\n\
+                 The link expires in one hour and can only be used once. \
+                 If you did not request a reset you can ignore this email.\n",
+                link
+            ),
+        }
+    }
+
+    pub fn email_verification(to: &str, link: &str) -> Self {
+        Self {
+            to: to.to_string(),
+            subject: "Verify your LotaBots email address".to_string(),
+            body: format!(
+                "Please confirm your email address by following this link:\n{}\n\n\
+                 The link expires in 24 hours.\n",
+                link
+            ),
+        }
+    }
+}
+
+/// Delivers transactional email. Implementations must be cheap to share
+/// between actix workers.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailerError>;
+}
+
+/// Sends mail through an SMTP relay using STARTTLS
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        port: u16,
+        credentials: Option<(String, String)>,
+        from: &str,
+    ) -> Result<Self, MailerError> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+            .map_err(|e| MailerError::Transport(e.to_string()))?
+            .port(port);
+
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: from
+                .parse()
+                .map_err(|_| MailerError::InvalidAddress(from.to_string()))?,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), MailerError> {
+        let to: Mailbox = email
+            .to
+            .parse()
+            .map_err(|_| MailerError::InvalidAddress(email.to.clone()))?;
+
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(email.subject)
+            .body(email.body)
+            .map_err(|e| MailerError::Message(e.to_string()))?;
+
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| MailerError::Transport(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+/// Writes each email as a JSON file into a directory. Useful for local
+/// development where no SMTP relay is available.
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, MailerError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(|e| MailerError::Config(e.to_string()))?;
+        Ok(Self { dir })
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), MailerError> {
+        let path = self.dir.join(format!(
+            "{}-{}.json",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+            Uuid::new_v4()
+        ));
+        let contents =
+            serde_json::to_vec_pretty(&email).map_err(|e| MailerError::Message(e.to_string()))?;
+
+        tokio::fs::write(&path, contents)
+            .await
+            .map_err(|e| MailerError::Transport(e.to_string()))
+    }
+}
+
+/// Keeps sent emails in memory so tests can inspect them
+#[derive(Clone, Default)]
+pub struct InMemoryMailer {
+    sent: Arc<Mutex<Vec<Email>>>,
+}
+
+impl InMemoryMailer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    pub fn last_sent_to(&self, to: &str) -> Option<Email> {
+        self.sent
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|email| email.to == to)
+            .cloned()
+    }
+}
+
+#[async_trait]
+impl Mailer for InMemoryMailer {
+    async fn send(&self, email: Email) -> Result<(), MailerError> {
+        self.sent.lock().unwrap().push(email);
+        Ok(())
+    }
+}
+
+/// Mailer settings loaded from the environment
+#[derive(Debug, Clone)]
+pub struct MailerConfig {
+    /// Base URL of the frontend, used to build links in emails
+    pub public_url: String,
+    pub from: String,
+    pub transport: MailTransport,
+}
+
+#[derive(Debug, Clone)]
+pub enum MailTransport {
+    Smtp {
+        host: String,
+        port: u16,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    File {
+        dir: PathBuf,
+    },
+}
+
+impl MailerConfig {
+    pub fn from_env() -> Result<Self, MailerError> {
+        let transport = match env::var("MAILER").as_deref() {
+            Ok("smtp") => MailTransport::Smtp {
+                host: env::var("SMTP_HOST")
+                    .map_err(|_| MailerError::Config("SMTP_HOST must be set".to_string()))?,
+                port: env::var("SMTP_PORT")
+                    .ok()
+                    .and_then(|p| p.parse().ok())
+                    .unwrap_or(587),
+                username: env::var("SMTP_USERNAME").ok(),
+                password: env::var("SMTP_PASSWORD").ok(),
+            },
+            _ => MailTransport::File {
+                dir: env::var("MAIL_OUTBOX_DIR")
+                    .unwrap_or_else(|_| "./outbox".to_string())
+                    .into(),
+            },
+        };
+
+        Ok(Self {
+            public_url: env::var("PUBLIC_URL")
+                .unwrap_or_else(|_| "http://localhost:3000".to_string()),
+            from: env::var("MAIL_FROM")
+                .unwrap_or_else(|_| "LotaBots <no-reply@lotabots.local>".to_string()),
+            transport,
+        })
+    }
+
+    pub fn build_mailer(&self) -> Result<Arc<dyn Mailer>, MailerError> {
+        match &self.transport {
+            MailTransport::Smtp {
+                host,
+                port,
+                username,
+                password,
+            } => {
+                let credentials = username.clone().zip(password.clone());
+                Ok(Arc::new(SmtpMailer::new(host, *port, credentials, &self.from)?))
+            }
+            MailTransport::File { dir } => Ok(Arc::new(FileMailer::new(dir.clone())?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_memory_mailer_records_emails() {
+        let mailer = InMemoryMailer::new();
+        mailer
+            .send(Email::password_reset("a@example.com", "http://link/1"))
+            .await
+            .unwrap();
+        mailer
+            .send(Email::email_verification("b@example.com", "http://link/2"))
+            .await
+            .unwrap();
+
+        assert_eq!(mailer.sent().len(), 2);
+        let email = mailer.last_sent_to("a@example.com").unwrap();
+        assert!(email.body.contains("http://link/1"));
+        assert!(mailer.last_sent_to("c@example.com").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_file_mailer_writes_json() {
+        let dir = std::env::temp_dir().join(format!("lotabots-outbox-{}", Uuid::new_v4()));
+        let mailer = FileMailer::new(&dir).unwrap();
+        let email = Email::password_reset("a@example.com", "http://link/1");
+
+        mailer.send(email.clone()).await.unwrap();
+
+        let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        let written: Email =
+            serde_json::from_slice(&std::fs::read(entries[0].as_ref().unwrap().path()).unwrap())
+                .unwrap();
+        assert_eq!(written, email);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
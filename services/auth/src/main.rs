@@ -1,16 +1,21 @@
 use actix_web::{middleware, web::Data, App, HttpServer};
 use dotenv::dotenv;
+use lotabots_password::PasswordHasher;
+use lotabots_rate_limit::{actix::RateLimiter, RateLimit};
+use lotabots_secrets::{envelope, EnvelopeCipher, WatchOptions};
 use sqlx::postgres::PgPoolOptions;
-use tracing::info;
+use std::{sync::Arc, time::Duration};
+use tracing::{error, info};
 
 use lotabots_auth::{
     config::Config,
     handlers,
-    middleware::rate_limit::RateLimiter,
+    mailer::MailerConfig,
     repository::AuthRepository,
+    secret_rotation::{self, RotationPolicy, SecretRotation},
+    secrets,
     service::AuthService,
 };
-use shared::config::SecretsManager;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -33,16 +38,95 @@ async fn main() -> std::io::Result<()> {
 
     // Create repository and service
     let repository = AuthRepository::new(pool);
-    let jwt_secret = SecretsManager::get_secret("JWT_SECRET")
-        .expect("JWT_SECRET must be set");
-    let service = AuthService::new(repository, jwt_secret);
+    let secrets = Arc::new(secrets::from_env().expect("Invalid secrets configuration"));
+
+    // Encrypted columns: install the key-encryption key, bring stored values
+    // up to date and reseal them whenever the key is rotated
+    let kek_name = secrets::field_kek_name();
+    let cipher = EnvelopeCipher::load(secrets.as_ref(), &kek_name)
+        .await
+        .expect("Failed to load the field encryption key");
+    let kek_version = cipher.current_version();
+    info!(
+        "Field encryption uses {} at version {}",
+        kek_name, kek_version
+    );
+    envelope::install(cipher);
+    repository
+        .reseal_client_secrets()
+        .await
+        .expect("Failed to encrypt OIDC client secrets");
+    let reseal_repository = repository.clone();
+    envelope::follow(
+        secrets.clone(),
+        &kek_name,
+        kek_version,
+        WatchOptions::from_env(),
+        move |cipher| {
+            envelope::install(cipher);
+            let repository = reseal_repository.clone();
+            async move {
+                if let Err(e) = repository.reseal_client_secrets().await {
+                    error!("Failed to reseal OIDC client secrets: {}", e);
+                }
+            }
+        },
+    );
+
+    let rotation_policy = RotationPolicy::from_env();
+    let rotation = rotation_policy
+        .as_ref()
+        .map(|policy| Arc::new(SecretRotation::new(secrets.clone(), policy.grace_period)));
+    let grace_period = rotation_policy
+        .as_ref()
+        .map_or(Duration::from_secs(24 * 60 * 60), |policy| {
+            policy.grace_period
+        });
+    let jwt_keys = secret_rotation::follow_secret(
+        secrets.clone(),
+        "JWT_SECRET",
+        rotation.as_deref(),
+        grace_period,
+    )
+    .await
+    .expect("JWT_SECRET must be set");
+    if let (Some(rotation), Some(policy)) = (rotation, rotation_policy) {
+        info!("Rotating JWT_SECRET every {:?}", policy.interval);
+        rotation.schedule(vec!["JWT_SECRET".to_string()], policy.interval);
+    }
+    let mailer_config = MailerConfig::from_env().expect("Failed to load mailer configuration");
+    let mailer = mailer_config
+        .build_mailer()
+        .expect("Failed to initialize mailer");
+    let password_hasher =
+        PasswordHasher::from_env().expect("Invalid password hashing configuration");
+    let service = AuthService::new(
+        repository,
+        jwt_keys.current().value,
+        mailer,
+        mailer_config.public_url,
+    )
+    .with_password_hasher(password_hasher)
+    .with_jwt_keys(jwt_keys);
+
+    let rate_limits = lotabots_rate_limit::from_env()
+        .await
+        .expect("Invalid rate limit configuration");
 
     info!("Starting server at {}", addr);
 
     // Start HTTP server
     HttpServer::new(move || {
         App::new()
-            .wrap(RateLimiter::new(60)) // 60 requests per minute
+            .wrap(
+                RateLimiter::new(rate_limits.clone(), "auth", RateLimit::per_minute(60))
+                    // Usually reached through the gateway
+                    .with_key(|req| {
+                        req.connection_info()
+                            .realip_remote_addr()
+                            .map(str::to_string)
+                    }),
+            )
             .wrap(middleware::Logger::default())
             .app_data(Data::new(service.clone()))
             .configure(handlers::config)
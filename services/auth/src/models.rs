@@ -10,6 +10,10 @@ pub struct User {
     pub email: String,
     #[serde(skip_serializing)]
     pub password_hash: String,
+    /// Tenant whose identity provider created the account, if any
+    pub tenant: Option<String>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub password_changed_at: DateTime<Utc>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -22,6 +26,9 @@ impl User {
             username,
             email,
             password_hash,
+            tenant: None,
+            email_verified_at: None,
+            password_changed_at: now,
             created_at: now,
             updated_at: now,
         }
@@ -33,6 +40,7 @@ pub struct UserResponse {
     pub id: Uuid,
     pub username: String,
     pub email: String,
+    pub email_verified: bool,
     pub created_at: DateTime<Utc>,
 }
 
@@ -42,6 +50,7 @@ impl From<User> for UserResponse {
             id: user.id,
             username: user.username,
             email: user.email,
+            email_verified: user.email_verified_at.is_some(),
             created_at: user.created_at,
         }
     }
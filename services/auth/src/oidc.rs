@@ -0,0 +1,507 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use lotabots_secrets::{Column, Encrypted};
+use rand::RngCore;
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use url::Url;
+
+/// How long discovery documents and key sets are cached before they are
+/// fetched again
+const METADATA_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Signing algorithms accepted for ID tokens. Symmetric algorithms are
+/// deliberately excluded.
+const ALLOWED_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Debug, Error)]
+pub enum OidcError {
+    #[error("No identity provider configured for tenant {0}")]
+    UnknownProvider(String),
+    #[error("Discovery failed: {0}")]
+    Discovery(String),
+    #[error("Invalid or expired login state")]
+    InvalidState,
+    #[error("Identity provider returned an error: {0}")]
+    Provider(String),
+    #[error("Code exchange failed: {0}")]
+    Exchange(String),
+    #[error("Invalid ID token: {0}")]
+    InvalidIdToken(String),
+    #[error("Email address is not verified by the identity provider")]
+    EmailNotVerified,
+    #[error("Another account already uses this email address")]
+    AccountConflict,
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+/// Identity provider settings for one tenant
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcProvider {
+    pub tenant: String,
+    pub issuer: String,
+    pub client_id: String,
+    /// Confidential clients authenticate with `client_secret_basic`; public
+    /// clients rely on PKCE alone
+    #[serde(skip_serializing)]
+    pub client_secret: Option<Encrypted<String, OidcClientSecret>>,
+    pub redirect_uri: String,
+    /// Space separated scopes requested in addition to `openid`
+    pub scopes: String,
+    /// Email domains the tenant has proven it controls. Logins may link to
+    /// an account outside the tenant only when its email is in one of these.
+    #[serde(default)]
+    pub verified_domains: Vec<String>,
+}
+
+impl OidcProvider {
+    /// Whether `email` belongs to one of the tenant's verified domains
+    pub fn owns_domain(&self, email: &str) -> bool {
+        let Some((_, domain)) = email.rsplit_once('@') else {
+            return false;
+        };
+        self.verified_domains
+            .iter()
+            .any(|verified| verified.eq_ignore_ascii_case(domain))
+    }
+}
+
+/// `oidc_providers.client_secret`
+pub struct OidcClientSecret;
+
+impl Column for OidcClientSecret {
+    const TABLE: &'static str = "oidc_providers";
+    const COLUMN: &'static str = "client_secret";
+}
+
+/// The subset of the OpenID Provider metadata used by the relying party
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiscoveryDocument {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// PKCE verifier and its S256 challenge (RFC 7636)
+#[derive(Debug, Clone)]
+pub struct Pkce {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl Pkce {
+    pub fn generate() -> Self {
+        Self::from_verifier(random_token())
+    }
+
+    pub fn from_verifier(verifier: String) -> Self {
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        Self { verifier, challenge }
+    }
+}
+
+/// 256 bits of randomness, base64url encoded. Used for `state`, `nonce` and
+/// PKCE verifiers.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// A login that has been started but not yet completed. Everything except
+/// `url` must be kept server side until the callback arrives.
+#[derive(Debug, Clone)]
+pub struct AuthorizationRequest {
+    pub url: String,
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+/// The server side half of a started login, looked up by `state` when the
+/// provider redirects back
+#[derive(Debug, Clone)]
+pub struct PendingLogin {
+    pub tenant: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    pub id_token: String,
+    pub access_token: Option<String>,
+    pub token_type: Option<String>,
+}
+
+/// Claims of a validated ID token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    /// Some providers send this as the string `"true"`
+    #[serde(default, deserialize_with = "deserialize_bool_or_string")]
+    pub email_verified: bool,
+    pub name: Option<String>,
+    pub preferred_username: Option<String>,
+}
+
+impl IdTokenClaims {
+    /// The email address, if the provider vouches for it
+    pub fn verified_email(&self) -> Option<&str> {
+        self.email.as_deref().filter(|_| self.email_verified)
+    }
+}
+
+fn deserialize_bool_or_string<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrString {
+        Bool(bool),
+        String(String),
+    }
+
+    Ok(match BoolOrString::deserialize(deserializer)? {
+        BoolOrString::Bool(value) => value,
+        BoolOrString::String(value) => value.eq_ignore_ascii_case("true"),
+    })
+}
+
+#[derive(Clone)]
+struct ProviderMetadata {
+    discovery: DiscoveryDocument,
+    jwks: JwkSet,
+    fetched_at: Instant,
+}
+
+/// OpenID Connect relying party. Cheap to clone; discovery documents and
+/// key sets are cached per issuer and shared between clones.
+#[derive(Clone)]
+pub struct OidcClient {
+    http: reqwest::Client,
+    metadata: Arc<RwLock<HashMap<String, ProviderMetadata>>>,
+}
+
+impl Default for OidcClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OidcClient {
+    pub fn new() -> Self {
+        Self {
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("Failed to build HTTP client"),
+            metadata: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub async fn discover(&self, issuer: &str) -> Result<DiscoveryDocument, OidcError> {
+        Ok(self.metadata(issuer, false).await?.discovery)
+    }
+
+    /// Builds the URL the user agent is sent to, along with the state,
+    /// nonce and PKCE verifier that the callback will be checked against
+    pub async fn authorization_request(
+        &self,
+        provider: &OidcProvider,
+    ) -> Result<AuthorizationRequest, OidcError> {
+        let discovery = self.discover(&provider.issuer).await?;
+        let state = random_token();
+        let nonce = random_token();
+        let pkce = Pkce::generate();
+
+        let mut scopes = vec!["openid"];
+        scopes.extend(provider.scopes.split_whitespace().filter(|s| *s != "openid"));
+
+        let mut url = Url::parse(&discovery.authorization_endpoint)
+            .map_err(|e| OidcError::Discovery(format!("Invalid authorization endpoint: {}", e)))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &provider.client_id)
+            .append_pair("redirect_uri", &provider.redirect_uri)
+            .append_pair("scope", &scopes.join(" "))
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &pkce.challenge)
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(AuthorizationRequest {
+            url: url.into(),
+            state,
+            nonce,
+            code_verifier: pkce.verifier,
+        })
+    }
+
+    pub async fn exchange_code(
+        &self,
+        provider: &OidcProvider,
+        code: &str,
+        code_verifier: &str,
+    ) -> Result<TokenResponse, OidcError> {
+        let discovery = self.discover(&provider.issuer).await?;
+
+        let mut request = self.http.post(&discovery.token_endpoint).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", provider.redirect_uri.as_str()),
+            ("client_id", provider.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ]);
+        if let Some(secret) = provider.client_secret.as_deref() {
+            request = request.basic_auth(&provider.client_id, Some(secret));
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| OidcError::Exchange(e.to_string()))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(OidcError::Exchange(format!("{}: {}", status, body)));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| OidcError::Exchange(format!("Invalid token response: {}", e)))
+    }
+
+    /// Validates an ID token against the provider's published keys. An
+    /// unknown key id triggers one refresh of the key set to pick up key
+    /// rotation at the provider.
+    pub async fn validate_id_token(
+        &self,
+        provider: &OidcProvider,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, OidcError> {
+        let metadata = self.metadata(&provider.issuer, false).await?;
+        match verify_id_token(id_token, &metadata.jwks, &provider.issuer, &provider.client_id, nonce) {
+            Err(OidcError::InvalidIdToken(reason)) if reason == UNKNOWN_KEY => {
+                let metadata = self.metadata(&provider.issuer, true).await?;
+                verify_id_token(id_token, &metadata.jwks, &provider.issuer, &provider.client_id, nonce)
+            }
+            result => result,
+        }
+    }
+
+    async fn metadata(&self, issuer: &str, refresh: bool) -> Result<ProviderMetadata, OidcError> {
+        if !refresh {
+            let cached = self.metadata.read().unwrap().get(issuer).cloned();
+            if let Some(cached) = cached.filter(|m| m.fetched_at.elapsed() < METADATA_TTL) {
+                return Ok(cached);
+            }
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let discovery: DiscoveryDocument = self.get_json(&url).await?;
+        // The issuer in the document must be exactly the one configured,
+        // otherwise tokens could be accepted for a different provider
+        if discovery.issuer != issuer {
+            return Err(OidcError::Discovery(format!(
+                "Issuer mismatch: expected {}, got {}",
+                issuer, discovery.issuer
+            )));
+        }
+        let jwks: JwkSet = self.get_json(&discovery.jwks_uri).await?;
+
+        let metadata = ProviderMetadata {
+            discovery,
+            jwks,
+            fetched_at: Instant::now(),
+        };
+        self.metadata
+            .write()
+            .unwrap()
+            .insert(issuer.to_string(), metadata.clone());
+
+        Ok(metadata)
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, OidcError> {
+        self.http
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| OidcError::Discovery(format!("{}: {}", url, e)))?
+            .json()
+            .await
+            .map_err(|e| OidcError::Discovery(format!("{}: {}", url, e)))
+    }
+}
+
+const UNKNOWN_KEY: &str = "unknown signing key";
+
+/// Checks the signature, issuer, audience, expiry and nonce of an ID token
+pub fn verify_id_token(
+    id_token: &str,
+    jwks: &JwkSet,
+    issuer: &str,
+    client_id: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, OidcError> {
+    let header = decode_header(id_token).map_err(|e| OidcError::InvalidIdToken(e.to_string()))?;
+    if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+        return Err(OidcError::InvalidIdToken(format!(
+            "Unsupported algorithm {:?}",
+            header.alg
+        )));
+    }
+
+    let jwk = find_key(jwks, header.kid.as_deref())
+        .ok_or_else(|| OidcError::InvalidIdToken(UNKNOWN_KEY.to_string()))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|e| OidcError::InvalidIdToken(e.to_string()))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[issuer]);
+    validation.set_audience(&[client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+    let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+        .map_err(|e| OidcError::InvalidIdToken(e.to_string()))?
+        .claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(OidcError::InvalidIdToken("nonce mismatch".to_string()));
+    }
+
+    Ok(claims)
+}
+
+fn find_key<'a>(jwks: &'a JwkSet, kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => jwks.find(kid),
+        // Without a key id the token is only usable if there is no ambiguity
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    const ISSUER: &str = "https://idp.example.com";
+    const CLIENT_ID: &str = "lotabots";
+
+    fn jwks() -> JwkSet {
+        serde_json::from_str(include_str!("../tests/fixtures/oidc_jwks.json")).unwrap()
+    }
+
+    fn sign(claims: serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test-key".to_string());
+        let key = EncodingKey::from_rsa_pem(include_bytes!("../tests/fixtures/oidc_rsa.pem")).unwrap();
+        encode(&header, &claims, &key).unwrap()
+    }
+
+    fn claims() -> serde_json::Value {
+        json!({
+            "iss": ISSUER,
+            "aud": CLIENT_ID,
+            "sub": "user-1",
+            "exp": chrono::Utc::now().timestamp() + 300,
+            "nonce": "n-0S6_WzA2Mj",
+            "email": "jane@example.com",
+            "email_verified": "true",
+        })
+    }
+
+    #[test]
+    fn test_pkce_s256_challenge() {
+        // Example from RFC 7636, appendix B
+        let pkce = Pkce::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_string());
+        assert_eq!(pkce.challenge, "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+    }
+
+    #[test]
+    fn test_verify_valid_id_token() {
+        let claims = verify_id_token(&sign(claims()), &jwks(), ISSUER, CLIENT_ID, "n-0S6_WzA2Mj")
+            .unwrap();
+
+        assert_eq!(claims.sub, "user-1");
+        assert_eq!(claims.verified_email(), Some("jane@example.com"));
+    }
+
+    #[test]
+    fn test_reject_wrong_audience_issuer_and_nonce() {
+        let token = sign(claims());
+
+        assert!(verify_id_token(&token, &jwks(), ISSUER, "other-client", "n-0S6_WzA2Mj").is_err());
+        assert!(verify_id_token(&token, &jwks(), "https://evil.example.com", CLIENT_ID, "n-0S6_WzA2Mj").is_err());
+        assert!(verify_id_token(&token, &jwks(), ISSUER, CLIENT_ID, "other-nonce").is_err());
+    }
+
+    #[test]
+    fn test_reject_expired_id_token() {
+        let mut claims = claims();
+        claims["exp"] = json!(chrono::Utc::now().timestamp() - 3600);
+
+        assert!(verify_id_token(&sign(claims), &jwks(), ISSUER, CLIENT_ID, "n-0S6_WzA2Mj").is_err());
+    }
+
+    #[test]
+    fn test_reject_symmetric_and_unknown_keys() {
+        let hs256 = encode(
+            &Header::new(Algorithm::HS256),
+            &claims(),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert!(verify_id_token(&hs256, &jwks(), ISSUER, CLIENT_ID, "n-0S6_WzA2Mj").is_err());
+
+        let empty = JwkSet { keys: Vec::new() };
+        match verify_id_token(&sign(claims()), &empty, ISSUER, CLIENT_ID, "n-0S6_WzA2Mj") {
+            Err(OidcError::InvalidIdToken(reason)) => assert_eq!(reason, UNKNOWN_KEY),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unverified_email_is_not_exposed() {
+        let mut claims = claims();
+        claims["email_verified"] = json!(false);
+
+        let claims = verify_id_token(&sign(claims), &jwks(), ISSUER, CLIENT_ID, "n-0S6_WzA2Mj").unwrap();
+        assert_eq!(claims.verified_email(), None);
+    }
+}
@@ -1,8 +1,13 @@
+use chrono::{DateTime, Utc};
+use lotabots_secrets::Encrypted;
 use sqlx::PgPool;
-use time::OffsetDateTime;
 use uuid::Uuid;
 
-use crate::User;
+use crate::{
+    models::User,
+    oidc::{AuthorizationRequest, OidcClientSecret, OidcProvider, PendingLogin},
+    tokens::{IssuedToken, TokenPurpose},
+};
 
 #[derive(Clone)]
 pub struct AuthRepository {
@@ -18,14 +23,16 @@ impl AuthRepository {
         sqlx::query_as!(
             User,
             r#"
-            INSERT INTO users (id, username, email, password_hash, created_at, updated_at)
-            VALUES ($1, $2, $3, $4, $5, $6)
-            RETURNING id, username, email, password_hash, created_at, updated_at
+            INSERT INTO users (id, username, email, password_hash, tenant, password_changed_at, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id, username, email, password_hash, tenant, email_verified_at, password_changed_at, created_at, updated_at
             "#,
             user.id,
             user.username,
             user.email,
             user.password_hash,
+            user.tenant,
+            user.password_changed_at,
             user.created_at,
             user.updated_at,
         )
@@ -37,7 +44,7 @@ impl AuthRepository {
         sqlx::query_as!(
             User,
             r#"
-            SELECT id, username, email, password_hash, created_at, updated_at
+            SELECT id, username, email, password_hash, tenant, email_verified_at, password_changed_at, created_at, updated_at
             FROM users
             WHERE username = $1
             "#,
@@ -51,7 +58,7 @@ impl AuthRepository {
         sqlx::query_as!(
             User,
             r#"
-            SELECT id, username, email, password_hash, created_at, updated_at
+            SELECT id, username, email, password_hash, tenant, email_verified_at, password_changed_at, created_at, updated_at
             FROM users
             WHERE email = $1
             "#,
@@ -65,7 +72,7 @@ impl AuthRepository {
         sqlx::query_as!(
             User,
             r#"
-            SELECT id, username, email, password_hash, created_at, updated_at
+            SELECT id, username, email, password_hash, tenant, email_verified_at, password_changed_at, created_at, updated_at
             FROM users
             WHERE id = $1
             "#,
@@ -74,4 +81,332 @@ impl AuthRepository {
         .fetch_optional(&self.pool)
         .await
     }
+
+    pub async fn store_action_token(&self, token: &IssuedToken) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO auth_action_tokens (id, user_id, purpose, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            token.id,
+            token.user_id,
+            token.purpose.as_str(),
+            token.expires_at,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks a token as used. Returns `false` if the token is unknown, expired,
+    /// revoked or was already consumed, so each token works at most once.
+    pub async fn consume_action_token(
+        &self,
+        id: Uuid,
+        purpose: TokenPurpose,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE auth_action_tokens
+            SET used_at = NOW()
+            WHERE id = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW()
+            "#,
+            id,
+            purpose.as_str(),
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Invalidates all outstanding tokens of a purpose for a user
+    pub async fn revoke_action_tokens(
+        &self,
+        user_id: Uuid,
+        purpose: TokenPurpose,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE auth_action_tokens
+            SET used_at = NOW()
+            WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL
+            "#,
+            user_id,
+            purpose.as_str(),
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Consumes a password reset token and sets the new password in one
+    /// transaction. Bumping `password_changed_at` invalidates every JWT issued
+    /// before the reset, and the user's other reset links are revoked.
+    /// Returns `false`, changing nothing, if the token cannot be consumed.
+    pub async fn reset_password(
+        &self,
+        token_id: Uuid,
+        user_id: Uuid,
+        password_hash: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let purpose = TokenPurpose::PasswordReset.as_str();
+        let mut tx = self.pool.begin().await?;
+
+        let consumed = sqlx::query!(
+            r#"
+            UPDATE auth_action_tokens
+            SET used_at = NOW()
+            WHERE id = $1 AND user_id = $2 AND purpose = $3
+              AND used_at IS NULL AND expires_at > NOW()
+            "#,
+            token_id,
+            user_id,
+            purpose,
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if consumed != 1 {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET password_hash = $1, password_changed_at = NOW(), updated_at = NOW()
+            WHERE id = $2
+            "#,
+            password_hash,
+            user_id,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE auth_action_tokens
+            SET used_at = NOW()
+            WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL
+            "#,
+            user_id,
+            purpose,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Replaces the password hash without touching `password_changed_at`.
+    /// Used when an existing hash is upgraded to the current policy, which
+    /// must not log the user out.
+    pub async fn update_password_hash(&self, user_id: Uuid, password_hash: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET password_hash = $1, updated_at = NOW()
+            WHERE id = $2
+            "#,
+            password_hash,
+            user_id,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn mark_email_verified(&self, user_id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET email_verified_at = COALESCE(email_verified_at, NOW()), updated_at = NOW()
+            WHERE id = $1
+            "#,
+            user_id,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn find_oidc_provider(&self, tenant: &str) -> Result<Option<OidcProvider>, sqlx::Error> {
+        sqlx::query_as!(
+            OidcProvider,
+            r#"
+            SELECT tenant, issuer, client_id,
+                client_secret as "client_secret: Encrypted<String, OidcClientSecret>",
+                redirect_uri, scopes, verified_domains
+            FROM oidc_providers
+            WHERE tenant = $1
+            "#,
+            tenant
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn upsert_oidc_provider(&self, provider: &OidcProvider) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO oidc_providers (tenant, issuer, client_id, client_secret, redirect_uri, scopes, verified_domains)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (tenant) DO UPDATE
+            SET issuer = EXCLUDED.issuer,
+                client_id = EXCLUDED.client_id,
+                client_secret = EXCLUDED.client_secret,
+                redirect_uri = EXCLUDED.redirect_uri,
+                scopes = EXCLUDED.scopes,
+                verified_domains = EXCLUDED.verified_domains,
+                updated_at = NOW()
+            "#,
+            provider.tenant,
+            provider.issuer,
+            provider.client_id,
+            provider.client_secret as _,
+            provider.redirect_uri,
+            provider.scopes,
+            &provider.verified_domains,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Encrypts client secrets stored as plaintext and re-wraps those sealed
+    /// with an older key-encryption key. Returns the number of providers
+    /// updated; providers changed concurrently are picked up by the next run.
+    pub async fn reseal_client_secrets(&self) -> Result<u64, sqlx::Error> {
+        let providers = sqlx::query!(
+            r#"
+            SELECT tenant, client_secret as "client_secret!"
+            FROM oidc_providers
+            WHERE client_secret IS NOT NULL
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut updated = 0;
+        for provider in providers {
+            let sealed = Encrypted::<String, OidcClientSecret>::reseal(&provider.client_secret)
+                .map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+            if let Some(sealed) = sealed {
+                updated += sqlx::query!(
+                    r#"
+                    UPDATE oidc_providers
+                    SET client_secret = $1, updated_at = NOW()
+                    WHERE tenant = $2 AND client_secret = $3
+                    "#,
+                    sealed,
+                    provider.tenant,
+                    provider.client_secret,
+                )
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+            }
+        }
+
+        Ok(updated)
+    }
+
+    /// Remembers a started login until the provider redirects back. Expired
+    /// logins are cleaned up on the way.
+    pub async fn store_oidc_login_state(
+        &self,
+        tenant: &str,
+        request: &AuthorizationRequest,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM oidc_login_states WHERE expires_at <= NOW()")
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO oidc_login_states (state, tenant, nonce, code_verifier, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            request.state,
+            tenant,
+            request.nonce,
+            request.code_verifier,
+            expires_at,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes and returns a pending login, so each `state` value can
+    /// complete at most one login
+    pub async fn take_oidc_login_state(&self, state: &str) -> Result<Option<PendingLogin>, sqlx::Error> {
+        sqlx::query_as!(
+            PendingLogin,
+            r#"
+            DELETE FROM oidc_login_states
+            WHERE state = $1 AND expires_at > NOW()
+            RETURNING tenant, nonce, code_verifier
+            "#,
+            state
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn find_user_by_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query_as!(
+            User,
+            r#"
+            SELECT u.id, u.username, u.email, u.password_hash, u.tenant, u.email_verified_at, u.password_changed_at, u.created_at, u.updated_at
+            FROM users u
+            JOIN user_identities i ON i.user_id = u.id
+            WHERE i.issuer = $1 AND i.subject = $2
+            "#,
+            issuer,
+            subject
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Links an external identity to a user, or records a new login for an
+    /// identity that is already linked
+    pub async fn link_identity(
+        &self,
+        user_id: Uuid,
+        issuer: &str,
+        subject: &str,
+        email: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO user_identities (id, user_id, issuer, subject, email)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (issuer, subject) DO UPDATE
+            SET email = EXCLUDED.email, last_login_at = NOW()
+            "#,
+            Uuid::new_v4(),
+            user_id,
+            issuer,
+            subject,
+            email,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
 }
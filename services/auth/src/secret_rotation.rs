@@ -1,106 +1,241 @@
-use std::error::Error;
-use rand::RngCore;
-use crate::secrets::SecretManager;
+//! Scheduled rotation of the auth service's secrets.
+//!
+//! New versions are written through the shared secrets crate; the previous
+//! version is never revoked here. Services hold a [`KeyRing`] that follows
+//! the [`SecretChange`] notifications and keeps accepting the previous
+//! version for the grace period, so outstanding JWTs stay valid.
+
+use lotabots_secrets::{
+    KeyRing, Secret, SecretChange, SecretError, SecretManager, SecretRotator, WatchOptions,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::broadcast, task::JoinHandle};
+
+use crate::monitoring::{SecretMetrics, SecretOperationTimer};
+
+/// When and how secrets are rotated
+#[derive(Debug, Clone)]
+pub struct RotationPolicy {
+    pub interval: Duration,
+    /// How long the previous version keeps verifying. Must cover the
+    /// lifetime of the longest-lived token signed with it.
+    pub grace_period: Duration,
+}
+
+impl RotationPolicy {
+    /// Reads `SECRET_ROTATION_INTERVAL_SECS` and
+    /// `SECRET_ROTATION_GRACE_SECS` (default: one day, the JWT lifetime).
+    /// Returns `None` when no interval is set, i.e. rotation is disabled.
+    pub fn from_env() -> Option<Self> {
+        let secs = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_secs)
+        };
+
+        let interval = secs("SECRET_ROTATION_INTERVAL_SECS")?;
+        let grace_period =
+            secs("SECRET_ROTATION_GRACE_SECS").unwrap_or(Duration::from_secs(24 * 60 * 60));
+        Some(Self {
+            interval,
+            grace_period,
+        })
+    }
+}
 
 pub struct SecretRotation<T: SecretManager> {
-    secret_manager: T,
+    rotator: SecretRotator<T>,
 }
 
 impl<T: SecretManager> SecretRotation<T> {
-    pub fn new(secret_manager: T) -> Self {
-        Self { secret_manager }
+    pub fn new(secret_manager: T, grace_period: Duration) -> Self {
+        Self {
+            rotator: SecretRotator::new(secret_manager).with_grace_period(grace_period),
+        }
     }
 
-    pub async fn rotate_secret(&self, secret_name: &str) -> Result<(), Box<dyn Error>> {
-        // Generate new secret
-        let new_secret = self.generate_secure_secret()?;
-
-        // Store new secret
-        self.secret_manager.create_secret(secret_name, &new_secret).await?;
-
-        // Update application configuration
-        self.update_application_config(secret_name, &new_secret).await?;
-
-        // Revoke old secret
-        self.secret_manager.revoke_secret(secret_name).await?;
-
-        // Log rotation
-        tracing::info!("Successfully rotated secret: {}", secret_name);
-
-        Ok(())
+    /// Notifications of rotated secrets, for [`KeyRing::follow`]
+    pub fn subscribe(&self) -> broadcast::Receiver<SecretChange> {
+        self.rotator.subscribe()
     }
 
-    fn generate_secure_secret(&self) -> Result<String, Box<dyn Error>> {
-        let mut rng = rand::thread_rng();
-        let mut bytes = vec![0u8; 32];
-        rng.fill_bytes(&mut bytes);
-        Ok(hex::encode(bytes))
+    /// Writes a new version of `secret_name`. The previous version stays
+    /// readable so key rings can keep verifying with it.
+    pub async fn rotate_secret(&self, secret_name: &str) -> Result<Secret, SecretError> {
+        let _timer = SecretOperationTimer::new("rotate_secret");
+        let result = self.rotator.rotate(secret_name).await;
+        SecretMetrics::record_secret_rotation(secret_name, result.is_ok());
+        if let Err(e) = &result {
+            tracing::error!("Failed to rotate secret {}: {}", secret_name, e);
+        }
+        result
     }
+}
 
-    async fn update_application_config(&self, secret_name: &str, new_secret: &str) -> Result<(), Box<dyn Error>> {
-        // Implementation will depend on your application's configuration management
-        // This is a placeholder implementation
-        tracing::info!("Updating application configuration for secret: {}", secret_name);
-
-        // TODO: Implement actual configuration update logic
-        // For example:
-        // - Update environment variables
-        // - Update configuration files
-        // - Signal services to reload configuration
-
-        Ok(())
+impl<T: SecretManager + 'static> SecretRotation<T> {
+    /// Rotates each of `secret_names` every `interval`. A failed rotation is
+    /// retried on the next tick.
+    pub fn schedule(
+        self: Arc<Self>,
+        secret_names: Vec<String>,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        // Rotating faster than the grace period would drop versions that
+        // still have valid tokens
+        let interval = interval.max(self.rotator.grace_period());
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            // The first tick fires immediately; the current versions are fresh
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                for name in &secret_names {
+                    let _ = self.rotate_secret(name).await;
+                }
+            }
+        })
     }
 }
 
+/// Loads the key ring for `secret_name` and keeps it in step with `rotation`
+/// when this process rotates, and with the backend otherwise, so replicas
+/// pick up a rotation done elsewhere
+pub async fn follow_secret<T>(
+    secret_manager: T,
+    secret_name: &str,
+    rotation: Option<&SecretRotation<T>>,
+    grace_period: Duration,
+) -> Result<KeyRing, SecretError>
+where
+    T: SecretManager + Clone + 'static,
+{
+    let _timer = SecretOperationTimer::new("load_key_ring");
+    let ring = KeyRing::load(&secret_manager, secret_name, grace_period).await;
+    SecretMetrics::record_secret_retrieval(secret_name, ring.is_ok());
+    let ring = ring?;
+
+    match rotation {
+        Some(rotation) => ring.follow(secret_manager, rotation.subscribe()),
+        None => ring.watch(secret_manager, WatchOptions::from_env(), grace_period),
+    };
+    Ok(ring)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use mockall::predicate::*;
-    use mockall::*;
+    use async_trait::async_trait;
+    use std::{collections::HashMap, sync::Mutex};
+
+    /// Keeps every version of every secret in memory
+    #[derive(Clone, Default)]
+    struct MemorySecrets {
+        versions: Arc<Mutex<HashMap<String, Vec<String>>>>,
+        fail_writes: bool,
+    }
+
+    #[async_trait]
+    impl SecretManager for MemorySecrets {
+        async fn get_secret(&self, name: &str) -> lotabots_secrets::Result<Secret> {
+            let versions = self.versions.lock().unwrap();
+            let values = versions
+                .get(name)
+                .ok_or_else(|| SecretError::NotFound(name.to_string()))?;
+            Ok(Secret {
+                value: values.last().cloned().unwrap_or_default(),
+                version: Some(values.len() as u64),
+                lease_duration: None,
+            })
+        }
+
+        async fn get_secret_version(
+            &self,
+            name: &str,
+            version: u64,
+        ) -> lotabots_secrets::Result<Secret> {
+            let versions = self.versions.lock().unwrap();
+            versions
+                .get(name)
+                .and_then(|values| values.get(version.checked_sub(1)? as usize))
+                .map(|value| Secret {
+                    value: value.clone(),
+                    version: Some(version),
+                    lease_duration: None,
+                })
+                .ok_or_else(|| SecretError::NotFound(name.to_string()))
+        }
 
-    mock! {
-        pub TestSecretManager {}
+        async fn create_secret(&self, name: &str, value: &str) -> lotabots_secrets::Result<Secret> {
+            if self.fail_writes {
+                return Err(SecretError::Backend("Failed to create secret".to_string()));
+            }
+            let mut versions = self.versions.lock().unwrap();
+            let values = versions.entry(name.to_string()).or_default();
+            values.push(value.to_string());
+            Ok(Secret {
+                value: value.to_string(),
+                version: Some(values.len() as u64),
+                lease_duration: None,
+            })
+        }
 
-        #[async_trait]
-        impl SecretManager for TestSecretManager {
-            async fn get_secret(&self, secret_name: &str) -> Result<String, Box<dyn Error>>;
-            async fn create_secret(&self, secret_name: &str, secret_value: &str) -> Result<(), Box<dyn Error>>;
-            async fn revoke_secret(&self, secret_name: &str) -> Result<(), Box<dyn Error>>;
+        async fn revoke_secret(&self, name: &str) -> lotabots_secrets::Result<()> {
+            self.versions.lock().unwrap().remove(name);
+            Ok(())
         }
     }
 
     #[tokio::test]
     async fn test_rotate_secret_success() {
-        let mut mock = MockTestSecretManager::new();
-
-        mock.expect_create_secret()
-            .with(eq("test_secret"), function(|s: &str| s.len() == 64))
-            .times(1)
-            .returning(|_, _| Ok(()));
-
-        mock.expect_revoke_secret()
-            .with(eq("test_secret"))
-            .times(1)
-            .returning(|_| Ok(()));
+        let secrets = MemorySecrets::default();
+        secrets
+            .create_secret("test_secret", "initial")
+            .await
+            .unwrap();
+
+        let rotation = SecretRotation::new(secrets.clone(), Duration::from_secs(60));
+        let ring = follow_secret(
+            secrets.clone(),
+            "test_secret",
+            Some(&rotation),
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+
+        let rotated = rotation.rotate_secret("test_secret").await.unwrap();
+        assert_eq!(rotated.value.len(), 64);
+        assert_eq!(rotated.version, Some(2));
+
+        for _ in 0..50 {
+            if ring.current() == rotated {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
 
-        let rotation = SecretRotation::new(mock);
-        let result = rotation.rotate_secret("test_secret").await;
-        assert!(result.is_ok());
+        // Both versions verify during the grace period
+        let keys: Vec<_> = ring
+            .verification_keys()
+            .into_iter()
+            .map(|k| k.value)
+            .collect();
+        assert_eq!(keys, vec![rotated.value, "initial".to_string()]);
     }
 
     #[tokio::test]
     async fn test_rotate_secret_creation_failure() {
-        let mut mock = MockTestSecretManager::new();
-
-        mock.expect_create_secret()
-            .times(1)
-            .returning(|_, _| Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Failed to create secret",
-            ))));
+        let secrets = MemorySecrets {
+            fail_writes: true,
+            ..MemorySecrets::default()
+        };
 
-        let rotation = SecretRotation::new(mock);
+        let rotation = SecretRotation::new(secrets, Duration::from_secs(60));
+        let mut changes = rotation.subscribe();
         let result = rotation.rotate_secret("test_secret").await;
         assert!(result.is_err());
+        // Nothing is announced for a failed rotation
+        assert!(changes.try_recv().is_err());
     }
 }
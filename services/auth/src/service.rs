@@ -1,35 +1,78 @@
-use bcrypt::{hash, verify, DEFAULT_COST};
-use jsonwebtoken::{encode, EncodingKey, Header};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use lotabots_password::{PasswordHasher, Verification};
+use lotabots_secrets::{KeyRing, Secret};
 use serde::{Deserialize, Serialize};
-use time::OffsetDateTime;
+use std::sync::Arc;
 use uuid::Uuid;
 use validator::Validate;
 
 use crate::{
+    mailer::{Email, Mailer},
+    models::User,
+    oidc::{random_token, IdTokenClaims, OidcClient, OidcError, OidcProvider},
     repository::AuthRepository,
-    LoginRequest, RegisterRequest, User,
+    tokens::{TokenError, TokenPurpose, TokenSigner, VerifiedToken},
+    EmailVerificationRequest, ForgotPasswordRequest, LoginRequest, OidcCallbackQuery,
+    PasswordResetRequest, RegisterRequest, VerifyEmailRequest,
 };
 
+/// How long a user has to finish logging in at the identity provider
+const OIDC_LOGIN_TTL_MINUTES: i64 = 10;
+
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: String,
-    exp: usize,
+pub struct Claims {
+    pub sub: String,
+    pub exp: usize,
+    pub iat: usize,
 }
 
 #[derive(Clone)]
 pub struct AuthService {
     repository: AuthRepository,
-    jwt_secret: String,
+    jwt_keys: KeyRing,
+    token_signer: TokenSigner,
+    password_hasher: PasswordHasher,
+    oidc: OidcClient,
+    mailer: Arc<dyn Mailer>,
+    public_url: String,
 }
 
 impl AuthService {
-    pub fn new(repository: AuthRepository, jwt_secret: String) -> Self {
+    pub fn new(
+        repository: AuthRepository,
+        jwt_secret: String,
+        mailer: Arc<dyn Mailer>,
+        public_url: String,
+    ) -> Self {
+        let jwt_keys = KeyRing::new("JWT_SECRET", Secret::new(jwt_secret));
         Self {
             repository,
-            jwt_secret,
+            token_signer: TokenSigner::new(jwt_keys.clone()),
+            jwt_keys,
+            password_hasher: PasswordHasher::default(),
+            oidc: OidcClient::new(),
+            mailer,
+            public_url: public_url.trim_end_matches('/').to_string(),
         }
     }
 
+    /// Replaces the default hashing policy, e.g. with one loaded from the
+    /// environment.
+    pub fn with_password_hasher(mut self, password_hasher: PasswordHasher) -> Self {
+        self.password_hasher = password_hasher;
+        self
+    }
+
+    /// Signs and verifies JWTs, and the action tokens sent by email, with a
+    /// key ring that follows secret rotations instead of the fixed secret
+    /// given to `new`.
+    pub fn with_jwt_keys(mut self, jwt_keys: KeyRing) -> Self {
+        self.token_signer = TokenSigner::new(jwt_keys.clone());
+        self.jwt_keys = jwt_keys;
+        self
+    }
+
     pub async fn register(&self, req: RegisterRequest) -> Result<String, String> {
         // Validate request
         if let Err(e) = req.validate() {
@@ -37,27 +80,29 @@ impl AuthService {
         }
 
         // Hash password
-        let password_hash = hash(req.password.as_bytes(), DEFAULT_COST)
+        let password_hash = self
+            .password_hasher
+            .hash(&req.password)
             .map_err(|e| format!("Password hashing error: {}", e))?;
 
         // Create user
-        let user = User {
-            id: Uuid::new_v4(),
-            username: req.username,
-            email: req.email,
-            password_hash,
-            created_at: OffsetDateTime::now_utc(),
-            updated_at: OffsetDateTime::now_utc(),
-        };
+        let user = User::new(req.username, req.email, password_hash);
 
         let user_id = user.id;
 
         // Save user
-        self.repository
+        let user = self
+            .repository
             .create_user(user)
             .await
             .map_err(|e| format!("Database error: {}", e))?;
 
+        // A failed verification email must not fail the registration; the
+        // user can ask for another one.
+        if let Err(e) = self.send_verification_email(&user).await {
+            tracing::warn!("Failed to send verification email: {}", e);
+        }
+
         // Generate JWT
         self.generate_token(user_id)
     }
@@ -71,27 +116,393 @@ impl AuthService {
             .map_err(|e| format!("Database error: {}", e))?
             .ok_or_else(|| "Invalid credentials".to_string())?;
 
-        // Verify password
-        if !verify(req.password.as_bytes(), &user.password_hash)
-            .map_err(|e| format!("Password verification error: {}", e))? {
-            return Err("Invalid credentials".to_string());
+        // Verify password, upgrading the stored hash if the policy changed
+        match self
+            .password_hasher
+            .verify(&req.password, &user.password_hash)
+            .map_err(|e| format!("Password verification error: {}", e))?
+        {
+            Verification::Invalid => return Err("Invalid credentials".to_string()),
+            Verification::NeedsRehash(password_hash) => {
+                // The login already succeeded; a failed upgrade is retried
+                // on the next one.
+                if let Err(e) = self
+                    .repository
+                    .update_password_hash(user.id, &password_hash)
+                    .await
+                {
+                    tracing::warn!("Failed to upgrade password hash: {}", e);
+                }
+            }
+            Verification::Valid => {}
         }
 
         // Generate JWT
         self.generate_token(user.id)
     }
 
+    /// Starts a password reset. Always succeeds for well-formed requests so
+    /// callers cannot probe which email addresses are registered.
+    pub async fn request_password_reset(&self, req: ForgotPasswordRequest) -> Result<(), String> {
+        if let Err(e) = req.validate() {
+            return Err(format!("Validation error: {}", e));
+        }
+
+        let user = match self
+            .repository
+            .find_user_by_email(&req.email)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?
+        {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+
+        // Only the most recent reset link stays valid
+        self.repository
+            .revoke_action_tokens(user.id, TokenPurpose::PasswordReset)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?;
+
+        let issued = self.token_signer.issue(user.id, TokenPurpose::PasswordReset);
+        self.repository
+            .store_action_token(&issued)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?;
+
+        // Failing here would tell the caller the address is registered;
+        // the user can ask for another link.
+        let link = format!("{}/reset-password?token={}", self.public_url, issued.token);
+        if let Err(e) = self
+            .mailer
+            .send(Email::password_reset(&user.email, &link))
+            .await
+        {
+            tracing::warn!("Failed to send password reset email: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Completes a password reset. The token is consumed, the new password
+    /// stored and every session issued before the change is invalidated, all
+    /// or nothing: if any step fails the link can be used again.
+    pub async fn reset_password(&self, req: PasswordResetRequest) -> Result<(), String> {
+        if let Err(e) = req.validate() {
+            return Err(format!("Validation error: {}", e));
+        }
+
+        let token = self
+            .token_signer
+            .verify(&req.token, TokenPurpose::PasswordReset)
+            .map_err(|e| e.to_string())?;
+
+        let password_hash = self
+            .password_hasher
+            .hash(&req.new_password)
+            .map_err(|e| format!("Password hashing error: {}", e))?;
+
+        let reset = self
+            .repository
+            .reset_password(token.id, token.user_id, &password_hash)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?;
+        if !reset {
+            return Err(TokenError::AlreadyUsed.to_string());
+        }
+
+        tracing::info!("Password reset completed for user {}", token.user_id);
+        Ok(())
+    }
+
+    /// Sends a new verification link. Like password resets, unknown or
+    /// already verified addresses are silently accepted.
+    pub async fn request_email_verification(
+        &self,
+        req: EmailVerificationRequest,
+    ) -> Result<(), String> {
+        if let Err(e) = req.validate() {
+            return Err(format!("Validation error: {}", e));
+        }
+
+        match self
+            .repository
+            .find_user_by_email(&req.email)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?
+        {
+            Some(user) if user.email_verified_at.is_none() => {
+                self.send_verification_email(&user).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn verify_email(&self, req: VerifyEmailRequest) -> Result<(), String> {
+        if let Err(e) = req.validate() {
+            return Err(format!("Validation error: {}", e));
+        }
+
+        let token = self
+            .consume_token(&req.token, TokenPurpose::EmailVerification)
+            .await?;
+
+        self.repository
+            .mark_email_verified(token.user_id)
+            .await
+            .map_err(|e| format!("Database error: {}", e))
+    }
+
+    /// Starts single sign-on with the tenant's identity provider and returns
+    /// the URL the user agent has to be redirected to.
+    pub async fn begin_oidc_login(&self, tenant: &str) -> Result<String, OidcError> {
+        let provider = self.oidc_provider(tenant).await?;
+        let request = self.oidc.authorization_request(&provider).await?;
+
+        self.repository
+            .store_oidc_login_state(
+                tenant,
+                &request,
+                Utc::now() + Duration::minutes(OIDC_LOGIN_TTL_MINUTES),
+            )
+            .await
+            .map_err(|e| OidcError::Internal(format!("Database error: {}", e)))?;
+
+        Ok(request.url)
+    }
+
+    /// Handles the redirect back from the identity provider: redeems the
+    /// code, validates the ID token and signs the linked user in.
+    pub async fn complete_oidc_login(&self, callback: OidcCallbackQuery) -> Result<String, OidcError> {
+        // Consume the state first so it cannot be replayed, even when the
+        // provider reports an error
+        let pending = self
+            .repository
+            .take_oidc_login_state(&callback.state)
+            .await
+            .map_err(|e| OidcError::Internal(format!("Database error: {}", e)))?
+            .ok_or(OidcError::InvalidState)?;
+
+        if let Some(error) = callback.error {
+            return Err(OidcError::Provider(callback.error_description.unwrap_or(error)));
+        }
+        let code = callback
+            .code
+            .ok_or_else(|| OidcError::Provider("Missing authorization code".to_string()))?;
+
+        let provider = self.oidc_provider(&pending.tenant).await?;
+        let tokens = self
+            .oidc
+            .exchange_code(&provider, &code, &pending.code_verifier)
+            .await?;
+        let claims = self
+            .oidc
+            .validate_id_token(&provider, &tokens.id_token, &pending.nonce)
+            .await?;
+
+        let user = self.resolve_oidc_user(&provider, &claims).await?;
+        self.repository
+            .link_identity(user.id, &claims.iss, &claims.sub, claims.email.as_deref())
+            .await
+            .map_err(|e| OidcError::Internal(format!("Database error: {}", e)))?;
+
+        tracing::info!("User {} signed in through {}", user.id, provider.issuer);
+        self.generate_token(user.id).map_err(OidcError::Internal)
+    }
+
+    /// Validates a JWT and rejects tokens issued before the user's last
+    /// password change. Tokens signed with the previous JWT secret are
+    /// accepted until its rotation grace period ends.
+    pub async fn validate_token(&self, token: &str) -> Result<Claims, String> {
+        self.verify_session(token).await.map(|(claims, _)| claims)
+    }
+
+    /// Authenticates a request's bearer token, with the same checks as
+    /// [`validate_token`](Self::validate_token), and returns its user
+    pub async fn authenticate(&self, token: &str) -> Result<User, String> {
+        self.verify_session(token).await.map(|(_, user)| user)
+    }
+
+    async fn verify_session(&self, token: &str) -> Result<(Claims, User), String> {
+        let claims = self
+            .jwt_keys
+            .verification_keys()
+            .iter()
+            .find_map(|key| {
+                decode::<Claims>(
+                    token,
+                    &DecodingKey::from_secret(key.value.as_bytes()),
+                    &Validation::default(),
+                )
+                .ok()
+            })
+            .ok_or_else(|| "Invalid token".to_string())?
+            .claims;
+
+        let user_id = Uuid::parse_str(&claims.sub).map_err(|_| "Invalid token".to_string())?;
+        let user = self
+            .repository
+            .find_user_by_id(user_id)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?
+            .ok_or_else(|| "Invalid token".to_string())?;
+
+        // `iat` has a resolution of one second: tokens issued in the second
+        // of the change are still accepted
+        if (claims.iat as i64) < user.password_changed_at.timestamp() {
+            return Err("Session expired".to_string());
+        }
+
+        Ok((claims, user))
+    }
+
+    async fn oidc_provider(&self, tenant: &str) -> Result<OidcProvider, OidcError> {
+        self.repository
+            .find_oidc_provider(tenant)
+            .await
+            .map_err(|e| OidcError::Internal(format!("Database error: {}", e)))?
+            .ok_or_else(|| OidcError::UnknownProvider(tenant.to_string()))
+    }
+
+    /// Finds the local account for an external identity: an existing link,
+    /// then an account with the same verified email, then a new account.
+    /// Emails are only trusted when both sides have verified them, so an
+    /// account registered with someone else's address cannot be taken over.
+    /// An identity provider only speaks for its own tenant's accounts, and for
+    /// accounts of no tenant on the domains the tenant has verified. Accounts
+    /// of another tenant are always refused, whatever their domain.
+    async fn resolve_oidc_user(
+        &self,
+        provider: &OidcProvider,
+        claims: &IdTokenClaims,
+    ) -> Result<User, OidcError> {
+        let db_error = |e: sqlx::Error| OidcError::Internal(format!("Database error: {}", e));
+
+        if let Some(user) = self
+            .repository
+            .find_user_by_identity(&claims.iss, &claims.sub)
+            .await
+            .map_err(db_error)?
+        {
+            return Ok(user);
+        }
+
+        let email = claims.verified_email().ok_or(OidcError::EmailNotVerified)?;
+        if let Some(user) = self
+            .repository
+            .find_user_by_email(email)
+            .await
+            .map_err(db_error)?
+        {
+            let may_link = match user.tenant.as_deref() {
+                Some(tenant) => tenant == provider.tenant,
+                None => provider.owns_domain(email),
+            };
+            if user.email_verified_at.is_none() || !may_link {
+                return Err(OidcError::AccountConflict);
+            }
+            return Ok(user);
+        }
+
+        // SSO-only accounts get a random password; a local password can be
+        // set later through the reset flow
+        let password_hash = self
+            .password_hasher
+            .hash(&random_token())
+            .map_err(|e| OidcError::Internal(format!("Password hashing error: {}", e)))?;
+        let mut user = User::new(sso_username(claims, email), email.to_string(), password_hash);
+        user.tenant = Some(provider.tenant.clone());
+
+        let user = self.repository.create_user(user).await.map_err(db_error)?;
+        self.repository
+            .mark_email_verified(user.id)
+            .await
+            .map_err(db_error)?;
+
+        Ok(user)
+    }
+
+    async fn send_verification_email(&self, user: &User) -> Result<(), String> {
+        self.repository
+            .revoke_action_tokens(user.id, TokenPurpose::EmailVerification)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?;
+
+        let issued = self
+            .token_signer
+            .issue(user.id, TokenPurpose::EmailVerification);
+        self.repository
+            .store_action_token(&issued)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?;
+
+        let link = format!("{}/verify-email?token={}", self.public_url, issued.token);
+        self.mailer
+            .send(Email::email_verification(&user.email, &link))
+            .await
+            .map_err(|e| format!("Mail error: {}", e))
+    }
+
+    async fn consume_token(
+        &self,
+        token: &str,
+        purpose: TokenPurpose,
+    ) -> Result<VerifiedToken, String> {
+        let verified = self
+            .token_signer
+            .verify(token, purpose)
+            .map_err(|e| e.to_string())?;
+
+        let consumed = self
+            .repository
+            .consume_action_token(verified.id, purpose)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?;
+
+        if !consumed {
+            return Err(TokenError::AlreadyUsed.to_string());
+        }
+
+        Ok(verified)
+    }
+
     fn generate_token(&self, user_id: Uuid) -> Result<String, String> {
+        let now = Utc::now();
         let claims = Claims {
             sub: user_id.to_string(),
-            exp: (OffsetDateTime::now_utc() + time::Duration::hours(24)).unix_timestamp() as usize,
+            exp: (now + Duration::hours(24)).timestamp() as usize,
+            iat: now.timestamp() as usize,
         };
 
+        let key = self.jwt_keys.current();
+        let header = Header {
+            kid: key.version.map(|version| version.to_string()),
+            ..Header::default()
+        };
         encode(
-            &Header::default(),
+            &header,
             &claims,
-            &EncodingKey::from_secret(self.jwt_secret.as_bytes()),
+            &EncodingKey::from_secret(key.value.as_bytes()),
         )
         .map_err(|e| format!("Token generation error: {}", e))
     }
 }
+
+/// Derives a unique username for a new SSO account from the provider's
+/// preferred username or the email's local part
+fn sso_username(claims: &IdTokenClaims, email: &str) -> String {
+    let base: String = claims
+        .preferred_username
+        .as_deref()
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default())
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        .take(40)
+        .collect();
+    let suffix = &Uuid::new_v4().simple().to_string()[..8];
+
+    if base.is_empty() {
+        format!("user-{}", suffix)
+    } else {
+        format!("{}-{}", base, suffix)
+    }
+}
@@ -0,0 +1,278 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use lotabots_secrets::KeyRing;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Separates the action token key from the JWT key it is derived from
+const KEY_CONTEXT: &[u8] = b"lotabots-auth/action-token";
+
+/// What an action token may be used for. A token issued for one purpose is
+/// never accepted for another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenPurpose {
+    PasswordReset,
+    EmailVerification,
+}
+
+impl TokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenPurpose::PasswordReset => "password_reset",
+            TokenPurpose::EmailVerification => "email_verification",
+        }
+    }
+
+    /// Default lifetime of a token issued for this purpose
+    pub fn default_ttl(&self) -> Duration {
+        match self {
+            TokenPurpose::PasswordReset => Duration::hours(1),
+            TokenPurpose::EmailVerification => Duration::hours(24),
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TokenError {
+    #[error("Malformed token")]
+    Malformed,
+    #[error("Invalid token signature")]
+    InvalidSignature,
+    #[error("Token expired")]
+    Expired,
+    #[error("Token issued for a different purpose")]
+    WrongPurpose,
+    #[error("Token already used")]
+    AlreadyUsed,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenPayload {
+    jti: Uuid,
+    sub: Uuid,
+    purpose: TokenPurpose,
+    exp: i64,
+}
+
+/// A freshly issued token. `token` is handed to the user; only `id` is
+/// persisted so the token can be consumed exactly once.
+#[derive(Debug, Clone)]
+pub struct IssuedToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub purpose: TokenPurpose,
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A token whose signature, purpose and expiry have been checked. It still
+/// has to be consumed through the repository before it is acted upon.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub purpose: TokenPurpose,
+}
+
+/// Issues and verifies HMAC-SHA256 signed action tokens of the form
+/// `<base64url payload>.<base64url signature>`.
+///
+/// The signing key is derived from the current version of a key ring, so
+/// tokens follow secret rotations: they are signed with the current version
+/// and verified with every version still in its grace period.
+#[derive(Clone)]
+pub struct TokenSigner {
+    keys: KeyRing,
+}
+
+impl TokenSigner {
+    pub fn new(keys: KeyRing) -> Self {
+        Self { keys }
+    }
+
+    pub fn issue(&self, user_id: Uuid, purpose: TokenPurpose) -> IssuedToken {
+        self.issue_with_ttl(user_id, purpose, purpose.default_ttl())
+    }
+
+    pub fn issue_with_ttl(&self, user_id: Uuid, purpose: TokenPurpose, ttl: Duration) -> IssuedToken {
+        let expires_at = Utc::now() + ttl;
+        let payload = TokenPayload {
+            jti: Uuid::new_v4(),
+            sub: user_id,
+            purpose,
+            exp: expires_at.timestamp(),
+        };
+
+        let encoded = URL_SAFE_NO_PAD.encode(
+            serde_json::to_vec(&payload).expect("token payload is always serializable"),
+        );
+        let mut mac = mac(self.keys.current().value.as_bytes());
+        mac.update(encoded.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+        IssuedToken {
+            id: payload.jti,
+            user_id,
+            purpose,
+            token: format!("{}.{}", encoded, signature),
+            expires_at: Utc.timestamp_opt(payload.exp, 0).single().unwrap_or(expires_at),
+        }
+    }
+
+    pub fn verify(&self, token: &str, purpose: TokenPurpose) -> Result<VerifiedToken, TokenError> {
+        let (encoded, signature) = token.split_once('.').ok_or(TokenError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Malformed)?;
+
+        let signed = self.keys.verification_keys().iter().any(|key| {
+            let mut mac = mac(key.value.as_bytes());
+            mac.update(encoded.as_bytes());
+            mac.verify_slice(&signature).is_ok()
+        });
+        if !signed {
+            return Err(TokenError::InvalidSignature);
+        }
+
+        let payload: TokenPayload = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or(TokenError::Malformed)?;
+
+        if payload.purpose != purpose {
+            return Err(TokenError::WrongPurpose);
+        }
+        if payload.exp <= Utc::now().timestamp() {
+            return Err(TokenError::Expired);
+        }
+
+        Ok(VerifiedToken {
+            id: payload.jti,
+            user_id: payload.sub,
+            purpose: payload.purpose,
+        })
+    }
+
+}
+
+/// HMAC keyed with the action token key derived from `secret`
+fn mac(secret: &[u8]) -> HmacSha256 {
+    let mut derive = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    derive.update(KEY_CONTEXT);
+    HmacSha256::new_from_slice(&derive.finalize().into_bytes())
+        .expect("HMAC accepts keys of any length")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lotabots_secrets::Secret;
+
+    fn signer_with(secret: &str) -> TokenSigner {
+        TokenSigner::new(KeyRing::new("JWT_SECRET", Secret::new(secret.to_string())))
+    }
+
+    #[test]
+    fn test_issue_and_verify() {
+        let signer = signer_with("test_secret");
+        let user_id = Uuid::new_v4();
+        let issued = signer.issue(user_id, TokenPurpose::PasswordReset);
+
+        let verified = signer
+            .verify(&issued.token, TokenPurpose::PasswordReset)
+            .unwrap();
+        assert_eq!(verified.id, issued.id);
+        assert_eq!(verified.user_id, user_id);
+    }
+
+    #[test]
+    fn test_wrong_purpose_rejected() {
+        let signer = signer_with("test_secret");
+        let issued = signer.issue(Uuid::new_v4(), TokenPurpose::EmailVerification);
+
+        assert_eq!(
+            signer.verify(&issued.token, TokenPurpose::PasswordReset),
+            Err(TokenError::WrongPurpose)
+        );
+    }
+
+    #[test]
+    fn test_expired_token_rejected() {
+        let signer = signer_with("test_secret");
+        let issued = signer.issue_with_ttl(
+            Uuid::new_v4(),
+            TokenPurpose::PasswordReset,
+            Duration::seconds(-1),
+        );
+
+        assert_eq!(
+            signer.verify(&issued.token, TokenPurpose::PasswordReset),
+            Err(TokenError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_tampered_token_rejected() {
+        let signer = signer_with("test_secret");
+        let issued = signer.issue(Uuid::new_v4(), TokenPurpose::PasswordReset);
+
+        let other = signer.issue(Uuid::new_v4(), TokenPurpose::PasswordReset);
+        let (_, other_signature) = other.token.split_once('.').unwrap();
+        let (payload, _) = issued.token.split_once('.').unwrap();
+        let forged = format!("{}.{}", payload, other_signature);
+
+        assert_eq!(
+            signer.verify(&forged, TokenPurpose::PasswordReset),
+            Err(TokenError::InvalidSignature)
+        );
+        assert_eq!(
+            signer_with("other_secret").verify(&issued.token, TokenPurpose::PasswordReset),
+            Err(TokenError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_malformed_token_rejected() {
+        let signer = signer_with("test_secret");
+        for token in ["", "no-dot", "a.b.c", "!!!.???"] {
+            assert!(signer.verify(token, TokenPurpose::PasswordReset).is_err());
+        }
+    }
+
+    #[test]
+    fn test_tokens_follow_rotation() {
+        let keys = KeyRing::new("JWT_SECRET", Secret::new("old_secret".to_string()));
+        let rotating = TokenSigner::new(keys.clone());
+        let issued = rotating.issue(Uuid::new_v4(), TokenPurpose::PasswordReset);
+
+        keys.rotate_to(
+            Secret::new("new_secret".to_string()),
+            std::time::Duration::from_secs(60),
+        );
+        assert!(rotating
+            .verify(&issued.token, TokenPurpose::PasswordReset)
+            .is_ok());
+
+        let reissued = rotating.issue(Uuid::new_v4(), TokenPurpose::PasswordReset);
+        assert_eq!(
+            signer_with("old_secret").verify(&reissued.token, TokenPurpose::PasswordReset),
+            Err(TokenError::InvalidSignature)
+        );
+
+        keys.rotate_to(
+            Secret::new("newer_secret".to_string()),
+            std::time::Duration::ZERO,
+        );
+        assert_eq!(
+            rotating.verify(&issued.token, TokenPurpose::PasswordReset),
+            Err(TokenError::InvalidSignature)
+        );
+    }
+}
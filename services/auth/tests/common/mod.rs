@@ -0,0 +1,86 @@
+use actix_web::{web::Data, App};
+use sqlx::{postgres::PgPoolOptions, Connection, PgConnection, PgPool};
+use std::sync::Arc;
+use url::Url;
+use uuid::Uuid;
+
+use lotabots_auth::{
+    handlers, mailer::InMemoryMailer, repository::AuthRepository, service::AuthService,
+};
+
+pub const JWT_SECRET: &str = "test_secret";
+
+/// Connects to the auth test database and runs the migrations. `None` when
+/// `TEST_DATABASE_URL` is not set.
+///
+/// The auth schema has its own `users` table, so it lives in a database of
+/// its own next to the one `TEST_DATABASE_URL` names, with an `_auth`
+/// suffix. It is created on first use.
+pub async fn connect() -> Option<PgPool> {
+    let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL is not set; skipping");
+        return None;
+    };
+    let mut url = Url::parse(&url).expect("Invalid TEST_DATABASE_URL");
+    let database = format!("{}_auth", url.path().trim_start_matches('/'));
+
+    let mut admin = PgConnection::connect(url.as_str())
+        .await
+        .expect("Failed to connect to test database");
+    let exists: bool =
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM pg_database WHERE datname = $1)")
+            .bind(&database)
+            .fetch_one(&mut admin)
+            .await
+            .unwrap();
+    if !exists {
+        // Tests run concurrently; another one may have won the race
+        let _ = sqlx::query(&format!("CREATE DATABASE \"{}\"", database))
+            .execute(&mut admin)
+            .await;
+    }
+    admin.close().await.ok();
+
+    url.set_path(&database);
+    let pool = PgPoolOptions::new()
+        .max_connections(5)
+        .connect(url.as_str())
+        .await
+        .expect("Failed to connect to auth test database");
+    sqlx::migrate!("./migrations")
+        .run(&pool)
+        .await
+        .expect("Failed to run migrations");
+    Some(pool)
+}
+
+pub fn service(repository: AuthRepository, mailer: InMemoryMailer) -> AuthService {
+    AuthService::new(
+        repository,
+        JWT_SECRET.to_string(),
+        Arc::new(mailer),
+        "http://localhost:3000".to_string(),
+    )
+}
+
+pub fn app(
+    service: AuthService,
+) -> App<
+    impl actix_web::dev::ServiceFactory<
+        actix_web::dev::ServiceRequest,
+        Config = (),
+        Response = actix_web::dev::ServiceResponse,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .app_data(Data::new(service))
+        .configure(handlers::config)
+}
+
+/// `name` with a random suffix, so reruns against the same database do not
+/// collide on unique columns
+pub fn unique(name: &str) -> String {
+    format!("{}{}", name, &Uuid::new_v4().simple().to_string()[..8])
+}
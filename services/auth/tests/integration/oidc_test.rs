@@ -0,0 +1,350 @@
+use actix_web::{dev::ServiceResponse, test};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use lotabots_secrets::{envelope, Encrypted, EnvelopeCipher};
+use serde_json::json;
+use url::Url;
+use wiremock::{
+    matchers::{body_string_contains, method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use lotabots_auth::{
+    mailer::InMemoryMailer, models::User, oidc::OidcProvider, repository::AuthRepository,
+    service::AuthService,
+};
+
+use crate::common::{self, unique};
+
+const CLIENT_ID: &str = "lotabots-test";
+
+/// A local identity provider serving discovery and keys. The token endpoint
+/// is mounted per test once the nonce is known.
+async fn start_mock_idp() -> MockServer {
+    let idp = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/.well-known/openid-configuration"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "issuer": idp.uri(),
+            "authorization_endpoint": format!("{}/authorize", idp.uri()),
+            "token_endpoint": format!("{}/token", idp.uri()),
+            "jwks_uri": format!("{}/jwks", idp.uri()),
+        })))
+        .mount(&idp)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path("/jwks"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_raw(include_str!("../fixtures/oidc_jwks.json"), "application/json"),
+        )
+        .mount(&idp)
+        .await;
+
+    idp
+}
+
+fn id_token(issuer: &str, subject: &str, email: &str, email_verified: bool, nonce: &str) -> String {
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some("test-key".to_string());
+
+    encode(
+        &header,
+        &json!({
+            "iss": issuer,
+            "aud": CLIENT_ID,
+            "sub": subject,
+            "exp": chrono::Utc::now().timestamp() + 300,
+            "iat": chrono::Utc::now().timestamp(),
+            "nonce": nonce,
+            "email": email,
+            "email_verified": email_verified,
+        }),
+        &EncodingKey::from_rsa_pem(include_bytes!("../fixtures/oidc_rsa.pem")).unwrap(),
+    )
+    .unwrap()
+}
+
+async fn setup_service(
+    idp: &MockServer,
+    tenant: &str,
+    verified_domains: &[&str],
+) -> Option<(AuthService, AuthRepository)> {
+    let pool = common::connect().await?;
+
+    envelope::install(EnvelopeCipher::new(1, &[7; 32]));
+
+    let repository = AuthRepository::new(pool);
+    repository
+        .upsert_oidc_provider(&OidcProvider {
+            tenant: tenant.to_string(),
+            issuer: idp.uri(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: Some(Encrypted::new("client-secret".to_string()).unwrap()),
+            redirect_uri: "http://localhost:8080/auth/oidc/callback".to_string(),
+            scopes: "openid email profile".to_string(),
+            verified_domains: verified_domains.iter().map(|d| d.to_string()).collect(),
+        })
+        .await
+        .expect("Failed to configure provider");
+
+    let service = common::service(repository.clone(), InMemoryMailer::new());
+    Some((service, repository))
+}
+
+fn authorize_request(tenant: &str) -> test::TestRequest {
+    test::TestRequest::get().uri(&format!("/auth/oidc/{}/authorize", tenant))
+}
+
+/// Checks the redirect to the IdP and returns the `state` and `nonce` it
+/// carries
+fn authorization_params(resp: &ServiceResponse) -> (String, String) {
+    assert_eq!(resp.status(), 302);
+
+    let location = Url::parse(resp.headers().get("location").unwrap().to_str().unwrap()).unwrap();
+    let param = |name: &str| {
+        location
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+            .unwrap_or_else(|| panic!("missing {} parameter", name))
+    };
+
+    assert_eq!(param("client_id"), CLIENT_ID);
+    assert_eq!(param("code_challenge_method"), "S256");
+    assert!(param("scope").starts_with("openid"));
+
+    (param("state"), param("nonce"))
+}
+
+async fn mount_token_endpoint(idp: &MockServer, code: &str, id_token: String) {
+    Mock::given(method("POST"))
+        .and(path("/token"))
+        .and(body_string_contains(format!("code={}", code).as_str()))
+        .and(body_string_contains("code_verifier="))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "access_token": "idp-access-token",
+            "token_type": "Bearer",
+            "id_token": id_token,
+        })))
+        .mount(idp)
+        .await;
+}
+
+#[actix_web::test]
+async fn test_oidc_login_creates_and_links_account() {
+    let idp = start_mock_idp().await;
+    let tenant = unique("acme");
+    let Some((service, repository)) = setup_service(&idp, &tenant, &[]).await else {
+        return;
+    };
+    let app = test::init_service(common::app(service)).await;
+    let email = format!("{}@acme.test", unique("sso"));
+
+    let resp = test::call_service(&app, authorize_request(&tenant).to_request()).await;
+    let (state, nonce) = authorization_params(&resp);
+    mount_token_endpoint(&idp, "code-1", id_token(&idp.uri(), "sub-1", &email, true, &nonce)).await;
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/auth/oidc/callback?state={}&code=code-1", state))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    let user = repository
+        .find_user_by_identity(&idp.uri(), "sub-1")
+        .await
+        .unwrap()
+        .expect("identity was not linked");
+    assert_eq!(user.email, email);
+    assert_eq!(user.tenant.as_deref(), Some(tenant.as_str()));
+    assert!(user.email_verified_at.is_some());
+
+    // The state cannot be replayed
+    let req = test::TestRequest::get()
+        .uri(&format!("/auth/oidc/callback?state={}&code=code-1", state))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+}
+
+#[actix_web::test]
+async fn test_oidc_login_links_existing_account_by_verified_email() {
+    let idp = start_mock_idp().await;
+    let tenant = unique("globex");
+    let Some((service, repository)) = setup_service(&idp, &tenant, &["globex.test"]).await else {
+        return;
+    };
+    let app = test::init_service(common::app(service)).await;
+    let username = unique("globexuser");
+    let email = format!("{}@globex.test", username);
+
+    let req = test::TestRequest::post()
+        .uri("/auth/register")
+        .set_json(json!({
+            "username": username,
+            "email": email,
+            "password": "Password123!@#"
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 201);
+
+    let local = repository
+        .find_user_by_email(&email)
+        .await
+        .unwrap()
+        .unwrap();
+
+    // An unverified local account is not linked
+    let resp = test::call_service(&app, authorize_request(&tenant).to_request()).await;
+    let (state, nonce) = authorization_params(&resp);
+    mount_token_endpoint(&idp, "code-2", id_token(&idp.uri(), "sub-2", &email, true, &nonce)).await;
+    let req = test::TestRequest::get()
+        .uri(&format!("/auth/oidc/callback?state={}&code=code-2", state))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 409);
+
+    // Once verified locally, the identity is linked to the existing user
+    repository.mark_email_verified(local.id).await.unwrap();
+
+    let resp = test::call_service(&app, authorize_request(&tenant).to_request()).await;
+    let (state, nonce) = authorization_params(&resp);
+    mount_token_endpoint(&idp, "code-3", id_token(&idp.uri(), "sub-2", &email, true, &nonce)).await;
+    let req = test::TestRequest::get()
+        .uri(&format!("/auth/oidc/callback?state={}&code=code-3", state))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    let linked = repository
+        .find_user_by_identity(&idp.uri(), "sub-2")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(linked.id, local.id);
+}
+
+#[actix_web::test]
+async fn test_oidc_login_does_not_link_accounts_outside_the_tenant() {
+    let idp = start_mock_idp().await;
+    let tenant = unique("hooli");
+    let Some((service, repository)) = setup_service(&idp, &tenant, &[]).await else {
+        return;
+    };
+    let app = test::init_service(common::app(service)).await;
+    let username = unique("umbrellauser");
+    let email = format!("{}@umbrella.test", username);
+
+    let req = test::TestRequest::post()
+        .uri("/auth/register")
+        .set_json(json!({
+            "username": username,
+            "email": email,
+            "password": "Password123!@#"
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 201);
+
+    let local = repository
+        .find_user_by_email(&email)
+        .await
+        .unwrap()
+        .unwrap();
+    repository.mark_email_verified(local.id).await.unwrap();
+
+    // The tenant's provider vouches for the address, but neither the account
+    // nor the domain belongs to the tenant
+    let resp = test::call_service(&app, authorize_request(&tenant).to_request()).await;
+    let (state, nonce) = authorization_params(&resp);
+    mount_token_endpoint(&idp, "code-6", id_token(&idp.uri(), "sub-6", &email, true, &nonce)).await;
+    let req = test::TestRequest::get()
+        .uri(&format!("/auth/oidc/callback?state={}&code=code-6", state))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 409);
+
+    assert!(repository
+        .find_user_by_identity(&idp.uri(), "sub-6")
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[actix_web::test]
+async fn test_oidc_login_does_not_link_accounts_of_another_tenant_on_a_verified_domain() {
+    let idp = start_mock_idp().await;
+    let tenant = unique("globex");
+    let Some((service, repository)) = setup_service(&idp, &tenant, &["globex.test"]).await else {
+        return;
+    };
+    let app = test::init_service(common::app(service)).await;
+    let username = unique("otheruser");
+    let email = format!("{}@globex.test", username);
+
+    // Another tenant's account on the domain this tenant has verified
+    let mut other = User::new(username, email.clone(), "unused".to_string());
+    other.tenant = Some(unique("initrode"));
+    let other = repository.create_user(other).await.unwrap();
+    repository.mark_email_verified(other.id).await.unwrap();
+
+    let resp = test::call_service(&app, authorize_request(&tenant).to_request()).await;
+    let (state, nonce) = authorization_params(&resp);
+    mount_token_endpoint(&idp, "code-7", id_token(&idp.uri(), "sub-7", &email, true, &nonce)).await;
+    let req = test::TestRequest::get()
+        .uri(&format!("/auth/oidc/callback?state={}&code=code-7", state))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 409);
+
+    assert!(repository
+        .find_user_by_identity(&idp.uri(), "sub-7")
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[actix_web::test]
+async fn test_oidc_login_rejects_unverified_email_and_bad_nonce() {
+    let idp = start_mock_idp().await;
+    let tenant = unique("initech");
+    let Some((service, _)) = setup_service(&idp, &tenant, &[]).await else {
+        return;
+    };
+    let app = test::init_service(common::app(service)).await;
+    let email = format!("{}@initech.test", unique("new"));
+
+    let resp = test::call_service(&app, authorize_request(&tenant).to_request()).await;
+    let (state, nonce) = authorization_params(&resp);
+    mount_token_endpoint(&idp, "code-4", id_token(&idp.uri(), "sub-4", &email, false, &nonce)).await;
+    let req = test::TestRequest::get()
+        .uri(&format!("/auth/oidc/callback?state={}&code=code-4", state))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 401);
+
+    let resp = test::call_service(&app, authorize_request(&tenant).to_request()).await;
+    let (state, _) = authorization_params(&resp);
+    mount_token_endpoint(&idp, "code-5", id_token(&idp.uri(), "sub-5", &email, true, "forged")).await;
+    let req = test::TestRequest::get()
+        .uri(&format!("/auth/oidc/callback?state={}&code=code-5", state))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 401);
+}
+
+#[actix_web::test]
+async fn test_oidc_unknown_tenant() {
+    let idp = start_mock_idp().await;
+    let tenant = unique("acme");
+    let Some((service, _)) = setup_service(&idp, &tenant, &[]).await else {
+        return;
+    };
+    let app = test::init_service(common::app(service)).await;
+
+    let resp = test::call_service(&app, authorize_request("unknown-tenant").to_request()).await;
+    assert_eq!(resp.status(), 404);
+}
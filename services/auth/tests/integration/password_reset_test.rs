@@ -0,0 +1,230 @@
+use actix_web::test;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use lotabots_auth::{mailer::InMemoryMailer, repository::AuthRepository};
+
+use crate::common::{self, unique};
+
+fn token_from_email(mailer: &InMemoryMailer, to: &str) -> String {
+    let email = mailer.last_sent_to(to).expect("No email sent");
+    email
+        .body
+        .split("token=")
+        .nth(1)
+        .and_then(|rest| rest.split_whitespace().next())
+        .expect("Email does not contain a token")
+        .to_string()
+}
+
+#[actix_web::test]
+async fn test_password_reset_flow() {
+    let Some(pool) = common::connect().await else {
+        return;
+    };
+    let mailer = InMemoryMailer::new();
+    let service = common::service(AuthRepository::new(pool), mailer.clone());
+    let app = test::init_service(common::app(service)).await;
+    let username = unique("resetuser");
+    let email = format!("{}@example.com", username);
+
+    let req = test::TestRequest::post()
+        .uri("/auth/register")
+        .set_json(json!({
+            "username": username,
+            "email": email,
+            "password": "Password123!@#"
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 201);
+
+    // Request a reset link
+    let req = test::TestRequest::post()
+        .uri("/auth/password/forgot")
+        .set_json(json!({ "email": email }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 202);
+
+    let token = token_from_email(&mailer, &email);
+
+    // Complete the reset
+    let req = test::TestRequest::post()
+        .uri("/auth/password/reset")
+        .set_json(json!({ "token": token, "new_password": "N3wPassword!@#" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 204);
+
+    // The token is single-use
+    let req = test::TestRequest::post()
+        .uri("/auth/password/reset")
+        .set_json(json!({ "token": token, "new_password": "Another123!@#" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+
+    // Old password no longer works, new one does
+    let req = test::TestRequest::post()
+        .uri("/auth/login")
+        .set_json(json!({ "username": username, "password": "Password123!@#" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 401);
+
+    let req = test::TestRequest::post()
+        .uri("/auth/login")
+        .set_json(json!({ "username": username, "password": "N3wPassword!@#" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[actix_web::test]
+async fn test_password_reset_ends_existing_sessions() {
+    let Some(pool) = common::connect().await else {
+        return;
+    };
+    let mailer = InMemoryMailer::new();
+    let service = common::service(AuthRepository::new(pool), mailer.clone());
+    let app = test::init_service(common::app(service)).await;
+    let username = unique("sessionuser");
+    let email = format!("{}@example.com", username);
+
+    let req = test::TestRequest::post()
+        .uri("/auth/register")
+        .set_json(json!({
+            "username": username,
+            "email": email,
+            "password": "Password123!@#"
+        }))
+        .to_request();
+    let resp: Value = test::call_and_read_body_json(&app, req).await;
+    let old_token = resp["token"].as_str().unwrap().to_string();
+
+    let me = |token: &str| {
+        test::TestRequest::get()
+            .uri("/auth/me")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request()
+    };
+    let resp = test::call_service(&app, me(&old_token)).await;
+    assert_eq!(resp.status(), 200);
+
+    // Tokens carry their issue time in whole seconds
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/auth/password/forgot")
+        .set_json(json!({ "email": email }))
+        .to_request();
+    test::call_service(&app, req).await;
+    let token = token_from_email(&mailer, &email);
+    let req = test::TestRequest::post()
+        .uri("/auth/password/reset")
+        .set_json(json!({ "token": token, "new_password": "N3wPassword!@#" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 204);
+
+    // The token issued before the reset is refused, a new one is not
+    let resp = test::call_service(&app, me(&old_token)).await;
+    assert_eq!(resp.status(), 401);
+
+    let req = test::TestRequest::post()
+        .uri("/auth/login")
+        .set_json(json!({ "username": username, "password": "N3wPassword!@#" }))
+        .to_request();
+    let resp: Value = test::call_and_read_body_json(&app, req).await;
+    let new_token = resp["token"].as_str().unwrap();
+    let resp = test::call_service(&app, me(new_token)).await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[actix_web::test]
+async fn test_forgot_password_unknown_email() {
+    let Some(pool) = common::connect().await else {
+        return;
+    };
+    let mailer = InMemoryMailer::new();
+    let service = common::service(AuthRepository::new(pool), mailer.clone());
+    let app = test::init_service(common::app(service)).await;
+    let email = format!("{}@example.com", unique("nobody"));
+
+    // Unknown addresses get the same response and no email is sent
+    let req = test::TestRequest::post()
+        .uri("/auth/password/forgot")
+        .set_json(json!({ "email": email }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 202);
+    assert!(mailer.last_sent_to(&email).is_none());
+}
+
+#[actix_web::test]
+async fn test_reset_with_invalid_token() {
+    let Some(pool) = common::connect().await else {
+        return;
+    };
+    let service = common::service(AuthRepository::new(pool), InMemoryMailer::new());
+    let app = test::init_service(common::app(service)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/auth/password/reset")
+        .set_json(json!({ "token": "forged.token", "new_password": "N3wPassword!@#" }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+}
+
+#[actix_web::test]
+async fn test_email_verification_flow() {
+    let Some(pool) = common::connect().await else {
+        return;
+    };
+    let mailer = InMemoryMailer::new();
+    let service = common::service(AuthRepository::new(pool), mailer.clone());
+    let app = test::init_service(common::app(service)).await;
+    let username = unique("verifyuser");
+    let email = format!("{}@example.com", username);
+
+    // Registration sends a verification email
+    let req = test::TestRequest::post()
+        .uri("/auth/register")
+        .set_json(json!({
+            "username": username,
+            "email": email,
+            "password": "Password123!@#"
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 201);
+
+    let first_token = token_from_email(&mailer, &email);
+
+    // Requesting a new link revokes the previous one
+    let req = test::TestRequest::post()
+        .uri("/auth/email/verification")
+        .set_json(json!({ "email": email }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 202);
+
+    let second_token = token_from_email(&mailer, &email);
+    assert_ne!(first_token, second_token);
+
+    let req = test::TestRequest::post()
+        .uri("/auth/email/verify")
+        .set_json(json!({ "token": first_token }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+
+    let req = test::TestRequest::post()
+        .uri("/auth/email/verify")
+        .set_json(json!({ "token": second_token }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 204);
+}
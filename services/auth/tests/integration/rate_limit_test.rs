@@ -1,11 +1,13 @@
 use actix_web::{test, web::Data, App};
 use serde_json::json;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::sleep;
 
 use lotabots_auth::{
     config::Config,
     handlers,
+    mailer::InMemoryMailer,
     middleware::rate_limit::RateLimiter,
     repository::AuthRepository,
     service::AuthService,
@@ -35,7 +37,12 @@ async fn setup_rate_limited_app() -> actix_web::App {
 
     // Create repository and service
     let repository = AuthRepository::new(pool);
-    let service = AuthService::new(repository, config.jwt_secret);
+    let service = AuthService::new(
+        repository,
+        config.jwt_secret,
+        Arc::new(InMemoryMailer::new()),
+        "http://localhost:3000".to_string(),
+    );
 
     // Create app with rate limiting
     App::new()
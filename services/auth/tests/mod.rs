@@ -1,3 +1,5 @@
+mod common;
+
 #[cfg(test)]
 mod unit {
     mod service_test;
@@ -6,4 +8,6 @@ mod unit {
 #[cfg(test)]
 mod integration {
     mod api_test;
+    mod oidc_test;
+    mod password_reset_test;
 }
@@ -1,26 +0,0 @@
-use std::env;
-
-pub struct SecretsManager;
-
-impl SecretsManager {
-    pub fn get_secret(key: &str) -> Result<String, Box<dyn std::error::Error>> {
-        match env::var("ENVIRONMENT").as_deref() {
-            Ok("production") => {
-                // Implement retrieval from secrets manager
-                // Example: AWS Secrets Manager integration
-                let secret = get_from_aws_secrets_manager(key)?;
-                Ok(secret)
-            }
-            _ => {
-                // For development and testing, use environment variables
-                env::var(key).map_err(|_| "Secret not found".into())
-            }
-        }
-    }
-}
-
-// Placeholder functions
-fn get_from_aws_secrets_manager(_key: &str) -> Result<String, Box<dyn std::error::Error>> {
-    // Implement actual secrets retrieval logic
-    Ok("production-secret".to_string())
-}
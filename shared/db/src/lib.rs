@@ -1,10 +1,9 @@
 use sqlx::postgres::{PgPool, PgPoolOptions};
 use thiserror::Error;
 
-pub mod config;
-pub mod connection;
-pub mod migrations;
-pub mod models;
+pub mod tenant;
+
+pub use tenant::TenantContext;
 
 #[derive(Error, Debug)]
 pub enum DatabaseError {
@@ -26,15 +25,26 @@ pub struct DatabaseConfig {
     pub min_connections: u32,
     pub max_lifetime_secs: u64,
     pub idle_timeout_secs: u64,
+    /// Sets `app.tenant_id` and `app.user_id` from the current
+    /// [`TenantContext`] on every connection handed out, for row-level
+    /// security policies to check
+    pub tenant_isolation: bool,
 }
 
 /// Creates a new database connection pool
 pub async fn create_pool(config: &DatabaseConfig) -> Result<PgPool> {
-    PgPoolOptions::new()
+    let options = PgPoolOptions::new()
         .max_connections(config.max_connections)
         .min_connections(config.min_connections)
         .max_lifetime(std::time::Duration::from_secs(config.max_lifetime_secs))
-        .idle_timeout(std::time::Duration::from_secs(config.idle_timeout_secs))
+        .idle_timeout(std::time::Duration::from_secs(config.idle_timeout_secs));
+    let options = if config.tenant_isolation {
+        tenant::isolate(options)
+    } else {
+        options
+    };
+
+    options
         .connect(&config.url)
         .await
         .map_err(DatabaseError::ConnectionError)
@@ -0,0 +1,233 @@
+//! Runs against the database in `TEST_DATABASE_URL` and is skipped when it
+//! is not set. The URL may name a superuser; the pools under test connect
+//! as an ordinary role created here, since policies do not apply to
+//! superusers.
+//!
+//! As with the migrations, policies are not forced: the tables are owned by
+//! the role in the URL, and isolation relies on the application role not
+//! owning them.
+
+use lotabots_db::{create_pool, DatabaseConfig, TenantContext};
+use sqlx::{PgPool, Row};
+use url::Url;
+use uuid::Uuid;
+
+const APP_ROLE: &str = "lotabots_rls_test";
+const APP_PASSWORD: &str = "lotabots_rls_test";
+
+struct Fixture {
+    /// Connects as the application role with tenant isolation
+    pool: PgPool,
+    table: String,
+    acme: Uuid,
+    globex: Uuid,
+    acme_doc: Uuid,
+    globex_doc: Uuid,
+}
+
+async fn setup(max_connections: u32) -> Option<Fixture> {
+    let Ok(admin_url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL is not set; skipping");
+        return None;
+    };
+    let admin = PgPool::connect(&admin_url).await.unwrap();
+
+    // A table per test, owned by the migrating role as in production
+    let table = format!("rls_documents_{}", Uuid::new_v4().simple());
+    sqlx::query(&format!(
+        r#"
+        DO $$ BEGIN
+            CREATE ROLE {role} LOGIN PASSWORD '{password}';
+        EXCEPTION WHEN duplicate_object OR unique_violation THEN NULL;
+        END $$
+        "#,
+        role = APP_ROLE,
+        password = APP_PASSWORD,
+    ))
+    .execute(&admin)
+    .await
+    .unwrap();
+    sqlx::query(&format!("GRANT USAGE ON SCHEMA public TO {}", APP_ROLE))
+        .execute(&admin)
+        .await
+        .unwrap();
+
+    for statement in [
+        format!(
+            "CREATE TABLE {} (id UUID PRIMARY KEY, tenant_id UUID NOT NULL, title TEXT NOT NULL)",
+            table
+        ),
+        format!("ALTER TABLE {} ENABLE ROW LEVEL SECURITY", table),
+        format!(
+            "CREATE POLICY tenant_isolation ON {}
+                USING (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid)
+                WITH CHECK (tenant_id = NULLIF(current_setting('app.tenant_id', true), '')::uuid)",
+            table
+        ),
+        format!(
+            "GRANT SELECT, INSERT, UPDATE, DELETE ON {} TO {}",
+            table, APP_ROLE
+        ),
+    ] {
+        sqlx::query(&statement).execute(&admin).await.unwrap();
+    }
+
+    let mut app_url = Url::parse(&admin_url).unwrap();
+    app_url.set_username(APP_ROLE).unwrap();
+    app_url.set_password(Some(APP_PASSWORD)).unwrap();
+
+    let pool = create_pool(&DatabaseConfig {
+        url: app_url.to_string(),
+        max_connections,
+        min_connections: 0,
+        max_lifetime_secs: 60,
+        idle_timeout_secs: 60,
+        tenant_isolation: true,
+    })
+    .await
+    .unwrap();
+
+    // Unforced policies are only enforced for roles that neither own the
+    // table nor bypass row-level security
+    let (owns, bypasses): (bool, bool) = sqlx::query_as(
+        "SELECT t.tableowner = current_user, r.rolbypassrls OR r.rolsuper
+         FROM pg_tables t, pg_roles r
+         WHERE t.tablename = $1 AND r.rolname = current_user",
+    )
+    .bind(&table)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert!(!owns && !bypasses);
+
+    let (acme, globex) = (Uuid::new_v4(), Uuid::new_v4());
+    let (acme_doc, globex_doc) = (Uuid::new_v4(), Uuid::new_v4());
+    for (tenant, doc) in [(acme, acme_doc), (globex, globex_doc)] {
+        TenantContext::new(tenant)
+            .scope(
+                sqlx::query(&format!(
+                    "INSERT INTO {} (id, tenant_id, title) VALUES ($1, $2, 'contract')",
+                    table
+                ))
+                .bind(doc)
+                .bind(tenant)
+                .execute(&pool),
+            )
+            .await
+            .unwrap();
+    }
+
+    Some(Fixture {
+        pool,
+        table,
+        acme,
+        globex,
+        acme_doc,
+        globex_doc,
+    })
+}
+
+/// Ids of every row the query can see, with no tenant filter of its own
+async fn visible_ids(pool: &PgPool, table: &str) -> Vec<Uuid> {
+    sqlx::query(&format!("SELECT id FROM {}", table))
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| row.get("id"))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_unfiltered_queries_only_see_the_current_tenant() {
+    let Some(f) = setup(5).await else { return };
+
+    let seen = TenantContext::new(f.acme)
+        .scope(visible_ids(&f.pool, &f.table))
+        .await;
+    assert_eq!(seen, vec![f.acme_doc]);
+
+    // Fetching another tenant's row by id finds nothing
+    let found = TenantContext::new(f.acme)
+        .scope(
+            sqlx::query(&format!("SELECT id FROM {} WHERE id = $1", f.table))
+                .bind(f.globex_doc)
+                .fetch_optional(&f.pool),
+        )
+        .await
+        .unwrap();
+    assert!(found.is_none());
+
+    // Without a context nothing is visible at all
+    assert!(visible_ids(&f.pool, &f.table).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_writes_cannot_cross_tenants() {
+    let Some(f) = setup(5).await else { return };
+    let acme = TenantContext::new(f.acme);
+
+    let updated = acme
+        .scope(sqlx::query(&format!("UPDATE {} SET title = 'stolen'", f.table)).execute(&f.pool))
+        .await
+        .unwrap();
+    assert_eq!(updated.rows_affected(), 1);
+
+    let deleted = acme
+        .scope(
+            sqlx::query(&format!("DELETE FROM {} WHERE id = $1", f.table))
+                .bind(f.globex_doc)
+                .execute(&f.pool),
+        )
+        .await
+        .unwrap();
+    assert_eq!(deleted.rows_affected(), 0);
+
+    // Rows cannot be planted in another tenant
+    let planted = acme
+        .scope(
+            sqlx::query(&format!(
+                "INSERT INTO {} (id, tenant_id, title) VALUES ($1, $2, 'planted')",
+                f.table
+            ))
+            .bind(Uuid::new_v4())
+            .bind(f.globex)
+            .execute(&f.pool),
+        )
+        .await;
+    assert!(planted.is_err());
+
+    let titles: Vec<String> = TenantContext::new(f.globex)
+        .scope(sqlx::query_scalar(&format!("SELECT title FROM {}", f.table)).fetch_all(&f.pool))
+        .await
+        .unwrap();
+    assert_eq!(titles, vec!["contract".to_string()]);
+}
+
+#[tokio::test]
+async fn test_reused_connections_are_rebound() {
+    // One connection, so every query reuses it
+    let Some(f) = setup(1).await else { return };
+
+    for (tenant, doc) in [(f.acme, f.acme_doc), (f.globex, f.globex_doc)] {
+        let seen = TenantContext::new(tenant)
+            .scope(visible_ids(&f.pool, &f.table))
+            .await;
+        assert_eq!(seen, vec![doc]);
+    }
+    assert!(visible_ids(&f.pool, &f.table).await.is_empty());
+
+    // Also within transactions
+    let seen = TenantContext::new(f.globex)
+        .scope(async {
+            let mut tx = f.pool.begin().await.unwrap();
+            let ids: Vec<Uuid> = sqlx::query_scalar(&format!("SELECT id FROM {}", f.table))
+                .fetch_all(&mut *tx)
+                .await
+                .unwrap();
+            tx.commit().await.unwrap();
+            ids
+        })
+        .await;
+    assert_eq!(seen, vec![f.globex_doc]);
+}
@@ -0,0 +1,399 @@
+//! Password hashing shared by every service that stores credentials.
+//!
+//! New hashes are produced with the configured algorithm (Argon2id by
+//! default) and stored as self-describing strings: Argon2id hashes use the
+//! PHC string format, bcrypt hashes keep their standard `$2b$` form. Because
+//! the stored string carries its algorithm and parameters, [`PasswordHasher::verify`]
+//! can check any supported hash and report when it should be upgraded to the
+//! current policy.
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, SaltString},
+    Argon2, Params, PasswordHasher as _, PasswordVerifier as _, Version,
+};
+use std::{env, str::FromStr};
+use thiserror::Error;
+
+const BCRYPT_MIN_COST: u32 = 4;
+const BCRYPT_MAX_COST: u32 = 31;
+
+#[derive(Debug, Error)]
+pub enum PasswordError {
+    #[error("Invalid hashing parameters: {0}")]
+    InvalidParams(String),
+    #[error("Unsupported password hash format")]
+    UnsupportedHash,
+    #[error("Password hashing failed: {0}")]
+    Hashing(String),
+}
+
+pub type Result<T> = std::result::Result<T, PasswordError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Argon2id,
+    Bcrypt,
+}
+
+impl FromStr for Algorithm {
+    type Err = PasswordError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "argon2id" | "argon2" => Ok(Algorithm::Argon2id),
+            "bcrypt" => Ok(Algorithm::Bcrypt),
+            other => Err(PasswordError::InvalidParams(format!(
+                "unknown algorithm '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Argon2id cost parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Params {
+    /// Memory cost in KiB
+    pub memory_kib: u32,
+    /// Number of passes
+    pub iterations: u32,
+    /// Degree of parallelism
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Params {
+    /// OWASP recommended minimum: 19 MiB, 2 iterations, 1 lane
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Which algorithm new hashes use and how expensive they are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashPolicy {
+    pub algorithm: Algorithm,
+    pub argon2: Argon2Params,
+    pub bcrypt_cost: u32,
+}
+
+impl Default for HashPolicy {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::Argon2id,
+            argon2: Argon2Params::default(),
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+        }
+    }
+}
+
+impl HashPolicy {
+    /// Loads the policy from `PASSWORD_HASH_ALGORITHM`, `ARGON2_MEMORY_KIB`,
+    /// `ARGON2_ITERATIONS`, `ARGON2_PARALLELISM` and `BCRYPT_COST`, falling
+    /// back to the defaults for unset variables.
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+
+        Ok(Self {
+            algorithm: match env::var("PASSWORD_HASH_ALGORITHM") {
+                Ok(value) => value.parse()?,
+                Err(_) => defaults.algorithm,
+            },
+            argon2: Argon2Params {
+                memory_kib: env_u32("ARGON2_MEMORY_KIB")?.unwrap_or(defaults.argon2.memory_kib),
+                iterations: env_u32("ARGON2_ITERATIONS")?.unwrap_or(defaults.argon2.iterations),
+                parallelism: env_u32("ARGON2_PARALLELISM")?
+                    .unwrap_or(defaults.argon2.parallelism),
+            },
+            bcrypt_cost: env_u32("BCRYPT_COST")?.unwrap_or(defaults.bcrypt_cost),
+        })
+    }
+}
+
+fn env_u32(key: &str) -> Result<Option<u32>> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| PasswordError::InvalidParams(format!("{} must be a number", key))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Result of checking a password against a stored hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verification {
+    /// The password does not match
+    Invalid,
+    /// The password matches and the hash satisfies the current policy
+    Valid,
+    /// The password matches but the stored hash uses an outdated algorithm
+    /// or cost. The contained hash should replace the stored one.
+    NeedsRehash(String),
+}
+
+impl Verification {
+    pub fn is_valid(&self) -> bool {
+        !matches!(self, Verification::Invalid)
+    }
+}
+
+/// Hashes and verifies passwords according to a [`HashPolicy`]
+#[derive(Clone)]
+pub struct PasswordHasher {
+    policy: HashPolicy,
+    argon2: Argon2<'static>,
+}
+
+impl PasswordHasher {
+    pub fn new(policy: HashPolicy) -> Result<Self> {
+        let params = Params::new(
+            policy.argon2.memory_kib,
+            policy.argon2.iterations,
+            policy.argon2.parallelism,
+            None,
+        )
+        .map_err(|e| PasswordError::InvalidParams(e.to_string()))?;
+
+        if !(BCRYPT_MIN_COST..=BCRYPT_MAX_COST).contains(&policy.bcrypt_cost) {
+            return Err(PasswordError::InvalidParams(format!(
+                "bcrypt cost must be between {} and {}",
+                BCRYPT_MIN_COST, BCRYPT_MAX_COST
+            )));
+        }
+
+        Ok(Self {
+            policy,
+            argon2: Argon2::new(argon2::Algorithm::Argon2id, Version::V0x13, params),
+        })
+    }
+
+    pub fn from_env() -> Result<Self> {
+        Self::new(HashPolicy::from_env()?)
+    }
+
+    pub fn policy(&self) -> &HashPolicy {
+        &self.policy
+    }
+
+    /// Hashes a password with the current policy
+    pub fn hash(&self, password: &str) -> Result<String> {
+        match self.policy.algorithm {
+            Algorithm::Argon2id => {
+                let salt = SaltString::generate(&mut OsRng);
+                self.argon2
+                    .hash_password(password.as_bytes(), &salt)
+                    .map(|hash| hash.to_string())
+                    .map_err(|e| PasswordError::Hashing(e.to_string()))
+            }
+            Algorithm::Bcrypt => bcrypt::hash(password.as_bytes(), self.policy.bcrypt_cost)
+                .map_err(|e| PasswordError::Hashing(e.to_string())),
+        }
+    }
+
+    /// Checks a password against a stored Argon2 or bcrypt hash. A matching
+    /// password whose hash is outdated comes back as
+    /// [`Verification::NeedsRehash`] with a replacement hash.
+    pub fn verify(&self, password: &str, stored: &str) -> Result<Verification> {
+        let valid = match StoredHash::parse(stored)? {
+            StoredHash::Argon2(hash) => Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+            StoredHash::Bcrypt => bcrypt::verify(password.as_bytes(), stored)
+                .map_err(|e| PasswordError::Hashing(e.to_string()))?,
+        };
+
+        if !valid {
+            return Ok(Verification::Invalid);
+        }
+
+        if self.needs_rehash(stored)? {
+            Ok(Verification::NeedsRehash(self.hash(password)?))
+        } else {
+            Ok(Verification::Valid)
+        }
+    }
+
+    /// Whether a stored hash was produced with a different algorithm or
+    /// different cost parameters than the current policy
+    pub fn needs_rehash(&self, stored: &str) -> Result<bool> {
+        Ok(match (StoredHash::parse(stored)?, self.policy.algorithm) {
+            (StoredHash::Argon2(hash), Algorithm::Argon2id) => {
+                let params = Params::try_from(hash.as_ref()).map_err(|_| PasswordError::UnsupportedHash)?;
+                hash.algorithm != argon2::Algorithm::Argon2id.ident()
+                    || hash.version != Some(Version::V0x13.into())
+                    || params.m_cost() != self.policy.argon2.memory_kib
+                    || params.t_cost() != self.policy.argon2.iterations
+                    || params.p_cost() != self.policy.argon2.parallelism
+            }
+            (StoredHash::Bcrypt, Algorithm::Bcrypt) => {
+                bcrypt_cost(stored).ok_or(PasswordError::UnsupportedHash)? != self.policy.bcrypt_cost
+            }
+            _ => true,
+        })
+    }
+}
+
+impl Default for PasswordHasher {
+    fn default() -> Self {
+        Self::new(HashPolicy::default()).expect("default hash policy is valid")
+    }
+}
+
+enum StoredHash<'a> {
+    Argon2(Box<PasswordHash<'a>>),
+    Bcrypt,
+}
+
+impl<'a> StoredHash<'a> {
+    fn parse(stored: &'a str) -> Result<Self> {
+        if stored.starts_with("$argon2") {
+            PasswordHash::new(stored)
+                .map(|hash| StoredHash::Argon2(Box::new(hash)))
+                .map_err(|_| PasswordError::UnsupportedHash)
+        } else if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| stored.starts_with(prefix))
+        {
+            Ok(StoredHash::Bcrypt)
+        } else {
+            Err(PasswordError::UnsupportedHash)
+        }
+    }
+}
+
+fn bcrypt_cost(stored: &str) -> Option<u32> {
+    stored.get(4..6)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap parameters so the tests stay fast
+    fn fast_policy(algorithm: Algorithm) -> HashPolicy {
+        HashPolicy {
+            algorithm,
+            argon2: Argon2Params {
+                memory_kib: 1024,
+                iterations: 1,
+                parallelism: 1,
+            },
+            bcrypt_cost: 4,
+        }
+    }
+
+    #[test]
+    fn test_argon2id_hash_is_phc_string() {
+        let hasher = PasswordHasher::new(fast_policy(Algorithm::Argon2id)).unwrap();
+        let hash = hasher.hash("correct horse").unwrap();
+
+        assert!(hash.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+        assert_eq!(hasher.verify("correct horse", &hash).unwrap(), Verification::Valid);
+        assert_eq!(hasher.verify("wrong horse", &hash).unwrap(), Verification::Invalid);
+    }
+
+    #[test]
+    fn test_bcrypt_hash_roundtrip() {
+        let hasher = PasswordHasher::new(fast_policy(Algorithm::Bcrypt)).unwrap();
+        let hash = hasher.hash("correct horse").unwrap();
+
+        assert!(hash.starts_with("$2b$04$"));
+        assert_eq!(hasher.verify("correct horse", &hash).unwrap(), Verification::Valid);
+        assert_eq!(hasher.verify("wrong horse", &hash).unwrap(), Verification::Invalid);
+    }
+
+    #[test]
+    fn test_bcrypt_hash_upgraded_to_argon2id() {
+        let legacy = bcrypt::hash("correct horse", 4).unwrap();
+        let hasher = PasswordHasher::new(fast_policy(Algorithm::Argon2id)).unwrap();
+
+        match hasher.verify("correct horse", &legacy).unwrap() {
+            Verification::NeedsRehash(new_hash) => {
+                assert!(new_hash.starts_with("$argon2id$"));
+                assert_eq!(
+                    hasher.verify("correct horse", &new_hash).unwrap(),
+                    Verification::Valid
+                );
+            }
+            other => panic!("expected rehash, got {:?}", other),
+        }
+
+        // A wrong password never triggers a rehash
+        assert_eq!(hasher.verify("wrong horse", &legacy).unwrap(), Verification::Invalid);
+    }
+
+    #[test]
+    fn test_outdated_bcrypt_cost_needs_rehash() {
+        let legacy = bcrypt::hash("correct horse", 4).unwrap();
+        let mut policy = fast_policy(Algorithm::Bcrypt);
+        policy.bcrypt_cost = 5;
+        let hasher = PasswordHasher::new(policy).unwrap();
+
+        assert!(hasher.needs_rehash(&legacy).unwrap());
+        assert!(matches!(
+            hasher.verify("correct horse", &legacy).unwrap(),
+            Verification::NeedsRehash(hash) if hash.starts_with("$2b$05$")
+        ));
+    }
+
+    #[test]
+    fn test_outdated_argon2_params_need_rehash() {
+        let old = PasswordHasher::new(fast_policy(Algorithm::Argon2id)).unwrap();
+        let hash = old.hash("correct horse").unwrap();
+
+        let mut policy = fast_policy(Algorithm::Argon2id);
+        policy.argon2.iterations = 2;
+        let hasher = PasswordHasher::new(policy).unwrap();
+
+        assert!(hasher.needs_rehash(&hash).unwrap());
+        assert!(!old.needs_rehash(&hash).unwrap());
+    }
+
+    #[test]
+    fn test_argon2i_hash_needs_rehash() {
+        let policy = fast_policy(Algorithm::Argon2id);
+        let params = Params::new(1024, 1, 1, None).unwrap();
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::new(argon2::Algorithm::Argon2i, Version::V0x13, params)
+            .hash_password(b"correct horse", &salt)
+            .unwrap()
+            .to_string();
+
+        let hasher = PasswordHasher::new(policy).unwrap();
+        assert!(matches!(
+            hasher.verify("correct horse", &hash).unwrap(),
+            Verification::NeedsRehash(_)
+        ));
+    }
+
+    #[test]
+    fn test_unsupported_hash_format() {
+        let hasher = PasswordHasher::new(fast_policy(Algorithm::Argon2id)).unwrap();
+        assert!(matches!(
+            hasher.verify("password", "plaintext"),
+            Err(PasswordError::UnsupportedHash)
+        ));
+    }
+
+    #[test]
+    fn test_invalid_policy_rejected() {
+        let mut policy = fast_policy(Algorithm::Bcrypt);
+        policy.bcrypt_cost = 2;
+        assert!(PasswordHasher::new(policy).is_err());
+
+        let mut policy = fast_policy(Algorithm::Argon2id);
+        policy.argon2.memory_kib = 1;
+        assert!(PasswordHasher::new(policy).is_err());
+    }
+
+    #[test]
+    fn test_algorithm_from_str() {
+        assert_eq!("argon2id".parse::<Algorithm>().unwrap(), Algorithm::Argon2id);
+        assert_eq!("BCRYPT".parse::<Algorithm>().unwrap(), Algorithm::Bcrypt);
+        assert!("md5".parse::<Algorithm>().is_err());
+    }
+}
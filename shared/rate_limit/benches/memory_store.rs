@@ -0,0 +1,112 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use lotabots_rate_limit::{MemoryStore, RateLimit, RateLimitStore};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const CHECKS_PER_ITER: u64 = 1_000;
+
+static NEXT_KEY: AtomicU64 = AtomicU64::new(0);
+
+/// Runs `iters` rounds of checks spread over `threads` threads, each thread
+/// picking keys with `key`
+fn run_contended(
+    store: &Arc<MemoryStore>,
+    threads: usize,
+    iters: u64,
+    key: fn(usize, u64) -> String,
+) -> Duration {
+    let limit = RateLimit::per_second(1_000_000);
+    let checks = iters * CHECKS_PER_ITER / threads as u64;
+
+    let started = Instant::now();
+    thread::scope(|scope| {
+        for thread in 0..threads {
+            let store = store.clone();
+            scope.spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .build()
+                    .unwrap();
+                runtime.block_on(async {
+                    for i in 0..checks {
+                        let key = key(thread, i);
+                        black_box(store.check(&key, &limit, 1).await.unwrap());
+                    }
+                });
+            });
+        }
+    });
+    started.elapsed()
+}
+
+fn bench_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("MemoryStore contention");
+    group.throughput(Throughput::Elements(CHECKS_PER_ITER));
+
+    for threads in [1, 4, 8] {
+        for shards in [1, 32] {
+            let id = format!("{} shards", shards);
+
+            // Each client has its own key
+            let store = Arc::new(MemoryStore::with_shards(shards, 100_000));
+            group.bench_with_input(
+                BenchmarkId::new(format!("distinct keys/{}", id), threads),
+                &threads,
+                |b, &threads| {
+                    b.iter_custom(|iters| {
+                        run_contended(&store, threads, iters, |thread, i| {
+                            format!("client-{}-{}", thread, i % 1_000)
+                        })
+                    })
+                },
+            );
+
+            // Every thread hammers the same few keys
+            let store = Arc::new(MemoryStore::with_shards(shards, 100_000));
+            group.bench_with_input(
+                BenchmarkId::new(format!("hot keys/{}", id), threads),
+                &threads,
+                |b, &threads| {
+                    b.iter_custom(|iters| {
+                        run_contended(&store, threads, iters, |_, i| format!("hot-{}", i % 4))
+                    })
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
+fn bench_key_flood(c: &mut Criterion) {
+    let mut group = c.benchmark_group("MemoryStore key flood");
+    group.throughput(Throughput::Elements(CHECKS_PER_ITER));
+
+    // Every request comes from a new key, so the store stays at its cap and
+    // evicts continuously
+    let store = Arc::new(MemoryStore::with_max_keys(10_000));
+    for threads in [1, 8] {
+        group.bench_with_input(
+            BenchmarkId::new("new keys", threads),
+            &threads,
+            |b, &threads| {
+                b.iter_custom(|iters| {
+                    run_contended(&store, threads, iters, |_, _| {
+                        format!("flood-{}", NEXT_KEY.fetch_add(1, Ordering::Relaxed))
+                    })
+                })
+            },
+        );
+    }
+    assert!(store.len() <= 10_000);
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_contention, bench_key_flood);
+criterion_main!(benches);
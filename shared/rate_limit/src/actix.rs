@@ -0,0 +1,159 @@
+//! actix-web middleware.
+//!
+//! ```ignore
+//! App::new().service(
+//!     web::scope("/auth")
+//!         .wrap(RateLimiter::new(store.clone(), "auth", RateLimit::per_second(1)))
+//!         .configure(routes),
+//! )
+//! ```
+//!
+//! Behind authentication, limit per tenant with a [`Policy`](crate::Policy)
+//! and [`RateLimiter::with_subject`]; the limiter has to be wrapped before
+//! the authentication middleware so that it runs after it.
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderMap, HeaderName, HeaderValue},
+    Error, HttpResponse,
+};
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+};
+
+use crate::{Decision, Policy, RateLimit, RateLimitStore, Subject};
+
+type SubjectFn = Arc<dyn Fn(&ServiceRequest) -> Option<Subject> + Send + Sync>;
+
+/// Limits requests per client. Every limited response carries `RateLimit-*`
+/// headers; requests over the limit are answered with `429 Too Many
+/// Requests` and a `Retry-After` header. If the store cannot be reached
+/// requests are let through, so an outage of Redis does not take the
+/// service down with it.
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    scope: Arc<str>,
+    policy: Arc<Policy>,
+    subject: SubjectFn,
+}
+
+impl RateLimiter {
+    /// Limits each peer IP according to `policy`, which may be a single
+    /// [`RateLimit`]. `scope` keeps the counts of differently limited routes
+    /// apart.
+    pub fn new(store: Arc<dyn RateLimitStore>, scope: &str, policy: impl Into<Policy>) -> Self {
+        Self {
+            store,
+            scope: scope.into(),
+            policy: Arc::new(policy.into()),
+            subject: Arc::new(|req| peer_ip(req).map(Subject::client)),
+        }
+    }
+
+    /// Identifies clients with `key` instead of by peer IP. Requests for
+    /// which it returns `None` are not limited.
+    pub fn with_key<F>(self, key: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
+    {
+        self.with_subject(move |req| key(req).map(Subject::client))
+    }
+
+    /// Identifies who a request is counted for, e.g. from the claims the
+    /// authentication middleware stored. Requests for which it returns
+    /// `None` are not limited.
+    pub fn with_subject<F>(mut self, subject: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Option<Subject> + Send + Sync + 'static,
+    {
+        self.subject = Arc::new(subject);
+        self
+    }
+}
+
+/// The address of the connected peer. Only use a forwarded address when the
+/// service is reachable through a trusted proxy alone.
+pub fn peer_ip(req: &ServiceRequest) -> Option<String> {
+    req.peer_addr().map(|addr| addr.ip().to_string())
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimiterMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware {
+            service: Rc::new(service),
+            limiter: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimiterMiddleware<S> {
+    service: Rc<S>,
+    limiter: RateLimiter,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = self.limiter.clone();
+        let subject = (limiter.subject)(&req);
+        let method = req.method().to_string();
+        let path = req.path().to_string();
+
+        Box::pin(async move {
+            let checked = limiter
+                .policy
+                .check(&*limiter.store, &limiter.scope, &method, &path, subject)
+                .await;
+
+            match checked {
+                Some((decision, limit)) if !decision.allowed => {
+                    let mut response = HttpResponse::TooManyRequests()
+                        .json(serde_json::json!({ "error": "Rate limit exceeded" }));
+                    insert_headers(response.headers_mut(), &decision, &limit);
+                    Ok(req.into_response(response).map_into_right_body())
+                }
+                Some((decision, limit)) => {
+                    let mut res = service.call(req).await?;
+                    insert_headers(res.headers_mut(), &decision, &limit);
+                    Ok(res.map_into_left_body())
+                }
+                None => service.call(req).await.map(|res| res.map_into_left_body()),
+            }
+        })
+    }
+}
+
+fn insert_headers(headers: &mut HeaderMap, decision: &Decision, limit: &RateLimit) {
+    for (name, value) in decision.headers(limit) {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Tower layer for axum.
+//!
+//! ```ignore
+//! let app = Router::new()
+//!     .route("/login", post(login))
+//!     .layer(RateLimitLayer::new(store, "auth", RateLimit::per_second(1)));
+//! ```
+//!
+//! Serve the router with `into_make_service_with_connect_info::<SocketAddr>`
+//! so the peer address is known, or use [`RateLimitLayer::with_subject`] to
+//! limit per tenant.
+
+use ::axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::{
+        header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::Response,
+};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+use crate::{Decision, Policy, RateLimit, RateLimitStore, Subject};
+
+type SubjectFn = Arc<dyn Fn(&Request) -> Option<Subject> + Send + Sync>;
+
+/// Limits requests per client; see [`crate::actix::RateLimiter`] for the
+/// behaviour, which is the same
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    store: Arc<dyn RateLimitStore>,
+    scope: Arc<str>,
+    policy: Arc<Policy>,
+    subject: SubjectFn,
+}
+
+impl RateLimitLayer {
+    /// Limits each peer IP according to `policy`, which may be a single
+    /// [`RateLimit`]. `scope` keeps the counts of differently limited routes
+    /// apart.
+    pub fn new(store: Arc<dyn RateLimitStore>, scope: &str, policy: impl Into<Policy>) -> Self {
+        Self {
+            store,
+            scope: scope.into(),
+            policy: Arc::new(policy.into()),
+            subject: Arc::new(|req| peer_ip(req).map(Subject::client)),
+        }
+    }
+
+    /// Identifies clients with `key` instead of by peer IP. Requests for
+    /// which it returns `None` are not limited.
+    pub fn with_key<F>(self, key: F) -> Self
+    where
+        F: Fn(&Request) -> Option<String> + Send + Sync + 'static,
+    {
+        self.with_subject(move |req| key(req).map(Subject::client))
+    }
+
+    /// Identifies who a request is counted for. Requests for which it
+    /// returns `None` are not limited.
+    pub fn with_subject<F>(mut self, subject: F) -> Self
+    where
+        F: Fn(&Request) -> Option<Subject> + Send + Sync + 'static,
+    {
+        self.subject = Arc::new(subject);
+        self
+    }
+}
+
+/// The address of the connected peer, from [`ConnectInfo`]
+pub fn peer_ip(req: &Request) -> Option<String> {
+    req.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: RateLimitLayer,
+}
+
+impl<S> Service<Request> for RateLimitService<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // The clone is not ready; keep the service that was polled
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        let subject = (limiter.subject)(&req);
+        let method = req.method().to_string();
+        let path = req.uri().path().to_string();
+
+        Box::pin(async move {
+            let checked = limiter
+                .policy
+                .check(&*limiter.store, &limiter.scope, &method, &path, subject)
+                .await;
+
+            match checked {
+                Some((decision, limit)) if !decision.allowed => {
+                    let mut response = Response::builder()
+                        .status(StatusCode::TOO_MANY_REQUESTS)
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Body::from(
+                            serde_json::json!({ "error": "Rate limit exceeded" }).to_string(),
+                        ))
+                        .expect("valid response");
+                    insert_headers(response.headers_mut(), &decision, &limit);
+                    Ok(response)
+                }
+                Some((decision, limit)) => {
+                    let mut response = inner.call(req).await?;
+                    insert_headers(response.headers_mut(), &decision, &limit);
+                    Ok(response)
+                }
+                None => inner.call(req).await,
+            }
+        })
+    }
+}
+
+fn insert_headers(headers: &mut HeaderMap, decision: &Decision, limit: &RateLimit) {
+    for (name, value) in decision.headers(limit) {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Rate limiting shared by LotaBots services.
+//!
+//! Limits are enforced with GCRA, the generic cell rate algorithm: a key
+//! stores a single timestamp, the theoretical arrival time (TAT) of its next
+//! request, so a check is one read and one write no matter how large the
+//! burst. Where that timestamp lives is up to the [`RateLimitStore`]:
+//! [`MemoryStore`] keeps it in the process, [`RedisStore`] in Redis so that
+//! every replica enforces the same limit instead of multiplying it.
+//!
+//! A [`Policy`] decides which bucket a request counts against and how
+//! much it costs: buckets are keyed by tenant, user, API key and route group
+//! ([`Subject`]), their limits come from the tenant's subscription tier
+//! ([`Tiers`]), and expensive routes can cost more than one request. The
+//! [`actix`] and [`axum`] modules apply a policy as middleware and report
+//! the outcome in `RateLimit-*` headers.
+
+use async_trait::async_trait;
+use std::{str::FromStr, sync::Arc, time::Duration};
+use thiserror::Error;
+
+#[cfg(feature = "actix")]
+pub mod actix;
+#[cfg(feature = "axum")]
+pub mod axum;
+mod memory;
+mod policy;
+#[cfg(feature = "redis")]
+mod redis;
+
+pub use memory::{MemoryStore, DEFAULT_MAX_KEYS};
+pub use policy::{Policy, Subject, Tiers};
+#[cfg(feature = "redis")]
+pub use redis::{RedisStore, GCRA_SCRIPT};
+
+#[derive(Debug, Error)]
+pub enum RateLimitError {
+    #[error("Rate limit store error: {0}")]
+    Store(String),
+    #[error("Invalid rate limit configuration: {0}")]
+    Config(String),
+}
+
+pub type Result<T> = std::result::Result<T, RateLimitError>;
+
+/// How many requests a key may make
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Time it takes for one request to be replenished
+    interval: Duration,
+    /// Requests that may be made at once after being idle
+    burst: u32,
+}
+
+impl RateLimit {
+    /// `requests` per `period`, all of which may be used at once
+    pub fn new(requests: u32, period: Duration) -> Self {
+        let requests = requests.max(1);
+        Self {
+            interval: period / requests,
+            burst: requests,
+        }
+    }
+
+    pub fn per_second(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(1))
+    }
+
+    pub fn per_minute(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(60))
+    }
+
+    /// Allow `burst` requests at once while keeping the sustained rate
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    /// Time for an exhausted burst to be fully replenished
+    pub fn window(&self) -> Duration {
+        self.interval * self.burst
+    }
+}
+
+/// Parses `<requests>/<period>`, optionally followed by `:<burst>`, e.g.
+/// `1000/day` or `2/s:5`. Periods are `s`, `min`, `h` and `day`.
+impl FromStr for RateLimit {
+    type Err = RateLimitError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || RateLimitError::Config(format!("Invalid rate limit '{}'", s));
+
+        let (rate, burst) = match s.split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst.trim().parse().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let (requests, period) = rate.split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        let period = match period.trim() {
+            "s" | "sec" | "second" => Duration::from_secs(1),
+            "m" | "min" | "minute" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(60 * 60),
+            "d" | "day" => Duration::from_secs(24 * 60 * 60),
+            _ => return Err(invalid()),
+        };
+        if requests == 0 {
+            return Err(invalid());
+        }
+
+        let limit = Self::new(requests, period);
+        Ok(match burst {
+            Some(burst) => limit.with_burst(burst),
+            None => limit,
+        })
+    }
+}
+
+/// Outcome of counting one request against a limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    /// The burst size of the limit
+    pub limit: u32,
+    /// Requests that could still be made right now
+    pub remaining: u32,
+    /// How long to wait before retrying; zero when allowed
+    pub retry_after: Duration,
+    /// How long until the full burst is available again
+    pub reset_after: Duration,
+}
+
+impl Decision {
+    /// `RateLimit-*` headers as proposed by the IETF httpapi working group,
+    /// plus `Retry-After` when the request was denied. Times are whole
+    /// seconds, rounded up.
+    pub fn headers(&self, limit: &RateLimit) -> Vec<(&'static str, String)> {
+        let secs = |d: Duration| d.as_secs() + u64::from(d.subsec_nanos() > 0);
+
+        let mut headers = vec![
+            ("ratelimit-limit", self.limit.to_string()),
+            ("ratelimit-remaining", self.remaining.to_string()),
+            ("ratelimit-reset", secs(self.reset_after).to_string()),
+            (
+                "ratelimit-policy",
+                format!("{};w={}", limit.burst, secs(limit.window())),
+            ),
+        ];
+        if !self.allowed {
+            headers.push(("retry-after", secs(self.retry_after).to_string()));
+        }
+        headers
+    }
+}
+
+/// Where rate limit state is kept.
+///
+/// Keys are opaque; middleware builds them from a scope and the client
+/// identity, e.g. `auth:203.0.113.7`.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Counts a request costing `cost` requests for `key` against `limit`.
+    /// Denied requests are not counted; a cost above the burst is always
+    /// denied.
+    async fn check(&self, key: &str, limit: &RateLimit, cost: u32) -> Result<Decision>;
+}
+
+#[async_trait]
+impl<T: RateLimitStore + ?Sized> RateLimitStore for Arc<T> {
+    async fn check(&self, key: &str, limit: &RateLimit, cost: u32) -> Result<Decision> {
+        (**self).check(key, limit, cost).await
+    }
+}
+
+/// Uses Redis when `RATE_LIMIT_REDIS_URL` or `REDIS_URL` is set, so limits
+/// are shared between replicas, and a per-process store otherwise
+pub async fn from_env() -> Result<Arc<dyn RateLimitStore>> {
+    #[cfg(feature = "redis")]
+    if let Ok(url) = std::env::var("RATE_LIMIT_REDIS_URL").or_else(|_| std::env::var("REDIS_URL")) {
+        return Ok(Arc::new(RedisStore::connect(&url).await?));
+    }
+
+    tracing::warn!("No Redis configured for rate limiting; limits apply per process");
+    Ok(Arc::new(MemoryStore::new()))
+}
+
+/// Applies a request costing `cost` arriving at `now` to a key whose
+/// theoretical arrival time is `tat`, both in microseconds. Returns the
+/// decision and, if the request is allowed, the TAT to store. Mirrors
+/// [`GCRA_SCRIPT`].
+pub(crate) fn gcra(
+    tat: Option<u64>,
+    now: u64,
+    limit: &RateLimit,
+    cost: u32,
+) -> (Decision, Option<u64>) {
+    let interval = (limit.interval.as_micros() as u64).max(1);
+    let tolerance = interval * u64::from(limit.burst);
+
+    let tat = tat.unwrap_or(now).max(now);
+    let new_tat = tat + interval * u64::from(cost);
+    // The request fits if it lands within the tolerance ahead of now
+    let horizon = now + tolerance;
+
+    if new_tat > horizon {
+        let decision = Decision {
+            allowed: false,
+            limit: limit.burst,
+            remaining: 0,
+            retry_after: Duration::from_micros(new_tat - horizon),
+            reset_after: Duration::from_micros(tat - now),
+        };
+        return (decision, None);
+    }
+
+    let decision = Decision {
+        allowed: true,
+        limit: limit.burst,
+        remaining: ((horizon - new_tat) / interval) as u32,
+        retry_after: Duration::ZERO,
+        reset_after: Duration::from_micros(new_tat - now),
+    };
+    (decision, Some(new_tat))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gcra_allows_burst_then_paces() {
+        let limit = RateLimit::per_second(2).with_burst(3);
+        assert_eq!(limit.interval(), Duration::from_millis(500));
+
+        let mut tat = None;
+        for remaining in [2, 1, 0] {
+            let (decision, next) = gcra(tat, 0, &limit, 1);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+            tat = next;
+        }
+
+        let (denied, next) = gcra(tat, 0, &limit, 1);
+        assert!(!denied.allowed);
+        assert_eq!(next, None);
+        assert_eq!(denied.retry_after, Duration::from_millis(500));
+        assert_eq!(denied.reset_after, Duration::from_millis(1500));
+
+        // One interval later exactly one request is available again
+        let (decision, tat) = gcra(tat, 500_000, &limit, 1);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert!(!gcra(tat, 500_000, &limit, 1).0.allowed);
+
+        // Idle keys get the full burst back
+        let (decision, _) = gcra(tat, 10_000_000, &limit, 1);
+        assert_eq!(decision.remaining, 2);
+
+        // Expensive requests use up several at once
+        let (decision, tat) = gcra(None, 0, &limit, 2);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 1);
+        assert!(!gcra(tat, 0, &limit, 2).0.allowed);
+        assert!(!gcra(None, 0, &limit, 4).0.allowed);
+    }
+
+    #[test]
+    fn test_parse_limits_and_headers() {
+        let limit: RateLimit = "1000/day".parse().unwrap();
+        assert_eq!(limit.burst(), 1000);
+        assert_eq!(limit.window(), Duration::from_secs(86_400));
+        assert_eq!(
+            "2/s:5".parse::<RateLimit>().unwrap(),
+            RateLimit::per_second(2).with_burst(5)
+        );
+        assert!("0/s".parse::<RateLimit>().is_err());
+        assert!("10/fortnight".parse::<RateLimit>().is_err());
+
+        let (denied, _) = gcra(Some(2_500_000), 0, &"2/s:5".parse().unwrap(), 1);
+        assert_eq!(
+            denied.headers(&"2/s:5".parse().unwrap()),
+            vec![
+                ("ratelimit-limit", "5".to_string()),
+                ("ratelimit-remaining", "0".to_string()),
+                ("ratelimit-reset", "3".to_string()),
+                ("ratelimit-policy", "5;w=3".to_string()),
+                ("retry-after", "1".to_string()),
+            ]
+        );
+    }
+}
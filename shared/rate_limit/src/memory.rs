@@ -0,0 +1,200 @@
+use async_trait::async_trait;
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{gcra, Decision, RateLimit, RateLimitStore, Result};
+
+/// Keys tracked at most, across all shards
+pub const DEFAULT_MAX_KEYS: usize = 100_000;
+
+const DEFAULT_SHARDS: usize = 32;
+
+/// How often a shard drops keys whose bucket has refilled
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps rate limit state in the process. Each replica enforces the limit
+/// on its own, so use [`RedisStore`](crate::RedisStore) when running more
+/// than one.
+///
+/// Keys are spread over independently locked shards so concurrent requests
+/// rarely wait on each other. A key is forgotten once its bucket is full
+/// again, which changes nothing about the limit, and the number of keys is
+/// capped so a flood of distinct clients cannot exhaust memory: at the cap
+/// the keys closest to a full bucket are evicted first.
+pub struct MemoryStore {
+    started: Instant,
+    shards: Box<[Mutex<Shard>]>,
+    keys_per_shard: usize,
+    /// Randomly seeded so clients cannot aim all their keys at one shard
+    hasher: RandomState,
+}
+
+#[derive(Default)]
+struct Shard {
+    /// Theoretical arrival time per key, in microseconds since `started`
+    tats: HashMap<String, u64>,
+    next_sweep: u64,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARDS, DEFAULT_MAX_KEYS)
+    }
+
+    /// Tracks at most `max_keys` keys
+    pub fn with_max_keys(max_keys: usize) -> Self {
+        Self::with_shards(DEFAULT_SHARDS, max_keys)
+    }
+
+    /// Spreads at most `max_keys` keys over `shards` locks
+    pub fn with_shards(shards: usize, max_keys: usize) -> Self {
+        let shards = shards.max(1);
+        Self {
+            started: Instant::now(),
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+            keys_per_shard: max_keys.div_ceil(shards).max(1),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Number of keys currently tracked
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().tats.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<Shard> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    fn check_at(&self, key: &str, limit: &RateLimit, cost: u32, now: Duration) -> Decision {
+        let now = now.as_micros() as u64;
+        let mut shard = self.shard(key).lock().unwrap();
+
+        if now >= shard.next_sweep {
+            shard.evict_idle(now);
+            shard.next_sweep = now + SWEEP_INTERVAL.as_micros() as u64;
+        }
+
+        let (decision, tat) = gcra(shard.tats.get(key).copied(), now, limit, cost);
+        if let Some(tat) = tat {
+            match shard.tats.get_mut(key) {
+                Some(existing) => *existing = tat,
+                None => {
+                    if shard.tats.len() >= self.keys_per_shard {
+                        shard.make_room(now, self.keys_per_shard);
+                    }
+                    shard.tats.insert(key.to_string(), tat);
+                }
+            }
+        }
+        decision
+    }
+}
+
+impl Shard {
+    /// Drops keys whose bucket is full again; they would start over with
+    /// the full burst anyway
+    fn evict_idle(&mut self, now: u64) {
+        self.tats.retain(|_, tat| *tat > now);
+    }
+
+    /// Frees at least one slot, evicting at least an eighth of the shard,
+    /// lowest TAT first, if idle keys alone do not make room
+    fn make_room(&mut self, now: u64, capacity: usize) {
+        self.evict_idle(now);
+        if self.tats.len() < capacity {
+            return;
+        }
+
+        let evict = (capacity / 8).max(1);
+        let mut tats: Vec<u64> = self.tats.values().copied().collect();
+        let (_, threshold, _) = tats.select_nth_unstable(evict - 1);
+        let threshold = *threshold;
+        self.tats.retain(|_, tat| *tat > threshold);
+        tracing::debug!("Rate limit store full; evicted active keys");
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn check(&self, key: &str, limit: &RateLimit, cost: u32) -> Result<Decision> {
+        Ok(self.check_at(key, limit, cost, self.started.elapsed()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_keys_are_limited_separately() {
+        let store = MemoryStore::new();
+        let limit = RateLimit::per_minute(2);
+
+        assert!(store.check("a", &limit, 1).await.unwrap().allowed);
+        assert!(store.check("a", &limit, 1).await.unwrap().allowed);
+        let denied = store.check("a", &limit, 1).await.unwrap();
+        assert!(!denied.allowed);
+        assert!(denied.retry_after > Duration::from_secs(29));
+
+        assert!(store.check("b", &limit, 1).await.unwrap().allowed);
+
+        // Replenished at the sustained rate
+        let later = store.started.elapsed() + Duration::from_secs(30);
+        assert!(store.check_at("a", &limit, 1, later).allowed);
+    }
+
+    #[test]
+    fn test_idle_keys_are_evicted() {
+        let store = MemoryStore::with_shards(1, DEFAULT_MAX_KEYS);
+        let limit = RateLimit::per_second(1);
+
+        for i in 0..100 {
+            store.check_at(&format!("client-{}", i), &limit, 1, Duration::ZERO);
+        }
+        assert_eq!(store.len(), 100);
+
+        // Every bucket has refilled by the next sweep
+        let later = SWEEP_INTERVAL + Duration::from_secs(1);
+        store.check_at("client-0", &limit, 1, later);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_key_count_is_capped() {
+        let store = MemoryStore::with_shards(4, 400);
+        let limit = RateLimit::per_minute(10);
+
+        // A client that has used up its budget
+        for _ in 0..10 {
+            store.check_at("heavy", &limit, 1, Duration::ZERO);
+        }
+
+        // A flood of distinct keys that each made one request
+        for i in 0..10_000 {
+            store.check_at(&format!("flood-{}", i), &limit, 1, Duration::ZERO);
+        }
+        assert!(store.len() <= 400);
+
+        // The most limited keys are the last to go
+        assert!(!store.check_at("heavy", &limit, 1, Duration::ZERO).allowed);
+    }
+}
@@ -0,0 +1,370 @@
+use std::{collections::HashMap, env};
+
+use crate::{Decision, RateLimit, RateLimitError, RateLimitStore, Result};
+
+/// Who a request is counted for. Every identity that is set becomes part of
+/// the key, so a subject with a tenant and a user is limited separately from
+/// another user of the same tenant; leave the user out to share one budget
+/// across the tenant.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Subject {
+    pub tenant_id: Option<String>,
+    pub user_id: Option<String>,
+    /// Prefix of the API key the request was made with
+    pub api_key: Option<String>,
+    /// Client acting without a user, e.g. its IP or OAuth client ID
+    pub client: Option<String>,
+    /// Tier to apply instead of the tenant's assigned one
+    pub tier: Option<String>,
+}
+
+impl Subject {
+    pub fn client(client: impl Into<String>) -> Self {
+        Self {
+            client: Some(client.into()),
+            ..Self::default()
+        }
+    }
+
+    pub fn tenant(tenant_id: impl Into<String>) -> Self {
+        Self {
+            tenant_id: Some(tenant_id.into()),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_user(mut self, user_id: impl Into<String>) -> Self {
+        self.user_id = Some(user_id.into());
+        self
+    }
+
+    pub fn with_api_key(mut self, prefix: impl Into<String>) -> Self {
+        self.api_key = Some(prefix.into());
+        self
+    }
+
+    pub fn with_tier(mut self, tier: impl Into<String>) -> Self {
+        self.tier = Some(tier.into());
+        self
+    }
+
+    /// `t=<tenant>,u=<user>,k=<api key>,c=<client>` for the identities that
+    /// are set; `None` if there are none
+    fn key(&self) -> Option<String> {
+        let parts: Vec<String> = [
+            ("t", &self.tenant_id),
+            ("u", &self.user_id),
+            ("k", &self.api_key),
+            ("c", &self.client),
+        ]
+        .into_iter()
+        .filter_map(|(name, id)| id.as_ref().map(|id| format!("{}={}", name, id)))
+        .collect();
+
+        (!parts.is_empty()).then(|| parts.join(","))
+    }
+}
+
+/// Limits per subscription tier and which tier each tenant is on
+#[derive(Debug, Clone)]
+pub struct Tiers {
+    limits: HashMap<String, RateLimit>,
+    default_tier: String,
+    tenants: HashMap<String, String>,
+}
+
+impl Tiers {
+    /// A single tier that applies to everyone
+    pub fn new(default_tier: &str, limit: RateLimit) -> Self {
+        Self {
+            limits: HashMap::from([(default_tier.to_string(), limit)]),
+            default_tier: default_tier.to_string(),
+            tenants: HashMap::new(),
+        }
+    }
+
+    /// Adds or replaces a tier
+    pub fn with_tier(mut self, tier: &str, limit: RateLimit) -> Self {
+        self.limits.insert(tier.to_string(), limit);
+        self
+    }
+
+    /// Puts `tenant_id` on `tier`
+    pub fn assign(mut self, tenant_id: &str, tier: &str) -> Self {
+        self.tenants.insert(tenant_id.to_string(), tier.to_string());
+        self
+    }
+
+    /// Starts from [`Tiers::default`] and applies:
+    ///
+    /// - `RATE_LIMIT_TIERS`: `<tier>=<limit>` pairs, e.g.
+    ///   `free=1000/day,custom=500/min:50`
+    /// - `RATE_LIMIT_DEFAULT_TIER`: tier of tenants without an assignment
+    /// - `RATE_LIMIT_TENANT_TIERS`: `<tenant id>=<tier>` pairs
+    pub fn from_env() -> Result<Self> {
+        let mut tiers = Self::default();
+
+        for (tier, limit) in pairs("RATE_LIMIT_TIERS")? {
+            tiers = tiers.with_tier(&tier, limit.parse()?);
+        }
+        if let Ok(tier) = env::var("RATE_LIMIT_DEFAULT_TIER") {
+            tiers.default_tier = tier;
+        }
+        for (tenant_id, tier) in pairs("RATE_LIMIT_TENANT_TIERS")? {
+            tiers = tiers.assign(&tenant_id, &tier);
+        }
+
+        tiers.validate()?;
+        Ok(tiers)
+    }
+
+    fn validate(&self) -> Result<()> {
+        std::iter::once(&self.default_tier)
+            .chain(self.tenants.values())
+            .find(|tier| !self.limits.contains_key(*tier))
+            .map_or(Ok(()), |tier| {
+                Err(RateLimitError::Config(format!("Unknown tier '{}'", tier)))
+            })
+    }
+
+    /// The tier `tenant_id` is on
+    pub fn tier_of(&self, tenant_id: &str) -> &str {
+        self.tenants
+            .get(tenant_id)
+            .filter(|tier| self.limits.contains_key(*tier))
+            .unwrap_or(&self.default_tier)
+    }
+
+    /// The subject's explicit tier, else its tenant's, else the default.
+    /// Unknown tiers fall back to the default.
+    pub fn limit_for(&self, subject: &Subject) -> RateLimit {
+        subject
+            .tier
+            .as_ref()
+            .or_else(|| {
+                subject
+                    .tenant_id
+                    .as_ref()
+                    .and_then(|tenant_id| self.tenants.get(tenant_id))
+            })
+            .and_then(|tier| self.limits.get(tier))
+            .unwrap_or(&self.limits[&self.default_tier])
+            .to_owned()
+    }
+}
+
+/// The subscription tiers: free, professional and enterprise, with free as
+/// the default
+impl Default for Tiers {
+    fn default() -> Self {
+        let day = std::time::Duration::from_secs(24 * 60 * 60);
+        Self::new("free", RateLimit::new(1_000, day))
+            .with_tier("professional", RateLimit::new(10_000, day))
+            .with_tier("enterprise", RateLimit::new(100_000, day))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    method: Option<String>,
+    prefix: String,
+    group: String,
+    cost: u32,
+}
+
+/// What a request is counted against: the limit from the subject's tier,
+/// and for routes in a group a separate bucket and a cost per request
+#[derive(Debug, Clone)]
+pub struct Policy {
+    tiers: Tiers,
+    routes: Vec<Route>,
+}
+
+impl Policy {
+    pub fn new(tiers: Tiers) -> Self {
+        Self {
+            tiers,
+            routes: Vec::new(),
+        }
+    }
+
+    /// Counts requests under `prefix`, optionally only those with `method`,
+    /// in their own `group` bucket at `cost` requests each. The longest
+    /// matching prefix wins.
+    pub fn route(mut self, method: Option<&str>, prefix: &str, group: &str, cost: u32) -> Self {
+        self.routes.push(Route {
+            method: method.map(str::to_uppercase),
+            prefix: prefix.to_string(),
+            group: group.to_string(),
+            cost: cost.max(1),
+        });
+        self
+    }
+
+    /// Tiers from [`Tiers::from_env`] and routes from `RATE_LIMIT_ROUTES`:
+    /// `[<method>] <prefix>=<group>[*<cost>]` entries separated by commas,
+    /// e.g. `POST /api/v1/documents=uploads*10`
+    pub fn from_env() -> Result<Self> {
+        let mut policy = Self::new(Tiers::from_env()?);
+
+        for (route, group) in pairs("RATE_LIMIT_ROUTES")? {
+            let invalid = || RateLimitError::Config(format!("Invalid route '{}'", route));
+            let (method, prefix) = match route.split_once(' ') {
+                Some((method, prefix)) => (Some(method), prefix.trim()),
+                None => (None, route.as_str()),
+            };
+            let (group, cost) = match group.split_once('*') {
+                Some((group, cost)) => (group, cost.trim().parse().map_err(|_| invalid())?),
+                None => (group.as_str(), 1),
+            };
+            if !prefix.starts_with('/') || group.is_empty() {
+                return Err(invalid());
+            }
+            policy = policy.route(method, prefix, group.trim(), cost);
+        }
+
+        Ok(policy)
+    }
+
+    fn find_route(&self, method: &str, path: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|route| {
+                route
+                    .method
+                    .as_ref()
+                    .is_none_or(|m| m.eq_ignore_ascii_case(method))
+                    && path.starts_with(&route.prefix)
+            })
+            .max_by_key(|route| route.prefix.len())
+    }
+
+    /// Counts the request and returns the decision with the limit it was
+    /// checked against. Store failures are logged and let the request
+    /// through, as are subjects without any identity.
+    pub(crate) async fn check(
+        &self,
+        store: &dyn RateLimitStore,
+        scope: &str,
+        method: &str,
+        path: &str,
+        subject: Option<Subject>,
+    ) -> Option<(Decision, RateLimit)> {
+        let subject = subject?;
+        let route = self.find_route(method, path);
+        let key = match route {
+            Some(route) => format!("{}:{}:{}", scope, route.group, subject.key()?),
+            None => format!("{}:{}", scope, subject.key()?),
+        };
+        let limit = self.tiers.limit_for(&subject);
+        let cost = route.map_or(1, |route| route.cost);
+
+        match store.check(&key, &limit, cost).await {
+            Ok(decision) => Some((decision, limit)),
+            Err(e) => {
+                tracing::warn!("Rate limit check for {} failed: {}", key, e);
+                None
+            }
+        }
+    }
+}
+
+/// The same limit for everyone
+impl From<RateLimit> for Policy {
+    fn from(limit: RateLimit) -> Self {
+        Self::new(Tiers::new("default", limit))
+    }
+}
+
+/// Comma-separated `<name>=<value>` pairs from `var`, empty if it is unset
+fn pairs(var: &str) -> Result<Vec<(String, String)>> {
+    let Ok(value) = env::var(var) else {
+        return Ok(Vec::new());
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            pair.split_once('=')
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .ok_or_else(|| RateLimitError::Config(format!("Invalid {} entry '{}'", var, pair)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryStore;
+
+    #[test]
+    fn test_tiers_follow_tenant_assignment() {
+        let tiers = Tiers::default().assign("acme", "enterprise");
+
+        let acme = Subject::tenant("acme").with_user("u1");
+        assert_eq!(tiers.limit_for(&acme).burst(), 100_000);
+        assert_eq!(tiers.limit_for(&Subject::tenant("other")).burst(), 1_000);
+        assert_eq!(
+            tiers
+                .limit_for(&Subject::tenant("other").with_tier("professional"))
+                .burst(),
+            10_000
+        );
+        assert!(Tiers::default()
+            .assign("acme", "platinum")
+            .validate()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_keys_and_costs_follow_routes() {
+        let store = MemoryStore::new();
+        let policy = Policy::from(RateLimit::per_minute(10)).route(
+            Some("post"),
+            "/api/v1/documents",
+            "uploads",
+            5,
+        );
+        let subject = || Some(Subject::tenant("acme").with_api_key("abc123"));
+        let check = |method, path, subject| policy.check(&store, "api", method, path, subject);
+
+        // Uploads cost five and have their own bucket
+        let (decision, _) = check("POST", "/api/v1/documents", subject()).await.unwrap();
+        assert_eq!(decision.remaining, 5);
+        assert!(
+            check("POST", "/api/v1/documents/x", subject())
+                .await
+                .unwrap()
+                .0
+                .allowed
+        );
+        assert!(
+            !check("POST", "/api/v1/documents", subject())
+                .await
+                .unwrap()
+                .0
+                .allowed
+        );
+
+        let (decision, _) = check("GET", "/api/v1/documents", subject()).await.unwrap();
+        assert_eq!(decision.remaining, 9);
+
+        // Other keys of the tenant are counted separately
+        let other = Some(Subject::tenant("acme").with_api_key("def456"));
+        assert!(
+            check("POST", "/api/v1/documents", other)
+                .await
+                .unwrap()
+                .0
+                .allowed
+        );
+
+        assert!(check("GET", "/", Some(Subject::default())).await.is_none());
+        assert_eq!(
+            subject().unwrap().key().unwrap(),
+            "t=acme,k=abc123".to_string()
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use ::redis::{aio::ConnectionLike, aio::ConnectionManager, Client, RedisError, Script};
+use async_trait::async_trait;
+use std::time::Duration;
+
+use crate::{Decision, RateLimit, RateLimitError, RateLimitStore, Result};
+
+/// GCRA in one round trip. The clock is Redis' own, so replicas with skewed
+/// clocks still agree, and the key expires once the bucket is full again.
+///
+/// `KEYS[1]` is the key, `ARGV[1]` the emission interval, `ARGV[2]` the
+/// burst and `ARGV[3]` the cost of the request. Returns `{allowed,
+/// remaining, retry_after, reset_after}` with times in microseconds.
+pub const GCRA_SCRIPT: &str = r#"
+local interval = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+local tolerance = interval * burst
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+
+local tat = tonumber(redis.call('GET', KEYS[1])) or now
+if tat < now then
+  tat = now
+end
+local new_tat = tat + interval * cost
+local allow_at = new_tat - tolerance
+
+if allow_at > now then
+  return {0, 0, allow_at - now, tat - now}
+end
+
+redis.call('SET', KEYS[1], new_tat, 'PX', math.ceil((new_tat - now) / 1000))
+return {1, math.floor((now - allow_at) / interval), 0, new_tat - now}
+"#;
+
+const KEY_PREFIX: &str = "rate_limit:";
+
+/// Keeps rate limit state in Redis, shared by every replica
+pub struct RedisStore<C = ConnectionManager> {
+    connection: C,
+    script: Script,
+}
+
+impl RedisStore {
+    /// Connects to `url`. The connection is re-established automatically
+    /// after failures.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let client = Client::open(url).map_err(|e| RateLimitError::Config(e.to_string()))?;
+        Ok(Self::new(ConnectionManager::new(client).await?))
+    }
+}
+
+impl<C> RedisStore<C>
+where
+    C: ConnectionLike + Clone + Send + Sync,
+{
+    pub fn new(connection: C) -> Self {
+        Self {
+            connection,
+            script: Script::new(GCRA_SCRIPT),
+        }
+    }
+}
+
+#[async_trait]
+impl<C> RateLimitStore for RedisStore<C>
+where
+    C: ConnectionLike + Clone + Send + Sync,
+{
+    async fn check(&self, key: &str, limit: &RateLimit, cost: u32) -> Result<Decision> {
+        let mut connection = self.connection.clone();
+        let (allowed, remaining, retry_after, reset_after): (i64, u32, u64, u64) = self
+            .script
+            .key(format!("{}{}", KEY_PREFIX, key))
+            .arg((limit.interval().as_micros() as u64).max(1))
+            .arg(limit.burst())
+            .arg(cost)
+            .invoke_async(&mut connection)
+            .await?;
+
+        Ok(Decision {
+            allowed: allowed == 1,
+            limit: limit.burst(),
+            remaining,
+            retry_after: Duration::from_micros(retry_after),
+            reset_after: Duration::from_micros(reset_after),
+        })
+    }
+}
+
+impl From<RedisError> for RateLimitError {
+    fn from(err: RedisError) -> Self {
+        RateLimitError::Store(err.to_string())
+    }
+}
@@ -0,0 +1,214 @@
+use async_trait::async_trait;
+use lotabots_rate_limit::{
+    Decision, MemoryStore, Policy, RateLimit, RateLimitError, RateLimitStore, Result, Subject,
+    Tiers,
+};
+use std::sync::Arc;
+
+/// A store whose backend is down
+struct Unreachable;
+
+#[async_trait]
+impl RateLimitStore for Unreachable {
+    async fn check(&self, _key: &str, _limit: &RateLimit, _cost: u32) -> Result<Decision> {
+        Err(RateLimitError::Store("connection refused".to_string()))
+    }
+}
+
+mod with_actix {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+    use lotabots_rate_limit::actix::RateLimiter;
+
+    #[actix_rt::test]
+    async fn test_requests_over_the_limit_are_rejected() {
+        let store: Arc<dyn RateLimitStore> = Arc::new(MemoryStore::new());
+        let app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/auth")
+                        .wrap(
+                            RateLimiter::new(store.clone(), "auth", RateLimit::per_minute(2))
+                                .with_key(|req| {
+                                    req.headers()
+                                        .get("x-client")
+                                        .and_then(|v| v.to_str().ok())
+                                        .map(str::to_string)
+                                }),
+                        )
+                        .route("/login", web::post().to(HttpResponse::Ok)),
+                )
+                .route("/health", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let login = |client: &str| {
+            test::TestRequest::post()
+                .uri("/auth/login")
+                .insert_header(("x-client", client))
+                .to_request()
+        };
+
+        for remaining in ["1", "0"] {
+            let res = test::call_service(&app, login("a")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get("ratelimit-limit").unwrap(), "2");
+            assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), remaining);
+            assert_eq!(res.headers().get("ratelimit-policy").unwrap(), "2;w=60");
+        }
+        let res = test::call_service(&app, login("a")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("retry-after").unwrap(), "30");
+        assert_eq!(res.headers().get("ratelimit-reset").unwrap(), "60");
+
+        let res = test::call_service(&app, login("b")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Routes outside the scope are not limited
+        let res =
+            test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("ratelimit-limit").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_tenants_are_limited_by_tier_and_route_cost() {
+        let tiers = Tiers::new("free", RateLimit::per_minute(4))
+            .with_tier("enterprise", RateLimit::per_minute(100))
+            .assign("acme", "enterprise");
+        let policy = Policy::new(tiers).route(Some("POST"), "/documents", "uploads", 4);
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(Arc::new(MemoryStore::new()), "api", policy).with_subject(
+                        |req| {
+                            req.headers()
+                                .get("x-tenant")
+                                .and_then(|v| v.to_str().ok())
+                                .map(Subject::tenant)
+                        },
+                    ),
+                )
+                .route("/documents", web::get().to(HttpResponse::Ok))
+                .route("/documents", web::post().to(HttpResponse::Created)),
+        )
+        .await;
+
+        let upload = |tenant: &str| {
+            test::TestRequest::post()
+                .uri("/documents")
+                .insert_header(("x-tenant", tenant))
+                .to_request()
+        };
+
+        // One upload uses up the free tier's uploads, not its reads
+        let res = test::call_service(&app, upload("small")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), "0");
+        let res = test::call_service(&app, upload("small")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/documents")
+                .insert_header(("x-tenant", "small"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), "3");
+
+        let res = test::call_service(&app, upload("acme")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers().get("ratelimit-limit").unwrap(), "100");
+        assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), "96");
+    }
+
+    #[actix_rt::test]
+    async fn test_store_failures_let_requests_through() {
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(Arc::new(Unreachable), "api", RateLimit::per_minute(1))
+                        .with_key(|_| Some("client".to_string())),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for _ in 0..3 {
+            let res = test::call_service(&app, test::TestRequest::get().to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+    }
+}
+
+mod with_axum {
+    use super::*;
+    use axum::{
+        body::Body,
+        extract::{ConnectInfo, Request},
+        http::StatusCode,
+        response::Response,
+    };
+    use lotabots_rate_limit::axum::RateLimitLayer;
+    use std::{convert::Infallible, net::SocketAddr};
+    use tower::{service_fn, Layer, ServiceExt};
+
+    fn request(peer: &str) -> Request {
+        let mut req = Request::new(Body::empty());
+        let addr: SocketAddr = peer.parse().unwrap();
+        req.extensions_mut().insert(ConnectInfo(addr));
+        req
+    }
+
+    #[tokio::test]
+    async fn test_requests_over_the_limit_are_rejected() {
+        let layer = RateLimitLayer::new(
+            Arc::new(MemoryStore::new()),
+            "api",
+            RateLimit::per_second(1).with_burst(2),
+        );
+        let service = layer.layer(service_fn(|_req: Request| async {
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        }));
+
+        for _ in 0..2 {
+            let res = service
+                .clone()
+                .oneshot(request("203.0.113.7:4000"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = service
+            .clone()
+            .oneshot(request("203.0.113.7:4001"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("retry-after").unwrap(), "1");
+        assert_eq!(res.headers().get("ratelimit-remaining").unwrap(), "0");
+        assert_eq!(res.headers().get("ratelimit-policy").unwrap(), "2;w=2");
+
+        let res = service.oneshot(request("198.51.100.1:4000")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_store_failures_let_requests_through() {
+        let layer = RateLimitLayer::new(Arc::new(Unreachable), "api", RateLimit::per_minute(1));
+        let service = layer.layer(service_fn(|_req: Request| async {
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        }));
+
+        for _ in 0..3 {
+            let res = service
+                .clone()
+                .oneshot(request("203.0.113.7:4000"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+    }
+}
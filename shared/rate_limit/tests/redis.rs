@@ -0,0 +1,287 @@
+use lotabots_rate_limit::{RateLimit, RateLimitStore, RedisStore};
+use mlua::{Lua, Variadic};
+use redis::{
+    aio::ConnectionLike, Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, RedisResult, Value,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Just enough of Redis to run the rate limit script: scripts are run by a
+/// real Lua 5.1 interpreter, as in Redis, against an in-memory keyspace and
+/// a clock the test controls
+#[derive(Clone)]
+struct FakeRedis {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    /// Microseconds since the epoch
+    now: u64,
+    scripts: HashMap<String, String>,
+    /// Value and expiry time per key
+    keys: HashMap<String, (String, u64)>,
+    loads: usize,
+}
+
+impl FakeRedis {
+    fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                now: 1_700_000_000_000_000,
+                scripts: HashMap::new(),
+                keys: HashMap::new(),
+                loads: 0,
+            })),
+        }
+    }
+
+    fn advance(&self, by: Duration) {
+        self.state.lock().unwrap().now += by.as_micros() as u64;
+    }
+
+    fn ttl(&self, key: &str) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        state.expire();
+        let now = state.now;
+        state
+            .keys
+            .get(key)
+            .map(|(_, expires_at)| Duration::from_micros(expires_at - now))
+    }
+}
+
+impl State {
+    fn expire(&mut self) {
+        let now = self.now;
+        self.keys.retain(|_, (_, expires_at)| *expires_at > now);
+    }
+
+    fn execute(&mut self, args: Vec<String>) -> RedisResult<Value> {
+        match args[0].to_uppercase().as_str() {
+            "SCRIPT" if args[1].eq_ignore_ascii_case("LOAD") => {
+                let sha = sha1_smol::Sha1::from(&args[2]).digest().to_string();
+                self.scripts.insert(sha.clone(), args[2].clone());
+                self.loads += 1;
+                Ok(Value::Data(sha.into_bytes()))
+            }
+            "EVALSHA" => {
+                let code = self.scripts.get(&args[1]).cloned().ok_or_else(|| {
+                    RedisError::from((ErrorKind::NoScriptError, "No matching script"))
+                })?;
+                let numkeys: usize = args[2].parse().unwrap();
+                let keys = args[3..3 + numkeys].to_vec();
+                let argv = args[3 + numkeys..].to_vec();
+                self.eval(&code, keys, argv)
+            }
+            other => Err((
+                ErrorKind::ResponseError,
+                "unknown command",
+                other.to_string(),
+            )
+                .into()),
+        }
+    }
+
+    fn eval(&mut self, code: &str, keys: Vec<String>, argv: Vec<String>) -> RedisResult<Value> {
+        let lua = Lua::new();
+        let result = lua.scope(|scope| {
+            let redis = lua.create_table()?;
+            redis.set(
+                "call",
+                scope.create_function_mut(|lua, args: Variadic<mlua::Value>| {
+                    let args: Vec<String> = args.iter().map(lua_arg).collect();
+                    self.call(lua, args)
+                })?,
+            )?;
+            lua.globals().set("redis", redis)?;
+            lua.globals().set("KEYS", keys)?;
+            lua.globals().set("ARGV", argv)?;
+            let value: mlua::Value = lua.load(code).eval()?;
+            Ok(to_redis(&value))
+        });
+        result.map_err(|e| (ErrorKind::ResponseError, "script error", e.to_string()).into())
+    }
+
+    fn call<'lua>(&mut self, lua: &'lua Lua, args: Vec<String>) -> mlua::Result<mlua::Value<'lua>> {
+        self.expire();
+        match args[0].to_uppercase().as_str() {
+            "TIME" => {
+                let time = lua.create_sequence_from([
+                    (self.now / 1_000_000).to_string(),
+                    (self.now % 1_000_000).to_string(),
+                ])?;
+                Ok(mlua::Value::Table(time))
+            }
+            "GET" => match self.keys.get(&args[1]) {
+                Some((value, _)) => Ok(mlua::Value::String(lua.create_string(value)?)),
+                None => Ok(mlua::Value::Boolean(false)),
+            },
+            "SET" => {
+                assert!(args[3].eq_ignore_ascii_case("PX"), "SET without expiry");
+                let ms: u64 = args[4].parse().unwrap();
+                let expires_at = self.now + ms * 1000;
+                self.keys
+                    .insert(args[1].clone(), (args[2].clone(), expires_at));
+                let ok = lua.create_table()?;
+                ok.set("ok", "OK")?;
+                Ok(mlua::Value::Table(ok))
+            }
+            other => Err(mlua::Error::RuntimeError(format!(
+                "unsupported command {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Converts an argument to `redis.call` the way Redis does, which formats
+/// numbers with `%.17g`
+fn lua_arg(value: &mlua::Value) -> String {
+    match value {
+        mlua::Value::String(s) => s.to_str().unwrap().to_string(),
+        mlua::Value::Integer(i) => i.to_string(),
+        mlua::Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e17 => (*n as i64).to_string(),
+        mlua::Value::Number(n) => n.to_string(),
+        other => panic!("unsupported argument {:?}", other),
+    }
+}
+
+/// Converts a script result the way Redis does: numbers are truncated to
+/// integers and tables become arrays
+fn to_redis(value: &mlua::Value) -> Value {
+    match value {
+        mlua::Value::Integer(i) => Value::Int(*i),
+        mlua::Value::Number(n) => Value::Int(*n as i64),
+        mlua::Value::String(s) => Value::Data(s.as_bytes().to_vec()),
+        mlua::Value::Boolean(true) => Value::Int(1),
+        mlua::Value::Table(table) => Value::Bulk(
+            table
+                .clone()
+                .sequence_values::<mlua::Value>()
+                .map(|value| to_redis(&value.unwrap()))
+                .collect(),
+        ),
+        _ => Value::Nil,
+    }
+}
+
+impl ConnectionLike for FakeRedis {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let args = cmd
+            .args_iter()
+            .map(|arg| match arg {
+                Arg::Simple(data) => String::from_utf8_lossy(data).into_owned(),
+                Arg::Cursor => "0".to_string(),
+            })
+            .collect();
+        let result = self.state.lock().unwrap().execute(args);
+        Box::pin(async move { result })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        _cmd: &'a Pipeline,
+        _offset: usize,
+        _count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async { Err((ErrorKind::ClientError, "pipelines are not supported").into()) })
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+#[tokio::test]
+async fn test_script_enforces_burst_and_sustained_rate() {
+    let redis = FakeRedis::new();
+    let store = RedisStore::new(redis.clone());
+    let limit = RateLimit::per_second(2).with_burst(3);
+
+    for remaining in [2, 1, 0] {
+        let decision = store.check("api:203.0.113.7", &limit, 1).await.unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, remaining);
+        assert_eq!(decision.limit, 3);
+    }
+
+    let denied = store.check("api:203.0.113.7", &limit, 1).await.unwrap();
+    assert!(!denied.allowed);
+    assert_eq!(denied.retry_after, Duration::from_millis(500));
+    assert_eq!(denied.reset_after, Duration::from_millis(1500));
+
+    // Other clients have their own budget
+    assert!(
+        store
+            .check("api:198.51.100.1", &limit, 1)
+            .await
+            .unwrap()
+            .allowed
+    );
+
+    redis.advance(Duration::from_millis(500));
+    let decision = store.check("api:203.0.113.7", &limit, 1).await.unwrap();
+    assert!(decision.allowed);
+    assert_eq!(decision.remaining, 0);
+
+    // Expensive requests take several at once
+    let decision = store.check("uploads:client", &limit, 2).await.unwrap();
+    assert!(decision.allowed);
+    assert_eq!(decision.remaining, 1);
+    let denied = store.check("uploads:client", &limit, 2).await.unwrap();
+    assert!(!denied.allowed);
+    assert_eq!(denied.retry_after, Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn test_replicas_share_one_limit() {
+    let redis = FakeRedis::new();
+    let replicas = [
+        RedisStore::new(redis.clone()),
+        RedisStore::new(redis.clone()),
+    ];
+    let limit = RateLimit::per_minute(4);
+
+    let mut allowed = 0;
+    for i in 0..8 {
+        if replicas[i % 2]
+            .check("auth:client", &limit, 1)
+            .await
+            .unwrap()
+            .allowed
+        {
+            allowed += 1;
+        }
+    }
+    assert_eq!(allowed, 4);
+}
+
+#[tokio::test]
+async fn test_script_is_loaded_once_and_keys_expire() {
+    let redis = FakeRedis::new();
+    let store = RedisStore::new(redis.clone());
+    let limit = RateLimit::per_second(10);
+
+    store.check("api:client", &limit, 1).await.unwrap();
+    store.check("api:client", &limit, 1).await.unwrap();
+    assert_eq!(redis.state.lock().unwrap().loads, 1);
+
+    // The key lives exactly as long as it takes to refill the bucket
+    assert_eq!(
+        redis.ttl("rate_limit:api:client"),
+        Some(Duration::from_millis(200))
+    );
+    redis.advance(Duration::from_millis(200));
+    assert_eq!(redis.ttl("rate_limit:api:client"), None);
+    assert_eq!(
+        store
+            .check("api:client", &limit, 1)
+            .await
+            .unwrap()
+            .remaining,
+        9
+    );
+}
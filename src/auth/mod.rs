@@ -1,10 +1,12 @@
 use crate::error::AppError;
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use lazy_static::lazy_static;
 use log::error;
+use lotabots_password::PasswordHasher;
 use serde::{Deserialize, Serialize};
 use std::env;
+use uuid::Uuid;
 
 pub type AppResult<T> = Result<T, AppError>;
 
@@ -13,6 +15,15 @@ pub struct Claims {
     pub sub: String,
     pub exp: i64,
     pub role: String,
+    /// Session the token was issued for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<Uuid>,
+    /// Identifies a refresh token so a replayed one can be detected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<Uuid>,
+    /// Tenant the user belongs to; the token is only valid for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<Uuid>,
 }
 
 impl Claims {
@@ -21,8 +32,21 @@ impl Claims {
             sub,
             exp: (Utc::now() + Duration::hours(1)).timestamp(),
             role,
+            sid: None,
+            jti: None,
+            tenant_id: None,
         }
     }
+
+    pub fn with_session(mut self, sid: Uuid) -> Self {
+        self.sid = Some(sid);
+        self
+    }
+
+    pub fn with_tenant(mut self, tenant_id: Uuid) -> Self {
+        self.tenant_id = Some(tenant_id);
+        self
+    }
 }
 
 lazy_static! {
@@ -37,6 +61,13 @@ lazy_static! {
         .unwrap_or_else(|_| "2592000".to_string())
         .parse()
         .unwrap_or(2592000);
+    static ref PASSWORD_HASHER: PasswordHasher =
+        PasswordHasher::from_env().expect("Invalid password hashing configuration");
+}
+
+/// Password hasher configured from `PASSWORD_HASH_ALGORITHM` and friends
+pub fn password_hasher() -> &'static PasswordHasher {
+    &PASSWORD_HASHER
 }
 
 pub fn create_access_token(claims: Claims) -> AppResult<String> {
@@ -53,14 +84,21 @@ pub fn create_access_token(claims: Claims) -> AppResult<String> {
     .map_err(|e| AppError::Internal(format!("Failed to create access token: {}", e)))
 }
 
-pub fn create_refresh_token(claims: Claims) -> AppResult<String> {
+/// When a refresh token issued now, and the session it belongs to, expires
+pub fn refresh_token_expires_at() -> DateTime<Utc> {
+    Utc::now() + Duration::days(7)
+}
+
+/// Creates a refresh token identified by `token_id`
+pub fn create_refresh_token(claims: Claims, token_id: Uuid) -> AppResult<String> {
     let jwt_secret = env::var("JWT_SECRET").map_err(|_| {
         error!("JWT_SECRET not set");
         AppError::Internal("JWT configuration error".to_string())
     })?;
 
     let mut refresh_claims = claims;
-    refresh_claims.exp = (Utc::now() + Duration::days(7)).timestamp();
+    refresh_claims.exp = refresh_token_expires_at().timestamp();
+    refresh_claims.jti = Some(token_id);
 
     encode(
         &Header::default(),
@@ -86,6 +124,19 @@ pub fn validate_token(token: &str) -> AppResult<Claims> {
     Ok(token_data.claims)
 }
 
+/// Validates a token presented as a bearer credential. Refresh tokens are
+/// signed with the same key but carry a `jti`, and are only good for
+/// `/auth/refresh`.
+pub fn validate_access_token(token: &str) -> AppResult<Claims> {
+    let claims = validate_token(token)?;
+    if claims.jti.is_some() {
+        return Err(AppError::Authentication(
+            "Refresh tokens cannot be used as access tokens".to_string(),
+        ));
+    }
+    Ok(claims)
+}
+
 pub fn get_user_id_from_token(token: &str) -> AppResult<String> {
     let mut validation = Validation::default();
     validation.validate_exp = true;
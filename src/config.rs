@@ -1,11 +1,32 @@
 use serde::Deserialize;
 use std::env;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct AppConfig {
     pub host: String,
     pub port: u16,
     pub log_level: String,
+    pub vault_config: Option<VaultConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VaultConfig {
+    pub address: String,
+    pub token: Option<String>,
+    pub role_id: Option<String>,
+    pub secret_id: Option<String>,
+}
+
+impl VaultConfig {
+    /// Vault is optional; it is configured when `VAULT_ADDR` is set
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            address: env::var("VAULT_ADDR").ok()?,
+            token: env::var("VAULT_TOKEN").ok(),
+            role_id: env::var("VAULT_ROLE_ID").ok(),
+            secret_id: env::var("VAULT_SECRET_ID").ok(),
+        })
+    }
 }
 
 impl AppConfig {
@@ -17,6 +38,7 @@ impl AppConfig {
                 .parse()
                 .unwrap_or(8080),
             log_level: env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
+            vault_config: VaultConfig::from_env(),
         })
     }
 }
@@ -0,0 +1,159 @@
+//! Encrypted columns.
+//!
+//! Sensitive columns hold values sealed with the envelope cipher from the
+//! shared secrets crate, read and written through `Encrypted<T, C>`, where
+//! `C` names the column the value is bound to. Values are not bound to
+//! their row; [`Column`] explains why.
+//!
+//! The cipher's key-encryption key comes from the secrets backend and is
+//! installed at startup; [`follow_kek`] reloads it when it is rotated and
+//! reseals every encrypted column with [`reseal_all`].
+
+use lotabots_db::TenantContext;
+use lotabots_secrets::{
+    envelope, Column, Encrypted, EnvelopeCipher, SecretManager, WatchOptions,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::PgPool;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use super::DbResult;
+use crate::{error::AppError, models::document::Document, services::user_service::UserService};
+
+/// Secret holding the key-encryption key, unless `FIELD_ENCRYPTION_KEK` names
+/// another one
+pub const DEFAULT_KEK_NAME: &str = "db/field-kek";
+
+/// `users.mfa_secret`
+pub struct MfaSecret;
+
+impl Column for MfaSecret {
+    const TABLE: &'static str = "users";
+    const COLUMN: &'static str = "mfa_secret";
+}
+
+/// `documents.metadata`
+pub struct DocumentMetadata;
+
+impl Column for DocumentMetadata {
+    const TABLE: &'static str = "documents";
+    const COLUMN: &'static str = "metadata";
+}
+
+fn kek_name() -> String {
+    std::env::var("FIELD_ENCRYPTION_KEK").unwrap_or_else(|_| DEFAULT_KEK_NAME.to_string())
+}
+
+/// Loads the key-encryption key, with the earlier versions still needed to
+/// read older rows, and makes it the cipher used by `Encrypted<T, C>`
+pub async fn install_cipher<M>(manager: &M) -> DbResult<()>
+where
+    M: SecretManager + ?Sized,
+{
+    let name = kek_name();
+    let cipher = EnvelopeCipher::load(manager, &name).await?;
+    info!(
+        "Field encryption uses {} at version {}",
+        name,
+        cipher.current_version()
+    );
+    envelope::install(cipher);
+    Ok(())
+}
+
+/// Watches the key-encryption key installed by [`install_cipher`]. When the
+/// secrets backend has a new version, the new cipher is installed and every
+/// encrypted column resealed, so rows no longer need the older version.
+pub fn follow_kek<M>(manager: Arc<M>, pool: PgPool) -> DbResult<JoinHandle<()>>
+where
+    M: SecretManager + ?Sized + 'static,
+{
+    let current = envelope::installed()?.current_version();
+    Ok(envelope::follow(
+        manager,
+        &kek_name(),
+        current,
+        WatchOptions::from_env(),
+        move |cipher| {
+            envelope::install(cipher);
+            let pool = pool.clone();
+            async move {
+                if let Err(e) = reseal_all(&pool).await {
+                    error!("Failed to reseal encrypted columns: {}", e);
+                }
+            }
+        },
+    ))
+}
+
+/// Brings every encrypted column up to date with the installed cipher.
+/// Returns the number of rows updated.
+///
+/// Runs tenant by tenant within each tenant's [`TenantContext`], so pools
+/// with tenant isolation see the rows their row-level security policies
+/// would otherwise hide.
+pub async fn reseal_all(pool: &PgPool) -> DbResult<u64> {
+    // Tenants themselves are not tenant-owned and are always visible
+    let tenants: Vec<Uuid> = sqlx::query_scalar("SELECT id FROM tenants")
+        .fetch_all(pool)
+        .await
+        .map_err(AppError::Database)?;
+
+    let mut updated = 0;
+    for tenant_id in tenants {
+        updated += TenantContext::new(tenant_id)
+            .scope(reseal_tenant(pool, tenant_id))
+            .await?;
+    }
+    Ok(updated)
+}
+
+async fn reseal_tenant(pool: &PgPool, tenant_id: Uuid) -> DbResult<u64> {
+    let secrets = UserService::new(pool.clone())
+        .reencrypt_mfa_secrets()
+        .await?;
+    let metadata = Document::reencrypt_metadata(pool, tenant_id).await?;
+    Ok(secrets + metadata)
+}
+
+/// Encrypts plaintext left in the column `C` of `tenant_id`'s rows, which
+/// holds `Encrypted<T, C>` values, and re-wraps values sealed with an older
+/// key-encryption key. Returns the number of rows updated.
+///
+/// Rows changed concurrently are left alone and picked up by the next run.
+pub async fn reseal_column<T, C>(pool: &PgPool, tenant_id: Uuid) -> DbResult<u64>
+where
+    T: DeserializeOwned + Serialize,
+    C: Column,
+{
+    // Identifiers cannot be bound; they come from the column's fixed names
+    let (table, column) = (C::TABLE, C::COLUMN);
+    let rows: Vec<(Uuid, String)> = sqlx::query_as(&format!(
+        "SELECT id, {column} FROM {table} WHERE tenant_id = $1 AND {column} IS NOT NULL"
+    ))
+    .bind(tenant_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::Database)?;
+
+    let update = format!("UPDATE {table} SET {column} = $1 WHERE id = $2 AND {column} = $3");
+    let mut updated = 0;
+    for (id, stored) in rows {
+        if let Some(sealed) = Encrypted::<T, C>::reseal(&stored)? {
+            updated += sqlx::query(&update)
+                .bind(sealed)
+                .bind(id)
+                .bind(&stored)
+                .execute(pool)
+                .await
+                .map_err(AppError::Database)?
+                .rows_affected();
+        }
+    }
+
+    info!("Resealed {} values in {}.{}", updated, table, column);
+    Ok(updated)
+}
@@ -3,6 +3,8 @@ use sqlx::PgPool;
 pub type DbResult<T> = Result<T, AppError>;
 
 pub mod documents;
+pub mod encryption;
+pub mod sessions;
 pub mod users;
 pub mod workflows;
 
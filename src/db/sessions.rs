@@ -0,0 +1,170 @@
+use crate::{AppError, AppResult};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// A logged-in device. Each session owns one refresh token at a time;
+/// `refresh_token_id` is rotated on every refresh.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    #[serde(skip_serializing)]
+    pub refresh_token_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+pub async fn create_session(
+    pool: &PgPool,
+    tenant_id: Uuid,
+    user_id: Uuid,
+    user_agent: Option<&str>,
+    ip_address: Option<&str>,
+    refresh_token_id: Uuid,
+    expires_at: DateTime<Utc>,
+) -> AppResult<Session> {
+    sqlx::query_as!(
+        Session,
+        r#"
+        INSERT INTO user_sessions
+            (tenant_id, user_id, user_agent, ip_address, refresh_token_id, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, user_id, user_agent, ip_address, refresh_token_id,
+                  created_at, last_seen_at, expires_at, revoked_at
+        "#,
+        tenant_id,
+        user_id,
+        user_agent,
+        ip_address,
+        refresh_token_id,
+        expires_at
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(AppError::Database)
+}
+
+/// Fetches a session that is neither revoked nor expired
+pub async fn get_active_session(pool: &PgPool, id: Uuid) -> AppResult<Option<Session>> {
+    sqlx::query_as!(
+        Session,
+        r#"
+        SELECT id, user_id, user_agent, ip_address, refresh_token_id,
+               created_at, last_seen_at, expires_at, revoked_at
+        FROM user_sessions
+        WHERE id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(AppError::Database)
+}
+
+/// Swaps the session's refresh token and records the activity. Returns
+/// `false` if `current_token_id` is no longer the session's token, which
+/// means a refresh token was replayed.
+pub async fn rotate_session(
+    pool: &PgPool,
+    id: Uuid,
+    current_token_id: Uuid,
+    new_token_id: Uuid,
+    ip_address: Option<&str>,
+    expires_at: DateTime<Utc>,
+) -> AppResult<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE user_sessions
+        SET refresh_token_id = $3,
+            ip_address = COALESCE($4, ip_address),
+            last_seen_at = NOW(),
+            expires_at = $5
+        WHERE id = $1 AND refresh_token_id = $2 AND revoked_at IS NULL
+        "#,
+        id,
+        current_token_id,
+        new_token_id,
+        ip_address,
+        expires_at
+    )
+    .execute(pool)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn list_active_sessions(pool: &PgPool, user_id: Uuid) -> AppResult<Vec<Session>> {
+    sqlx::query_as!(
+        Session,
+        r#"
+        SELECT id, user_id, user_agent, ip_address, refresh_token_id,
+               created_at, last_seen_at, expires_at, revoked_at
+        FROM user_sessions
+        WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+        ORDER BY last_seen_at DESC
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::Database)
+}
+
+pub async fn count_active_sessions(pool: &PgPool, user_id: Uuid) -> AppResult<i64> {
+    let count = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) AS "count!"
+        FROM user_sessions
+        WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+        "#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(count)
+}
+
+/// Revokes one of the user's sessions. Returns `false` if there is no such
+/// active session.
+pub async fn revoke_session(pool: &PgPool, user_id: Uuid, id: Uuid) -> AppResult<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE user_sessions
+        SET revoked_at = NOW()
+        WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+        "#,
+        id,
+        user_id
+    )
+    .execute(pool)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revokes every session of the user and returns how many were active
+pub async fn revoke_all_sessions(pool: &PgPool, user_id: Uuid) -> AppResult<u64> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE user_sessions
+        SET revoked_at = NOW()
+        WHERE user_id = $1 AND revoked_at IS NULL
+        "#,
+        user_id
+    )
+    .execute(pool)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(result.rows_affected())
+}
@@ -2,15 +2,20 @@ use crate::{AppError, AppResult};
 use sqlx::PgPool;
 use uuid::Uuid;
 
-pub async fn get_user_by_id(pool: &PgPool, user_id: Uuid) -> AppResult<Option<User>> {
+pub async fn get_user_by_id(
+    pool: &PgPool,
+    tenant_id: Uuid,
+    user_id: Uuid,
+) -> AppResult<Option<User>> {
     let user = sqlx::query_as!(
         User,
         r#"
-        SELECT id, email, password_hash, role, created_at, updated_at
+        SELECT id, tenant_id, email, password_hash, role, created_at, updated_at
         FROM users
-        WHERE id = $1
+        WHERE id = $1 AND tenant_id = $2
         "#,
-        user_id
+        user_id,
+        tenant_id
     )
     .fetch_optional(pool)
     .await
@@ -22,6 +27,7 @@ pub async fn get_user_by_id(pool: &PgPool, user_id: Uuid) -> AppResult<Option<Us
 #[derive(Debug, sqlx::FromRow)]
 pub struct User {
     pub id: Uuid,
+    pub tenant_id: Uuid,
     pub email: String,
     pub password_hash: String,
     pub role: String,
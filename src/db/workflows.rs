@@ -2,15 +2,20 @@ use crate::{AppError, AppResult};
 use sqlx::PgPool;
 use uuid::Uuid;
 
-pub async fn get_workflow_by_id(pool: &PgPool, workflow_id: Uuid) -> AppResult<Option<Workflow>> {
+pub async fn get_workflow_by_id(
+    pool: &PgPool,
+    tenant_id: Uuid,
+    workflow_id: Uuid,
+) -> AppResult<Option<Workflow>> {
     let workflow = sqlx::query_as!(
         Workflow,
         r#"
-        SELECT id, name, description, status, created_at, updated_at
+        SELECT id, tenant_id, name, description, status, created_at, updated_at
         FROM workflows
-        WHERE id = $1
+        WHERE id = $1 AND tenant_id = $2
         "#,
-        workflow_id
+        workflow_id,
+        tenant_id
     )
     .fetch_optional(pool)
     .await
@@ -22,6 +27,7 @@ pub async fn get_workflow_by_id(pool: &PgPool, workflow_id: Uuid) -> AppResult<O
 #[derive(Debug, sqlx::FromRow)]
 pub struct Workflow {
     pub id: Uuid,
+    pub tenant_id: Uuid,
     pub name: String,
     pub description: Option<String>,
     pub status: String,
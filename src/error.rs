@@ -49,3 +49,15 @@ impl ResponseError for AppError {
         }
     }
 }
+
+impl From<lotabots_secrets::SecretError> for AppError {
+    fn from(err: lotabots_secrets::SecretError) -> Self {
+        AppError::Internal(err.to_string())
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        AppError::Database(err)
+    }
+}
@@ -1,11 +1,24 @@
 use crate::{
-    auth::{create_access_token, create_refresh_token, validate_token, Claims},
+    auth::{
+        create_access_token, create_refresh_token, refresh_token_expires_at, validate_token, Claims,
+    },
+    db::sessions,
     error::AppError,
-    models::user::{CreateUserRequest, User},
+    middleware::TenantMiddleware,
+    models::{
+        tenant::Tenant,
+        user::{CreateUserRequest, User},
+    },
 };
-use actix_web::{post, web, HttpResponse};
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use lotabots_db::TenantContext;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Longest user agent kept for a session
+const MAX_USER_AGENT_LEN: usize = 512;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginRequest {
@@ -18,16 +31,60 @@ pub struct RefreshTokenRequest {
     pub refresh_token: String,
 }
 
+/// User agent and client address of the request, as recorded on sessions
+fn client_info(req: &HttpRequest) -> (Option<String>, Option<String>) {
+    let user_agent = req
+        .headers()
+        .get("User-Agent")
+        .and_then(|h| h.to_str().ok())
+        .map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect());
+    let ip_address = req
+        .connection_info()
+        .realip_remote_addr()
+        .map(|addr| addr.to_string());
+
+    (user_agent, ip_address)
+}
+
+/// Opens a session for the user on the requesting device and issues its
+/// tokens
+async fn start_session(
+    pool: &PgPool,
+    http_req: &HttpRequest,
+    user: &mut User,
+) -> Result<(String, String), AppError> {
+    let (user_agent, ip_address) = client_info(http_req);
+    let refresh_token_id = Uuid::new_v4();
+    let session = sessions::create_session(
+        pool,
+        user.tenant_id,
+        user.id,
+        user_agent.as_deref(),
+        ip_address.as_deref(),
+        refresh_token_id,
+        refresh_token_expires_at(),
+    )
+    .await?;
+    user.load_session_count(pool).await?;
+
+    let claims = Claims::new(user.id.to_string(), user.role.clone())
+        .with_session(session.id)
+        .with_tenant(user.tenant_id);
+    let access_token = create_access_token(claims.clone())?;
+    let refresh_jwt = create_refresh_token(claims, refresh_token_id)?;
+
+    Ok((access_token, refresh_jwt))
+}
+
 #[post("/register")]
 pub async fn register(
-    pool: web::Data<sqlx::PgPool>,
+    pool: web::Data<PgPool>,
+    tenant: web::ReqData<Tenant>,
+    http_req: HttpRequest,
     req: web::Json<CreateUserRequest>,
 ) -> Result<HttpResponse, AppError> {
-    let user = User::create(&pool, req.0).await?;
-    let claims = Claims::new(user.id.to_string(), user.role.clone());
-
-    let access_token = create_access_token(claims.clone())?;
-    let refresh_jwt = create_refresh_token(claims)?;
+    let mut user = User::create(&pool, tenant.id, req.0).await?;
+    let (access_token, refresh_jwt) = start_session(&pool, &http_req, &mut user).await?;
 
     Ok(HttpResponse::Created().json(json!({
         "user": user,
@@ -38,14 +95,13 @@ pub async fn register(
 
 #[post("/login")]
 pub async fn login(
-    pool: web::Data<sqlx::PgPool>,
+    pool: web::Data<PgPool>,
+    tenant: web::ReqData<Tenant>,
+    http_req: HttpRequest,
     req: web::Json<LoginRequest>,
 ) -> Result<HttpResponse, AppError> {
-    let user = User::authenticate(&pool, &req.email, &req.password).await?;
-    let claims = Claims::new(user.id.to_string(), user.role.clone());
-
-    let access_token = create_access_token(claims.clone())?;
-    let refresh_jwt = create_refresh_token(claims)?;
+    let mut user = User::authenticate(&pool, tenant.id, &req.email, &req.password).await?;
+    let (access_token, refresh_jwt) = start_session(&pool, &http_req, &mut user).await?;
 
     Ok(HttpResponse::Ok().json(json!({
         "user": user,
@@ -55,10 +111,77 @@ pub async fn login(
 }
 
 #[post("/refresh")]
-pub async fn refresh_token(req: web::Json<RefreshTokenRequest>) -> Result<HttpResponse, AppError> {
+pub async fn refresh_token(
+    pool: web::Data<PgPool>,
+    http_req: HttpRequest,
+    req: web::Json<RefreshTokenRequest>,
+) -> Result<HttpResponse, AppError> {
     let claims = validate_token(&req.refresh_token)?;
+    let (Some(session_id), Some(token_id), Some(tenant_id)) =
+        (claims.sid, claims.jti, claims.tenant_id)
+    else {
+        return Err(AppError::Authentication(
+            "Invalid refresh token".to_string(),
+        ));
+    };
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::Authentication("Invalid refresh token".to_string()))?;
+
+    // Refresh tokens carry their tenant instead of going through
+    // TenantMiddleware, so the same checks are made here
+    let tenant = Tenant::get(&pool, tenant_id).await?;
+    if !tenant.is_active() {
+        return Err(AppError::Authorization(format!(
+            "Tenant {} is not active",
+            tenant.id
+        )));
+    }
+
+    TenantContext::new(tenant.id)
+        .with_user(user_id)
+        .scope(rotate_tokens(&pool, &http_req, claims, session_id, token_id))
+        .await
+}
+
+/// Swaps the refresh token of the session for a new one and issues a new
+/// access token
+async fn rotate_tokens(
+    pool: &PgPool,
+    http_req: &HttpRequest,
+    claims: Claims,
+    session_id: Uuid,
+    token_id: Uuid,
+) -> Result<HttpResponse, AppError> {
+    let session = sessions::get_active_session(pool, session_id)
+        .await?
+        .ok_or_else(|| AppError::Authentication("Session has ended".to_string()))?;
+
+    let (_, ip_address) = client_info(http_req);
+    let new_token_id = Uuid::new_v4();
+    let rotated = sessions::rotate_session(
+        pool,
+        session.id,
+        token_id,
+        new_token_id,
+        ip_address.as_deref(),
+        refresh_token_expires_at(),
+    )
+    .await?;
+
+    if !rotated {
+        // An already used refresh token was presented, so it may have been
+        // stolen. End the session rather than guess which holder is genuine.
+        sessions::revoke_session(pool, session.user_id, session.id).await?;
+        return Err(AppError::Authentication(
+            "Refresh token has already been used".to_string(),
+        ));
+    }
+
+    let tenant_id = claims.tenant_id;
+    let mut claims = Claims::new(claims.sub, claims.role).with_session(session.id);
+    claims.tenant_id = tenant_id;
     let new_access_token = create_access_token(claims.clone())?;
-    let new_refresh_jwt = create_refresh_token(claims)?;
+    let new_refresh_jwt = create_refresh_token(claims, new_token_id)?;
 
     Ok(HttpResponse::Ok().json(json!({
         "access_token": new_access_token,
@@ -67,10 +190,15 @@ pub async fn refresh_token(req: web::Json<RefreshTokenRequest>) -> Result<HttpRe
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
+    // Users register and log in to the tenant named by the header or the
+    // subdomain; refresh tokens carry their tenant, which `refresh_token`
+    // resolves itself
     cfg.service(
-        web::scope("/auth")
-            .service(register)
-            .service(login)
-            .service(refresh_token),
+        web::scope("/auth").service(refresh_token).service(
+            web::scope("")
+                .wrap(TenantMiddleware::from_env())
+                .service(register)
+                .service(login),
+        ),
     );
 }
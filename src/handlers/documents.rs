@@ -1,6 +1,8 @@
 use crate::auth::Claims;
 use crate::error::AppError;
+use crate::middleware::{jwt::JwtAuth, TenantMiddleware};
 use crate::models::document::{CreateDocumentRequest, Document, UpdateDocumentRequest};
+use crate::models::tenant::Tenant;
 use actix_web::{get, post, put, web, HttpResponse};
 use serde::Deserialize;
 use sqlx::PgPool;
@@ -16,11 +18,12 @@ pub struct ListDocumentsQuery {
 pub async fn create_document(
     db: web::Data<PgPool>,
     claims: web::ReqData<Claims>,
+    tenant: web::ReqData<Tenant>,
     req: web::Json<CreateDocumentRequest>,
 ) -> Result<HttpResponse, AppError> {
     let user_id = Uuid::parse_str(&claims.sub)
         .map_err(|_| AppError::BadRequest("Invalid user ID".to_string()))?;
-    let document = Document::create(db.get_ref(), user_id, req.into_inner()).await?;
+    let document = Document::create(db.get_ref(), tenant.id, user_id, req.into_inner()).await?;
     Ok(HttpResponse::Created().json(document))
 }
 
@@ -28,11 +31,12 @@ pub async fn create_document(
 pub async fn get_document(
     db: web::Data<PgPool>,
     claims: web::ReqData<Claims>,
+    tenant: web::ReqData<Tenant>,
     id: web::Path<Uuid>,
 ) -> Result<HttpResponse, AppError> {
     let user_id = Uuid::parse_str(&claims.sub)
         .map_err(|_| AppError::BadRequest("Invalid user ID".to_string()))?;
-    let document = Document::get(db.get_ref(), user_id, *id).await?;
+    let document = Document::get(db.get_ref(), tenant.id, user_id, *id).await?;
     Ok(HttpResponse::Ok().json(document))
 }
 
@@ -40,13 +44,14 @@ pub async fn get_document(
 pub async fn list_documents(
     db: web::Data<PgPool>,
     claims: web::ReqData<Claims>,
+    tenant: web::ReqData<Tenant>,
     query: web::Query<ListDocumentsQuery>,
 ) -> Result<HttpResponse, AppError> {
     let user_id = Uuid::parse_str(&claims.sub)
         .map_err(|_| AppError::BadRequest("Invalid user ID".to_string()))?;
     let page = query.page.unwrap_or(1);
     let per_page = query.per_page.unwrap_or(10);
-    let documents = Document::list(db.get_ref(), user_id, page, per_page).await?;
+    let documents = Document::list(db.get_ref(), tenant.id, user_id, page, per_page).await?;
     Ok(HttpResponse::Ok().json(documents))
 }
 
@@ -54,18 +59,23 @@ pub async fn list_documents(
 pub async fn update_document(
     db: web::Data<PgPool>,
     claims: web::ReqData<Claims>,
+    tenant: web::ReqData<Tenant>,
     id: web::Path<Uuid>,
     req: web::Json<UpdateDocumentRequest>,
 ) -> Result<HttpResponse, AppError> {
     let user_id = Uuid::parse_str(&claims.sub)
         .map_err(|_| AppError::BadRequest("Invalid user ID".to_string()))?;
-    let document = Document::update(db.get_ref(), user_id, *id, req.into_inner()).await?;
+    let document =
+        Document::update(db.get_ref(), tenant.id, user_id, *id, req.into_inner()).await?;
     Ok(HttpResponse::Ok().json(document))
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/documents")
+            // Authentication runs first, then the tenant is resolved
+            .wrap(TenantMiddleware::from_env())
+            .wrap(JwtAuth)
             .service(create_document)
             .service(get_document)
             .service(list_documents)
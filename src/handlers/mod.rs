@@ -3,9 +3,11 @@ use actix_web::web;
 pub mod auth;
 pub mod documents;
 pub mod health;
+pub mod sessions;
 
 pub fn configure_routes(cfg: &mut web::ServiceConfig) {
     cfg.configure(auth::config)
         .configure(documents::config)
-        .configure(health::config);
+        .configure(health::config)
+        .configure(sessions::config);
 }
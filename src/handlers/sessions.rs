@@ -0,0 +1,76 @@
+use crate::auth::Claims;
+use crate::db::sessions::{self, Session};
+use crate::error::AppError;
+use crate::middleware::{jwt::JwtAuth, TenantMiddleware};
+use actix_web::{delete, get, web, HttpResponse};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize)]
+pub struct SessionView {
+    #[serde(flatten)]
+    pub session: Session,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
+
+fn user_id(claims: &Claims) -> Result<Uuid, AppError> {
+    Uuid::parse_str(&claims.sub).map_err(|_| AppError::BadRequest("Invalid user ID".to_string()))
+}
+
+#[get("")]
+pub async fn list_sessions(
+    db: web::Data<PgPool>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user_id(&claims)?;
+    let sessions = sessions::list_active_sessions(db.get_ref(), user_id)
+        .await?
+        .into_iter()
+        .map(|session| SessionView {
+            current: claims.sid == Some(session.id),
+            session,
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+/// Logs a single device out. Its access and refresh tokens stop working
+/// immediately.
+#[delete("/{id}")]
+pub async fn revoke_session(
+    db: web::Data<PgPool>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user_id(&claims)?;
+    if !sessions::revoke_session(db.get_ref(), user_id, *id).await? {
+        return Err(AppError::NotFound(format!("Session {} not found", id)));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Logs out everywhere, including the current device
+#[delete("")]
+pub async fn revoke_all_sessions(
+    db: web::Data<PgPool>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user_id(&claims)?;
+    let revoked = sessions::revoke_all_sessions(db.get_ref(), user_id).await?;
+    Ok(HttpResponse::Ok().json(json!({ "revoked": revoked })))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/sessions")
+            // Authentication runs first, then the tenant is resolved
+            .wrap(TenantMiddleware::from_env())
+            .wrap(JwtAuth)
+            .service(list_sessions)
+            .service(revoke_session)
+            .service(revoke_all_sessions),
+    );
+}
@@ -12,12 +12,21 @@
 //!
 //! ## Example
 //!
-//! ```rust
-//! use lotabots_api_gateway::App;
+//! ```rust,no_run
+//! use actix_web::{web, App, HttpServer};
+//! use document_automation::handlers;
 //!
 //! #[actix_web::main]
-//! async fn main() {
-//!     App::new().run().await.unwrap();
+//! async fn main() -> std::io::Result<()> {
+//!     let pool = sqlx::PgPool::connect_lazy("postgres://localhost/lotabots").unwrap();
+//!     HttpServer::new(move || {
+//!         App::new()
+//!             .app_data(web::Data::new(pool.clone()))
+//!             .configure(handlers::configure_routes)
+//!     })
+//!     .bind("127.0.0.1:8080")?
+//!     .run()
+//!     .await
 //! }
 //! ```
 
@@ -28,6 +37,7 @@ pub mod error;
 pub mod handlers;
 pub mod middleware;
 pub mod models;
+pub mod services;
 
 pub use error::AppError;
 pub type AppResult<T> = Result<T, AppError>;
@@ -1,12 +1,13 @@
 use actix_web::{middleware, web, App, HttpServer};
 use document_automation::{
     config::AppConfig,
+    db::encryption,
     handlers,
     middleware::{RequestId, SecurityHeaders},
 };
 use dotenv::dotenv;
 use sqlx::postgres::PgPool;
-use std::env;
+use std::{env, sync::Arc};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -14,9 +15,33 @@ async fn main() -> std::io::Result<()> {
     env_logger::init();
 
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPool::connect(&database_url)
+    // With isolation on, row-level security limits every query to the
+    // tenant of the request; the role must not be a superuser
+    let pool = if env::var("DB_TENANT_ISOLATION").is_ok_and(|v| v == "true") {
+        lotabots_db::create_pool(&lotabots_db::DatabaseConfig {
+            url: database_url,
+            max_connections: 10,
+            min_connections: 0,
+            max_lifetime_secs: 30 * 60,
+            idle_timeout_secs: 10 * 60,
+            tenant_isolation: true,
+        })
         .await
-        .expect("Failed to create pool");
+        .expect("Failed to create pool")
+    } else {
+        PgPool::connect(&database_url)
+            .await
+            .expect("Failed to create pool")
+    };
+
+    let secrets =
+        Arc::new(lotabots_secrets::from_env().expect("Failed to configure secrets backends"));
+    encryption::install_cipher(&secrets)
+        .await
+        .expect("Failed to load the field encryption key");
+    // Reseals the encrypted columns whenever the key is rotated
+    encryption::follow_kek(secrets.clone(), pool.clone())
+        .expect("Failed to watch the field encryption key");
 
     let config = AppConfig::from_env().expect("Failed to load configuration");
     let bind_addr = format!("{}:{}", config.host, config.port);
@@ -4,7 +4,7 @@ use actix_web::{
 };
 use futures::future::{ready, LocalBoxFuture, Ready};
 
-use crate::{auth::validate_token, AppError};
+use crate::{auth::validate_access_token, AppError};
 
 #[allow(dead_code)]
 pub struct Auth<S> {
@@ -64,7 +64,7 @@ where
         };
 
         // Validate token synchronously
-        let claims = match validate_token(token) {
+        let claims = match validate_access_token(token) {
             Ok(c) => c,
             Err(e) => {
                 let err_string = e.to_string();
@@ -1,11 +1,17 @@
 use actix_web::{
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpMessage,
+    web, Error, HttpMessage,
 };
 use futures::future::{ready, LocalBoxFuture, Ready};
+use lotabots_db::TenantContext;
+use sqlx::PgPool;
+use std::rc::Rc;
 
-use crate::{auth::validate_token, AppError};
+use crate::{auth::validate_access_token, db::sessions, AppError};
 
+/// Authenticates requests with a bearer access token. Tokens issued for a
+/// session are refused once the session is revoked or has expired, which
+/// needs a `web::Data<PgPool>` in the app data.
 pub struct JwtAuth;
 
 impl<S, B> Transform<S, ServiceRequest> for JwtAuth
@@ -20,12 +26,14 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(JwtAuthMiddleware { service }))
+        ready(Ok(JwtAuthMiddleware {
+            service: Rc::new(service),
+        }))
     }
 }
 
 pub struct JwtAuthMiddleware<S> {
-    service: S,
+    service: Rc<S>,
 }
 
 impl<S, B> Service<ServiceRequest> for JwtAuthMiddleware<S>
@@ -61,7 +69,7 @@ where
         };
 
         // Validate token synchronously
-        let claims = match validate_token(token) {
+        let claims = match validate_access_token(token) {
             Ok(c) => c,
             Err(e) => {
                 let err_string = e.to_string();
@@ -70,13 +78,32 @@ where
             }
         };
 
-        // Insert claims and call next service
-        let req = req;
-        req.extensions_mut().insert(claims);
-        let fut = self.service.call(req);
+        let pool = req.app_data::<web::Data<PgPool>>().cloned();
+        let service = self.service.clone();
         Box::pin(async move {
-            let res = fut.await?;
-            Ok(res)
+            if let Some(sid) = claims.sid {
+                let pool = pool.ok_or_else(|| {
+                    AppError::Internal("Database pool not configured".to_string())
+                })?;
+                // Runs before TenantMiddleware, so the session is looked up
+                // within the tenant the token was issued for
+                let session = sessions::get_active_session(&pool, sid);
+                let session = match claims.tenant_id {
+                    Some(tenant_id) => TenantContext::new(tenant_id).scope(session).await?,
+                    None => session.await?,
+                };
+                let active =
+                    session.is_some_and(|session| session.user_id.to_string() == claims.sub);
+                if !active {
+                    return Err(Error::from(AppError::Authentication(
+                        "Session has been revoked".to_string(),
+                    )));
+                }
+            }
+
+            // Insert claims and call next service
+            req.extensions_mut().insert(claims);
+            service.call(req).await
         })
     }
 }
@@ -3,9 +3,11 @@ pub mod jwt;
 pub mod rate_limit;
 pub mod request_id;
 pub mod security;
+pub mod tenant;
 
 pub use auth::Auth;
 pub use jwt::JwtAuthMiddleware;
 pub use rate_limit::RateLimiter;
 pub use request_id::RequestId;
 pub use security::SecurityHeaders;
+pub use tenant::TenantMiddleware;
@@ -0,0 +1,217 @@
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error, HttpMessage,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use lotabots_db::TenantContext;
+use sqlx::PgPool;
+use std::{env, rc::Rc};
+use uuid::Uuid;
+
+use crate::{auth::Claims, models::tenant::Tenant, AppError};
+
+/// Header naming the tenant of requests without a tenant-bound token
+pub const TENANT_HEADER: &str = "X-Tenant-ID";
+
+/// How a request names its tenant
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TenantRef {
+    Id(Uuid),
+    Subdomain(String),
+}
+
+/// Resolves the tenant of each request and rejects requests for unknown or
+/// inactive tenants. The tenant is taken from, in order, the token's
+/// `tenant_id`, the `X-Tenant-ID` header and the subdomain of the host. A
+/// token bound to a tenant cannot be used for another one.
+///
+/// Handlers read the tenant with `web::ReqData<Tenant>`, and run within a
+/// [`TenantContext`] so a pool created with `tenant_isolation` only shows
+/// them the tenant's rows. Wrap this middleware inside the authentication
+/// middleware so the claims are known.
+pub struct TenantMiddleware {
+    base_domain: Option<Rc<String>>,
+}
+
+impl TenantMiddleware {
+    /// Resolves tenants from tokens and the header only
+    pub fn new() -> Self {
+        Self { base_domain: None }
+    }
+
+    /// Also resolves `<subdomain>.<base_domain>` hosts
+    pub fn with_base_domain(mut self, base_domain: impl Into<String>) -> Self {
+        self.base_domain = Some(Rc::new(base_domain.into()));
+        self
+    }
+
+    /// Uses `TENANT_BASE_DOMAIN` as the base domain when set
+    pub fn from_env() -> Self {
+        match env::var("TENANT_BASE_DOMAIN") {
+            Ok(base_domain) => Self::new().with_base_domain(base_domain),
+            Err(_) => Self::new(),
+        }
+    }
+}
+
+impl Default for TenantMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for TenantMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TenantMiddlewareService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TenantMiddlewareService {
+            service: Rc::new(service),
+            base_domain: self.base_domain.clone(),
+        }))
+    }
+}
+
+pub struct TenantMiddlewareService<S> {
+    service: Rc<S>,
+    base_domain: Option<Rc<String>>,
+}
+
+impl<S, B> Service<ServiceRequest> for TenantMiddlewareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let (token_tenant, user_id) = req.extensions().get::<Claims>().map_or((None, None), |c| {
+            (c.tenant_id, Uuid::parse_str(&c.sub).ok())
+        });
+        let header = req
+            .headers()
+            .get(TENANT_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string);
+        let host = req.connection_info().host().to_string();
+        let tenant_ref = resolve(
+            token_tenant,
+            header.as_deref(),
+            &host,
+            self.base_domain.as_deref().map(String::as_str),
+        );
+        let pool = req.app_data::<web::Data<PgPool>>().cloned();
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let pool =
+                pool.ok_or_else(|| AppError::Internal("Database pool not configured".to_string()))?;
+            let tenant = match tenant_ref? {
+                TenantRef::Id(id) => Tenant::get(&pool, id).await?,
+                TenantRef::Subdomain(subdomain) => {
+                    Tenant::find_by_subdomain(&pool, &subdomain).await?
+                }
+            };
+            if !tenant.is_active() {
+                return Err(
+                    AppError::Authorization(format!("Tenant {} is not active", tenant.id)).into(),
+                );
+            }
+
+            // Connections acquired by the handler are bound to the tenant
+            // when the pool enforces row-level security
+            let mut context = TenantContext::new(tenant.id);
+            if let Some(user_id) = user_id {
+                context = context.with_user(user_id);
+            }
+            req.extensions_mut().insert(tenant);
+            context.scope(service.call(req)).await
+        })
+    }
+}
+
+fn resolve(
+    token_tenant: Option<Uuid>,
+    header: Option<&str>,
+    host: &str,
+    base_domain: Option<&str>,
+) -> Result<TenantRef, AppError> {
+    let header = header
+        .map(|value| {
+            Uuid::parse_str(value.trim())
+                .map_err(|_| AppError::BadRequest(format!("Invalid {} header", TENANT_HEADER)))
+        })
+        .transpose()?;
+
+    match (token_tenant, header) {
+        (Some(token), Some(header)) if token != header => Err(AppError::Authorization(
+            "Token is not valid for this tenant".to_string(),
+        )),
+        (Some(id), _) | (None, Some(id)) => Ok(TenantRef::Id(id)),
+        (None, None) => base_domain
+            .and_then(|base_domain| subdomain(host, base_domain))
+            .map(TenantRef::Subdomain)
+            .ok_or_else(|| AppError::BadRequest("Tenant could not be determined".to_string())),
+    }
+}
+
+/// `acme` for `acme.example.com[:port]` with base domain `example.com`
+fn subdomain(host: &str, base_domain: &str) -> Option<String> {
+    let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+    let label = host
+        .to_ascii_lowercase()
+        .strip_suffix(&base_domain.to_ascii_lowercase())?
+        .strip_suffix('.')?
+        .to_string();
+
+    (!label.is_empty() && !label.contains('.')).then_some(label)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_resolution_order() {
+        let acme = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let base = Some("example.com");
+
+        assert_eq!(
+            resolve(Some(acme), None, "other.example.com", base).unwrap(),
+            TenantRef::Id(acme)
+        );
+        assert_eq!(
+            resolve(None, Some(&other.to_string()), "acme.example.com", base).unwrap(),
+            TenantRef::Id(other)
+        );
+        assert_eq!(
+            resolve(None, None, "Acme.Example.com:8080", base).unwrap(),
+            TenantRef::Subdomain("acme".to_string())
+        );
+
+        // A token bound to one tenant cannot be pointed at another
+        assert!(matches!(
+            resolve(Some(acme), Some(&other.to_string()), "", base),
+            Err(AppError::Authorization(_))
+        ));
+        assert!(matches!(
+            resolve(None, Some("acme"), "", base),
+            Err(AppError::BadRequest(_))
+        ));
+        assert!(resolve(None, None, "a.b.example.com", base).is_err());
+        assert!(resolve(None, None, "example.com", base).is_err());
+        assert!(resolve(None, None, "acme.example.com", None).is_err());
+    }
+}
@@ -1,4 +1,8 @@
-use crate::error::AppError;
+use crate::{
+    db::encryption::{self, DocumentMetadata},
+    error::AppError,
+};
+use lotabots_secrets::Encrypted;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use uuid::Uuid;
@@ -6,12 +10,14 @@ use uuid::Uuid;
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Document {
     pub id: Uuid,
+    pub tenant_id: Uuid,
     pub title: String,
     pub content: Option<String>,
     pub file_path: String,
     pub content_type: String,
     pub size: i64,
-    pub metadata: Option<serde_json::Value>,
+    /// May contain PII, so it is stored encrypted
+    pub metadata: Option<Encrypted<serde_json::Value, DocumentMetadata>>,
     pub user_id: Uuid,
     pub document_type: String,
     pub status: String,
@@ -45,18 +51,23 @@ pub struct UpdateDocumentRequest {
 impl Document {
     pub async fn create(
         pool: &PgPool,
+        tenant_id: Uuid,
         user_id: Uuid,
         req: CreateDocumentRequest,
     ) -> Result<Self, AppError> {
+        let metadata = req
+            .metadata
+            .map(Encrypted::<_, DocumentMetadata>::new)
+            .transpose()?;
         sqlx::query_as::<_, Document>(
             r#"
             INSERT INTO documents (
                 title, content, file_path, content_type, size,
-                metadata, user_id, document_type, status
+                metadata, user_id, tenant_id, document_type, status
             )
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, 'pending')
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, 'pending')
             RETURNING id, title, content, file_path, content_type, size,
-                      metadata, user_id, document_type, status, created_at, updated_at
+                      metadata, user_id, tenant_id, document_type, status, created_at, updated_at
             "#,
         )
         .bind(&req.title)
@@ -64,25 +75,32 @@ impl Document {
         .bind(&req.file_path)
         .bind(&req.content_type)
         .bind(req.size)
-        .bind(&req.metadata)
+        .bind(&metadata)
         .bind(user_id)
+        .bind(tenant_id)
         .bind(&req.document_type)
         .fetch_one(pool)
         .await
         .map_err(AppError::Database)
     }
 
-    pub async fn get(pool: &PgPool, user_id: Uuid, id: Uuid) -> Result<Self, AppError> {
+    pub async fn get(
+        pool: &PgPool,
+        tenant_id: Uuid,
+        user_id: Uuid,
+        id: Uuid,
+    ) -> Result<Self, AppError> {
         sqlx::query_as::<_, Document>(
             r#"
             SELECT id, title, content, file_path, content_type, size,
-                   metadata, user_id, document_type, status, created_at, updated_at
+                   metadata, user_id, tenant_id, document_type, status, created_at, updated_at
             FROM documents
-            WHERE id = $1 AND user_id = $2
+            WHERE id = $1 AND user_id = $2 AND tenant_id = $3
             "#,
         )
         .bind(id)
         .bind(user_id)
+        .bind(tenant_id)
         .fetch_one(pool)
         .await
         .map_err(|_| AppError::NotFound(format!("Document {} not found", id)))
@@ -90,6 +108,7 @@ impl Document {
 
     pub async fn list(
         pool: &PgPool,
+        tenant_id: Uuid,
         user_id: Uuid,
         page: i64,
         per_page: i64,
@@ -99,9 +118,9 @@ impl Document {
         sqlx::query_as::<_, Document>(
             r#"
             SELECT id, title, content, file_path, content_type, size,
-                   metadata, user_id, document_type, status, created_at, updated_at
+                   metadata, user_id, tenant_id, document_type, status, created_at, updated_at
             FROM documents
-            WHERE user_id = $1
+            WHERE user_id = $1 AND tenant_id = $4
             ORDER BY created_at DESC
             LIMIT $2 OFFSET $3
             "#,
@@ -109,6 +128,7 @@ impl Document {
         .bind(user_id)
         .bind(per_page)
         .bind(offset)
+        .bind(tenant_id)
         .fetch_all(pool)
         .await
         .map_err(AppError::Database)
@@ -116,10 +136,15 @@ impl Document {
 
     pub async fn update(
         pool: &PgPool,
+        tenant_id: Uuid,
         user_id: Uuid,
         id: Uuid,
         req: UpdateDocumentRequest,
     ) -> Result<Self, AppError> {
+        let metadata = req
+            .metadata
+            .map(Encrypted::<_, DocumentMetadata>::new)
+            .transpose()?;
         sqlx::query_as::<_, Document>(
             r#"
             UPDATE documents
@@ -133,9 +158,9 @@ impl Document {
                 document_type = COALESCE($7, document_type),
                 status = COALESCE($8, status),
                 updated_at = NOW()
-            WHERE id = $9 AND user_id = $10
+            WHERE id = $9 AND user_id = $10 AND tenant_id = $11
             RETURNING id, title, content, file_path, content_type, size,
-                      metadata, user_id, document_type, status, created_at, updated_at
+                      metadata, user_id, tenant_id, document_type, status, created_at, updated_at
             "#,
         )
         .bind(&req.title)
@@ -143,13 +168,20 @@ impl Document {
         .bind(&req.file_path)
         .bind(&req.content_type)
         .bind(req.size)
-        .bind(&req.metadata)
+        .bind(&metadata)
         .bind(&req.document_type)
         .bind(&req.status)
         .bind(id)
         .bind(user_id)
+        .bind(tenant_id)
         .fetch_one(pool)
         .await
         .map_err(|_| AppError::NotFound(format!("Document {} not found", id)))
     }
+
+    /// Encrypts metadata stored before encryption was introduced and
+    /// re-wraps metadata sealed with a rotated key-encryption key
+    pub async fn reencrypt_metadata(pool: &PgPool, tenant_id: Uuid) -> Result<u64, AppError> {
+        encryption::reseal_column::<serde_json::Value, DocumentMetadata>(pool, tenant_id).await
+    }
 }
@@ -1,4 +1,5 @@
 pub mod auth;
 pub mod document;
+pub mod tenant;
 pub mod user;
 pub mod workflow;
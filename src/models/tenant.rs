@@ -0,0 +1,117 @@
+use crate::error::AppError;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, PgPool};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum TenantStatus {
+    Active,
+    Suspended,
+    Deleted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum SubscriptionTier {
+    Free,
+    Professional,
+    Enterprise,
+    /// Limits are set per tenant
+    Custom,
+}
+
+/// What a tenant may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantLimits {
+    pub requests_per_day: u64,
+    pub max_users: u64,
+    pub max_documents: u64,
+    pub max_storage_bytes: u64,
+}
+
+impl TenantLimits {
+    /// Defaults of a tier; custom tenants start from the enterprise limits
+    pub fn for_tier(tier: SubscriptionTier) -> Self {
+        const GB: u64 = 1024 * 1024 * 1024;
+        match tier {
+            SubscriptionTier::Free => Self {
+                requests_per_day: 1_000,
+                max_users: 5,
+                max_documents: 1_000,
+                max_storage_bytes: GB,
+            },
+            SubscriptionTier::Professional => Self {
+                requests_per_day: 10_000,
+                max_users: 50,
+                max_documents: 50_000,
+                max_storage_bytes: 50 * GB,
+            },
+            SubscriptionTier::Enterprise | SubscriptionTier::Custom => Self {
+                requests_per_day: 100_000,
+                max_users: 1_000,
+                max_documents: 1_000_000,
+                max_storage_bytes: 1_000 * GB,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Tenant {
+    pub id: Uuid,
+    pub name: String,
+    /// Tenants can be addressed as `<subdomain>.<TENANT_BASE_DOMAIN>`
+    pub subdomain: String,
+    pub status: TenantStatus,
+    pub tier: SubscriptionTier,
+    /// Overrides the tier's limits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<Json<TenantLimits>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Tenant {
+    pub fn is_active(&self) -> bool {
+        self.status == TenantStatus::Active
+    }
+
+    pub fn limits(&self) -> TenantLimits {
+        self.limits
+            .as_ref()
+            .map_or_else(|| TenantLimits::for_tier(self.tier), |limits| limits.0)
+    }
+
+    pub async fn get(pool: &PgPool, id: Uuid) -> Result<Self, AppError> {
+        sqlx::query_as::<_, Tenant>(
+            r#"
+            SELECT id, name, subdomain, status, tier, limits, created_at, updated_at
+            FROM tenants
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .map_err(AppError::Database)?
+        .ok_or_else(|| AppError::NotFound(format!("Tenant {} not found", id)))
+    }
+
+    pub async fn find_by_subdomain(pool: &PgPool, subdomain: &str) -> Result<Self, AppError> {
+        sqlx::query_as::<_, Tenant>(
+            r#"
+            SELECT id, name, subdomain, status, tier, limits, created_at, updated_at
+            FROM tenants
+            WHERE subdomain = $1
+            "#,
+        )
+        .bind(subdomain)
+        .fetch_optional(pool)
+        .await
+        .map_err(AppError::Database)?
+        .ok_or_else(|| AppError::NotFound(format!("Tenant {} not found", subdomain)))
+    }
+}
@@ -1,4 +1,9 @@
-use crate::{db::users::User as DbUser, error::AppError};
+use crate::{
+    auth::password_hasher,
+    db::{sessions, users::User as DbUser},
+    error::AppError,
+};
+use lotabots_password::Verification;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use uuid::Uuid;
@@ -6,16 +11,22 @@ use uuid::Uuid;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub id: Uuid,
+    pub tenant_id: Uuid,
     pub email: String,
     pub role: String,
+    /// Number of devices the user is currently logged in on
+    #[serde(default)]
+    pub session_count: i64,
 }
 
 impl From<DbUser> for User {
     fn from(db_user: DbUser) -> Self {
         Self {
             id: db_user.id,
+            tenant_id: db_user.tenant_id,
             email: db_user.email,
             role: db_user.role,
+            session_count: 0,
         }
     }
 }
@@ -36,44 +47,70 @@ pub struct UpdateUserRequest {
 impl User {
     pub async fn authenticate(
         pool: &PgPool,
+        tenant_id: Uuid,
         email: &str,
         password: &str,
     ) -> Result<Self, AppError> {
         let user = sqlx::query_as!(
             DbUser,
             r#"
-            SELECT id, email, password_hash, role, created_at, updated_at
+            SELECT id, tenant_id, email, password_hash, role, created_at, updated_at
             FROM users
-            WHERE email = $1
+            WHERE email = $1 AND tenant_id = $2
             "#,
-            email
+            email,
+            tenant_id
         )
         .fetch_one(pool)
         .await
         .map_err(|_| AppError::Authentication("Invalid email or password".to_string()))?;
 
-        if !bcrypt::verify(password, &user.password_hash)
+        match password_hasher()
+            .verify(password, &user.password_hash)
             .map_err(|e| AppError::Internal(format!("Password verification error: {}", e)))?
         {
-            return Err(AppError::Authentication(
-                "Invalid email or password".to_string(),
-            ));
+            Verification::Invalid => {
+                return Err(AppError::Authentication(
+                    "Invalid email or password".to_string(),
+                ));
+            }
+            Verification::NeedsRehash(password_hash) => {
+                // The login already succeeded; a failed upgrade is retried
+                // on the next one
+                if let Err(e) = sqlx::query!(
+                    "UPDATE users SET password_hash = $1 WHERE id = $2",
+                    password_hash,
+                    user.id
+                )
+                .execute(pool)
+                .await
+                {
+                    tracing::warn!("Failed to upgrade password hash: {}", e);
+                }
+            }
+            Verification::Valid => {}
         }
 
         Ok(user.into())
     }
 
-    pub async fn create(pool: &PgPool, req: CreateUserRequest) -> Result<Self, AppError> {
-        let password_hash = bcrypt::hash(req.password.as_bytes(), bcrypt::DEFAULT_COST)
+    pub async fn create(
+        pool: &PgPool,
+        tenant_id: Uuid,
+        req: CreateUserRequest,
+    ) -> Result<Self, AppError> {
+        let password_hash = password_hasher()
+            .hash(&req.password)
             .map_err(|e| AppError::Internal(e.to_string()))?;
 
         let user = sqlx::query_as!(
             DbUser,
             r#"
-            INSERT INTO users (email, password_hash, role)
-            VALUES ($1, $2, 'user')
-            RETURNING id, email, password_hash, role, created_at, updated_at
+            INSERT INTO users (tenant_id, email, password_hash, role)
+            VALUES ($1, $2, $3, 'user')
+            RETURNING id, tenant_id, email, password_hash, role, created_at, updated_at
             "#,
+            tenant_id,
             req.email,
             password_hash,
         )
@@ -84,27 +121,42 @@ impl User {
         Ok(user.into())
     }
 
-    pub async fn get(pool: &PgPool, id: Uuid) -> Result<Self, AppError> {
+    pub async fn get(pool: &PgPool, tenant_id: Uuid, id: Uuid) -> Result<Self, AppError> {
         let user = sqlx::query_as!(
             DbUser,
             r#"
-            SELECT id, email, password_hash, role, created_at, updated_at
+            SELECT id, tenant_id, email, password_hash, role, created_at, updated_at
             FROM users
-            WHERE id = $1
+            WHERE id = $1 AND tenant_id = $2
             "#,
-            id
+            id,
+            tenant_id
         )
         .fetch_one(pool)
         .await
         .map_err(|_| AppError::NotFound(format!("User {} not found", id)))?;
 
-        Ok(user.into())
+        let mut user = Self::from(user);
+        user.load_session_count(pool).await?;
+        Ok(user)
     }
 
-    pub async fn update(pool: &PgPool, id: Uuid, req: UpdateUserRequest) -> Result<Self, AppError> {
+    /// Refreshes `session_count` from the active sessions
+    pub async fn load_session_count(&mut self, pool: &PgPool) -> Result<(), AppError> {
+        self.session_count = sessions::count_active_sessions(pool, self.id).await?;
+        Ok(())
+    }
+
+    pub async fn update(
+        pool: &PgPool,
+        tenant_id: Uuid,
+        id: Uuid,
+        req: UpdateUserRequest,
+    ) -> Result<Self, AppError> {
         let password_hash = if let Some(password) = req.password {
             Some(
-                bcrypt::hash(password.as_bytes(), bcrypt::DEFAULT_COST)
+                password_hasher()
+                    .hash(&password)
                     .map_err(|e| AppError::Internal(e.to_string()))?,
             )
         } else {
@@ -120,13 +172,14 @@ impl User {
                 password_hash = COALESCE($2, password_hash),
                 role = COALESCE($3, role),
                 updated_at = NOW()
-            WHERE id = $4
-            RETURNING id, email, password_hash, role, created_at, updated_at
+            WHERE id = $4 AND tenant_id = $5
+            RETURNING id, tenant_id, email, password_hash, role, created_at, updated_at
             "#,
             req.email,
             password_hash,
             req.role,
-            id
+            id,
+            tenant_id
         )
         .fetch_one(pool)
         .await
@@ -1,6 +1,7 @@
 use actix_web::{post, web, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 
+use crate::middleware::TenantMiddleware;
 use crate::services::auth_service::AuthService;
 use crate::services::user_service::UserService;
 use crate::error::ServiceError;
@@ -28,3 +29,12 @@ pub async fn login(
     let token = AuthService::generate_token(user.id.to_string())?;
     Ok(HttpResponse::Ok().json(LoginResponse { token }))
 }
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    // `UserService` only reads the users of the tenant resolved here
+    cfg.service(
+        web::scope("/auth")
+            .wrap(TenantMiddleware::from_env())
+            .service(login),
+    );
+}
@@ -1,31 +1,23 @@
 use dashmap::DashMap;
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use lotabots_secrets::{
+    SecretError, SecretHandle, SecretManager, SecretStream, VaultAuth, VaultSecretManager,
+    WatchOptions,
+};
 use std::{
+    fmt,
     sync::Arc,
     time::{Duration, Instant},
 };
 use tracing::{error, info};
-use vaultrs::{
-    client::{VaultClient, VaultClientSettingsBuilder},
-    error::ClientError,
-};
 
 use crate::config::AppConfig;
 
-/// Represents a secret value with optional metadata
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Secret {
-    /// The actual secret value
-    pub value: String,
-    /// Additional metadata associated with the secret
-    pub metadata: Option<serde_json::Value>,
-}
+pub use lotabots_secrets::Secret;
 
 /// A cached secret with expiration time
 #[derive(Debug, Clone)]
 struct CachedSecret {
-    /// The secret value and metadata
+    /// The secret value and version
     secret: Secret,
     /// When this cached value should expire
     expires_at: Instant,
@@ -36,37 +28,30 @@ struct CachedSecret {
 pub enum SecretsError {
     /// Vault client is not initialized
     VaultClientNotInitialized,
-    /// Error from the Vault client
-    VaultError(()),
-    /// Error during serialization/deserialization
-    SerializationError(String),
-    /// HTTP client error
-    HttpError(()),
+    /// Error from the secrets backend
+    Backend(SecretError),
 }
 
-impl From<ClientError> for SecretsError {
-    fn from(_err: ClientError) -> Self {
-        SecretsError::VaultError(())
+impl fmt::Display for SecretsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretsError::VaultClientNotInitialized => write!(f, "Vault is not configured"),
+            SecretsError::Backend(err) => write!(f, "{}", err),
+        }
     }
 }
 
-impl From<reqwest::Error> for SecretsError {
-    fn from(_err: reqwest::Error) -> Self {
-        SecretsError::HttpError(())
+impl From<SecretError> for SecretsError {
+    fn from(err: SecretError) -> Self {
+        SecretsError::Backend(err)
     }
 }
 
 /// Manages secrets using HashiCorp Vault with local caching
 #[derive(Clone)]
 pub struct SecretsManager {
-    /// Vault client for direct API access
-    client: Option<Arc<VaultClient>>,
-    /// HTTP client for REST API calls
-    http_client: Arc<Client>,
-    /// Base URL of the Vault server
-    base_url: String,
-    /// Authentication token for Vault
-    token: String,
+    /// Vault KV v2 backend from the shared secrets crate
+    client: Option<Arc<VaultSecretManager>>,
     /// Local cache of secrets
     cache: Arc<DashMap<String, CachedSecret>>,
     /// Time-to-live for cached secrets
@@ -79,46 +64,47 @@ impl SecretsManager {
     /// # Arguments
     /// * `config` - Application configuration containing Vault settings
     pub async fn new(config: &AppConfig) -> Self {
-        let http_client = Client::new();
-        let http_client = Arc::new(http_client);
-
-        let (client, base_url, token) = if let Some(vault_config) = &config.vault_config {
-            let token = vault_config.token.clone().unwrap_or_default();
-            let settings = VaultClientSettingsBuilder::default()
-                .address(&vault_config.address)
-                .token(&token)
-                .build()
-                .expect("Failed to build Vault client settings");
-
-            match VaultClient::new(settings) {
+        let client = if let Some(vault_config) = &config.vault_config {
+            // Prefer AppRole, whose tokens can be replaced when they expire
+            let auth = match (&vault_config.role_id, &vault_config.secret_id) {
+                (Some(role_id), Some(secret_id)) => VaultAuth::app_role(role_id, secret_id),
+                _ => VaultAuth::Token(vault_config.token.clone().unwrap_or_default()),
+            };
+            let settings = lotabots_secrets::VaultConfig::with_auth(&vault_config.address, auth);
+
+            match VaultSecretManager::new(settings) {
                 Ok(client) => {
                     info!("Successfully initialized Vault client");
-                    (Some(Arc::new(client)), vault_config.address.clone(), token)
+                    client.start_renewal();
+                    Some(Arc::new(client))
                 }
                 Err(err) => {
                     error!("Failed to create Vault client: {}", err);
-                    (None, String::new(), String::new())
+                    None
                 }
             }
         } else {
             info!("No Vault configuration provided, running without secrets management");
-            (None, String::new(), String::new())
+            None
         };
 
         SecretsManager {
             client,
-            http_client,
-            base_url,
-            token,
             cache: Arc::new(DashMap::new()),
             ttl: Duration::from_secs(300),
         }
     }
 
+    fn client(&self) -> Result<&VaultSecretManager, SecretsError> {
+        self.client
+            .as_deref()
+            .ok_or(SecretsError::VaultClientNotInitialized)
+    }
+
     /// Retrieves a secret from Vault or cache
     ///
     /// # Arguments
-    /// * `path` - Path to the secret in Vault
+    /// * `path` - Path to the secret in Vault, optionally with a `#field`
     ///
     /// # Returns
     /// * `Result<Secret, SecretsError>` - The secret value or an error
@@ -130,86 +116,80 @@ impl SecretsManager {
                 return Ok(cached.secret.clone());
             }
             info!("Cache expired for secret at path: {}", path);
-            self.cache.remove(path);
-        }
-
-        // If no client, return error
-        if self.client.is_none() {
-            return Err(SecretsError::VaultClientNotInitialized);
         }
+        self.cache.remove(path);
 
         info!("Fetching secret from Vault at path: {}", path);
+        let secret = self.client()?.get_secret(path).await?;
 
-        // Get from Vault using HTTP API
-        let url = format!("{}/v1/secret/data/{}", self.base_url, path);
-        let response = self
-            .http_client
-            .get(&url)
-            .header("X-Vault-Token", &self.token)
-            .send()
-            .await?
-            .json::<serde_json::Value>()
-            .await?;
-
-        let secret = match response.get("data").and_then(|d| d.get("data")) {
-            Some(value) => Secret {
-                value: serde_json::to_string(value).unwrap_or_default(),
-                metadata: None,
-            },
-            None => {
-                error!("Invalid secret format received from Vault");
-                return Err(SecretsError::SerializationError(
-                    "Invalid secret format".to_string(),
-                ));
-            }
-        };
-
-        // Cache the result
+        // Cache the result, no longer than its lease if it has one
         info!("Caching secret at path: {}", path);
         self.cache.insert(
             path.to_string(),
             CachedSecret {
                 secret: secret.clone(),
-                expires_at: Instant::now() + self.ttl,
+                expires_at: expires_at(&secret, self.ttl),
             },
         );
 
         Ok(secret)
     }
 
+    /// Watches a secret in Vault, yielding its current value and then every
+    /// change. Each change also refreshes the cache.
+    ///
+    /// # Arguments
+    /// * `path` - Path to the secret in Vault, optionally with a `#field`
+    pub fn subscribe(&self, path: &str) -> Result<SecretStream, SecretsError> {
+        let client = self
+            .client
+            .clone()
+            .ok_or(SecretsError::VaultClientNotInitialized)?;
+        Ok(lotabots_secrets::watch(
+            CachingClient {
+                client,
+                cache: self.cache.clone(),
+                ttl: self.ttl,
+            },
+            path,
+            WatchOptions::from_env(),
+        ))
+    }
+
+    /// Returns a handle that always holds the secret's latest value, for
+    /// consumers such as connection pools that read it on every use
+    ///
+    /// # Arguments
+    /// * `path` - Path to the secret in Vault, optionally with a `#field`
+    pub async fn handle(&self, path: &str) -> Result<SecretHandle, SecretsError> {
+        let client = self
+            .client
+            .clone()
+            .ok_or(SecretsError::VaultClientNotInitialized)?;
+        let handle = SecretHandle::watch(
+            CachingClient {
+                client,
+                cache: self.cache.clone(),
+                ttl: self.ttl,
+            },
+            path,
+            WatchOptions::from_env(),
+        )
+        .await?;
+        Ok(handle)
+    }
+
     /// Stores a secret in Vault
     ///
     /// # Arguments
     /// * `path` - Path where to store the secret
-    /// * `secret` - The secret to store
+    /// * `value` - The value to store
     ///
     /// # Returns
-    /// * `Result<(), SecretsError>` - Success or error
-    pub async fn set_secret(&self, path: &str, secret: Secret) -> Result<(), SecretsError> {
-        // If no client, return error
-        if self.client.is_none() {
-            return Err(SecretsError::VaultClientNotInitialized);
-        }
-
+    /// * `Result<Secret, SecretsError>` - The stored secret with its version
+    pub async fn set_secret(&self, path: &str, value: &str) -> Result<Secret, SecretsError> {
         info!("Setting secret at path: {}", path);
-
-        let value: serde_json::Value = serde_json::from_str(&secret.value)
-            .map_err(|e| SecretsError::SerializationError(e.to_string()))?;
-
-        let payload = serde_json::json!({
-            "data": {
-                "data": value
-            }
-        });
-
-        // Set in Vault using HTTP API
-        let url = format!("{}/v1/secret/data/{}", self.base_url, path);
-        self.http_client
-            .put(&url)
-            .header("X-Vault-Token", &self.token)
-            .json(&payload)
-            .send()
-            .await?;
+        let secret = self.client()?.create_secret(path, value).await?;
 
         // Update cache
         info!("Updating cache for path: {}", path);
@@ -217,11 +197,11 @@ impl SecretsManager {
             path.to_string(),
             CachedSecret {
                 secret: secret.clone(),
-                expires_at: Instant::now() + self.ttl,
+                expires_at: expires_at(&secret, self.ttl),
             },
         );
 
-        Ok(())
+        Ok(secret)
     }
 
     /// Deletes a secret from Vault
@@ -232,20 +212,8 @@ impl SecretsManager {
     /// # Returns
     /// * `Result<(), SecretsError>` - Success or error
     pub async fn delete_secret(&self, path: &str) -> Result<(), SecretsError> {
-        // If no client, return error
-        if self.client.is_none() {
-            return Err(SecretsError::VaultClientNotInitialized);
-        }
-
         info!("Deleting secret at path: {}", path);
-
-        // Delete from Vault using HTTP API
-        let url = format!("{}/v1/secret/data/{}", self.base_url, path);
-        self.http_client
-            .delete(&url)
-            .header("X-Vault-Token", &self.token)
-            .send()
-            .await?;
+        self.client()?.revoke_secret(path).await?;
 
         // Remove from cache
         info!("Removing from cache: {}", path);
@@ -255,6 +223,43 @@ impl SecretsManager {
     }
 }
 
+/// When a cached copy of `secret` goes stale: after `ttl`, or sooner if the
+/// secret's lease runs out first
+fn expires_at(secret: &Secret, ttl: Duration) -> Instant {
+    Instant::now() + secret.lease_duration.map_or(ttl, |lease| lease.min(ttl))
+}
+
+/// Vault client that stores what watches read in the shared cache, so
+/// `get_secret` callers see changes as soon as a watch does
+struct CachingClient {
+    client: Arc<VaultSecretManager>,
+    cache: Arc<DashMap<String, CachedSecret>>,
+    ttl: Duration,
+}
+
+#[async_trait::async_trait]
+impl SecretManager for CachingClient {
+    async fn get_secret(&self, name: &str) -> lotabots_secrets::Result<Secret> {
+        let secret = self.client.get_secret(name).await?;
+        self.cache.insert(
+            name.to_string(),
+            CachedSecret {
+                secret: secret.clone(),
+                expires_at: expires_at(&secret, self.ttl),
+            },
+        );
+        Ok(secret)
+    }
+
+    async fn create_secret(&self, name: &str, value: &str) -> lotabots_secrets::Result<Secret> {
+        self.client.create_secret(name, value).await
+    }
+
+    async fn revoke_secret(&self, name: &str) -> lotabots_secrets::Result<()> {
+        self.client.revoke_secret(name).await
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,25 +1,55 @@
-use crate::error::{AppError, AppResult};
-use crate::models::User;
-use bcrypt::{hash, verify, DEFAULT_COST};
+use crate::auth::password_hasher;
+use crate::db::encryption::{self, MfaSecret};
+use crate::{error::AppError, AppResult};
+use lotabots_db::TenantContext;
+use lotabots_password::Verification;
+use lotabots_secrets::Encrypted;
 use sqlx::PgPool;
 use uuid::Uuid;
 
+/// A user with the credentials and MFA state `UserService` works with. The
+/// MFA secret itself is only read decrypted, through
+/// [`UserService::get_mfa_secret`].
+#[derive(Debug, sqlx::FromRow)]
+pub struct User {
+    pub id: Uuid,
+    pub username: Option<String>,
+    pub email: String,
+    pub password_hash: String,
+    pub mfa_enabled: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Users of the tenant being served. Every method runs within the
+/// [`TenantContext`] set by `TenantMiddleware` and only reads or writes that
+/// tenant's users.
 pub struct UserService {
     pool: PgPool,
 }
 
+/// The tenant of the running request
+fn current_tenant() -> AppResult<Uuid> {
+    TenantContext::current()
+        .map(|context| context.tenant_id)
+        .ok_or_else(|| AppError::Internal("User query outside of a tenant context".to_string()))
+}
+
 impl UserService {
     pub fn new(pool: PgPool) -> Self {
         Self { pool }
     }
 
     pub async fn register_user(&self, username: &str, password: &str, email: &str) -> AppResult<User> {
+        let tenant_id = current_tenant()?;
+
         // Check if user already exists
         let existing_user = sqlx::query!(
             r#"
             SELECT id FROM users
-            WHERE username = $1 OR email = $2
+            WHERE tenant_id = $1 AND (username = $2 OR email = $3)
             "#,
+            tenant_id,
             username,
             email
         )
@@ -27,21 +57,23 @@ impl UserService {
         .await?;
 
         if existing_user.is_some() {
-            return Err(AppError::Validation("Username or email already exists".into()));
+            return Err(AppError::BadRequest("Username or email already exists".into()));
         }
 
         // Hash password
-        let password_hash = hash(password.as_bytes(), DEFAULT_COST)
+        let password_hash = password_hasher()
+            .hash(password)
             .map_err(|e| AppError::Internal(format!("Failed to hash password: {}", e)))?;
 
         // Create user
         let user = sqlx::query_as!(
             User,
             r#"
-            INSERT INTO users (username, email, password_hash)
-            VALUES ($1, $2, $3)
-            RETURNING id, username, email, password_hash, mfa_enabled, mfa_secret, created_at, updated_at
+            INSERT INTO users (tenant_id, username, email, password_hash)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, username, email, password_hash, mfa_enabled, created_at, updated_at
             "#,
+            tenant_id,
             username,
             email,
             password_hash
@@ -53,38 +85,65 @@ impl UserService {
     }
 
     pub async fn authenticate_user(&self, username: &str, password: &str) -> AppResult<User> {
+        let tenant_id = current_tenant()?;
         let user = sqlx::query_as!(
             User,
             r#"
-            SELECT id, username, email, password_hash, mfa_enabled, mfa_secret, created_at, updated_at
+            SELECT id, username, email, password_hash, mfa_enabled, created_at, updated_at
             FROM users
-            WHERE username = $1
+            WHERE tenant_id = $1 AND username = $2
             "#,
+            tenant_id,
             username
         )
         .fetch_optional(&self.pool)
         .await?
         .ok_or_else(|| AppError::Authentication("Invalid credentials".into()))?;
 
-        let valid = verify(password.as_bytes(), &user.password_hash)
+        let verification = password_hasher()
+            .verify(password, &user.password_hash)
             .map_err(|e| AppError::Internal(format!("Failed to verify password: {}", e)))?;
 
-        if !valid {
-            return Err(AppError::Authentication("Invalid credentials".into()));
+        match verification {
+            Verification::Invalid => {
+                return Err(AppError::Authentication("Invalid credentials".into()));
+            }
+            Verification::NeedsRehash(password_hash) => {
+                // The login already succeeded; a failed upgrade is retried
+                // on the next one
+                if let Err(e) = sqlx::query!(
+                    r#"
+                    UPDATE users
+                    SET password_hash = $1
+                    WHERE id = $2 AND tenant_id = $3
+                    "#,
+                    password_hash,
+                    user.id,
+                    tenant_id
+                )
+                .execute(&self.pool)
+                .await
+                {
+                    tracing::warn!("Failed to upgrade password hash: {}", e);
+                }
+            }
+            Verification::Valid => {}
         }
 
         Ok(user)
     }
 
     pub async fn get_user_by_id(&self, id: &Uuid) -> AppResult<Option<User>> {
+        let tenant_id = current_tenant()?;
         let user = sqlx::query_as!(
             User,
             r#"
-            SELECT id, username, email, password_hash, mfa_enabled, mfa_secret, created_at, updated_at
+            SELECT id, username, email, password_hash, mfa_enabled, created_at, updated_at
             FROM users
-            WHERE id = $1
+            WHERE id = $1 AND tenant_id = $2
             "#,
-            id
+            id,
+            tenant_id
         )
         .fetch_optional(&self.pool)
         .await?;
@@ -92,15 +151,19 @@ impl UserService {
         Ok(user)
     }
 
+    /// Stores the TOTP secret envelope encrypted
     pub async fn save_mfa_secret(&self, user_id: &Uuid, secret: &str) -> AppResult<()> {
+        let tenant_id = current_tenant()?;
+        let secret = Encrypted::<_, MfaSecret>::new(secret.to_string())?;
         sqlx::query!(
             r#"
             UPDATE users
             SET mfa_secret = $1
-            WHERE id = $2
+            WHERE id = $2 AND tenant_id = $3
             "#,
-            secret,
-            user_id
+            secret as _,
+            user_id,
+            tenant_id
         )
         .execute(&self.pool)
         .await?;
@@ -108,14 +171,43 @@ impl UserService {
         Ok(())
     }
 
+    /// The decrypted TOTP secret, if one was saved
+    pub async fn get_mfa_secret(&self, user_id: &Uuid) -> AppResult<Option<String>> {
+        let tenant_id = current_tenant()?;
+        let row = sqlx::query!(
+            r#"
+            SELECT mfa_secret as "mfa_secret: Encrypted<String, MfaSecret>"
+            FROM users
+            WHERE id = $1 AND tenant_id = $2
+            "#,
+            user_id,
+            tenant_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row
+            .and_then(|row| row.mfa_secret)
+            .map(Encrypted::into_inner))
+    }
+
+    /// Encrypts MFA secrets saved before encryption was introduced and
+    /// re-wraps those sealed with a rotated key-encryption key
+    pub async fn reencrypt_mfa_secrets(&self) -> AppResult<u64> {
+        let tenant_id = current_tenant()?;
+        encryption::reseal_column::<String, MfaSecret>(&self.pool, tenant_id).await
+    }
+
     pub async fn enable_mfa(&self, user_id: &Uuid) -> AppResult<()> {
+        let tenant_id = current_tenant()?;
         sqlx::query!(
             r#"
             UPDATE users
             SET mfa_enabled = true
-            WHERE id = $1
+            WHERE id = $1 AND tenant_id = $2
             "#,
-            user_id
+            user_id,
+            tenant_id
         )
         .execute(&self.pool)
         .await?;
@@ -124,13 +216,15 @@ impl UserService {
     }
 
     pub async fn disable_mfa(&self, user_id: &Uuid) -> AppResult<()> {
+        let tenant_id = current_tenant()?;
         sqlx::query!(
             r#"
             UPDATE users
             SET mfa_enabled = false, mfa_secret = NULL
-            WHERE id = $1
+            WHERE id = $1 AND tenant_id = $2
             "#,
-            user_id
+            user_id,
+            tenant_id
         )
         .execute(&self.pool)
         .await?;
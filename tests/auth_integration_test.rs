@@ -1,34 +1,29 @@
 use actix_web::{test, web, App};
-use document_automation::{
-    handlers,
-    models::auth::{CreateUserRequest, UserRole},
-};
-use dotenv::dotenv;
+use document_automation::handlers;
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use serde_json::json;
 use std::env;
+use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AuthResponse {
-    token: String,
-}
+mod common;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ValidateTokenResponse {
-    username: String,
-}
+/// Tenant created by the tenants migration
+const DEFAULT_TENANT: &str = "00000000-0000-0000-0000-000000000001";
 
-async fn setup_db() -> PgPool {
-    dotenv().ok();
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPool::connect(&database_url).await.unwrap();
-    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
-    pool
+#[derive(Debug, Serialize, Deserialize)]
+struct AuthResponse {
+    access_token: String,
+    refresh_token: String,
 }
 
 #[actix_web::test]
 async fn test_auth_flow() {
-    let pool = setup_db().await;
+    let Some(pool) = common::connect().await else {
+        return;
+    };
+    // Tokens are signed with JWT_SECRET and checked against JWT_KEY
+    env::set_var("JWT_SECRET", "auth-test-secret");
+    env::set_var("JWT_KEY", "auth-test-secret");
 
     let app = test::init_service(
         App::new()
@@ -36,55 +31,63 @@ async fn test_auth_flow() {
             .configure(handlers::auth::config),
     )
     .await;
+    let email = format!("{}@auth.test", Uuid::new_v4());
 
     // Test registration
-    let register_request = CreateUserRequest {
-        username: "testuser".to_string(),
-        password: "password123".to_string(),
-        email: "test@example.com".to_string(),
-        role: UserRole::User,
-    };
-
     let req = test::TestRequest::post()
         .uri("/auth/register")
-        .set_json(&register_request)
+        .insert_header(("X-Tenant-ID", DEFAULT_TENANT))
+        .set_json(json!({ "email": email, "password": "password123" }))
         .to_request();
     let resp = test::call_service(&app, req).await;
-    assert!(resp.status().is_success());
+    assert_eq!(resp.status(), 201);
 
     let register_body: AuthResponse = test::read_body_json(resp).await;
-    assert!(!register_body.token.is_empty());
+    assert!(!register_body.access_token.is_empty());
 
     // Test login
-    let login_request = serde_json::json!({
-        "username": "testuser",
-        "password": "password123"
-    });
-
     let req = test::TestRequest::post()
         .uri("/auth/login")
-        .set_json(&login_request)
+        .insert_header(("X-Tenant-ID", DEFAULT_TENANT))
+        .set_json(json!({ "email": email, "password": "password123" }))
         .to_request();
     let resp = test::call_service(&app, req).await;
-    assert!(resp.status().is_success());
+    assert_eq!(resp.status(), 200);
 
     let login_body: AuthResponse = test::read_body_json(resp).await;
-    assert!(!login_body.token.is_empty());
+    assert!(!login_body.access_token.is_empty());
 
-    // Test token validation
-    let req = test::TestRequest::get()
-        .uri("/auth/validate")
-        .insert_header(("Authorization", format!("Bearer {}", login_body.token)))
+    // Wrong password
+    let req = test::TestRequest::post()
+        .uri("/auth/login")
+        .insert_header(("X-Tenant-ID", DEFAULT_TENANT))
+        .set_json(json!({ "email": email, "password": "wrongpassword" }))
         .to_request();
     let resp = test::call_service(&app, req).await;
-    assert!(resp.status().is_success());
+    assert_eq!(resp.status(), 401);
+
+    // Test token refresh; a refresh token is single-use
+    let refresh = |token: &str| {
+        test::TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(json!({ "refresh_token": token }))
+            .to_request()
+    };
+    let resp = test::call_service(&app, refresh(&login_body.refresh_token)).await;
+    assert_eq!(resp.status(), 200);
+    let refreshed: AuthResponse = test::read_body_json(resp).await;
+    assert_ne!(refreshed.refresh_token, login_body.refresh_token);
 
-    let validate_body: ValidateTokenResponse = test::read_body_json(resp).await;
-    assert_eq!(validate_body.username, "testuser");
+    let resp = test::call_service(&app, refresh(&login_body.refresh_token)).await;
+    assert_eq!(resp.status(), 401);
 
     // Cleanup
-    sqlx::query!("DELETE FROM users WHERE username = $1", "testuser")
-        .execute(&pool)
-        .await
-        .unwrap();
+    sqlx::query!(
+        "DELETE FROM users WHERE email = $1 AND tenant_id = $2",
+        email,
+        Uuid::parse_str(DEFAULT_TENANT).unwrap()
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
 }
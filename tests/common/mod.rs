@@ -0,0 +1,92 @@
+use lotabots_db::{create_pool, DatabaseConfig};
+use sqlx::{Connection, PgConnection, PgPool};
+use url::Url;
+
+const APP_ROLE: &str = "document_automation_test";
+const APP_PASSWORD: &str = "document_automation_test";
+
+/// URL of the application test database. `None` when `TEST_DATABASE_URL`
+/// is not set.
+///
+/// Other services migrate the database `TEST_DATABASE_URL` names with a
+/// `users` table of their own, so the application uses one next to it with
+/// an `_app` suffix.
+pub fn database_url() -> Option<Url> {
+    let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL is not set; skipping");
+        return None;
+    };
+    let mut url = Url::parse(&url).expect("Invalid TEST_DATABASE_URL");
+    let database = format!("{}_app", url.path().trim_start_matches('/'));
+    url.set_path(&database);
+    Some(url)
+}
+
+/// Connects to the application test database and runs the migrations. The
+/// database is created on first use.
+pub async fn connect() -> Option<PgPool> {
+    let url = database_url()?;
+    let database = url.path().trim_start_matches('/');
+
+    // Connect to the database next to it, which exists already
+    let server = std::env::var("TEST_DATABASE_URL").unwrap();
+    let mut admin = PgConnection::connect(&server).await.unwrap();
+    let exists: bool =
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM pg_database WHERE datname = $1)")
+            .bind(database)
+            .fetch_one(&mut admin)
+            .await
+            .unwrap();
+    if !exists {
+        // Tests run concurrently; another one may have won the race
+        let _ = sqlx::query(&format!("CREATE DATABASE \"{}\"", database))
+            .execute(&mut admin)
+            .await;
+    }
+    admin.close().await.ok();
+
+    let pool = PgPool::connect(url.as_str()).await.unwrap();
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    Some(pool)
+}
+
+/// A pool with tenant isolation on the application test database, as an
+/// ordinary role that does not own the tables and so is subject to their
+/// row-level security policies. `admin` is a pool from [`connect`].
+#[allow(dead_code)]
+pub async fn connect_isolated(admin: &PgPool) -> PgPool {
+    sqlx::query(&format!(
+        r#"
+        DO $$ BEGIN
+            CREATE ROLE {role} LOGIN PASSWORD '{password}';
+        EXCEPTION WHEN duplicate_object OR unique_violation THEN NULL;
+        END $$
+        "#,
+        role = APP_ROLE,
+        password = APP_PASSWORD,
+    ))
+    .execute(admin)
+    .await
+    .unwrap();
+    sqlx::query(&format!(
+        "GRANT SELECT, INSERT, UPDATE, DELETE ON ALL TABLES IN SCHEMA public TO {}",
+        APP_ROLE
+    ))
+    .execute(admin)
+    .await
+    .unwrap();
+
+    let mut url = database_url().unwrap();
+    url.set_username(APP_ROLE).unwrap();
+    url.set_password(Some(APP_PASSWORD)).unwrap();
+    create_pool(&DatabaseConfig {
+        url: url.to_string(),
+        max_connections: 2,
+        min_connections: 0,
+        max_lifetime_secs: 60,
+        idle_timeout_secs: 60,
+        tenant_isolation: true,
+    })
+    .await
+    .unwrap()
+}
@@ -1,12 +1,10 @@
-use dotenv::dotenv;
-use sqlx::postgres::PgPool;
-use std::env;
+mod common;
 
 #[actix_web::test]
 async fn test_db_connection() {
-    dotenv().ok();
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPool::connect(&database_url).await.unwrap();
+    let Some(pool) = common::connect().await else {
+        return;
+    };
 
     // Test a simple query
     let result = sqlx::query!("SELECT 1 as one")
@@ -19,12 +17,10 @@ async fn test_db_connection() {
 
 #[actix_web::test]
 async fn test_db_migrations() {
-    dotenv().ok();
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPool::connect(&database_url).await.unwrap();
-
-    // Test that migrations can be applied
-    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    // Applies the migrations
+    let Some(pool) = common::connect().await else {
+        return;
+    };
 
     // Test that users table exists
     let result = sqlx::query!(
@@ -1,19 +1,22 @@
-use dotenv::dotenv;
-use sqlx::postgres::PgPool;
-use std::env;
+use uuid::Uuid;
+
+mod common;
 
 #[actix_web::test]
 async fn test_db_operations() {
-    dotenv().ok();
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPool::connect(&database_url).await.unwrap();
+    let Some(pool) = common::connect().await else {
+        return;
+    };
+    let email = format!("{}@example.com", Uuid::new_v4());
 
     // Test user creation
     let result = sqlx::query!(
-        "INSERT INTO users (username, password_hash, email) VALUES ($1, $2, $3) RETURNING id",
+        "INSERT INTO users (tenant_id, username, password_hash, email) VALUES ($1, $2, $3, $4) RETURNING id",
+        // Tenant created by the tenants migration
+        Uuid::from_u128(1),
         "testuser",
         "hashedpassword",
-        "test@example.com"
+        email
     )
     .fetch_one(&pool)
     .await
@@ -27,8 +30,8 @@ async fn test_db_operations() {
         .await
         .unwrap();
 
-    assert_eq!(user.username, "testuser");
-    assert_eq!(user.email, "test@example.com");
+    assert_eq!(user.username.as_deref(), Some("testuser"));
+    assert_eq!(user.email, email);
 
     // Cleanup
     sqlx::query!("DELETE FROM users WHERE id = $1", result.id)
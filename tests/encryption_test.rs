@@ -0,0 +1,65 @@
+//! Resealing encrypted columns through a pool with tenant isolation, as an
+//! ordinary role that does not own the tables and so is subject to their
+//! row-level security policies.
+
+use document_automation::db::encryption;
+use lotabots_secrets::{envelope, EnvelopeCipher};
+use uuid::Uuid;
+
+mod common;
+
+#[tokio::test]
+async fn test_reseal_all_under_tenant_isolation() {
+    let Some(admin) = common::connect().await else {
+        return;
+    };
+    envelope::install(EnvelopeCipher::new(1, &[7; 32]));
+
+    // Plaintext from before the columns were encrypted, in a tenant of its own
+    let tenant_id = Uuid::new_v4();
+    sqlx::query("INSERT INTO tenants (id, name, subdomain) VALUES ($1, 'Reseal', $2)")
+        .bind(tenant_id)
+        .bind(tenant_id.simple().to_string())
+        .execute(&admin)
+        .await
+        .unwrap();
+    let user_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO users (tenant_id, email, password_hash, mfa_secret)
+         VALUES ($1, 'reseal@example.com', 'unused', 'JBSWY3DPEHPK3PXP')
+         RETURNING id",
+    )
+    .bind(tenant_id)
+    .fetch_one(&admin)
+    .await
+    .unwrap();
+    let document_id: Uuid = sqlx::query_scalar(
+        "INSERT INTO documents
+             (tenant_id, user_id, title, file_path, content_type, size, document_type, metadata)
+         VALUES ($1, $2, 'contract', '/contract.pdf', 'application/pdf', 1, 'contract', $3)
+         RETURNING id",
+    )
+    .bind(tenant_id)
+    .bind(user_id)
+    .bind(r#"{"pages":3}"#)
+    .fetch_one(&admin)
+    .await
+    .unwrap();
+
+    let pool = common::connect_isolated(&admin).await;
+
+    assert!(encryption::reseal_all(&pool).await.unwrap() >= 2);
+
+    let mfa_secret: String = sqlx::query_scalar("SELECT mfa_secret FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(&admin)
+        .await
+        .unwrap();
+    assert_eq!(envelope::key_version(&mfa_secret), Some(1));
+
+    let metadata: String = sqlx::query_scalar("SELECT metadata FROM documents WHERE id = $1")
+        .bind(document_id)
+        .fetch_one(&admin)
+        .await
+        .unwrap();
+    assert_eq!(envelope::key_version(&metadata), Some(1));
+}
@@ -16,12 +16,14 @@ async fn test_api_routes() {
     let app = test::init_service(
         App::new()
             .configure(handlers::auth::config)
+            .configure(handlers::sessions::config)
             .configure(handlers::health::config),
     )
     .await;
 
-    let req = test::TestRequest::get().uri("/api/docs").to_request();
-    let resp = test::call_service(&app, req).await;
+    // Middleware answers with an error rather than a response
+    let req = test::TestRequest::get().uri("/sessions").to_request();
+    let err = test::try_call_service(&app, req).await.unwrap_err();
 
-    assert_eq!(resp.status().as_u16(), 401); // Should be unauthorized
+    assert_eq!(err.as_response_error().status_code().as_u16(), 401); // Should be unauthorized
 }
@@ -1,22 +1,34 @@
 use actix_web::{http::StatusCode, test, web, App};
-use document_automation::middleware::RateLimiter;
+use document_automation::middleware::{rate_limit::RateLimit, RateLimiter};
+use lotabots_rate_limit::MemoryStore;
+use std::{sync::Arc, time::Duration};
 
 #[actix_web::test]
 async fn test_rate_limiter() {
     let app = test::init_service(
         App::new()
-            .wrap(RateLimiter::new(1.0, 0.1)) // 1 request per 10 seconds
+            .wrap(RateLimiter::new(
+                Arc::new(MemoryStore::new()),
+                "test",
+                RateLimit::new(1, Duration::from_secs(10)),
+            ))
             .route("/test", web::get().to(|| async { "OK" })),
     )
     .await;
 
     // First request should succeed
-    let req = test::TestRequest::get().uri("/test").to_request();
+    let req = test::TestRequest::get()
+        .uri("/test")
+        .peer_addr("127.0.0.1:12345".parse().unwrap())
+        .to_request();
     let response = test::call_service(&app, req).await;
     assert_eq!(response.status(), StatusCode::OK);
 
     // Second request should be rate limited
-    let req = test::TestRequest::get().uri("/test").to_request();
+    let req = test::TestRequest::get()
+        .uri("/test")
+        .peer_addr("127.0.0.1:12345".parse().unwrap())
+        .to_request();
     let response = test::call_service(&app, req).await;
     assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
 }
@@ -0,0 +1,289 @@
+use actix_web::{test, web, App};
+use document_automation::{
+    auth::{create_access_token, create_refresh_token, refresh_token_expires_at, Claims},
+    db::sessions,
+    handlers,
+    models::user::{CreateUserRequest, User},
+};
+use serde_json::Value;
+use sqlx::PgPool;
+use std::env;
+use uuid::Uuid;
+
+mod common;
+
+/// Tenant created by the tenants migration
+const DEFAULT_TENANT: Uuid = Uuid::from_u128(1);
+
+async fn setup_db() -> Option<PgPool> {
+    // Tokens are signed with JWT_SECRET and checked against JWT_KEY
+    env::set_var("JWT_SECRET", "session-test-secret");
+    env::set_var("JWT_KEY", "session-test-secret");
+
+    common::connect().await
+}
+
+async fn create_user(pool: &PgPool) -> User {
+    User::create(
+        pool,
+        DEFAULT_TENANT,
+        CreateUserRequest {
+            email: format!("{}@sessions.test", Uuid::new_v4()),
+            password: "Password123!@#".to_string(),
+        },
+    )
+    .await
+    .unwrap()
+}
+
+/// Opens a session for `user` and returns it with its access token
+async fn login(pool: &PgPool, user: &User, user_agent: &str) -> (Uuid, String) {
+    let session = sessions::create_session(
+        pool,
+        DEFAULT_TENANT,
+        user.id,
+        Some(user_agent),
+        None,
+        Uuid::new_v4(),
+        refresh_token_expires_at(),
+    )
+    .await
+    .unwrap();
+    let claims = Claims::new(user.id.to_string(), user.role.clone())
+        .with_session(session.id)
+        .with_tenant(DEFAULT_TENANT);
+    (session.id, create_access_token(claims).unwrap())
+}
+
+fn bearer(token: &str) -> (&'static str, String) {
+    ("Authorization", format!("Bearer {}", token))
+}
+
+#[actix_web::test]
+async fn test_sessions_require_authentication() {
+    let Some(pool) = setup_db().await else {
+        return;
+    };
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .configure(handlers::sessions::config),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/sessions").to_request();
+    // Middleware answers with an error rather than a response
+    let err = test::try_call_service(&app, req).await.unwrap_err();
+    assert_eq!(err.as_response_error().status_code(), 401);
+}
+
+#[actix_web::test]
+async fn test_refresh_token_is_not_an_access_token() {
+    let Some(pool) = setup_db().await else {
+        return;
+    };
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .configure(handlers::sessions::config),
+    )
+    .await;
+
+    let user = create_user(&pool).await;
+    let (session, _) = login(&pool, &user, "laptop").await;
+    let claims = Claims::new(user.id.to_string(), user.role.clone())
+        .with_session(session)
+        .with_tenant(DEFAULT_TENANT);
+    let refresh_token = create_refresh_token(claims, Uuid::new_v4()).unwrap();
+
+    let req = test::TestRequest::get()
+        .uri("/sessions")
+        .insert_header(bearer(&refresh_token))
+        .to_request();
+    let err = test::try_call_service(&app, req).await.unwrap_err();
+    assert_eq!(err.as_response_error().status_code(), 401);
+}
+
+#[actix_web::test]
+async fn test_list_sessions_marks_current() {
+    let Some(pool) = setup_db().await else {
+        return;
+    };
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .configure(handlers::sessions::config),
+    )
+    .await;
+
+    let user = create_user(&pool).await;
+    let (laptop, token) = login(&pool, &user, "laptop").await;
+    let (phone, _) = login(&pool, &user, "phone").await;
+
+    let req = test::TestRequest::get()
+        .uri("/sessions")
+        .insert_header(bearer(&token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    let body: Vec<Value> = test::read_body_json(resp).await;
+    assert_eq!(body.len(), 2);
+    let current = |id: Uuid| {
+        body.iter()
+            .find(|session| session["id"] == id.to_string())
+            .map(|session| session["current"].as_bool().unwrap())
+            .unwrap()
+    };
+    assert!(current(laptop));
+    assert!(!current(phone));
+    assert!(body
+        .iter()
+        .all(|session| session.get("refresh_token_id").is_none()));
+}
+
+#[actix_web::test]
+async fn test_revoke_session_ends_its_access_token() {
+    let Some(pool) = setup_db().await else {
+        return;
+    };
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .configure(handlers::sessions::config),
+    )
+    .await;
+
+    let user = create_user(&pool).await;
+    let (_, laptop_token) = login(&pool, &user, "laptop").await;
+    let (phone, phone_token) = login(&pool, &user, "phone").await;
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/sessions/{}", phone))
+        .insert_header(bearer(&laptop_token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 204);
+
+    // Already revoked
+    let req = test::TestRequest::delete()
+        .uri(&format!("/sessions/{}", phone))
+        .insert_header(bearer(&laptop_token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+
+    // The revoked device is logged out; the other one is not
+    let req = test::TestRequest::get()
+        .uri("/sessions")
+        .insert_header(bearer(&phone_token))
+        .to_request();
+    let err = test::try_call_service(&app, req).await.unwrap_err();
+    assert_eq!(err.as_response_error().status_code(), 401);
+
+    let req = test::TestRequest::get()
+        .uri("/sessions")
+        .insert_header(bearer(&laptop_token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[actix_web::test]
+async fn test_revoke_session_of_another_user() {
+    let Some(pool) = setup_db().await else {
+        return;
+    };
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .configure(handlers::sessions::config),
+    )
+    .await;
+
+    let user = create_user(&pool).await;
+    let other = create_user(&pool).await;
+    let (_, token) = login(&pool, &user, "laptop").await;
+    let (other_session, _) = login(&pool, &other, "laptop").await;
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/sessions/{}", other_session))
+        .insert_header(bearer(&token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+    assert!(sessions::get_active_session(&pool, other_session)
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[actix_web::test]
+async fn test_revoke_all_sessions() {
+    let Some(pool) = setup_db().await else {
+        return;
+    };
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .configure(handlers::sessions::config),
+    )
+    .await;
+
+    let user = create_user(&pool).await;
+    let (_, laptop_token) = login(&pool, &user, "laptop").await;
+    let (_, phone_token) = login(&pool, &user, "phone").await;
+
+    let req = test::TestRequest::delete()
+        .uri("/sessions")
+        .insert_header(bearer(&laptop_token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["revoked"], 2);
+
+    // Every device is logged out, including the one that asked
+    for token in [&laptop_token, &phone_token] {
+        let req = test::TestRequest::get()
+            .uri("/sessions")
+            .insert_header(bearer(token))
+            .to_request();
+        let err = test::try_call_service(&app, req).await.unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 401);
+    }
+}
+
+#[actix_web::test]
+async fn test_sessions_under_tenant_isolation() {
+    let Some(pool) = setup_db().await else {
+        return;
+    };
+    let isolated = common::connect_isolated(&pool).await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(isolated.clone()))
+            .configure(handlers::sessions::config),
+    )
+    .await;
+
+    let user = create_user(&pool).await;
+    let (session, token) = login(&pool, &user, "laptop").await;
+
+    // Sessions are only visible within their tenant
+    assert!(sessions::get_active_session(&isolated, session)
+        .await
+        .unwrap()
+        .is_none());
+
+    let req = test::TestRequest::get()
+        .uri("/sessions")
+        .insert_header(bearer(&token))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    let body: Vec<Value> = test::read_body_json(resp).await;
+    assert_eq!(body.len(), 1);
+    assert_eq!(body[0]["id"], session.to_string());
+}